@@ -1,5 +1,5 @@
 # locally hosted server
-# ./target/debug/cli "http://localhost:8002/user" 1 6.1
+# ./target/debug/cli -s "http://localhost:8002" send 1 6.1
 
 # remote server
-./target/debug/cli "https://sciota.practica.site/user" 1 6.1
+./target/debug/cli -s "https://sciota.practica.site" send 1 6.1
@@ -3,95 +3,753 @@ extern crate serde_json;
 extern crate serde_derive;
 extern crate clap;
 
-use clap::{Arg, App, SubCommand};
-use reqwest;
-use serde_json::Value;
-use std::collections::HashMap;
-use std::time::SystemTime;
-use std::convert::TryInto;
+mod output;
+mod profile;
+mod stream;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use output::{Column, Format};
+use sciota_client::protocol::{SaveDevice, SaveMeasurement, SaveSensor};
+use sciota_client::{messages, Client, Queue, Uploader};
 use std::fs::File;
-use std::io::Read;
-use std::io::Write;
-use std::path::Path;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+//  cli login -s http://localhost:8002 -u me
+//  cli devices list
+//  cli send 1 5.1
+
+const DEVICE_COLUMNS: [Column; 4] = [
+  ("id", "id"),
+  ("name", "name"),
+  ("description", "description"),
+  ("changed", "changeddate"),
+];
+const SENSOR_COLUMNS: [Column; 5] = [
+  ("id", "id"),
+  ("device", "device"),
+  ("name", "name"),
+  ("description", "description"),
+  ("changed", "changeddate"),
+];
+const MEASUREMENT_COLUMNS: [Column; 3] = [
+  ("id", "id"),
+  ("measured", "measuredate"),
+  ("value", "value"),
+];
+const USER_COLUMNS: [Column; 8] = [
+  ("id", "id"),
+  ("name", "name"),
+  ("email", "email"),
+  ("registered", "registered"),
+  ("admin", "admin"),
+  ("disabled", "disabled"),
+  ("devices", "devices"),
+  ("measurements", "measurements"),
+];
+
+fn arg<'a>(sub: &'a ArgMatches, name: &str) -> Result<&'a str, String> {
+  sub
+    .value_of(name)
+    .ok_or_else(|| format!("missing {}", name))
+}
+
+fn id_arg(sub: &ArgMatches, name: &str) -> Result<i64, String> {
+  arg(sub, name)?
+    .parse::<i64>()
+    .map_err(|_| format!("{} must be a number", name))
+}
 
-//  ./target/debug/cli "http://localhost:8002/user" 1 5.1
+fn opt_ms_arg(sub: &ArgMatches, name: &str) -> Result<Option<i64>, String> {
+  match sub.value_of(name) {
+    Some(v) => v
+      .parse::<i64>()
+      .map(Some)
+      .map_err(|_| format!("{} must be milliseconds since the epoch", name)),
+    None => Ok(None),
+  }
+}
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct UserMessage {
-  pub uid: String,
-  pwd: String,
-  what: String,
-  data: Option<serde_json::Value>,
+fn prompt(text: &str) -> Result<String, Box<dyn std::error::Error>> {
+  eprint!("{}", text);
+  std::io::stderr().flush()?;
+  let mut line = String::new();
+  std::io::stdin().lock().read_line(&mut line)?;
+  Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct SaveMeasurement {
-  value: f64,
+// check the login with the server, and save it as the profile.
+async fn login(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+  let name = profile::profile_name(matches);
+  let mut profiles = profile::load_profiles()?;
+  let saved = profiles.get(name.as_str()).cloned();
+
+  let server = match profile::setting(matches, "server", "SCIOTA_SERVER") {
+    Some(s) => s,
+    None => match &saved {
+      Some(p) => p.server.clone(),
+      None => prompt("server: ")?,
+    },
+  };
+  let user = match profile::setting(matches, "user", "SCIOTA_USER") {
+    Some(u) => u,
+    None => match &saved {
+      Some(p) => p.user.clone(),
+      None => prompt("user: ")?,
+    },
+  };
+  let password = match profile::setting(matches, "password", "SCIOTA_PASSWORD") {
+    Some(p) => p,
+    None => prompt("password: ")?,
+  };
+
+  let p = profile::Profile {
+    server: profile::base_url(server.as_str()),
+    user,
+    password,
+  };
+  p.client().call(messages::login()).await?;
+
+  profiles.insert(name.clone(), p);
+  let path = profile::save_profiles(&profiles)?;
+  eprintln!("logged in; saved profile '{}' in {}", name, path.display());
+  Ok(())
+}
+
+fn logout(matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+  let name = profile::profile_name(matches);
+  let mut profiles = profile::load_profiles()?;
+  match profiles.remove(name.as_str()) {
+    Some(_) => {
+      profile::save_profiles(&profiles)?;
+      eprintln!("removed profile '{}'", name);
+    }
+    None => eprintln!("no profile '{}'", name),
+  }
+  Ok(())
+}
+
+// post 'count' savemeasurement messages with 'concurrency' requests in flight,
+// and report the insert rate.  Returns (succeeded, failed).
+async fn bench(
+  client: &Client,
   sensor: i64,
-  measuredate: i64,
+  count: usize,
+  concurrency: usize,
+) -> Result<(usize, usize), Box<dyn std::error::Error>> {
+  let mut tasks = Vec::new();
+
+  for t in 0..concurrency {
+    let client = client.clone();
+    let n = count / concurrency + if t < count % concurrency { 1 } else { 0 };
+    tasks.push(tokio::spawn(async move {
+      let mut ok = 0;
+      let mut failed = 0;
+      for i in 0..n {
+        let sm = SaveMeasurement {
+          value: i as f64,
+          sensor,
+          measuredate: sciota_client::now(),
+        };
+        match client.call(messages::save_measurement(&sm)).await {
+          Ok(_) => ok += 1,
+          Err(_) => failed += 1,
+        }
+      }
+      (ok, failed)
+    }));
+  }
+
+  let mut ok = 0;
+  let mut failed = 0;
+  for task in tasks {
+    let (o, f) = task.await?;
+    ok += o;
+    failed += f;
+  }
+
+  Ok((ok, failed))
 }
 
-pub fn now() -> Result<i64, Box<dyn std::error::Error>> {
-  let nowsecs = SystemTime::now()
-    .duration_since(SystemTime::UNIX_EPOCH)
-    .map(|n| n.as_millis())?;
-  let s: i64 = nowsecs.try_into()?;
-  Ok(s)
+fn admin_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+  SubCommand::with_name(name).about(about).arg(
+    Arg::with_name("name")
+      .help("user name")
+      .required(true)
+      .index(1),
+  )
 }
 
-pub fn write_string(file_name: &str, text: &str) -> Result<usize, Box<dyn std::error::Error>> {
-  let path = &Path::new(&file_name);
-  let mut inf = File::create(path)?;
-  Ok(inf.write(text.as_bytes())?)
+fn id_subcommand<'a, 'b>(name: &'a str, about: &'a str, what: &'a str) -> App<'a, 'b> {
+  SubCommand::with_name(name)
+    .about(about)
+    .arg(Arg::with_name("id").help(what).required(true).index(1))
 }
 
+fn description_arg<'a, 'b>() -> Arg<'a, 'b> {
+  Arg::with_name("description")
+    .help("description")
+    .short("d")
+    .long("description")
+    .takes_value(true)
+    .default_value("")
+}
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-
-  let matches = App::new("measurelog cli - http")
-                          .version("1.0")
-                          .author("Ben Burdette")
-                          .about("sends a measurement to the measurelog server")
-                          .arg(Arg::with_name("server")
-                               .help("server address")
-                               .required(true)
-                               .index(1))
-                          .arg(Arg::with_name("sensor")
-                               .help("sensor id")
-                               .required(true)
-                               .index(2))
-                          .arg(Arg::with_name("value")
-                               .help("value")
-                               .required(true)
-                               .index(3))
-                          .get_matches();
-
-  let sm = SaveMeasurement {
-    value: matches.value_of("value").ok_or("bad value")?.parse::<f64>()?,
-    sensor: matches.value_of("sensor").ok_or("wat")?.parse::<i64>()?,
-    measuredate: now()?,
+// options shared by 'pipe' and 'tail'.
+fn stream_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+  SubCommand::with_name(name)
+    .about(about)
+    .arg(
+      Arg::with_name("sensor")
+        .help("sensor id for lines that are just a value")
+        .long("sensor")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("batch")
+        .help("measurements per upload")
+        .long("batch")
+        .default_value("100"),
+    )
+    .arg(
+      Arg::with_name("interval")
+        .help("seconds between uploads of partial batches")
+        .long("interval")
+        .default_value("5"),
+    )
+    .arg(
+      Arg::with_name("spool")
+        .help(
+          "file for measurements waiting to be uploaded; \
+       ~/.local/share/sciota/spool-<profile>.jsonl if missing",
+        )
+        .long("spool")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("spool-max")
+        .help("most measurements to keep in the spool; the oldest are dropped")
+        .long("spool-max")
+        .default_value("1000000"),
+    )
+    .arg(
+      Arg::with_name("drain")
+        .help("seconds to keep retrying at the end of the input")
+        .long("drain")
+        .default_value("60"),
+    )
+}
+
+// the command line: global options, then the subcommands.
+fn app<'a, 'b>() -> App<'a, 'b> {
+  App::new("sciota cli")
+    .version("1.0")
+    .author("Ben Burdette")
+    .about(
+      "talks to a sciota server.  The server and login come from the \
+       options, then SCIOTA_SERVER, SCIOTA_USER and SCIOTA_PASSWORD, \
+       then the profile saved by 'login'.",
+    )
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .arg(
+      Arg::with_name("server")
+        .help("server address, as in http://localhost:8002")
+        .short("s")
+        .long("server")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::with_name("user")
+        .help("user name")
+        .short("u")
+        .long("user")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::with_name("password")
+        .help("password")
+        .short("p")
+        .long("password")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::with_name("profile")
+        .help("saved login to use; SCIOTA_PROFILE or 'default' if missing")
+        .short("P")
+        .long("profile")
+        .takes_value(true)
+        .global(true),
+    )
+    .arg(
+      Arg::with_name("output")
+        .help("output format")
+        .short("o")
+        .long("output")
+        .possible_values(&["table", "json"])
+        .default_value("table")
+        .global(true),
+    )
+    .subcommand(
+      SubCommand::with_name("login")
+        .about("checks a login with the server and saves it as a profile"),
+    )
+    .subcommand(SubCommand::with_name("logout").about("removes a saved profile"))
+    .subcommand(devices_subcommand())
+    .subcommand(sensors_subcommand())
+    .subcommand(send_subcommand())
+    .subcommand(query_subcommand())
+    .subcommand(stream_subcommand(
+      "pipe",
+      "uploads measurements read from stdin, one per line: \
+       value, sensor,value or sensor,value,measuredate",
+    ))
+    .subcommand(tail_subcommand())
+    .subcommand(bench_subcommand())
+    .subcommand(
+      SubCommand::with_name("users").about("admin: list users with device and measurement counts"),
+    )
+    .subcommand(
+      SubCommand::with_name("export")
+        .about("saves all your devices, sensors and measurements to an archive file")
+        .arg(
+          Arg::with_name("file")
+            .help("archive file to write")
+            .required(true)
+            .index(1),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("import")
+        .about("adds the contents of an archive file to your account")
+        .arg(
+          Arg::with_name("file")
+            .help("archive file from 'export'")
+            .required(true)
+            .index(1),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("backup")
+        .about("admin: snapshot the server's database into its backup dir"),
+    )
+    .subcommand(admin_subcommand(
+      "enable-user",
+      "admin: enable a user account",
+    ))
+    .subcommand(admin_subcommand(
+      "disable-user",
+      "admin: disable a user account",
+    ))
+    .subcommand(admin_subcommand(
+      "delete-user",
+      "admin: delete a user and all their data",
+    ))
+    .subcommand(admin_subcommand(
+      "confirm-user",
+      "admin: complete a user's registration",
+    ))
+    .subcommand(impersonate_subcommand())
+}
+
+fn devices_subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("devices")
+    .about("lists, creates and deletes devices")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(SubCommand::with_name("list").about("lists your devices"))
+    .subcommand(
+      SubCommand::with_name("create")
+        .about("creates a device and prints its id")
+        .arg(
+          Arg::with_name("name")
+            .help("device name")
+            .required(true)
+            .index(1),
+        )
+        .arg(description_arg()),
+    )
+    .subcommand(id_subcommand(
+      "delete",
+      "deletes a device, its sensors and their measurements",
+      "device id",
+    ))
+}
+
+fn sensors_subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("sensors")
+    .about("lists, creates and deletes sensors")
+    .setting(AppSettings::SubcommandRequiredElseHelp)
+    .subcommand(
+      SubCommand::with_name("list")
+        .about("lists a device's sensors")
+        .arg(
+          Arg::with_name("device")
+            .help("device id")
+            .required(true)
+            .index(1),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("create")
+        .about("creates a sensor on a device")
+        .arg(
+          Arg::with_name("device")
+            .help("device id")
+            .required(true)
+            .index(1),
+        )
+        .arg(
+          Arg::with_name("name")
+            .help("sensor name")
+            .required(true)
+            .index(2),
+        )
+        .arg(description_arg()),
+    )
+    .subcommand(id_subcommand(
+      "delete",
+      "deletes a sensor and its measurements",
+      "sensor id",
+    ))
+}
+
+fn send_subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("send")
+    .about("sends a measurement")
+    .alias("measure")
+    .arg(
+      Arg::with_name("sensor")
+        .help("sensor id")
+        .required(true)
+        .index(1),
+    )
+    .arg(
+      Arg::with_name("value")
+        .help("value")
+        .required(true)
+        .allow_hyphen_values(true)
+        .index(2),
+    )
+    .arg(
+      Arg::with_name("date")
+        .help("measurement date, in ms since the epoch; now if missing")
+        .long("date")
+        .takes_value(true),
+    )
+}
+
+fn query_subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("query")
+    .about("lists a sensor's measurements")
+    .arg(
+      Arg::with_name("sensor")
+        .help("sensor id")
+        .required(true)
+        .index(1),
+    )
+    .arg(
+      Arg::with_name("from")
+        .help("earliest measurement date, in ms since the epoch")
+        .long("from")
+        .takes_value(true)
+        .allow_hyphen_values(true),
+    )
+    .arg(
+      Arg::with_name("to")
+        .help("measurement dates before this, in ms since the epoch")
+        .long("to")
+        .takes_value(true)
+        .allow_hyphen_values(true),
+    )
+}
+
+fn tail_subcommand<'a, 'b>() -> App<'a, 'b> {
+  stream_subcommand(
+    "tail",
+    "uploads measurements read from a serial port, in the same \
+       line formats as 'pipe'",
+  )
+  .arg(
+    Arg::with_name("device")
+      .help("serial device, as in /dev/ttyUSB0")
+      .required(true)
+      .index(1),
+  )
+  .arg(
+    Arg::with_name("baud")
+      .help("baud rate")
+      .long("baud")
+      .default_value("9600"),
+  )
+}
+
+fn bench_subcommand<'a, 'b>() -> App<'a, 'b> {
+  SubCommand::with_name("bench")
+    .about("times savemeasurement inserts against a server")
+    .arg(
+      Arg::with_name("sensor")
+        .help("sensor id")
+        .required(true)
+        .index(1),
+    )
+    .arg(
+      Arg::with_name("count")
+        .help("number of measurements to send")
+        .short("n")
+        .long("count")
+        .default_value("1000"),
+    )
+    .arg(
+      Arg::with_name("concurrency")
+        .help("number of requests in flight")
+        .short("c")
+        .long("concurrency")
+        .default_value("8"),
+    )
+}
+
+fn impersonate_subcommand<'a, 'b>() -> App<'a, 'b> {
+  admin_subcommand(
+    "impersonate",
+    "admin: run a read-only request as another user",
+  )
+  .arg(
+    Arg::with_name("what")
+      .help("what code: getdevicelisting, getsensorlisting or getmeasurementlisting")
+      .required(true)
+      .index(2),
+  )
+  .arg(
+    Arg::with_name("data")
+      .help("json data for the request")
+      .index(3),
+  )
+}
+
+async fn stream(
+  client: &Client,
+  cmd: &str,
+  sub: &ArgMatches<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let options = stream::Options {
+    sensor: match sub.value_of("sensor") {
+      Some(_) => Some(id_arg(sub, "sensor")?),
+      None => None,
+    },
+    interval: Duration::from_secs(arg(sub, "interval")?.parse::<u64>()?),
+    drain: Duration::from_secs(arg(sub, "drain")?.parse::<u64>()?),
+  };
+
+  let path = match sub.value_of("spool") {
+    Some(p) => PathBuf::from(p),
+    None => stream::default_spool(profile::profile_name(sub).as_str())?,
   };
+  let spool = Queue::open(path.as_path(), arg(sub, "spool-max")?.parse::<usize>()?)?;
+  if !spool.is_empty() {
+    eprintln!("{} measurements spooled from before", spool.len());
+  }
+  let mut uploader = Uploader::new(spool, arg(sub, "batch")?.parse::<usize>()?);
 
-  let um = UserMessage {
-    uid: "meh".to_string(),
-    pwd: "meh".to_string(),
-    what: "savemeasurement".to_string(),
-    data: Some(serde_json::to_value(sm)?),
+  let lines = if cmd == "tail" {
+    stream::read_serial(
+      arg(sub, "device")?.to_string(),
+      arg(sub, "baud")?.parse::<u32>()?,
+    )
+  } else {
+    stream::read_lines(BufReader::new(std::io::stdin()))
   };
 
-  write_string("message.txt", serde_json::to_string(&um)?.as_str())?;
+  stream::run(client, &mut uploader, lines, &options).await
+}
+
+async fn devices(
+  client: &Client,
+  format: Format,
+  sub: &ArgMatches<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  match sub.subcommand() {
+    ("list", Some(_)) => {
+      let res = client.call(messages::device_listing()).await?;
+      output::print(format, &serde_json::to_value(res)?, &DEVICE_COLUMNS)?;
+    }
+    ("create", Some(sub)) => {
+      let sd = SaveDevice {
+        id: None,
+        name: arg(sub, "name")?.to_string(),
+        description: arg(sub, "description")?.to_string(),
+      };
+      let id = client.call(messages::save_device(&sd)).await?;
+      output::print(format, &serde_json::to_value(id)?, &[])?;
+    }
+    ("delete", Some(sub)) => {
+      let id = id_arg(sub, "id")?;
+      client.call(messages::delete_device(id)).await?;
+    }
+    _ => (),
+  }
+  Ok(())
+}
+
+async fn sensors(
+  client: &Client,
+  format: Format,
+  sub: &ArgMatches<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  match sub.subcommand() {
+    ("list", Some(sub)) => {
+      let device = id_arg(sub, "device")?;
+      let res = client.call(messages::sensor_listing(device)).await?;
+      output::print(format, &serde_json::to_value(res)?, &SENSOR_COLUMNS)?;
+    }
+    ("create", Some(sub)) => {
+      let ss = SaveSensor {
+        id: None,
+        device: id_arg(sub, "device")?,
+        name: arg(sub, "name")?.to_string(),
+        description: arg(sub, "description")?.to_string(),
+      };
+      let res = client.call(messages::save_sensor(&ss)).await?;
+      output::print(format, &serde_json::to_value(res)?, &SENSOR_COLUMNS)?;
+    }
+    ("delete", Some(sub)) => {
+      let id = id_arg(sub, "id")?;
+      client.call(messages::delete_sensor(id)).await?;
+    }
+    _ => (),
+  }
+  Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+  match run().await {
+    Ok(()) => (),
+    Err(e) => {
+      eprintln!("error: {}", e);
+      std::process::exit(1);
+    }
+  }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+  let matches = app().get_matches();
+
+  // global args are only filled in on the subcommand's matches.
+  let (cmd, sub) = match matches.subcommand() {
+    (cmd, Some(sub)) => (cmd, sub),
+    _ => return Ok(()),
+  };
+
+  match cmd {
+    "login" => return login(sub).await,
+    "logout" => return logout(sub),
+    _ => (),
+  }
+
+  let format = Format::parse(arg(sub, "output")?)?;
+  let client = profile::resolve(sub)?.client();
 
-  write_string("message-pretty.txt", serde_json::to_string_pretty(&um)?.as_str())?;
+  match cmd {
+    "devices" => devices(&client, format, sub).await?,
+    "pipe" | "tail" => stream(&client, cmd, sub).await?,
+    "sensors" => sensors(&client, format, sub).await?,
+    "send" => {
+      let sm = SaveMeasurement {
+        value: arg(sub, "value")?
+          .parse::<f64>()
+          .map_err(|_| "value must be a number")?,
+        sensor: id_arg(sub, "sensor")?,
+        measuredate: match opt_ms_arg(sub, "date")? {
+          Some(d) => d,
+          None => sciota_client::now(),
+        },
+      };
+      let id = client.call(messages::save_measurement(&sm)).await?;
+      output::print(format, &serde_json::to_value(id)?, &[])?;
+    }
+    "query" => {
+      let res = client
+        .measurements(
+          id_arg(sub, "sensor")?,
+          opt_ms_arg(sub, "from")?,
+          opt_ms_arg(sub, "to")?,
+        )
+        .await?;
+      output::print(format, &serde_json::to_value(res)?, &MEASUREMENT_COLUMNS)?;
+    }
+    "bench" => {
+      let count = arg(sub, "count")?.parse::<usize>()?;
+      let concurrency = arg(sub, "concurrency")?.parse::<usize>()?.max(1);
+      let sensor = id_arg(sub, "sensor")?;
 
-  let client = reqwest::Client::new();
-  let res = client
-    .post(matches.value_of("server").ok_or("wat")?)
-    .json(&um)
-    .send()
-    .await?;
+      let start = Instant::now();
+      let (ok, failed) = bench(&client, sensor, count, concurrency).await?;
+      let secs = start.elapsed().as_secs_f64();
 
-  println!("result: {:?}", res);
+      println!(
+        "{} inserts, {} failed, in {:.2}s: {:.1} inserts/sec",
+        ok,
+        failed,
+        secs,
+        ok as f64 / secs
+      );
+    }
+    "users" => {
+      let res = client.call(messages::user_listing()).await?;
+      output::print(format, &res, &USER_COLUMNS)?;
+    }
+    "export" => {
+      let res = client.call(messages::export_data()).await?;
+      let file = File::create(arg(sub, "file")?)?;
+      serde_json::to_writer(file, &res)?;
+    }
+    "import" => {
+      let archive: serde_json::Value = serde_json::from_reader(File::open(arg(sub, "file")?)?)?;
+      let res = client.call(messages::import_data(&archive)).await?;
+      output::print(
+        format,
+        &res,
+        &[
+          ("devices", "devices"),
+          ("sensors", "sensors"),
+          ("measurements", "measurements"),
+        ],
+      )?;
+    }
+    "backup" => {
+      let path = client.call(messages::backup()).await?;
+      output::print(format, &serde_json::to_value(path)?, &[])?;
+    }
+    "impersonate" => {
+      let data = match sub.value_of("data") {
+        Some(d) => Some(serde_json::from_str::<serde_json::Value>(d)?),
+        None => None,
+      };
+      let imp = serde_json::json!({
+        "user": arg(sub, "name")?,
+        "what": arg(sub, "what")?,
+        "data": data,
+      });
+      let um = client.user_message("impersonate", Some(imp));
+      // the reply's 'what' depends on the impersonated what code.
+      let res = client.send(&um).await?;
+      println!("{}", serde_json::to_string_pretty(&res)?);
+    }
+    cmd => {
+      let name = arg(sub, "name")?;
+      let call = match cmd {
+        "enable-user" => messages::enable_user(name),
+        "disable-user" => messages::disable_user(name),
+        "delete-user" => messages::delete_user(name),
+        "confirm-user" => messages::confirm_user(name),
+        _ => Err(format!("unknown command: {}", cmd))?,
+      };
+      client.call(call).await?;
+    }
+  }
 
   Ok(())
 }
@@ -0,0 +1,110 @@
+use serde_json::Value;
+
+// command output: aligned tables for people, or the server's json as is.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Table,
+  Json,
+}
+
+impl Format {
+  pub fn parse(s: &str) -> Result<Format, String> {
+    match s {
+      "table" => Ok(Format::Table),
+      "json" => Ok(Format::Json),
+      _ => Err(format!("unknown output format: {}", s)),
+    }
+  }
+}
+
+// a table column: its heading and the object field it shows.  Fields ending
+// in 'date' are milliseconds since the epoch, shown as UTC times.
+pub type Column = (&'static str, &'static str);
+
+// "YYYY-MM-DD HH:MM:SS" in UTC, from ms since the epoch.
+pub fn format_date(ms: i64) -> String {
+  let secs = ms.div_euclid(1000);
+  let (days, daysecs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+  // days to a civil date; see http://howardhinnant.github.io/date_algorithms.html
+  let z = days + 719468;
+  let era = z.div_euclid(146097);
+  let doe = z - era * 146097;
+  let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let d = doy - (153 * mp + 2) / 5 + 1;
+  let m = if mp < 10 { mp + 3 } else { mp - 9 };
+  let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+  format!(
+    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+    y,
+    m,
+    d,
+    daysecs / 3600,
+    daysecs % 3600 / 60,
+    daysecs % 60
+  )
+}
+
+fn cell(field: &str, v: &Value) -> String {
+  match v {
+    Value::Null => "".to_string(),
+    Value::String(s) => s.clone(),
+    Value::Number(n) if field.ends_with("date") => match n.as_i64() {
+      Some(ms) => format_date(ms),
+      None => n.to_string(),
+    },
+    v => v.to_string(),
+  }
+}
+
+pub fn table(rows: &[Value], columns: &[Column]) -> String {
+  let cells: Vec<Vec<String>> = rows
+    .iter()
+    .map(|r| columns.iter().map(|(_, f)| cell(f, &r[*f])).collect())
+    .collect();
+
+  let widths: Vec<usize> = columns
+    .iter()
+    .enumerate()
+    .map(|(i, (h, _))| {
+      cells
+        .iter()
+        .map(|r| r[i].chars().count())
+        .fold(h.len(), usize::max)
+    })
+    .collect();
+
+  let line = |vals: Vec<&str>| {
+    vals
+      .iter()
+      .zip(widths.iter())
+      .map(|(v, w)| format!("{:<w$}", v, w = w))
+      .collect::<Vec<String>>()
+      .join("  ")
+      .trim_end()
+      .to_string()
+  };
+
+  let mut out = vec![line(columns.iter().map(|(h, _)| *h).collect())];
+  for r in cells.iter() {
+    out.push(line(r.iter().map(|c| c.as_str()).collect()));
+  }
+  out.join("\n")
+}
+
+// print a reply: a table of its rows (or of the one object), or its json.
+pub fn print(format: Format, v: &Value, columns: &[Column]) -> Result<(), serde_json::Error> {
+  match format {
+    Format::Json => println!("{}", serde_json::to_string_pretty(v)?),
+    Format::Table => match v {
+      Value::Array(rows) => println!("{}", table(rows, columns)),
+      Value::Object(_) => println!("{}", table(std::slice::from_ref(v), columns)),
+      v => println!("{}", cell("", v)),
+    },
+  }
+  Ok(())
+}
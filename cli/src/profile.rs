@@ -0,0 +1,132 @@
+use clap::ArgMatches;
+use sciota_client::Client;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+// saved logins, by profile name, in $SCIOTA_CONFIG or
+// ~/.config/sciota/profiles.json.  The server doesn't issue tokens, so a
+// profile holds the password; the file is only readable by its owner.
+//
+// Each setting comes from, in order: the command line, the environment
+// (SCIOTA_SERVER, SCIOTA_USER, SCIOTA_PASSWORD), then the profile named by
+// --profile or SCIOTA_PROFILE, "default" if neither.
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Profile {
+  pub server: String,
+  pub user: String,
+  pub password: String,
+}
+
+impl Profile {
+  pub fn client(&self) -> Client {
+    Client::new(
+      self.server.as_str(),
+      self.user.as_str(),
+      self.password.as_str(),
+    )
+  }
+}
+
+pub type Profiles = BTreeMap<String, Profile>;
+
+pub fn profiles_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+  if let Ok(p) = env::var("SCIOTA_CONFIG") {
+    return Ok(PathBuf::from(p));
+  }
+  let config = match env::var("XDG_CONFIG_HOME") {
+    Ok(c) if !c.is_empty() => PathBuf::from(c),
+    _ => PathBuf::from(env::var("HOME").map_err(|_| "can't find the config dir: HOME isn't set")?)
+      .join(".config"),
+  };
+  Ok(config.join("sciota").join("profiles.json"))
+}
+
+pub fn load_profiles() -> Result<Profiles, Box<dyn std::error::Error>> {
+  let path = profiles_path()?;
+  if !path.exists() {
+    return Ok(Profiles::new());
+  }
+  let text = fs::read_to_string(&path)?;
+  serde_json::from_str(text.as_str())
+    .map_err(|e| format!("bad profile file {}: {}", path.display(), e).into())
+}
+
+pub fn save_profiles(profiles: &Profiles) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let path = profiles_path()?;
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+  let mut file = options.open(&path)?;
+  file.write_all(serde_json::to_string_pretty(profiles)?.as_bytes())?;
+  Ok(path)
+}
+
+pub fn profile_name(matches: &ArgMatches) -> String {
+  match matches.value_of("profile") {
+    Some(p) => p.to_string(),
+    None => env::var("SCIOTA_PROFILE").unwrap_or_else(|_| "default".to_string()),
+  }
+}
+
+// a setting from the command line, then the environment.
+pub fn setting(matches: &ArgMatches, arg: &str, var: &str) -> Option<String> {
+  match matches.value_of(arg) {
+    Some(v) => Some(v.to_string()),
+    None => env::var(var).ok(),
+  }
+}
+
+// the server, user and password to use, with whatever's missing from the
+// command line and environment taken from the profile.
+pub fn resolve(matches: &ArgMatches) -> Result<Profile, Box<dyn std::error::Error>> {
+  let server = setting(matches, "server", "SCIOTA_SERVER");
+  let user = setting(matches, "user", "SCIOTA_USER");
+  let password = setting(matches, "password", "SCIOTA_PASSWORD");
+
+  let saved = if server.is_none() || user.is_none() || password.is_none() {
+    load_profiles()?.remove(profile_name(matches).as_str())
+  } else {
+    None
+  };
+
+  let missing = |what: &str| {
+    format!(
+      "no {} given; use --{}, set SCIOTA_{}, or run 'login'",
+      what,
+      what,
+      what.to_uppercase()
+    )
+  };
+
+  Ok(Profile {
+    server: match server.or_else(|| saved.as_ref().map(|p| p.server.clone())) {
+      Some(s) => base_url(s.as_str()),
+      None => return Err(missing("server").into()),
+    },
+    user: user
+      .or_else(|| saved.as_ref().map(|p| p.user.clone()))
+      .ok_or_else(|| missing("user"))?,
+    password: password
+      .or_else(|| saved.as_ref().map(|p| p.password.clone()))
+      .ok_or_else(|| missing("password"))?,
+  })
+}
+
+// the server's base url.  Accepts the message interface url too, as in
+// http://localhost:8002/user.
+pub fn base_url(server: &str) -> String {
+  let s = server.trim_end_matches('/');
+  s.strip_suffix("/user").unwrap_or(s).to_string()
+}
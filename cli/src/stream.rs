@@ -0,0 +1,295 @@
+use sciota_client::protocol::SaveMeasurement;
+use sciota_client::{Client, Uploader};
+use std::env;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+// 'pipe' and 'tail': read measurements a line at a time from stdin or a
+// serial port, and upload them in batches.  Every measurement goes through
+// the spool, a sciota_client::Queue, so if the server can't be reached
+// they're kept and retried, with backoff, until it can.
+//
+// A line is one of
+//
+//   <value>                          for the --sensor sensor, measured now
+//   <sensor>,<value>                 measured now
+//   <sensor>,<value>,<measuredate>   in ms since the epoch
+//
+// "Now" is by the server's clock, once it's replied; given measuredates are
+// sent as is.
+//
+// Blank lines and lines starting with # are skipped, and so are lines that
+// don't parse, with a warning.
+
+pub struct Options {
+  pub sensor: Option<i64>,
+  pub interval: Duration,
+  // how long to keep retrying at the end of the input before giving up and
+  // leaving the rest in the spool.
+  pub drain: Duration,
+}
+
+pub fn parse_line(
+  line: &str,
+  sensor: Option<i64>,
+  now: i64,
+) -> Result<Option<SaveMeasurement>, String> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('#') {
+    return Ok(None);
+  }
+
+  let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+  let num = |s: &str| s.parse::<f64>().map_err(|_| format!("bad value '{}'", s));
+  let id = |s: &str, what: &str| {
+    s.parse::<i64>()
+      .map_err(|_| format!("bad {} '{}'", what, s))
+  };
+
+  let m = match fields.as_slice() {
+    [value] => SaveMeasurement {
+      sensor: sensor.ok_or("a value without a sensor; use sensor,value or --sensor")?,
+      value: num(value)?,
+      measuredate: now,
+    },
+    [s, value] => SaveMeasurement {
+      sensor: id(s, "sensor")?,
+      value: num(value)?,
+      measuredate: now,
+    },
+    [s, value, date] => SaveMeasurement {
+      sensor: id(s, "sensor")?,
+      value: num(value)?,
+      measuredate: id(date, "measuredate")?,
+    },
+    _ => return Err("expected value, sensor,value or sensor,value,measuredate".to_string()),
+  };
+
+  if m.value.is_finite() {
+    Ok(Some(m))
+  } else {
+    Err(format!("bad value '{}'", m.value))
+  }
+}
+
+// read lines on a thread of their own, since stdin and serial ports block.
+// The channel closes at the end of the input.
+pub fn read_lines<R: BufRead + Send + 'static>(mut input: R) -> mpsc::UnboundedReceiver<String> {
+  let (tx, rx) = mpsc::unbounded_channel();
+  std::thread::spawn(move || {
+    let mut buf = Vec::new();
+    loop {
+      match input.read_until(b'\n', &mut buf) {
+        Ok(0) => break,
+        Ok(_) => {
+          if tx.send(String::from_utf8_lossy(&buf).into_owned()).is_err() {
+            break;
+          }
+          buf.clear();
+        }
+        // serial ports time out between lines; what's been read so far stays
+        // in buf.
+        Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => (),
+        Err(e) => {
+          eprintln!("read error: {}", e);
+          break;
+        }
+      }
+    }
+  });
+  rx
+}
+
+// lines from a serial port.  If the port goes away (say, the device is
+// unplugged), reopen it every few seconds until it's back.
+pub fn read_serial(device: String, baud: u32) -> mpsc::UnboundedReceiver<String> {
+  let (tx, rx) = mpsc::unbounded_channel();
+  std::thread::spawn(move || loop {
+    match serialport::new(device.as_str(), baud)
+      .timeout(Duration::from_secs(1))
+      .open()
+    {
+      Ok(port) => {
+        eprintln!("reading {} at {} baud", device, baud);
+        let mut input = BufReader::new(port);
+        let mut buf = Vec::new();
+        loop {
+          match input.read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {
+              if tx.send(String::from_utf8_lossy(&buf).into_owned()).is_err() {
+                return;
+              }
+              buf.clear();
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => {
+              eprintln!("{}: {}", device, e);
+              break;
+            }
+          }
+        }
+      }
+      Err(e) => eprintln!("can't open {}: {}", device, e),
+    }
+    std::thread::sleep(Duration::from_secs(5));
+  });
+  rx
+}
+
+// $XDG_DATA_HOME/sciota or ~/.local/share/sciota, one spool per profile.
+pub fn default_spool(profile: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+  let data = match env::var("XDG_DATA_HOME") {
+    Ok(d) if !d.is_empty() => PathBuf::from(d),
+    _ => PathBuf::from(
+      env::var("HOME").map_err(|_| "can't find the data dir: HOME isn't set; use --spool")?,
+    )
+    .join(".local")
+    .join("share"),
+  };
+  Ok(data.join("sciota").join(format!("spool-{}.jsonl", profile)))
+}
+
+// upload what's due, and report what went wrong.  Returns false if anything's
+// left to upload.
+async fn flush(
+  client: &Client,
+  uploader: &mut Uploader,
+  all: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+  let flush = uploader.flush(client, all).await?;
+  for (m, e) in flush.dropped.iter() {
+    eprintln!("dropping sensor {} value {}: {}", m.sensor, m.value, e);
+  }
+  if let Some(e) = flush.failed {
+    eprintln!(
+      "upload failed, {} measurements spooled; retrying in {}s: {}",
+      uploader.queue.len(),
+      uploader
+        .backoff
+        .retry_at()
+        .saturating_duration_since(Instant::now())
+        .as_secs(),
+      e
+    );
+  }
+  Ok(uploader.queue.is_empty())
+}
+
+pub async fn run(
+  client: &Client,
+  uploader: &mut Uploader,
+  mut lines: mpsc::UnboundedReceiver<String>,
+  options: &Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut next_flush = Instant::now();
+  let mut dropped = uploader.queue.dropped;
+
+  loop {
+    let wait = next_flush.saturating_duration_since(Instant::now());
+    match tokio::time::timeout(wait, lines.recv()).await {
+      Ok(Some(line)) => match parse_line(line.as_str(), options.sensor, client.clock().now()) {
+        Ok(Some(m)) => {
+          uploader.queue.push(m)?;
+          if uploader.due(false) {
+            flush(client, uploader, false).await?;
+          }
+        }
+        Ok(None) => (),
+        Err(e) => eprintln!("skipping '{}': {}", line.trim(), e),
+      },
+      Ok(None) => break,
+      Err(_) => {
+        flush(client, uploader, true).await?;
+        next_flush = Instant::now() + options.interval;
+      }
+    }
+
+    if uploader.queue.dropped > dropped {
+      eprintln!(
+        "spool full; dropped {} oldest measurements",
+        uploader.queue.dropped - dropped
+      );
+      dropped = uploader.queue.dropped;
+    }
+  }
+
+  // the end of the input: keep trying for a while, then leave the rest for
+  // next time.
+  let give_up = Instant::now() + options.drain;
+  while !flush(client, uploader, true).await? {
+    if uploader.backoff.retry_at() > give_up {
+      return Err(
+        format!(
+          "{} measurements left in {}; they'll be sent on the next run",
+          uploader.queue.len(),
+          uploader.queue.path().display()
+        )
+        .into(),
+      );
+    }
+    tokio::time::delay_until(uploader.backoff.retry_at().into()).await;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const NOW: i64 = 1_600_000_000_000;
+
+  fn parsed(line: &str, sensor: Option<i64>) -> (i64, f64, i64) {
+    let m = parse_line(line, sensor, NOW).unwrap().unwrap();
+    (m.sensor, m.value, m.measuredate)
+  }
+
+  #[test]
+  fn accepted() {
+    assert_eq!(parsed("21.5", Some(3)), (3, 21.5, NOW));
+    assert_eq!(parsed("4,21.5", None), (4, 21.5, NOW));
+    // the line's sensor wins over --sensor.
+    assert_eq!(parsed("4,21.5", Some(3)), (4, 21.5, NOW));
+    assert_eq!(parsed("4,-1e3,1500", None), (4, -1000.0, 1500));
+    assert_eq!(parsed("  4 , 7 , 1500 \r", None), (4, 7.0, 1500));
+  }
+
+  #[test]
+  fn skipped() {
+    for line in ["", "   ", "# sensor,value", "  # indented"].iter() {
+      assert!(
+        parse_line(line, Some(3), NOW).unwrap().is_none(),
+        "{}",
+        line
+      );
+    }
+  }
+
+  #[test]
+  fn rejected() {
+    for line in [
+      "21.5",
+      "abc",
+      "x,21.5",
+      "4.5,21.5",
+      "4,",
+      "4,abc",
+      "4,NaN",
+      "4,inf",
+      "4,21.5,",
+      "4,21.5,noon",
+      "4,21.5,1500.5",
+      "4,21.5,1500,1",
+      ",,",
+    ]
+    .iter()
+    {
+      assert!(parse_line(line, None, NOW).is_err(), "accepted '{}'", line);
+    }
+    assert!(parse_line("NaN", Some(3), NOW).is_err());
+    assert!(parse_line("", None, NOW).unwrap().is_none());
+  }
+}
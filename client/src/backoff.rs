@@ -0,0 +1,124 @@
+//! Exponential backoff between failed uploads.
+
+use std::time::{Duration, Instant, SystemTime};
+
+pub const MIN_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+pub struct Backoff {
+  min: Duration,
+  max: Duration,
+  delay: Duration,
+  retry_at: Instant,
+}
+
+impl Default for Backoff {
+  fn default() -> Backoff {
+    Backoff::new(MIN_BACKOFF, MAX_BACKOFF)
+  }
+}
+
+impl Backoff {
+  /// Waits start at `min` and double after each failure, up to `max`.
+  pub fn new(min: Duration, max: Duration) -> Backoff {
+    Backoff {
+      min,
+      max: max.max(min),
+      delay: min,
+      retry_at: Instant::now(),
+    }
+  }
+
+  /// Whether it's time to try again.
+  pub fn ready(&self) -> bool {
+    Instant::now() >= self.retry_at
+  }
+
+  pub fn retry_at(&self) -> Instant {
+    self.retry_at
+  }
+
+  /// Note a failure, and return how long to wait.  Up to a quarter of the
+  /// wait is random, so a fleet of devices that lost the server together
+  /// don't all come back at once.
+  pub fn failed(&mut self) -> Duration {
+    let wait = self.delay + self.delay.mul_f64(jitter() / 4.0);
+    self.retry_at = Instant::now() + wait;
+    self.delay = (self.delay * 2).min(self.max);
+    wait
+  }
+
+  pub fn succeeded(&mut self) {
+    self.delay = self.min;
+    self.retry_at = Instant::now();
+  }
+}
+
+// a number in [0, 1), from the clock's nanoseconds; it only has to differ
+// from one device to the next.
+fn jitter() -> f64 {
+  let nanos = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.subsec_nanos())
+    .unwrap_or(0);
+  // spread the low bits, which change fastest, over the whole range.
+  let mut x = nanos.wrapping_mul(2654435761);
+  x ^= x >> 16;
+  f64::from(x) / (f64::from(u32::MAX) + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn doubles_up_to_max() {
+    let (min, max) = (Duration::from_millis(100), Duration::from_millis(700));
+    let mut b = Backoff::new(min, max);
+    assert!(b.ready());
+    let mut expect = Vec::new();
+    for _ in 0..5 {
+      let wait = b.failed();
+      assert!(!b.ready());
+      assert!(b.retry_at() > Instant::now());
+      expect.push(wait);
+    }
+    // each wait is its delay plus up to a quarter more.
+    for (wait, delay) in expect.iter().zip([100, 200, 400, 700, 700].iter()) {
+      let delay = Duration::from_millis(*delay);
+      assert!(
+        *wait >= delay && *wait < delay + delay / 4,
+        "{:?} for {:?}",
+        wait,
+        delay
+      );
+    }
+  }
+
+  #[test]
+  fn success_resets() {
+    let mut b = Backoff::new(Duration::from_millis(100), Duration::from_secs(10));
+    for _ in 0..4 {
+      b.failed();
+    }
+    b.succeeded();
+    assert!(b.ready());
+    assert!(b.failed() < Duration::from_millis(125));
+  }
+
+  #[test]
+  fn max_is_at_least_min() {
+    let mut b = Backoff::new(Duration::from_millis(200), Duration::from_millis(50));
+    b.failed();
+    assert!(b.failed() >= Duration::from_millis(200));
+  }
+
+  #[test]
+  fn jitter_range() {
+    for _ in 0..1000 {
+      let j = jitter();
+      assert!((0.0..1.0).contains(&j), "{}", j);
+    }
+  }
+}
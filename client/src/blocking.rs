@@ -0,0 +1,81 @@
+//! The client without async, for programs that don't run their own
+//! runtime.  Each client runs the async one on a runtime of its own, so
+//! like reqwest's blocking client it can't be used from inside an async
+//! function.
+
+use crate::clock::Clock;
+use crate::messages::Call;
+use crate::protocol::{Measurement, ServerResponse, UserMessage};
+use crate::{Error, Flush};
+use serde::de::DeserializeOwned;
+use std::sync::Mutex;
+use tokio::runtime::Runtime;
+
+pub struct Client {
+  inner: crate::Client,
+  runtime: Mutex<Runtime>,
+}
+
+impl Client {
+  pub fn new(server: &str, user: &str, password: &str) -> Result<Client, Error> {
+    Ok(Client {
+      inner: crate::Client::new(server, user, password),
+      runtime: Mutex::new(
+        tokio::runtime::Builder::new()
+          .basic_scheduler()
+          .enable_all()
+          .build()?,
+      ),
+    })
+  }
+
+  // run a future on the client's runtime.
+  fn block_on<F: std::future::Future>(&self, f: F) -> F::Output {
+    match self.runtime.lock() {
+      Ok(mut rt) => rt.block_on(f),
+      // a panic mid-call leaves the runtime as usable as it was.
+      Err(poisoned) => poisoned.into_inner().block_on(f),
+    }
+  }
+
+  /// The async client underneath.
+  pub fn inner(&self) -> &crate::Client {
+    &self.inner
+  }
+
+  pub fn clock(&self) -> &Clock {
+    self.inner.clock()
+  }
+
+  pub fn user_message(&self, what: &str, data: Option<serde_json::Value>) -> UserMessage {
+    self.inner.user_message(what, data)
+  }
+
+  pub fn send(&self, um: &UserMessage) -> Result<ServerResponse, Error> {
+    self.block_on(self.inner.send(um))
+  }
+
+  pub fn call<T: DeserializeOwned>(&self, call: Call<T>) -> Result<T, Error> {
+    self.block_on(self.inner.call(call))
+  }
+
+  pub fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<T, Error> {
+    self.block_on(self.inner.get(path, query))
+  }
+
+  pub fn measurements(
+    &self,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error> {
+    self.block_on(self.inner.measurements(sensor, from, to))
+  }
+}
+
+impl crate::Uploader {
+  /// `flush` for the blocking client.
+  pub fn flush_blocking(&mut self, client: &Client, all: bool) -> Result<Flush, Error> {
+    client.block_on(self.flush(&client.inner, all))
+  }
+}
@@ -0,0 +1,171 @@
+use crate::clock::Clock;
+use crate::messages::Call;
+use crate::protocol::{Measurement, ServerResponse, UserMessage};
+use crate::Error;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// A user's connection to a server: its message interface (POST /user) and
+/// REST routes (/api/...).  Clones share the connection pool and clock.
+#[derive(Clone)]
+pub struct Client {
+  server: String,
+  user: String,
+  password: String,
+  http: reqwest::Client,
+  clock: Arc<Clock>,
+}
+
+// a "server error" reply's {code, message}.
+pub(crate) fn server_error(status: u16, body: &Value) -> Error {
+  match (
+    body["content"]["code"].as_str(),
+    body["content"]["message"].as_str(),
+  ) {
+    (Some(code), Some(message)) => Error::Server {
+      status,
+      code: code.to_string(),
+      message: message.to_string(),
+    },
+    _ => Error::Server {
+      status,
+      code: "unknown".to_string(),
+      message: body.to_string(),
+    },
+  }
+}
+
+// a reply's content, if it's the expected one.
+pub(crate) fn content<T: DeserializeOwned>(sr: ServerResponse, reply: &str) -> Result<T, Error> {
+  if sr.what == reply {
+    Ok(serde_json::from_value(sr.content)?)
+  } else {
+    Err(Error::from_reply(sr.what, sr.content))
+  }
+}
+
+impl Client {
+  /// `server` is the address the server's routes are under, as in
+  /// http://localhost:8002.
+  pub fn new(server: &str, user: &str, password: &str) -> Client {
+    Client {
+      server: server.trim_end_matches('/').to_string(),
+      user: user.to_string(),
+      password: password.to_string(),
+      http: reqwest::Client::new(),
+      clock: Arc::new(Clock::new()),
+    }
+  }
+
+  pub fn server(&self) -> &str {
+    self.server.as_str()
+  }
+
+  pub fn user(&self) -> &str {
+    self.user.as_str()
+  }
+
+  /// The server's time, as seen in its replies.
+  pub fn clock(&self) -> &Clock {
+    &self.clock
+  }
+
+  pub fn user_message(&self, what: &str, data: Option<Value>) -> UserMessage {
+    UserMessage {
+      uid: self.user.clone(),
+      pwd: self.password.clone(),
+      what: what.to_string(),
+      data,
+    }
+  }
+
+  // send a request, noting the reply's time.
+  async fn request(&self, rb: reqwest::RequestBuilder) -> Result<(u16, Value), Error> {
+    let sent = crate::now();
+    let res = rb.send().await?;
+    if let Some(date) = res.headers().get(reqwest::header::DATE) {
+      if let Ok(date) = date.to_str() {
+        self.clock.observe(date, sent, crate::now());
+      }
+    }
+    let status = res.status().as_u16();
+    Ok((status, res.json().await?))
+  }
+
+  /// Send a message and return the reply, whatever its 'what', or an error
+  /// for a "server error".  The credentials go as basic auth too, which the
+  /// server wants before it reads a big importdata message.
+  pub async fn send(&self, um: &UserMessage) -> Result<ServerResponse, Error> {
+    let (status, body) = self
+      .request(
+        self
+          .http
+          .post(format!("{}/user", self.server).as_str())
+          .basic_auth(&self.user, Some(&self.password))
+          .json(um),
+      )
+      .await?;
+    if body["what"] == "server error" {
+      Err(server_error(status, &body))
+    } else {
+      Ok(serde_json::from_value(body)?)
+    }
+  }
+
+  /// Send a message and return its reply's content.
+  pub async fn call<T: DeserializeOwned>(&self, call: Call<T>) -> Result<T, Error> {
+    let sr = self
+      .send(&self.user_message(call.what.as_str(), call.data))
+      .await?;
+    content(sr, call.reply.as_str())
+  }
+
+  /// GET a REST route, as in "/api/devices", with basic auth.
+  pub async fn get<T: DeserializeOwned>(
+    &self,
+    path: &str,
+    query: &[(&str, String)],
+  ) -> Result<T, Error> {
+    let (status, body) = self
+      .request(
+        self
+          .http
+          .get(format!("{}{}", self.server, path).as_str())
+          .basic_auth(&self.user, Some(&self.password))
+          .query(query),
+      )
+      .await?;
+    if (200..300).contains(&status) {
+      Ok(serde_json::from_value(body)?)
+    } else {
+      Err(server_error(status, &body))
+    }
+  }
+
+  /// A sensor's measurements with `from` <= measuredate < `to`, calibrated.
+  pub async fn measurements(
+    &self,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error> {
+    self
+      .get(
+        format!("/api/sensors/{}/measurements", sensor).as_str(),
+        &range(from, to),
+      )
+      .await
+  }
+}
+
+pub(crate) fn range(from: Option<i64>, to: Option<i64>) -> Vec<(&'static str, String)> {
+  let mut query = Vec::new();
+  if let Some(from) = from {
+    query.push(("from", from.to_string()));
+  }
+  if let Some(to) = to {
+    query.push(("to", to.to_string()));
+  }
+  query
+}
@@ -0,0 +1,159 @@
+//! The server's time, for devices whose clocks are wrong: ones without a
+//! real time clock that start in 1970, or that drift between syncs.
+//!
+//! Every reply's Date header gives the server's time to the second.  The
+//! offset from the local clock is taken from the first reply, and replaced
+//! when a later one is off by more than the header's resolution allows.
+//! Until there's been a reply, `now` is the local time.
+
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+
+// a Date header is truncated to the second, and the reply took some time to
+// arrive; estimates closer than this to the current one are noise.
+const TOLERANCE: i64 = 1500;
+
+#[derive(Debug, Default)]
+pub struct Clock {
+  offset: AtomicI64,
+  known: AtomicBool,
+}
+
+impl Clock {
+  pub fn new() -> Clock {
+    Clock::default()
+  }
+
+  /// Server time minus local time, in ms; 0 until there's been a reply.
+  pub fn offset(&self) -> i64 {
+    self.offset.load(Ordering::Relaxed)
+  }
+
+  /// Whether the offset has been measured.
+  pub fn is_known(&self) -> bool {
+    self.known.load(Ordering::Relaxed)
+  }
+
+  /// The server's time now, in ms since the epoch, by the local clock.
+  pub fn now(&self) -> i64 {
+    self.correct(crate::now())
+  }
+
+  /// A local time as server time.
+  pub fn correct(&self, local: i64) -> i64 {
+    local + self.offset()
+  }
+
+  /// Note a reply's Date header, for a request sent at local time `sent`
+  /// and answered at `received`.
+  pub fn observe(&self, date: &str, sent: i64, received: i64) {
+    if let Some(secs) = parse_http_date(date) {
+      // the server's clock read somewhere in this second, around the middle
+      // of the round trip.
+      let offset = secs * 1000 + 500 - (sent + received) / 2;
+      if !self.is_known() || (offset - self.offset()).abs() > TOLERANCE {
+        self.offset.store(offset, Ordering::Relaxed);
+        self.known.store(true, Ordering::Relaxed);
+      }
+    }
+  }
+}
+
+/// Seconds since the epoch from an http date, as in
+/// "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn parse_http_date(date: &str) -> Option<i64> {
+  let fields: Vec<&str> = date.split_whitespace().collect();
+  let (day, month, year, time) = match fields.as_slice() {
+    [_, day, month, year, time, "GMT"] => (day, month, year, time),
+    _ => return None,
+  };
+
+  const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+  ];
+  let m = MONTHS.iter().position(|m| m == month)? as i64 + 1;
+  let d = day.parse::<i64>().ok()?;
+  let y = year.parse::<i64>().ok()?;
+  let hms: Vec<i64> = time.split(':').filter_map(|f| f.parse().ok()).collect();
+  let (h, min, s) = match hms.as_slice() {
+    [h, min, s] => (*h, *min, *s),
+    _ => return None,
+  };
+
+  // a civil date to days; see http://howardhinnant.github.io/date_algorithms.html
+  let y = if m <= 2 { y - 1 } else { y };
+  let era = y.div_euclid(400);
+  let yoe = y - era * 400;
+  let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  let days = era * 146097 + doe - 719468;
+
+  Some(days * 86400 + h * 3600 + min * 60 + s)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn http_dates() {
+    assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    assert_eq!(
+      parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+      Some(784111777)
+    );
+    // leap days, and the turn of a century that isn't a leap year.
+    assert_eq!(
+      parse_http_date("Thu, 29 Feb 2024 12:00:00 GMT"),
+      Some(1709208000)
+    );
+    assert_eq!(
+      parse_http_date("Thu, 01 Mar 2100 00:00:00 GMT"),
+      Some(4107542400)
+    );
+    assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), Some(-1));
+  }
+
+  #[test]
+  fn bad_http_dates() {
+    for date in [
+      "",
+      "Sun, 06 Nov 1994 08:49:37",
+      "Sun, 06 Nov 1994 08:49:37 UTC",
+      "Sunday, 06-Nov-94 08:49:37 GMT",
+      "Sun Nov  6 08:49:37 1994",
+      "Sun, 06 Foo 1994 08:49:37 GMT",
+      "Sun, xx Nov 1994 08:49:37 GMT",
+      "Sun, 06 Nov 1994 08:49 GMT",
+      "Sun, 06 Nov 1994 08:49:37:00 GMT",
+    ]
+    .iter()
+    {
+      assert_eq!(parse_http_date(date), None, "{}", date);
+    }
+  }
+
+  #[test]
+  fn offsets() {
+    let c = Clock::new();
+    assert!(!c.is_known());
+    assert_eq!(c.correct(1000), 1000);
+
+    // the server's clock is an hour ahead; the reply took 200ms.
+    let local = 1_600_000_000_000;
+    let date = "Sun, 13 Sep 2020 13:26:40 GMT";
+    assert_eq!(parse_http_date(date), Some(1_600_003_600));
+    c.observe(date, local, local + 200);
+    assert!(c.is_known());
+    assert_eq!(c.offset(), 3_600_000 + 400);
+
+    // within a second or so is noise, and bad dates are ignored.
+    c.observe(date, local + 1000, local + 1200);
+    assert_eq!(c.offset(), 3_600_000 + 400);
+    c.observe("yesterday", local, local);
+    assert_eq!(c.offset(), 3_600_000 + 400);
+
+    // a real change replaces it.
+    c.observe(date, local + 10_000, local + 10_000);
+    assert_eq!(c.offset(), 3_600_000 + 500 - 10_000);
+  }
+}
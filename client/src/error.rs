@@ -0,0 +1,94 @@
+use serde_json::Value;
+use std::fmt;
+
+/// Why a call failed.  `is_retryable` tells failures that may clear up on
+/// their own from ones where sending the same thing again won't help.
+#[derive(Debug)]
+pub enum Error {
+  /// No reply: the server couldn't be reached, or the connection failed.
+  Http(reqwest::Error),
+  /// A "server error" reply, with its http status.
+  Server {
+    status: u16,
+    code: String,
+    message: String,
+  },
+  /// The login was refused: "invalid user or pwd", "unregistered user" or
+  /// "account disabled".
+  Login(String),
+  /// A reply other than the one expected for the message, as in "user
+  /// exists" for a registration.
+  Reply {
+    what: String,
+    content: Value,
+  },
+  Json(serde_json::Error),
+  Io(std::io::Error),
+}
+
+// replies to a message with a bad login.
+const LOGIN_FAILURES: [&str; 3] = [
+  "invalid user or pwd",
+  "unregistered user",
+  "account disabled",
+];
+
+impl Error {
+  /// The error for a reply that isn't the expected one.
+  pub fn from_reply(what: String, content: Value) -> Error {
+    if LOGIN_FAILURES.contains(&what.as_str()) {
+      Error::Login(what)
+    } else {
+      Error::Reply { what, content }
+    }
+  }
+
+  /// Whether sending the same message later might work.  Login failures
+  /// count, since the data is still good once the account is fixed.
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      Error::Http(_) | Error::Login(_) => true,
+      Error::Server { status, .. } => {
+        *status >= 500 || *status == 401 || *status == 403 || *status == 429
+      }
+      Error::Reply { .. } | Error::Json(_) | Error::Io(_) => false,
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Http(e) => write!(f, "{}", e),
+      Error::Server {
+        status,
+        code,
+        message,
+      } => write!(f, "{} ({}): {}", code, status, message),
+      Error::Login(what) => write!(f, "login failed: {}", what),
+      Error::Reply { what, content } => write!(f, "unexpected reply: {} {}", what, content),
+      Error::Json(e) => write!(f, "{}", e),
+      Error::Io(e) => write!(f, "{}", e),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+  fn from(e: reqwest::Error) -> Error {
+    Error::Http(e)
+  }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Error {
+    Error::Json(e)
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Error {
+    Error::Io(e)
+  }
+}
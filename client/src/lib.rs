@@ -0,0 +1,48 @@
+//! A client for the sciota server, for devices, gateways and the cli.
+//!
+//! `Client` talks to the server's message interface (POST /user) and REST
+//! routes, with one `messages` function per 'what' code; `blocking::Client`
+//! is the same without async.  For measurements that have to get through
+//! while the server or the network is down, an `Uploader` keeps them in a
+//! `Queue` on disk and sends them in batches, backing off between failures.
+//!
+//! ```no_run
+//! # async fn f() -> Result<(), sciota_client::Error> {
+//! use sciota_client::{messages, Client};
+//!
+//! let client = Client::new("http://localhost:8002", "me", "secret");
+//! for device in client.call(messages::device_listing()).await? {
+//!   println!("{} {}", device.id, device.name);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backoff;
+pub mod blocking;
+mod client;
+pub mod clock;
+mod error;
+pub mod messages;
+pub mod queue;
+mod uploader;
+
+pub use crate::client::Client;
+pub use crate::error::Error;
+pub use crate::messages::Call;
+pub use crate::queue::Queue;
+pub use crate::uploader::{Flush, Uploader};
+pub use sciota_protocol::protocol;
+
+use std::time::SystemTime;
+
+/// The most measurements the server takes in one savemeasurements message.
+pub const MAX_BATCH: usize = 10000;
+
+/// The local time in ms since the epoch, uncorrected; see `Clock::now`.
+pub fn now() -> i64 {
+  match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+    Ok(d) => d.as_millis() as i64,
+    Err(e) => -(e.duration().as_millis() as i64),
+  }
+}
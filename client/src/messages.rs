@@ -0,0 +1,262 @@
+//! The server's message interface: one function per 'what' code, giving the
+//! message's data and the reply to expect.  Pass the result to
+//! `Client::call`.
+//!
+//! Messages whose data and replies have sciota-protocol types are typed;
+//! the rest (sensor types, readings, calibrations, templates and the like)
+//! take anything that serializes to the server's json, and reply with json.
+
+use crate::protocol::{
+  Device, Measurement, MeasurementQuery, SaveDevice, SaveMeasurement, SaveSensor, Sensor,
+};
+use serde::Serialize;
+use serde_json::Value;
+use std::marker::PhantomData;
+
+/// A message to send: its 'what' code and data, and the 'what' of a
+/// successful reply, whose content is a `T`.
+#[derive(Debug)]
+pub struct Call<T> {
+  pub what: String,
+  pub data: Option<Value>,
+  pub reply: String,
+  content: PhantomData<fn() -> T>,
+}
+
+impl<T> Call<T> {
+  pub fn new(what: &str, data: Option<Value>, reply: &str) -> Call<T> {
+    Call {
+      what: what.to_string(),
+      data,
+      reply: reply.to_string(),
+      content: PhantomData,
+    }
+  }
+}
+
+// message data.  The protocol types and json always serialize; anything
+// that doesn't is sent as null, which the server refuses.
+fn data<D: Serialize + ?Sized>(d: &D) -> Option<Value> {
+  Some(serde_json::to_value(d).unwrap_or(Value::Null))
+}
+
+fn call<T, D: Serialize + ?Sized>(what: &str, d: &D, reply: &str) -> Call<T> {
+  Call::new(what, data(d), reply)
+}
+
+/// Checks the login.
+pub fn login() -> Call<Value> {
+  Call::new("login", None, "logged in")
+}
+
+/// Registers the client's user and password, sending a confirmation email.
+pub fn register(email: &str) -> Call<Value> {
+  call(
+    "register",
+    &serde_json::json!({ "email": email }),
+    "registration sent",
+  )
+}
+
+// devices and sensors
+
+pub fn device_listing() -> Call<Vec<Device>> {
+  Call::new("getdevicelisting", None, "devicelisting")
+}
+
+/// Creates a device, or updates one if `id` is set; replies with its id.
+pub fn save_device(device: &SaveDevice) -> Call<i64> {
+  call("savedevice", device, "saveddevice")
+}
+
+pub fn delete_device(id: i64) -> Call<i64> {
+  call("deletedevice", &id, "deleteddevice")
+}
+
+pub fn sensor_listing(device: i64) -> Call<Vec<Sensor>> {
+  call("getsensorlisting", &device, "sensorlisting")
+}
+
+pub fn save_sensor(sensor: &SaveSensor) -> Call<Sensor> {
+  call("savesensor", sensor, "savedsensor")
+}
+
+pub fn delete_sensor(id: i64) -> Call<i64> {
+  call("deletesensor", &id, "deletedsensor")
+}
+
+// measurements
+
+/// Replies with the new measurement's id.
+pub fn save_measurement(measurement: &SaveMeasurement) -> Call<i64> {
+  call("savemeasurement", measurement, "savedmeasurement")
+}
+
+/// Saves up to `MAX_BATCH` measurements, all or none; replies with their ids.
+pub fn save_measurements(measurements: &[SaveMeasurement]) -> Call<Vec<i64>> {
+  call("savemeasurements", measurements, "savedmeasurements")
+}
+
+pub fn measurement_listing(query: &MeasurementQuery) -> Call<Vec<Measurement>> {
+  call("getmeasurementlisting", query, "measurementlisting")
+}
+
+/// The user's devices, sensors and measurements, as an archive.
+pub fn export_data() -> Call<Value> {
+  Call::new("exportdata", None, "exporteddata")
+}
+
+/// Adds an archive from `export_data` to the user's account.
+pub fn import_data(archive: &Value) -> Call<Value> {
+  call("importdata", archive, "importeddata")
+}
+
+// sensor types and readings
+
+pub fn sensor_type(sensor: i64) -> Call<Value> {
+  call("getsensortype", &sensor, "sensortype")
+}
+
+pub fn save_sensor_type<D: Serialize>(sensor_type: &D) -> Call<Value> {
+  call("savesensortype", sensor_type, "savedsensortype")
+}
+
+pub fn save_reading<D: Serialize>(reading: &D) -> Call<i64> {
+  call("savereading", reading, "savedreading")
+}
+
+pub fn reading_listing<D: Serialize>(query: &D) -> Call<Value> {
+  call("getreadinglisting", query, "readinglisting")
+}
+
+// calibrations and virtual sensors
+
+/// The calibration in force now.
+pub fn calibration(sensor: i64) -> Call<Value> {
+  call("getcalibration", &sensor, "calibration")
+}
+
+pub fn set_calibration<D: Serialize>(calibration: &D) -> Call<Value> {
+  call("setcalibration", calibration, "savedcalibration")
+}
+
+pub fn calibration_history(sensor: i64) -> Call<Value> {
+  call("getcalibrationhistory", &sensor, "calibrationhistory")
+}
+
+pub fn delete_calibration(id: i64) -> Call<i64> {
+  call("deletecalibration", &id, "deletedcalibration")
+}
+
+pub fn calibration_audit(sensor: i64) -> Call<Value> {
+  call("getcalibrationaudit", &sensor, "calibrationaudit")
+}
+
+pub fn virtual_sensor(sensor: i64) -> Call<Value> {
+  call("getvirtualsensor", &sensor, "virtualsensor")
+}
+
+pub fn set_virtual_sensor<D: Serialize>(virtual_sensor: &D) -> Call<Value> {
+  call("setvirtualsensor", virtual_sensor, "savedvirtualsensor")
+}
+
+// locations
+
+pub fn device_location(device: i64) -> Call<Value> {
+  call("getdevicelocation", &device, "devicelocation")
+}
+
+pub fn set_device_location<D: Serialize>(location: &D) -> Call<Value> {
+  call("setdevicelocation", location, "saveddevicelocation")
+}
+
+pub fn located_devices<D: Serialize>(query: &D) -> Call<Value> {
+  call("getlocateddevices", query, "locateddevices")
+}
+
+pub fn device_geojson<D: Serialize>(query: &D) -> Call<Value> {
+  call("getdevicegeojson", query, "devicegeojson")
+}
+
+pub fn reading_geojson<D: Serialize>(query: &D) -> Call<Value> {
+  call("getreadinggeojson", query, "readinggeojson")
+}
+
+// templates
+
+pub fn save_template<D: Serialize>(template: &D) -> Call<i64> {
+  call("savetemplate", template, "savedtemplate")
+}
+
+pub fn template(id: i64) -> Call<Value> {
+  call("gettemplate", &id, "template")
+}
+
+pub fn template_listing() -> Call<Value> {
+  Call::new("gettemplatelisting", None, "templatelisting")
+}
+
+pub fn delete_template(id: i64) -> Call<i64> {
+  call("deletetemplate", &id, "deletedtemplate")
+}
+
+pub fn instantiate<D: Serialize>(instantiate: &D) -> Call<Value> {
+  call("instantiate", instantiate, "instantiated")
+}
+
+// rollups and retention
+
+pub fn rollup_listing<D: Serialize>(query: &D) -> Call<Value> {
+  call("getrolluplisting", query, "rolluplisting")
+}
+
+pub fn retention(sensor: i64) -> Call<Value> {
+  call("getretention", &sensor, "retention")
+}
+
+pub fn save_retention<D: Serialize>(retention: &D) -> Call<Value> {
+  call("saveretention", retention, "savedretention")
+}
+
+// admin
+
+pub fn user_listing() -> Call<Value> {
+  Call::new("getuserlisting", None, "userlisting")
+}
+
+pub fn enable_user(name: &str) -> Call<String> {
+  call("enableuser", name, "enableduser")
+}
+
+pub fn disable_user(name: &str) -> Call<String> {
+  call("disableuser", name, "disableduser")
+}
+
+/// Deletes a user and all their data.
+pub fn delete_user(name: &str) -> Call<String> {
+  call("deleteuser", name, "deleteduser")
+}
+
+/// Completes a user's registration without the emailed link.
+pub fn confirm_user(name: &str) -> Call<String> {
+  call("confirmuser", name, "confirmeduser")
+}
+
+/// Snapshots the server's database; replies with the backup's path.
+pub fn backup() -> Call<String> {
+  Call::new("backup", None, "backedup")
+}
+
+/// Sends a read-only message as another user; the reply is the one `call`
+/// would get.
+pub fn impersonate<T>(user: &str, call: Call<T>) -> Call<T> {
+  Call::new(
+    "impersonate",
+    data(&serde_json::json!({
+      "user": user,
+      "what": call.what,
+      "data": call.data,
+    })),
+    call.reply.as_str(),
+  )
+}
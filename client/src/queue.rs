@@ -0,0 +1,380 @@
+//! Measurements waiting to be uploaded, kept in a file of json lines so they
+//! survive the server being down and the program being restarted.  New
+//! measurements are appended.  Uploaded ones stay in the file, and a second
+//! file next to it holds the byte offset of the first that hasn't been; the
+//! file is compacted once most of it has been uploaded.
+
+use crate::protocol::SaveMeasurement;
+use crate::Error;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Uploaded measurements are left in the file until there are this many
+/// bytes of them, and more than there are of the ones still waiting.
+pub const COMPACT_BYTES: u64 = 1 << 20;
+
+pub struct Queue {
+  path: PathBuf,
+  pending: VecDeque<SaveMeasurement>,
+  // the file offset just past each pending measurement's line.
+  ends: VecDeque<u64>,
+  // where the first pending measurement starts, and the file's length.
+  offset: u64,
+  len: u64,
+  file: fs::File,
+  max: usize,
+  /// How many of the oldest measurements have been dropped to stay under
+  /// the limit.
+  pub dropped: usize,
+}
+
+fn open_append(path: &Path) -> std::io::Result<fs::File> {
+  fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+// write a small file by renaming a new one over it.
+fn replace(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+  let mut tmp = path.as_os_str().to_owned();
+  tmp.push(".tmp");
+  fs::write(&tmp, contents)?;
+  fs::rename(&tmp, path)
+}
+
+impl Queue {
+  /// Open a queue, with whatever an earlier run left in it.  Holds at most
+  /// `max` measurements, dropping the oldest.
+  pub fn open(path: &Path, max: usize) -> Result<Queue, Error> {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+
+    let mut queue = Queue {
+      path: path.to_path_buf(),
+      pending: VecDeque::new(),
+      ends: VecDeque::new(),
+      offset: 0,
+      len: 0,
+      file: open_append(path)?,
+      max: max.max(1),
+      dropped: 0,
+    };
+
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    queue.len = reader.get_ref().metadata()?.len();
+    // a missing or unreadable offset means starting over, which sends some
+    // measurements twice rather than losing any.  One past the end is left
+    // by a crash while compacting, and means the same.
+    queue.offset = fs::read_to_string(queue.offset_path())
+      .ok()
+      .and_then(|s| s.trim().parse::<u64>().ok())
+      .filter(|o| *o <= queue.len)
+      .unwrap_or(0);
+
+    reader.seek(SeekFrom::Start(queue.offset))?;
+    let mut pos = queue.offset;
+    let mut line = Vec::new();
+    let mut torn = false;
+    loop {
+      line.clear();
+      let n = reader.read_until(b'\n', &mut line)?;
+      if n == 0 {
+        break;
+      }
+      pos += n as u64;
+      torn = line.last() != Some(&b'\n');
+      // a torn last line from a crash is skipped.
+      if let Ok(m) = serde_json::from_slice::<SaveMeasurement>(&line) {
+        queue.pending.push_back(m);
+        queue.ends.push_back(pos);
+      }
+    }
+    // end a torn line, so the next measurement doesn't run into it.
+    if torn {
+      queue.file.write_all(b"\n")?;
+      queue.len += 1;
+    }
+
+    let over = queue.pending.len().saturating_sub(queue.max);
+    if over > 0 {
+      queue.drop_oldest(over)?;
+    }
+    Ok(queue)
+  }
+
+  pub fn len(&self) -> usize {
+    self.pending.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+
+  pub fn path(&self) -> &Path {
+    self.path.as_path()
+  }
+
+  /// Where the read offset is kept: the queue's path with an .offset
+  /// extension.
+  pub fn offset_path(&self) -> PathBuf {
+    self.path.with_extension("offset")
+  }
+
+  pub fn push(&mut self, m: SaveMeasurement) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(&m)?;
+    line.push(b'\n');
+    self.file.write_all(&line)?;
+    self.file.flush()?;
+    self.len += line.len() as u64;
+    self.pending.push_back(m);
+    self.ends.push_back(self.len);
+
+    if self.pending.len() > self.max {
+      self.drop_oldest(1)?;
+    }
+    Ok(())
+  }
+
+  /// The oldest `n` measurements.
+  pub fn front(&mut self, n: usize) -> &[SaveMeasurement] {
+    let n = n.min(self.pending.len());
+    &self.pending.make_contiguous()[..n]
+  }
+
+  /// Remove the oldest `n`, once they're uploaded.
+  pub fn remove(&mut self, n: usize) -> Result<(), Error> {
+    let n = n.min(self.pending.len());
+    if n == 0 {
+      return Ok(());
+    }
+    self.skip(n)?;
+    // with the offset past more bytes than are left, a crash before the new
+    // offset is saved leaves it past the end of the compacted file, which
+    // reads as starting over rather than skipping measurements.
+    if self.offset >= COMPACT_BYTES && self.offset > self.len - self.offset {
+      self.compact()?;
+    }
+    Ok(())
+  }
+
+  fn drop_oldest(&mut self, n: usize) -> Result<(), Error> {
+    self.skip(n)?;
+    self.dropped += n;
+    Ok(())
+  }
+
+  // move the offset past the oldest n.
+  fn skip(&mut self, n: usize) -> Result<(), Error> {
+    self.pending.drain(..n);
+    if let Some(end) = self.ends.drain(..n).next_back() {
+      self.offset = end;
+    }
+    self.save_offset()
+  }
+
+  fn save_offset(&self) -> Result<(), Error> {
+    replace(
+      self.offset_path().as_path(),
+      self.offset.to_string().as_bytes(),
+    )?;
+    Ok(())
+  }
+
+  // replace the file with its pending part: copy that to a new one and rename
+  // it over the old, so a crash leaves one or the other.
+  fn compact(&mut self) -> Result<(), Error> {
+    let tmp = self.path.with_extension("tmp");
+    {
+      let mut from = fs::File::open(&self.path)?;
+      from.seek(SeekFrom::Start(self.offset))?;
+      let mut to = fs::File::create(&tmp)?;
+      std::io::copy(&mut from.take(self.len - self.offset), &mut to)?;
+      to.sync_data()?;
+    }
+    fs::rename(&tmp, &self.path)?;
+    self.file = open_append(&self.path)?;
+
+    let start = self.offset;
+    for end in self.ends.iter_mut() {
+      *end -= start;
+    }
+    self.len -= start;
+    self.offset = 0;
+    self.save_offset()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> TempDir {
+      let dir = std::env::temp_dir().join(format!("sciota-queue-{}-{}", name, std::process::id()));
+      let _ = fs::remove_dir_all(&dir);
+      TempDir(dir)
+    }
+
+    fn spool(&self) -> PathBuf {
+      self.0.join("spool.jsonl")
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn m(value: f64) -> SaveMeasurement {
+    SaveMeasurement {
+      value,
+      sensor: 1,
+      measuredate: 1000,
+    }
+  }
+
+  fn values(q: &mut Queue) -> Vec<f64> {
+    let n = q.len();
+    q.front(n).iter().map(|m| m.value).collect()
+  }
+
+  fn file_len(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().len()
+  }
+
+  #[test]
+  fn survives_reopening() {
+    let dir = TempDir::new("reopen");
+    {
+      let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+      assert!(q.is_empty());
+      for v in 0..5 {
+        q.push(m(f64::from(v))).unwrap();
+      }
+      q.remove(2).unwrap();
+      assert_eq!(values(&mut q), vec![2.0, 3.0, 4.0]);
+    }
+
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![2.0, 3.0, 4.0]);
+    q.push(m(5.0)).unwrap();
+    q.remove(1).unwrap();
+    drop(q);
+
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![3.0, 4.0, 5.0]);
+    assert_eq!(q.dropped, 0);
+  }
+
+  #[test]
+  fn removing_doesnt_rewrite() {
+    let dir = TempDir::new("offset");
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    for v in 0..10 {
+      q.push(m(f64::from(v))).unwrap();
+    }
+    let len = file_len(q.path());
+    q.remove(4).unwrap();
+    assert_eq!(file_len(q.path()), len);
+    q.remove(6).unwrap();
+    assert_eq!(file_len(q.path()), len);
+    assert!(q.is_empty());
+
+    drop(q);
+    assert!(Queue::open(dir.spool().as_path(), 100).unwrap().is_empty());
+  }
+
+  #[test]
+  fn compacts() {
+    let dir = TempDir::new("compact");
+    let mut q = Queue::open(dir.spool().as_path(), 1_000_000).unwrap();
+    let mut n = 0;
+    while file_len(q.path()) < 3 * COMPACT_BYTES {
+      q.push(m(f64::from(n))).unwrap();
+      n += 1;
+    }
+    // most of it uploaded.
+    q.remove(n as usize - 10).unwrap();
+    assert!(file_len(q.path()) < 1000);
+    assert_eq!(q.len(), 10);
+
+    q.push(m(-1.0)).unwrap();
+    q.remove(1).unwrap();
+    drop(q);
+    let mut q = Queue::open(dir.spool().as_path(), 1_000_000).unwrap();
+    let expect: Vec<f64> = (n - 9..n).map(f64::from).chain(vec![-1.0]).collect();
+    assert_eq!(values(&mut q), expect);
+  }
+
+  #[test]
+  fn a_crash_while_compacting_resends() {
+    let dir = TempDir::new("crash");
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    for v in 0..4 {
+      q.push(m(f64::from(v))).unwrap();
+    }
+    q.remove(3).unwrap();
+    let remaining = fs::read(q.path()).unwrap();
+    let offset = fs::read_to_string(q.offset_path()).unwrap();
+    drop(q);
+
+    // the compacted file went in, and the new offset didn't: the old one is
+    // past its end.
+    let mut line = serde_json::to_vec(&m(3.0)).unwrap();
+    line.push(b'\n');
+    fs::write(dir.spool(), &line).unwrap();
+    assert!(offset.parse::<u64>().unwrap() > file_len(dir.spool().as_path()));
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![3.0]);
+
+    // and a missing offset starts over.
+    drop(q);
+    fs::write(dir.spool(), &remaining).unwrap();
+    fs::remove_file(dir.0.join("spool.offset")).unwrap();
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![0.0, 1.0, 2.0, 3.0]);
+  }
+
+  #[test]
+  fn torn_lines_are_skipped() {
+    let dir = TempDir::new("torn");
+    {
+      let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+      q.push(m(1.0)).unwrap();
+      q.push(m(2.0)).unwrap();
+    }
+    let mut f = open_append(dir.spool().as_path()).unwrap();
+    f.write_all(b"{\"value\":3.0,\"sen").unwrap();
+    drop(f);
+
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![1.0, 2.0]);
+    q.push(m(4.0)).unwrap();
+    drop(q);
+    let mut q = Queue::open(dir.spool().as_path(), 100).unwrap();
+    assert_eq!(values(&mut q), vec![1.0, 2.0, 4.0]);
+  }
+
+  #[test]
+  fn drops_the_oldest() {
+    let dir = TempDir::new("max");
+    let mut q = Queue::open(dir.spool().as_path(), 3).unwrap();
+    for v in 0..5 {
+      q.push(m(f64::from(v))).unwrap();
+    }
+    assert_eq!(values(&mut q), vec![2.0, 3.0, 4.0]);
+    assert_eq!(q.dropped, 2);
+    drop(q);
+
+    let mut q = Queue::open(dir.spool().as_path(), 3).unwrap();
+    assert_eq!(values(&mut q), vec![2.0, 3.0, 4.0]);
+    drop(q);
+    let mut q = Queue::open(dir.spool().as_path(), 2).unwrap();
+    assert_eq!(values(&mut q), vec![3.0, 4.0]);
+    assert_eq!(q.dropped, 1);
+  }
+}
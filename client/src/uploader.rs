@@ -0,0 +1,108 @@
+use crate::backoff::Backoff;
+use crate::messages;
+use crate::protocol::SaveMeasurement;
+use crate::{Client, Error, Queue, MAX_BATCH};
+
+/// Uploads queued measurements in batches.  Measurements go into the queue
+/// first, so whatever can't be sent is kept for later; after a failure,
+/// uploads wait out a backoff.
+///
+/// A batch the server refuses is sent again a measurement at a time, so one
+/// bad measurement (say, for a deleted sensor) doesn't hold up the rest;
+/// those refused again are dropped, and listed in the `Flush`.
+pub struct Uploader {
+  pub queue: Queue,
+  pub backoff: Backoff,
+  batch: usize,
+}
+
+/// What a flush did.
+#[derive(Debug, Default)]
+pub struct Flush {
+  pub sent: usize,
+  /// Measurements the server refused, and why.
+  pub dropped: Vec<(SaveMeasurement, Error)>,
+  /// Why the flush stopped early, leaving measurements in the queue.
+  pub failed: Option<Error>,
+}
+
+fn copy(m: &SaveMeasurement) -> SaveMeasurement {
+  SaveMeasurement {
+    value: m.value,
+    sensor: m.sensor,
+    measuredate: m.measuredate,
+  }
+}
+
+impl Uploader {
+  /// `batch` is the most measurements per upload, up to `MAX_BATCH`.
+  pub fn new(queue: Queue, batch: usize) -> Uploader {
+    Uploader {
+      queue,
+      backoff: Backoff::default(),
+      batch: batch.clamp(1, MAX_BATCH),
+    }
+  }
+
+  pub fn batch(&self) -> usize {
+    self.batch
+  }
+
+  /// Queue a measurement of `sensor` taken now, by the server's clock.
+  pub fn measure(&mut self, client: &Client, sensor: i64, value: f64) -> Result<(), Error> {
+    self.queue.push(SaveMeasurement {
+      value,
+      sensor,
+      measuredate: client.clock().now(),
+    })
+  }
+
+  /// Whether there's a full batch to send, or anything at all if `all`.
+  pub fn due(&self, all: bool) -> bool {
+    self.queue.len() >= self.batch || (all && !self.queue.is_empty())
+  }
+
+  fn failed(&mut self, mut flush: Flush, e: Error) -> Flush {
+    self.backoff.failed();
+    flush.failed = Some(e);
+    flush
+  }
+
+  /// Upload full batches, then the rest if `all`, unless it's too soon
+  /// after a failure.  Errors are only for the queue's file; upload
+  /// failures are in the `Flush`.
+  pub async fn flush(&mut self, client: &Client, all: bool) -> Result<Flush, Error> {
+    let mut flush = Flush::default();
+    if !self.backoff.ready() {
+      return Ok(flush);
+    }
+
+    while self.due(all) {
+      let n = self.batch.min(self.queue.len());
+      match client
+        .call(messages::save_measurements(self.queue.front(n)))
+        .await
+      {
+        Ok(_) => flush.sent += n,
+        Err(e) if e.is_retryable() => return Ok(self.failed(flush, e)),
+        Err(_) => {
+          for i in 0..n {
+            let m = copy(&self.queue.front(n)[i]);
+            match client.call(messages::save_measurement(&m)).await {
+              Ok(_) => flush.sent += 1,
+              Err(e) if e.is_retryable() => {
+                // keep the ones not sent yet.
+                self.queue.remove(i)?;
+                return Ok(self.failed(flush, e));
+              }
+              Err(e) => flush.dropped.push((m, e)),
+            }
+          }
+        }
+      }
+      self.queue.remove(n)?;
+      self.backoff.succeeded();
+    }
+    Ok(flush)
+  }
+}
@@ -0,0 +1,120 @@
+use calibration::{Calibration, Formula};
+use error::Error;
+use geo::Location;
+use readings::ValueKind;
+use schemars::JsonSchema;
+
+// a user's devices, sensors and measurements, for moving them to another
+// server.  Records are nested rather than linked by id, so importing just
+// creates new rows under their new parents; the ids in here are the ones from
+// the exporting server, for reference only.
+
+// 2: sensor value kinds and measurement payloads.
+// 3: device locations and measurement positions.
+// 4: sensor units.
+// 5: calibrations and virtual sensors.
+// 6: calibration histories, and calibrated measurement values.
+pub const ARCHIVE_VERSION: i64 = 6;
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct Archive {
+  pub version: i64,
+  pub exportdate: i64,
+  pub user: String,
+  pub devices: Vec<ArchiveDevice>,
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct ArchiveDevice {
+  pub id: i64,
+  pub name: String,
+  pub description: String,
+  pub createdate: i64,
+  pub changeddate: i64,
+  pub location: Option<Location>,
+  pub sensors: Vec<ArchiveSensor>,
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct ArchiveSensor {
+  pub id: i64,
+  pub name: String,
+  pub description: String,
+  pub createdate: i64,
+  pub changeddate: i64,
+  pub kind: Option<ValueKind>,
+  pub dims: Option<i64>,
+  pub unit: Option<String>,
+  // version 5 archives' single calibration, in force from the start.
+  pub calibration: Option<Calibration>,
+  pub calibrations: Option<Vec<ArchiveCalibration>>,
+  // input sensor ids are the archive's.
+  pub formula: Option<Formula>,
+  pub rawdays: Option<i64>,
+  pub hourlydays: Option<i64>,
+  pub dailydays: Option<i64>,
+  pub rolledupto: Option<i64>,
+  pub measurements: Vec<ArchiveMeasurement>,
+  pub hourly: Vec<ArchiveRollup>,
+  pub daily: Vec<ArchiveRollup>,
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct ArchiveMeasurement {
+  pub value: f64,
+  pub payload: Option<String>,
+  pub measuredate: i64,
+  pub createdate: i64,
+  pub position: Option<Location>,
+  // the value with the calibration in force at measuredate, if there was one.
+  // Import ignores it and recomputes it from the calibration history.
+  pub calibrated: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct ArchiveCalibration {
+  pub effective: i64,
+  pub calibration: Option<Calibration>,
+  pub createdate: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct ArchiveRollup {
+  pub period: i64,
+  pub samples: i64,
+  pub total: f64,
+  pub minimum: f64,
+  pub maximum: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct ImportSummary {
+  pub devices: i64,
+  pub sensors: i64,
+  pub measurements: i64,
+}
+
+pub fn check_version(archive: &Archive) -> Result<(), Error> {
+  if archive.version > ARCHIVE_VERSION {
+    Err(Error::BadRequest(format!(
+      "archive version {} is newer than this server's {}",
+      archive.version, ARCHIVE_VERSION
+    )))
+  } else {
+    Ok(())
+  }
+}
+
+// a sensor's calibration history.  Version 5 archives have at most one
+// calibration, in force from the start.
+pub fn sensor_calibrations(sensor: &ArchiveSensor, exportdate: i64) -> Vec<ArchiveCalibration> {
+  match (&sensor.calibrations, &sensor.calibration) {
+    (Some(cs), _) => cs.clone(),
+    (None, Some(c)) => vec![ArchiveCalibration {
+      effective: i64::min_value(),
+      calibration: Some(c.clone()),
+      createdate: exportdate,
+    }],
+    (None, None) => Vec::new(),
+  }
+}
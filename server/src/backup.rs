@@ -0,0 +1,215 @@
+use actix_web::web;
+use error::Error;
+use rusqlite;
+use rusqlite::backup::Backup;
+use rusqlite::{params, Connection, OpenFlags};
+use sqldata;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use storage::{Db, Storage};
+use time;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+  // snapshots are written here, named <db file stem>-<utc date>-<time>.db
+  pub dir: PathBuf,
+  // number of snapshots to keep; older ones are deleted.
+  pub keep: Option<usize>,
+  // take a snapshot this often.  No schedule if missing.
+  pub interval_secs: Option<u64>,
+}
+
+fn snapshot_prefix(dbfile: &Path) -> String {
+  format!(
+    "{}-",
+    dbfile
+      .file_stem()
+      .map(|s| s.to_string_lossy().to_string())
+      .unwrap_or("sciota".to_string())
+  )
+}
+
+// the snapshots in the backup dir, oldest first.
+pub fn snapshots(dbfile: &Path, config: &BackupConfig) -> Result<Vec<PathBuf>, Error> {
+  let prefix = snapshot_prefix(dbfile);
+  let mut snaps = Vec::new();
+
+  for entry in fs::read_dir(&config.dir)? {
+    let path = entry?.path();
+    let name = path
+      .file_name()
+      .map(|n| n.to_string_lossy().to_string())
+      .unwrap_or(String::new());
+    if name.starts_with(prefix.as_str()) && name.ends_with(".db") {
+      snaps.push(path);
+    }
+  }
+
+  // the timestamps in the names sort chronologically.
+  snaps.sort();
+  Ok(snaps)
+}
+
+// take a snapshot of the live database, then delete old snapshots beyond
+// 'keep'.  Returns the path of the new snapshot.
+pub fn snapshot(db: &dyn Storage, dbfile: &Path, config: &BackupConfig) -> Result<PathBuf, Error> {
+  fs::create_dir_all(&config.dir)?;
+
+  let stamp = time::strftime("%Y%m%d-%H%M%S", &time::now_utc())
+    .map_err(|e| Error::Internal(e.to_string()))?;
+  let dest = config
+    .dir
+    .join(format!("{}{}.db", snapshot_prefix(dbfile), stamp));
+
+  // write under a temp name so a partial snapshot is never mistaken for a
+  // good one.
+  let partial = dest.with_extension("db.partial");
+  db.backup(partial.as_path())?;
+  fs::rename(&partial, &dest)?;
+  info!("wrote backup {:?}", dest);
+
+  match config.keep {
+    Some(keep) => {
+      let snaps = snapshots(dbfile, config)?;
+      if snaps.len() > keep {
+        for old in &snaps[..snaps.len() - keep] {
+          info!("removing old backup {:?}", old);
+          fs::remove_file(old)?;
+        }
+      }
+    }
+    None => (),
+  }
+
+  Ok(dest)
+}
+
+// take snapshots on the configured schedule in a background thread.
+pub fn start(db: web::Data<Db>, dbfile: PathBuf, config: BackupConfig) {
+  match config.interval_secs {
+    Some(secs) => {
+      thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(secs));
+        match snapshot(&**db, dbfile.as_path(), &config) {
+          Ok(_) => (),
+          Err(e) => error!("backup error: {:?}", e),
+        }
+      });
+    }
+    None => (),
+  }
+}
+
+// an exclusive lock on the database, held until the connection is dropped.
+// It's refused rather than waited for if anyone else has the database open,
+// as a running server does.  Leaving WAL mode checkpoints the wal into the
+// database file, and needs the database to itself.
+fn lock_exclusive(dbfile: &Path) -> Result<Connection, Error> {
+  let conn = Connection::open_with_flags(dbfile, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+  conn.busy_timeout(Duration::from_millis(0))?;
+
+  let locked = conn
+    .query_row("PRAGMA locking_mode = EXCLUSIVE", params![], |row| {
+      row.get::<_, String>(0)
+    })
+    .and_then(|_| {
+      conn.query_row("PRAGMA journal_mode = DELETE", params![], |row| {
+        row.get::<_, String>(0)
+      })
+    })
+    .and_then(|_| conn.execute_batch("BEGIN EXCLUSIVE; COMMIT;"));
+  match locked {
+    Ok(_) => Ok(conn),
+    Err(rusqlite::Error::SqliteFailure(
+      rusqlite::ffi::Error {
+        code: rusqlite::ffi::ErrorCode::DatabaseBusy,
+        ..
+      },
+      _,
+    )) => Err(Error::Conflict(format!(
+      "{:?} is in use; stop the server before restoring",
+      dbfile
+    ))),
+    Err(e) => Err(e.into()),
+  }
+}
+
+// replace the database file with a snapshot.  The server has to be stopped:
+// the database is locked for the swap, and if it's busy the restore is
+// refused.  The snapshot must pass an integrity check and be at a migration
+// level this server understands; older levels are migrated on the next start.
+// The current database is saved alongside as <db>.prerestore.
+pub fn restore(dbfile: &Path, snapshot: &Path) -> Result<(), Error> {
+  if !snapshot.is_file() {
+    return Err(Error::NotFound(format!("no snapshot at {:?}", snapshot)));
+  }
+
+  {
+    let conn = Connection::open_with_flags(snapshot, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let check: String = conn.query_row("PRAGMA integrity_check", params![], |row| row.get(0))?;
+    if check != "ok" {
+      return Err(Error::BadRequest(format!(
+        "{:?} failed its integrity check: {}",
+        snapshot, check
+      )));
+    }
+
+    let level = match sqldata::get_single_value(&conn, "migration_level") {
+      Ok(Some(level)) => level.parse::<i64>()?,
+      _ => {
+        return Err(Error::BadRequest(format!(
+          "{:?} has no migration_level; is it a sciota database?",
+          snapshot
+        )))
+      }
+    };
+    if level > sqldata::MIGRATION_LEVEL {
+      return Err(Error::BadRequest(format!(
+        "{:?} is at migration level {}, but this server only knows up to {}",
+        snapshot,
+        level,
+        sqldata::MIGRATION_LEVEL
+      )));
+    }
+  }
+
+  // nothing can use the current database from here until it's replaced.
+  let live = if dbfile.exists() {
+    Some(lock_exclusive(dbfile)?)
+  } else {
+    None
+  };
+
+  // copy the snapshot next to the database, so the final rename is atomic.
+  let restoring = dbfile.with_extension("restoring");
+  fs::copy(snapshot, &restoring)?;
+
+  match &live {
+    Some(conn) => {
+      // save the current database, its wal checkpointed in by the lock.
+      let prerestore = dbfile.with_extension("prerestore");
+      let mut dst = Connection::open(&prerestore)?;
+      Backup::new(conn, &mut dst)?.run_to_completion(1024, Duration::from_millis(0), None)?;
+      info!("saved current database as {:?}", prerestore);
+    }
+    None => (),
+  }
+
+  // the old wal and shm files would be applied to the restored database.
+  for ext in ["-wal", "-shm"].iter() {
+    let f = PathBuf::from(format!("{}{}", dbfile.display(), ext));
+    if f.exists() {
+      fs::remove_file(f)?;
+    }
+  }
+
+  fs::rename(&restoring, dbfile)?;
+  drop(live);
+  info!("restored {:?} from {:?}", dbfile, snapshot);
+
+  Ok(())
+}
@@ -0,0 +1,476 @@
+use error::Error;
+use expr;
+use retention::RollupEntry;
+use schemars::JsonSchema;
+use sciota_protocol::protocol::Measurement;
+use std::collections::{BTreeMap, HashMap};
+
+// per-sensor calibration, and virtual sensors computed from other sensors.
+//
+// Measurements are stored raw.  A sensor has a history of calibrations, each
+// in force from its effective date until the next one's.  Listings and exports
+// apply, to each measurement, the calibration in force at its measuredate, so
+// recalibrating a sensor doesn't change its older values.  Rollups are of the
+// raw values, and calibrated as they're listed.  Every change to a history is
+// recorded in an audit trail.
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Calibration {
+  // raw * scale + offset
+  Linear { offset: f64, scale: f64 },
+  // c0 + c1 * raw + c2 * raw^2 + ...
+  Polynomial { coefficients: Vec<f64> },
+  // [raw, value] points, in increasing raw order.  Values between points are
+  // interpolated; values outside them are clamped to the end points.
+  Table { points: Vec<[f64; 2]> },
+}
+
+pub const MAX_COEFFICIENTS: usize = 10;
+pub const MAX_TABLE_POINTS: usize = 1000;
+
+// set a sensor's calibration from 'effective' on or, with no calibration,
+// leave its measurements raw from then on.  A calibration already effective at
+// that date is replaced.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SensorCalibration {
+  pub sensor: i64,
+  // now if missing.  From getcalibration, the date the current calibration
+  // took effect; missing if there has never been one.
+  pub effective: Option<i64>,
+  pub calibration: Option<Calibration>,
+}
+
+// an entry in a sensor's calibration history.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct CalibrationRecord {
+  pub id: i64,
+  pub sensor: i64,
+  pub effective: i64,
+  pub calibration: Option<Calibration>,
+  pub createdate: i64,
+  // who set it.
+  pub user: String,
+}
+
+// audit actions.
+pub const AUDIT_SET: &str = "set";
+pub const AUDIT_DELETE: &str = "delete";
+pub const AUDIT_IMPORT: &str = "import";
+
+// a change to a sensor's calibration history: the record set, deleted or
+// imported, who did it and when.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct CalibrationAudit {
+  pub sensor: i64,
+  pub user: String,
+  pub action: String,
+  pub effective: i64,
+  pub calibration: Option<Calibration>,
+  pub changedate: i64,
+}
+
+// a calibration history as (effective, calibration), in effective order.
+pub type History = Vec<(i64, Option<Calibration>)>;
+
+fn check_finite<'a, I: IntoIterator<Item = &'a f64>>(vals: I) -> Result<(), Error> {
+  if vals.into_iter().all(|v| v.is_finite()) {
+    Ok(())
+  } else {
+    Err(Error::BadRequest(
+      "calibration numbers must be finite".to_string(),
+    ))
+  }
+}
+
+impl Calibration {
+  pub fn check(&self) -> Result<(), Error> {
+    match self {
+      Calibration::Linear { offset, scale } => check_finite(&[*offset, *scale])?,
+      Calibration::Polynomial { coefficients } => {
+        if coefficients.is_empty() || coefficients.len() > MAX_COEFFICIENTS {
+          return Err(Error::BadRequest(format!(
+            "polynomials need between 1 and {} coefficients",
+            MAX_COEFFICIENTS
+          )));
+        }
+        check_finite(coefficients)?;
+      }
+      Calibration::Table { points } => {
+        if points.len() < 2 || points.len() > MAX_TABLE_POINTS {
+          return Err(Error::BadRequest(format!(
+            "tables need between 2 and {} points",
+            MAX_TABLE_POINTS
+          )));
+        }
+        check_finite(points.iter().flat_map(|p| p.iter()))?;
+        if points.windows(2).any(|w| w[0][0] >= w[1][0]) {
+          return Err(Error::BadRequest(
+            "table points must be in increasing raw order".to_string(),
+          ));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn apply(&self, raw: f64) -> f64 {
+    match self {
+      Calibration::Linear { offset, scale } => raw * scale + offset,
+      Calibration::Polynomial { coefficients } => {
+        coefficients.iter().rev().fold(0.0, |acc, c| acc * raw + c)
+      }
+      Calibration::Table { points } => {
+        let last = points.len() - 1;
+        if raw <= points[0][0] {
+          points[0][1]
+        } else if raw >= points[last][0] {
+          points[last][1]
+        } else {
+          // the first point above raw; there is one, and it isn't the first.
+          let i = points.iter().position(|p| p[0] > raw).unwrap_or(last);
+          let (x0, y0, x1, y1) = (
+            points[i - 1][0],
+            points[i - 1][1],
+            points[i][0],
+            points[i][1],
+          );
+          y0 + (raw - x0) * (y1 - y0) / (x1 - x0)
+        }
+      }
+    }
+  }
+}
+
+// the calibration in force at 'date': the one with the latest effective date
+// at or before it.
+pub fn in_force(history: &[(i64, Option<Calibration>)], date: i64) -> Option<&Calibration> {
+  let i = match history.binary_search_by_key(&date, |(effective, _)| *effective) {
+    Ok(i) => i,
+    Err(0) => return None,
+    Err(i) => i - 1,
+  };
+  history[i].1.as_ref()
+}
+
+pub fn calibrate(history: &[(i64, Option<Calibration>)], measurements: &mut Vec<Measurement>) {
+  if history.is_empty() {
+    return;
+  }
+  for m in measurements.iter_mut() {
+    match in_force(history, m.measuredate) {
+      Some(c) => m.value = c.apply(m.value),
+      None => (),
+    }
+  }
+}
+
+// rollups of raw values, with the calibration in force at the start of each
+// period applied to their mean, min and max.  That's exact for a linear
+// calibration that doesn't change during the period.  Otherwise it's an
+// approximation: the mean of a curve isn't the curve of the mean, and a
+// calibration that isn't monotonic can have its extremes between min and max.
+pub fn calibrate_rollups(history: &[(i64, Option<Calibration>)], entries: &mut Vec<RollupEntry>) {
+  if history.is_empty() {
+    return;
+  }
+  for e in entries.iter_mut() {
+    match in_force(history, e.period) {
+      Some(c) => {
+        let (a, b) = (c.apply(e.min), c.apply(e.max));
+        e.mean = c.apply(e.mean);
+        e.min = a.min(b);
+        e.max = a.max(b);
+      }
+      None => (),
+    }
+  }
+}
+
+// a virtual sensor's expression, over named input sensors.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct Formula {
+  pub expression: String,
+  // input name -> sensor id.
+  pub inputs: BTreeMap<String, i64>,
+}
+
+// make a sensor virtual or, with no formula, a regular sensor again.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct VirtualSensor {
+  pub sensor: i64,
+  pub formula: Option<Formula>,
+}
+
+pub const MAX_INPUTS: usize = 16;
+
+impl Formula {
+  fn names(&self) -> Vec<String> {
+    self.inputs.keys().cloned().collect()
+  }
+
+  // check the input names and parse the expression.
+  pub fn compile(&self) -> Result<expr::Expr, Error> {
+    if self.inputs.is_empty() || self.inputs.len() > MAX_INPUTS {
+      return Err(Error::BadRequest(format!(
+        "virtual sensors need between 1 and {} inputs",
+        MAX_INPUTS
+      )));
+    }
+    for name in self.inputs.keys() {
+      expr::check_name(name.as_str())?;
+    }
+    expr::parse(self.expression.as_str(), &self.names())
+  }
+}
+
+// a virtual sensor's measurements from its inputs' measurements, given in the
+// order of formula.inputs.  There's a value at each measuredate where any
+// input has a measurement, once every input has one, using each input's most
+// recent measurement.  Those have id 0.  Non-finite results (say, from a
+// division by zero) are left out.
+pub fn evaluate(
+  sensor: i64,
+  formula: &Formula,
+  series: Vec<Vec<Measurement>>,
+) -> Result<Vec<Measurement>, Error> {
+  let e = formula.compile()?;
+
+  let mut events: Vec<(usize, Measurement)> = series
+    .into_iter()
+    .enumerate()
+    .flat_map(|(i, ms)| ms.into_iter().map(move |m| (i, m)))
+    .collect();
+  events.sort_by_key(|(_, m)| m.measuredate);
+
+  let mut current: Vec<Option<f64>> = vec![None; formula.inputs.len()];
+  let mut vals = vec![0.0; formula.inputs.len()];
+  let mut pv = Vec::new();
+
+  let mut i = 0;
+  while i < events.len() {
+    let measuredate = events[i].1.measuredate;
+    let mut createdate = i64::min_value();
+    while i < events.len() && events[i].1.measuredate == measuredate {
+      let (input, m) = &events[i];
+      current[*input] = Some(m.value);
+      createdate = createdate.max(m.createdate);
+      i += 1;
+    }
+
+    if current.iter().all(|c| c.is_some()) {
+      for (v, c) in vals.iter_mut().zip(current.iter()) {
+        *v = c.unwrap_or(0.0);
+      }
+      let value = e.eval(&vals);
+      if value.is_finite() {
+        pv.push(Measurement {
+          id: 0,
+          sensor: sensor,
+          value: value,
+          measuredate: measuredate,
+          createdate: createdate,
+        });
+      }
+    }
+  }
+
+  Ok(pv)
+}
+
+// an imported formula, with its inputs' archive ids replaced by their new ids.
+pub fn remap_inputs(f: &Formula, ids: &HashMap<i64, i64>) -> Result<Formula, Error> {
+  let mut inputs = BTreeMap::new();
+  for (name, input) in f.inputs.iter() {
+    match ids.get(input) {
+      Some(id) => {
+        inputs.insert(name.clone(), *id);
+      }
+      None => {
+        return Err(Error::BadRequest(format!(
+          "virtual sensor input '{}' is sensor {}, which isn't in the archive",
+          name, input
+        )))
+      }
+    }
+  }
+  Ok(Formula {
+    expression: f.expression.clone(),
+    inputs: inputs,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn measurement(value: f64, measuredate: i64) -> Measurement {
+    Measurement {
+      id: 1,
+      sensor: 1,
+      value: value,
+      measuredate: measuredate,
+      createdate: measuredate,
+    }
+  }
+
+  fn linear(offset: f64, scale: f64) -> Option<Calibration> {
+    Some(Calibration::Linear { offset, scale })
+  }
+
+  fn formula(expression: &str, inputs: &[&str]) -> Formula {
+    Formula {
+      expression: expression.to_string(),
+      inputs: inputs
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (n.to_string(), i as i64 + 1))
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn apply() {
+    assert_eq!(linear(1.0, 2.0).unwrap().apply(3.0), 7.0);
+    let poly = Calibration::Polynomial {
+      coefficients: vec![1.0, 2.0, 3.0],
+    };
+    assert_eq!(poly.apply(2.0), 17.0);
+    let table = Calibration::Table {
+      points: vec![[0.0, 0.0], [10.0, 100.0], [20.0, 120.0]],
+    };
+    assert_eq!(table.apply(5.0), 50.0);
+    assert_eq!(table.apply(10.0), 100.0);
+    assert_eq!(table.apply(15.0), 110.0);
+    // clamped outside the points.
+    assert_eq!(table.apply(-5.0), 0.0);
+    assert_eq!(table.apply(25.0), 120.0);
+  }
+
+  #[test]
+  fn check() {
+    assert!(linear(0.0, 1.0).unwrap().check().is_ok());
+    assert!(linear(std::f64::NAN, 1.0).unwrap().check().is_err());
+    let poly = |n: usize| Calibration::Polynomial {
+      coefficients: vec![1.0; n],
+    };
+    assert!(poly(0).check().is_err());
+    assert!(poly(MAX_COEFFICIENTS).check().is_ok());
+    assert!(poly(MAX_COEFFICIENTS + 1).check().is_err());
+    let table = |points: Vec<[f64; 2]>| Calibration::Table { points };
+    assert!(table(vec![[0.0, 0.0]]).check().is_err());
+    assert!(table(vec![[0.0, 0.0], [1.0, std::f64::INFINITY]])
+      .check()
+      .is_err());
+    assert!(table(vec![[1.0, 0.0], [1.0, 1.0]]).check().is_err());
+    assert!(table(vec![[1.0, 0.0], [0.0, 1.0]]).check().is_err());
+  }
+
+  #[test]
+  fn in_force_dates() {
+    let history: History = vec![
+      (100, linear(1.0, 1.0)),
+      (200, None),
+      (300, linear(3.0, 1.0)),
+    ];
+    assert!(in_force(&history, 99).is_none());
+    assert_eq!(in_force(&history, 100).unwrap().apply(0.0), 1.0);
+    assert_eq!(in_force(&history, 199).unwrap().apply(0.0), 1.0);
+    // none from 200 until the next one.
+    assert!(in_force(&history, 200).is_none());
+    assert!(in_force(&history, 299).is_none());
+    assert_eq!(in_force(&history, 300).unwrap().apply(0.0), 3.0);
+    assert_eq!(
+      in_force(&history, i64::max_value()).unwrap().apply(0.0),
+      3.0
+    );
+    assert!(in_force(&[], 100).is_none());
+  }
+
+  #[test]
+  fn calibrate_measurements() {
+    let history: History = vec![(100, linear(0.0, 2.0)), (200, linear(0.0, 10.0))];
+    let mut ms = vec![
+      measurement(1.0, 50),
+      measurement(1.0, 150),
+      measurement(1.0, 250),
+    ];
+    calibrate(&history, &mut ms);
+    assert_eq!(
+      ms.iter().map(|m| m.value).collect::<Vec<f64>>(),
+      vec![1.0, 2.0, 10.0]
+    );
+  }
+
+  #[test]
+  fn calibrate_rollup_extremes() {
+    // a negative scale swaps min and max.
+    let history: History = vec![(0, linear(0.0, -1.0))];
+    let mut entries = vec![RollupEntry {
+      sensor: 1,
+      period: 0,
+      samples: 2,
+      mean: 2.0,
+      min: 1.0,
+      max: 3.0,
+    }];
+    calibrate_rollups(&history, &mut entries);
+    assert_eq!(
+      (entries[0].mean, entries[0].min, entries[0].max),
+      (-2.0, -3.0, -1.0)
+    );
+  }
+
+  #[test]
+  fn evaluate_inputs() {
+    let f = formula("a - b", &["a", "b"]);
+    let a = vec![measurement(10.0, 100), measurement(20.0, 300)];
+    let b = vec![measurement(1.0, 200), measurement(2.0, 300)];
+    let v = evaluate(9, &f, vec![a, b]).unwrap();
+    // nothing until both inputs have a value, then one per date with the
+    // latest of each.
+    assert_eq!(
+      v.iter()
+        .map(|m| (m.measuredate, m.value))
+        .collect::<Vec<(i64, f64)>>(),
+      vec![(200, 9.0), (300, 18.0)]
+    );
+    assert!(v.iter().all(|m| m.id == 0 && m.sensor == 9));
+  }
+
+  #[test]
+  fn evaluate_drops_non_finite() {
+    let f = formula("a / b", &["a", "b"]);
+    let a = vec![measurement(1.0, 100)];
+    let b = vec![measurement(0.0, 100), measurement(2.0, 200)];
+    let v = evaluate(9, &f, vec![a, b]).unwrap();
+    assert_eq!(
+      v.iter()
+        .map(|m| (m.measuredate, m.value))
+        .collect::<Vec<(i64, f64)>>(),
+      vec![(200, 0.5)]
+    );
+  }
+
+  #[test]
+  fn compile() {
+    assert!(formula("a + 1", &["a"]).compile().is_ok());
+    assert!(formula("1", &[]).compile().is_err());
+    assert!(formula("a + c", &["a", "b"]).compile().is_err());
+    assert!(formula("max + 1", &["max"]).compile().is_err());
+    let many: Vec<String> = (0..MAX_INPUTS + 1).map(|i| format!("i{}", i)).collect();
+    let many: Vec<&str> = many.iter().map(|s| s.as_str()).collect();
+    assert!(formula("i0", &many).compile().is_err());
+    assert!(formula("i0", &many[..MAX_INPUTS]).compile().is_ok());
+  }
+
+  #[test]
+  fn remap() {
+    let f = formula("a + b", &["a", "b"]);
+    let ids: HashMap<i64, i64> = vec![(1, 11), (2, 12)].into_iter().collect();
+    let r = remap_inputs(&f, &ids).unwrap();
+    assert_eq!(r.inputs["a"], 11);
+    assert_eq!(r.inputs["b"], 12);
+    assert!(remap_inputs(&f, &vec![(1, 11)].into_iter().collect()).is_err());
+  }
+}
@@ -0,0 +1,239 @@
+use config::Config;
+use error::Error;
+use rpassword;
+use sqldata;
+use storage;
+use storage::Storage;
+use util;
+
+// server subcommands for setup and maintenance, for deployment scripts and
+// first time setup: they work on the configured database directly, without
+// the server running or any email being sent.  Results go to stdout, the log
+// to stderr.
+
+pub const USAGE: &str = "usage: server [--config <file>] [command]
+
+commands:
+  serve                               run the server (the default)
+  migrate                             bring the database schema up to date
+  create-user <name> [email]          add a registered user; the password is
+                                      read from stdin
+  reset-password <name>               set a user's password, read from stdin
+  check-db                            check the database's schema level and
+                                      integrity
+  config check                        show the effective configuration
+  backup                              snapshot the database to [backup] dir
+  restore <snapshot file>             replace the database with a snapshot;
+                                      run with the server stopped
+  bench <new db file> [measurements] [sensors]
+                                      benchmark a synthetic database";
+
+// a password, one line from stdin, not echoed if that's a terminal.  Prompts
+// on stderr, so it can be piped.
+fn read_password() -> Result<String, Error> {
+  let pwd = rpassword::prompt_password_stderr("password: ")?;
+  if pwd.is_empty() {
+    Err(Error::BadRequest("the password can't be empty".to_string()))
+  } else {
+    Ok(pwd)
+  }
+}
+
+pub fn migrate(config: &Config) -> Result<(), Error> {
+  let db = storage::open_storage(config)?;
+  println!("database at migration level {}", db.migration_level()?);
+  Ok(())
+}
+
+// add a user that's registered already, as if they'd followed the email link.
+// Admins are set from the config, as on startup.
+pub fn create_user(config: &Config, name: &str, email: Option<&str>) -> Result<(), Error> {
+  let db = storage::open_storage(config)?;
+  match db.read_user(name) {
+    Ok(_) => return Err(Error::Conflict(format!("user exists: {}", name))),
+    Err(Error::NotFound(_)) => (),
+    Err(e) => return Err(e),
+  }
+
+  let pwd = read_password()?;
+  add_user(&*db, config, name, email, pwd.as_str())?;
+
+  info!("created user {}", name);
+  println!("created user {}", name);
+  Ok(())
+}
+
+// create_user, once it has the password.
+fn add_user(
+  db: &dyn Storage,
+  config: &Config,
+  name: &str,
+  email: Option<&str>,
+  pwd: &str,
+) -> Result<i64, Error> {
+  let salt = util::salt_string();
+  let id = db.new_registered_user(
+    name.to_string(),
+    util::hash_password(pwd, salt.as_str()),
+    salt,
+    email.unwrap_or("").to_string(),
+  )?;
+  match &config.admins {
+    Some(admins) => db.set_admins(admins)?,
+    None => (),
+  }
+  Ok(id)
+}
+
+// set a new password, with a new salt.
+pub fn reset_password(config: &Config, name: &str) -> Result<(), Error> {
+  let db = storage::open_storage(config)?;
+  let mut user = db.read_user(name)?;
+
+  let pwd = read_password()?;
+  user.salt = util::salt_string();
+  user.hashwd = util::hash_password(pwd.as_str(), user.salt.as_str());
+  db.update_user(&user)?;
+
+  info!("reset the password for {}", name);
+  println!("reset the password for {}", name);
+  Ok(())
+}
+
+// report on the database without changing it; an error if it needs migrating
+// or has problems.
+pub fn check_db(config: &Config) -> Result<(), Error> {
+  let db = storage::connect(config, false)?;
+
+  let level = db.migration_level()?;
+  println!(
+    "migration level: {} (this server: {})",
+    level,
+    sqldata::MIGRATION_LEVEL
+  );
+  if level < sqldata::MIGRATION_LEVEL {
+    return Err(Error::Conflict(
+      "the database needs migrating: run 'server migrate'".to_string(),
+    ));
+  } else if level > sqldata::MIGRATION_LEVEL {
+    return Err(Error::Conflict(
+      "the database is from a newer server".to_string(),
+    ));
+  }
+
+  let problems = db.integrity_check()?;
+  for p in problems.iter() {
+    println!("problem: {}", p);
+  }
+
+  let users = db.user_listing()?;
+  println!(
+    "users: {} ({} registered, {} admin)",
+    users.len(),
+    users.iter().filter(|u| u.registered).count(),
+    users.iter().filter(|u| u.admin).count()
+  );
+
+  if problems.is_empty() {
+    println!("ok");
+    Ok(())
+  } else {
+    Err(Error::Internal(format!(
+      "the database failed its integrity check, with {} problems",
+      problems.len()
+    )))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rusqlite::{params, Connection};
+  use std::env;
+  use storage_tests;
+
+  #[test]
+  fn add_user_adds_a_registered_user() {
+    let (tmp, db) = storage_tests::sqlite();
+    let mut config = storage_tests::config(tmp.path.as_path());
+    let name = "create-user-test";
+    config.admins = Some(vec![name.to_string()]);
+
+    let id = add_user(&db, &config, name, Some("me@example.com"), "secret").unwrap();
+    let user = db.read_user(name).unwrap();
+    assert_eq!(user.id, id);
+    assert_eq!(user.registration_key, None);
+    assert_eq!(user.email, "me@example.com");
+    assert_eq!(
+      user.hashwd,
+      util::hash_password("secret", user.salt.as_str())
+    );
+    assert!(user.admin);
+
+    assert!(match add_user(&db, &config, name, None, "other") {
+      Err(Error::Conflict(_)) => true,
+      _ => false,
+    });
+
+    config.admins = None;
+    add_user(&db, &config, "create-user-test-2", None, "secret").unwrap();
+    let user = db.read_user("create-user-test-2").unwrap();
+    assert_eq!((user.email.as_str(), user.admin), ("", false));
+  }
+
+  #[test]
+  fn check_db_levels() {
+    let (tmp, _db) = storage_tests::sqlite();
+    let config = storage_tests::config(tmp.path.as_path());
+    check_db(&config).unwrap();
+
+    let set_level = |level: i64| {
+      Connection::open(tmp.path.as_path())
+        .unwrap()
+        .execute(
+          "UPDATE singlevalue SET value = ?1 WHERE name = 'migration_level'",
+          params![level.to_string()],
+        )
+        .unwrap();
+    };
+    for level in [sqldata::MIGRATION_LEVEL - 1, sqldata::MIGRATION_LEVEL + 1].iter() {
+      set_level(*level);
+      assert!(match check_db(&config) {
+        Err(Error::Conflict(_)) => true,
+        _ => false,
+      });
+    }
+    set_level(sqldata::MIGRATION_LEVEL);
+    check_db(&config).unwrap();
+  }
+
+  #[test]
+  fn check_db_problems() {
+    let (tmp, _db) = storage_tests::sqlite();
+    let config = storage_tests::config(tmp.path.as_path());
+
+    // a sensor on a device that isn't there.
+    Connection::open(tmp.path.as_path())
+      .unwrap()
+      .execute(
+        "INSERT INTO sensor (device, name, description, createdate, changeddate)
+         VALUES (9999, 'orphan', '', 0, 0)",
+        params![],
+      )
+      .unwrap();
+    assert!(match check_db(&config) {
+      Err(Error::Internal(_)) => true,
+      _ => false,
+    });
+  }
+
+  #[test]
+  fn check_db_needs_a_database() {
+    let config =
+      storage_tests::config(env::temp_dir().join("sciota-no-such-database.db").as_path());
+    assert!(match check_db(&config) {
+      Err(Error::NotFound(_)) => true,
+      _ => false,
+    });
+  }
+}
@@ -0,0 +1,215 @@
+use error::Error;
+use rand::Rng;
+use rusqlite::{params, ToSql};
+use sciota_protocol::protocol::{SaveDevice, SaveMeasurement, SaveSensor};
+use sqldata;
+use sqldata::DbPool;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// a reproducible load for timing the sqlite queries: fill a new database with
+// synthetic measurements, then time the listings, range queries and inserts.
+// Run it against the same parameters before and after a change.
+//
+//   server bench <new db file> [measurements] [sensors]
+
+const MINUTE: i64 = 60_000;
+
+fn timed<F>(name: &str, n: i64, mut f: F) -> Result<(), Error>
+where
+  F: FnMut(i64) -> Result<(), Error>,
+{
+  let start = Instant::now();
+  for i in 0..n {
+    f(i)?;
+  }
+  let secs = start.elapsed().as_secs_f64();
+  println!(
+    "{:<40} {:>8} runs {:>10.3}s {:>12.3}ms/run",
+    name,
+    n,
+    secs,
+    secs * 1000.0 / n as f64
+  );
+  Ok(())
+}
+
+// one reading per sensor per minute, ending now, inserted in big transactions.
+fn load(pool: &DbPool, sensors: &Vec<i64>, count: i64, start: i64) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+  let mut rng = rand::thread_rng();
+  let batch = 100_000;
+  let per_sensor = sensors.len() as i64;
+
+  let mut i = 0;
+  while i < count {
+    let tx = conn.transaction()?;
+    {
+      let mut pstmt = tx.prepare(
+        "INSERT INTO measurement (sensor, value, measuredate, createdate)
+         VALUES (?1, ?2, ?3, ?4)",
+      )?;
+      let end = if i + batch < count { i + batch } else { count };
+      while i < end {
+        let date = start + (i / per_sensor) * MINUTE;
+        pstmt.execute(params![
+          sensors[(i % per_sensor) as usize],
+          rng.gen_range(-50.0, 50.0),
+          date,
+          date
+        ])?;
+        i += 1;
+      }
+    }
+    tx.commit()?;
+  }
+
+  Ok(())
+}
+
+fn query_plan(pool: &DbPool, sql: &str, args: &[&dyn ToSql]) -> Result<(), Error> {
+  let conn = pool.get()?;
+  let mut pstmt = conn.prepare(format!("EXPLAIN QUERY PLAN {}", sql).as_str())?;
+  let rec_iter = pstmt.query_map(args, |row| row.get::<_, String>(3))?;
+
+  println!("{}", sql);
+  for rec in rec_iter {
+    println!("  {}", rec?);
+  }
+  Ok(())
+}
+
+pub fn run(dbfile: &Path, count: i64, sensorcount: i64) -> Result<(), Error> {
+  if dbfile.exists() {
+    return Err(Error::BadRequest(format!(
+      "{:?} exists; bench needs a new database file",
+      dbfile
+    )));
+  }
+  if sensorcount < 1 {
+    return Err(Error::BadRequest("need at least one sensor".to_string()));
+  }
+
+  sqldata::dbinit(dbfile)?;
+  let pool = sqldata::connection_pool(dbfile, 4, Duration::from_secs(5))?;
+
+  let uid = sqldata::new_user(
+    &pool,
+    "bench".to_string(),
+    String::new(),
+    String::new(),
+    String::new(),
+    String::new(),
+  )?;
+
+  // ten sensors per device.
+  let mut sensors = Vec::new();
+  let mut device = 0;
+  for s in 0..sensorcount {
+    if s % 10 == 0 {
+      device = sqldata::save_device(
+        &pool,
+        uid,
+        &SaveDevice {
+          id: None,
+          name: format!("device {}", s / 10),
+          description: String::new(),
+        },
+      )?;
+    }
+    sensors.push(
+      sqldata::save_sensor(
+        &pool,
+        uid,
+        &SaveSensor {
+          id: None,
+          device: device,
+          name: format!("sensor {}", s),
+          description: String::new(),
+        },
+      )?
+      .id,
+    );
+  }
+
+  let per_sensor = (count + sensorcount - 1) / sensorcount;
+  let start = sqldata::now()? - per_sensor * MINUTE;
+  let end = start + per_sensor * MINUTE;
+
+  println!(
+    "loading {} measurements for {} sensors, {} per sensor",
+    count, sensorcount, per_sensor
+  );
+  let loadstart = Instant::now();
+  load(&pool, &sensors, count, start)?;
+  let secs = loadstart.elapsed().as_secs_f64();
+  println!(
+    "loaded in {:.1}s: {:.0} inserts/sec",
+    secs,
+    count as f64 / secs
+  );
+
+  let mut rng = rand::thread_rng();
+  let mut sensor = || sensors[rng.gen_range(0, sensors.len())];
+  let mut rng = rand::thread_rng();
+  let mut window = |len: i64| {
+    let from = if end - len > start {
+      rng.gen_range(start, end - len)
+    } else {
+      start
+    };
+    (from, from + len)
+  };
+
+  timed("devicelisting", 100, |_| {
+    sqldata::devicelisting(&pool, uid).map(|_| ())
+  })?;
+  timed("sensorlisting", 100, |_| {
+    sqldata::sensorlisting(&pool, uid, None).map(|_| ())
+  })?;
+  timed("measurement_listing, whole sensor", 5, |_| {
+    sqldata::measurement_listing(&pool, uid, sensor(), None, None).map(|_| ())
+  })?;
+  timed("measurement_listing, 1 day range", 100, |_| {
+    let (from, to) = window(24 * 60 * MINUTE);
+    sqldata::measurement_listing(&pool, uid, sensor(), Some(from), Some(to)).map(|_| ())
+  })?;
+  timed("measurement_listing, 1 hour range", 1000, |_| {
+    let (from, to) = window(60 * MINUTE);
+    sqldata::measurement_listing(&pool, uid, sensor(), Some(from), Some(to)).map(|_| ())
+  })?;
+  timed("add_measurement", 1000, |i| {
+    sqldata::add_measurement(
+      &pool,
+      uid,
+      &SaveMeasurement {
+        value: i as f64,
+        sensor: sensor(),
+        measuredate: end + i,
+      },
+    )
+    .map(|_| ())
+  })?;
+
+  println!();
+  query_plan(
+    &pool,
+    "SELECT id, value, measuredate, createdate FROM measurement where sensor = ?1
+     AND measuredate >= ?2 AND measuredate < ?3 ORDER BY measuredate",
+    params![sensors[0], start, end],
+  )?;
+  query_plan(
+    &pool,
+    "SELECT sensor.id FROM sensor, device
+     WHERE sensor.id = ?1 AND sensor.device = device.id AND device.user = ?2",
+    params![sensors[0], uid],
+  )?;
+  query_plan(
+    &pool,
+    "SELECT id, device, name, description, createdate, changeddate FROM sensor
+     WHERE device IN (SELECT id FROM device WHERE user = ?1)",
+    params![uid],
+  )?;
+
+  Ok(())
+}
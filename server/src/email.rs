@@ -1,5 +1,6 @@
 use lettre::smtp::response::Response;
 use lettre::{EmailAddress, Envelope, SendableEmail, SmtpClient, SmtpTransport, Transport};
+use metrics;
 use std::error::Error;
 use util;
 
@@ -40,7 +41,9 @@ pub fn send_registration(
 
   let mut mailer = SmtpTransport::new(SmtpClient::new_unencrypted_localhost()?);
   // Send the email
-  mailer.send(email).map_err(|e| e.into())
+  let res = mailer.send(email);
+  metrics::email(res.is_ok());
+  res.map_err(|e| e.into())
 }
 
 pub fn send_registration_notification(
@@ -70,5 +73,7 @@ pub fn send_registration_notification(
 
   let mut mailer = SmtpTransport::new(SmtpClient::new_unencrypted_localhost()?);
   // Send the email
-  mailer.send(email).map_err(|e| e.into())
+  let res = mailer.send(email);
+  metrics::email(res.is_ok());
+  res.map_err(|e| e.into())
 }
@@ -0,0 +1,292 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use postgres;
+use postgres::error::{FOREIGN_KEY_VIOLATION, UNIQUE_VIOLATION};
+use r2d2;
+use rusqlite;
+use schemars::JsonSchema;
+use sciota_protocol::protocol::ServerResponse;
+use serde_json;
+use serde_json::error::Category;
+use std::convert::From;
+use std::fmt;
+use std::io;
+use std::num::{ParseIntError, TryFromIntError};
+use std::time::SystemTimeError;
+
+// errors returned from interfaces and sqldata.  Each maps to an http status
+// and a stable code that clients can match on.  Database and internal errors
+// are logged in full but only reported to the client generically.
+#[derive(Debug)]
+pub enum Error {
+  BadRequest(String),
+  Unauthorized(String),
+  InvalidWhat(String),
+  Forbidden(String),
+  NotFound(String),
+  Conflict(String),
+  TooLarge(String),
+  Database(rusqlite::Error),
+  Postgres(postgres::Error),
+  Email(String),
+  Internal(String),
+}
+
+// the 'content' of a "server error" response.
+#[derive(Serialize, Debug, JsonSchema)]
+pub struct ErrorContent {
+  pub code: String,
+  pub message: String,
+}
+
+impl Error {
+  pub fn code(&self) -> &'static str {
+    match self {
+      Error::BadRequest(_) => "bad_request",
+      Error::Unauthorized(_) => "unauthorized",
+      Error::InvalidWhat(_) => "invalid_what",
+      Error::Forbidden(_) => "forbidden",
+      Error::NotFound(_) => "not_found",
+      Error::Conflict(_) => "conflict",
+      Error::TooLarge(_) => "too_large",
+      Error::Database(_) => "database_error",
+      Error::Postgres(_) => "database_error",
+      Error::Email(_) => "email_error",
+      Error::Internal(_) => "internal_error",
+    }
+  }
+
+  pub fn status(&self) -> StatusCode {
+    match self {
+      Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+      Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+      Error::InvalidWhat(_) => StatusCode::BAD_REQUEST,
+      Error::Forbidden(_) => StatusCode::FORBIDDEN,
+      Error::NotFound(_) => StatusCode::NOT_FOUND,
+      Error::Conflict(_) => StatusCode::CONFLICT,
+      Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+      Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      Error::Postgres(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      Error::Email(_) => StatusCode::BAD_GATEWAY,
+      Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  // the message that's safe to show to clients.
+  pub fn public_message(&self) -> String {
+    match self {
+      Error::BadRequest(s) => s.clone(),
+      Error::Unauthorized(s) => s.clone(),
+      Error::InvalidWhat(wat) => format!("invalid 'what' code:'{}'", wat),
+      Error::Forbidden(s) => s.clone(),
+      Error::NotFound(s) => s.clone(),
+      Error::Conflict(s) => s.clone(),
+      Error::TooLarge(s) => s.clone(),
+      Error::Database(_) => "database error".to_string(),
+      Error::Postgres(_) => "database error".to_string(),
+      Error::Email(_) => "error sending email".to_string(),
+      Error::Internal(_) => "internal server error".to_string(),
+    }
+  }
+
+  pub fn server_response(&self) -> ServerResponse {
+    ServerResponse {
+      what: "server error".to_string(),
+      content: serde_json::to_value(ErrorContent {
+        code: self.code().to_string(),
+        message: self.public_message(),
+      })
+      .unwrap_or(serde_json::Value::Null),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Database(e) => write!(f, "database error: {}", e),
+      Error::Postgres(e) => write!(f, "database error: {}", e),
+      Error::Email(s) => write!(f, "email error: {}", s),
+      Error::Internal(s) => write!(f, "internal error: {}", s),
+      _ => write!(f, "{}", self.public_message()),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl ResponseError for Error {
+  fn error_response(&self) -> HttpResponse {
+    let mut builder = HttpResponse::build(self.status());
+    match self {
+      Error::Unauthorized(_) => {
+        builder.header("WWW-Authenticate", "Basic realm=\"sciota\"");
+      }
+      _ => (),
+    }
+    builder.json(self.server_response())
+  }
+}
+
+impl From<rusqlite::Error> for Error {
+  fn from(e: rusqlite::Error) -> Self {
+    match e {
+      rusqlite::Error::QueryReturnedNoRows => Error::NotFound("record not found".to_string()),
+      rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error {
+          code: rusqlite::ffi::ErrorCode::ConstraintViolation,
+          ..
+        },
+        _,
+      ) => Error::Conflict("constraint violation".to_string()),
+      e => Error::Database(e),
+    }
+  }
+}
+
+impl From<postgres::Error> for Error {
+  fn from(e: postgres::Error) -> Self {
+    match e.code() {
+      Some(c) if *c == UNIQUE_VIOLATION || *c == FOREIGN_KEY_VIOLATION => {
+        Error::Conflict("constraint violation".to_string())
+      }
+      _ => Error::Postgres(e),
+    }
+  }
+}
+
+impl From<r2d2::Error> for Error {
+  fn from(e: r2d2::Error) -> Self {
+    Error::Internal(e.to_string())
+  }
+}
+
+// bad json from the client is a bad request; failing to read it isn't.
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Self {
+    match e.classify() {
+      Category::Syntax | Category::Data | Category::Eof => Error::BadRequest(e.to_string()),
+      Category::Io => Error::Internal(e.to_string()),
+    }
+  }
+}
+
+impl From<SystemTimeError> for Error {
+  fn from(e: SystemTimeError) -> Self {
+    Error::Internal(e.to_string())
+  }
+}
+
+impl From<TryFromIntError> for Error {
+  fn from(e: TryFromIntError) -> Self {
+    Error::Internal(e.to_string())
+  }
+}
+
+impl From<ParseIntError> for Error {
+  fn from(e: ParseIntError) -> Self {
+    Error::Internal(e.to_string())
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(e: io::Error) -> Self {
+    Error::Internal(e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value;
+  use std::io::Read;
+
+  // a reader that fails.
+  struct Broken;
+
+  impl Read for Broken {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+      Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+    }
+  }
+
+  #[test]
+  fn bad_json_is_a_bad_request() {
+    for (json, category) in [
+      ("{\"what\": ", Category::Eof),
+      ("{\"what\"] ", Category::Syntax),
+    ]
+    .iter()
+    {
+      let e = serde_json::from_str::<Value>(json).unwrap_err();
+      assert_eq!(e.classify(), *category);
+      let e = Error::from(e);
+      assert_eq!(
+        (e.status(), e.code()),
+        (StatusCode::BAD_REQUEST, "bad_request")
+      );
+    }
+
+    let e = serde_json::from_str::<Vec<i64>>("[\"x\"]").unwrap_err();
+    assert_eq!(e.classify(), Category::Data);
+    let e = Error::from(e);
+    assert_eq!(
+      (e.status(), e.code()),
+      (StatusCode::BAD_REQUEST, "bad_request")
+    );
+    // the client sees what was wrong with it.
+    assert!(
+      e.public_message().contains("expected i64"),
+      "{}",
+      e.public_message()
+    );
+  }
+
+  #[test]
+  fn failing_to_read_json_is_internal() {
+    let e = Error::from(serde_json::from_reader::<_, Value>(Broken).unwrap_err());
+    assert_eq!(
+      (e.status(), e.code()),
+      (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+    );
+    assert_eq!(e.public_message(), "internal server error");
+    assert!(e.to_string().contains("reset"), "{}", e);
+  }
+
+  #[test]
+  fn statuses_and_codes() {
+    let errors = vec![
+      (Error::BadRequest("x".to_string()), 400, "bad_request"),
+      (Error::Unauthorized("x".to_string()), 401, "unauthorized"),
+      (Error::InvalidWhat("x".to_string()), 400, "invalid_what"),
+      (Error::Forbidden("x".to_string()), 403, "forbidden"),
+      (Error::NotFound("x".to_string()), 404, "not_found"),
+      (Error::Conflict("x".to_string()), 409, "conflict"),
+      (Error::TooLarge("x".to_string()), 413, "too_large"),
+      (
+        Error::Database(rusqlite::Error::InvalidColumnIndex(0)),
+        500,
+        "database_error",
+      ),
+      (Error::Email("x".to_string()), 502, "email_error"),
+      (Error::Internal("x".to_string()), 500, "internal_error"),
+    ];
+    for (e, status, code) in errors.iter() {
+      assert_eq!((e.status().as_u16(), e.code()), (*status, *code), "{:?}", e);
+      let content = e.server_response().content;
+      assert_eq!(content["code"], *code);
+      assert_eq!(content["message"], e.public_message());
+    }
+  }
+
+  #[test]
+  fn database_errors() {
+    assert_eq!(
+      Error::from(rusqlite::Error::QueryReturnedNoRows).code(),
+      "not_found"
+    );
+    let e = Error::from(rusqlite::Error::InvalidColumnIndex(0));
+    assert_eq!(e.code(), "database_error");
+    assert_eq!(e.public_message(), "database error");
+  }
+}
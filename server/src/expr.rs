@@ -0,0 +1,471 @@
+use error::Error;
+
+// the expression language for virtual sensors: numbers, input names,
+// + - * / ^, unary minus, parentheses and the functions in Func.  There are no
+// loops, assignments or user defined functions, so evaluating an expression
+// always terminates and can't touch anything but its inputs.
+//
+//   dewpoint(t, rh)
+//   (t * 9 / 5) + 32
+//   max(a, b) - min(a, b)
+
+pub const MAX_EXPRESSION_LEN: usize = 1000;
+
+// deepest nesting of parentheses, calls and unary minus.
+const MAX_DEPTH: usize = 50;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Pow,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Func {
+  Abs,
+  Sqrt,
+  Exp,
+  Ln,
+  Log10,
+  Round,
+  Min,
+  Max,
+  Pow,
+  // dew point in °C from temperature in °C and relative humidity in %.
+  Dewpoint,
+}
+
+impl Func {
+  // the function and its number of arguments.
+  fn lookup(name: &str) -> Option<(Func, usize)> {
+    match name {
+      "abs" => Some((Func::Abs, 1)),
+      "sqrt" => Some((Func::Sqrt, 1)),
+      "exp" => Some((Func::Exp, 1)),
+      "ln" => Some((Func::Ln, 1)),
+      "log10" => Some((Func::Log10, 1)),
+      "round" => Some((Func::Round, 1)),
+      "min" => Some((Func::Min, 2)),
+      "max" => Some((Func::Max, 2)),
+      "pow" => Some((Func::Pow, 2)),
+      "dewpoint" => Some((Func::Dewpoint, 2)),
+      _ => None,
+    }
+  }
+
+  fn apply(&self, args: &[f64]) -> f64 {
+    match self {
+      Func::Abs => args[0].abs(),
+      Func::Sqrt => args[0].sqrt(),
+      Func::Exp => args[0].exp(),
+      Func::Ln => args[0].ln(),
+      Func::Log10 => args[0].log10(),
+      Func::Round => args[0].round(),
+      Func::Min => args[0].min(args[1]),
+      Func::Max => args[0].max(args[1]),
+      Func::Pow => args[0].powf(args[1]),
+      Func::Dewpoint => {
+        // the Magnus formula.
+        let (b, c) = (17.62, 243.12);
+        let gamma = (args[1] / 100.0).ln() + b * args[0] / (c + args[0]);
+        c * gamma / (b - gamma)
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+  Number(f64),
+  // index into the variables the expression was parsed with.
+  Var(usize),
+  Neg(Box<Expr>),
+  Binary(Op, Box<Expr>, Box<Expr>),
+  Call(Func, Vec<Expr>),
+}
+
+impl Expr {
+  // non-finite results (division by zero, ln of a negative number) are left
+  // for the caller to deal with.
+  pub fn eval(&self, vars: &[f64]) -> f64 {
+    match self {
+      Expr::Number(n) => *n,
+      Expr::Var(i) => vars[*i],
+      Expr::Neg(e) => -e.eval(vars),
+      Expr::Binary(op, l, r) => {
+        let (l, r) = (l.eval(vars), r.eval(vars));
+        match op {
+          Op::Add => l + r,
+          Op::Sub => l - r,
+          Op::Mul => l * r,
+          Op::Div => l / r,
+          Op::Pow => l.powf(r),
+        }
+      }
+      Expr::Call(f, args) => {
+        let vals: Vec<f64> = args.iter().map(|a| a.eval(vars)).collect();
+        f.apply(&vals)
+      }
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Number(f64),
+  Ident(String),
+  Op(char),
+  LParen,
+  RParen,
+  Comma,
+}
+
+fn bad(msg: String) -> Error {
+  Error::BadRequest(format!("bad expression: {}", msg))
+}
+
+fn tokenize(src: &str) -> Result<Vec<Token>, Error> {
+  let chars: Vec<char> = src.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+
+  while i < chars.len() {
+    let c = chars[i];
+    if c.is_whitespace() {
+      i += 1;
+    } else if c.is_ascii_digit() || c == '.' {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+        i += 1;
+      }
+      // exponent, as in 1.5e-3
+      if i < chars.len() && (chars[i] == 'e' || chars[i] == 'E') {
+        i += 1;
+        if i < chars.len() && (chars[i] == '+' || chars[i] == '-') {
+          i += 1;
+        }
+        while i < chars.len() && chars[i].is_ascii_digit() {
+          i += 1;
+        }
+      }
+      let s: String = chars[start..i].iter().collect();
+      let n = s
+        .parse::<f64>()
+        .map_err(|_| bad(format!("bad number '{}'", s)))?;
+      tokens.push(Token::Number(n));
+    } else if c.is_ascii_alphabetic() || c == '_' {
+      let start = i;
+      while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+        i += 1;
+      }
+      tokens.push(Token::Ident(chars[start..i].iter().collect()));
+    } else {
+      tokens.push(match c {
+        '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+        '(' => Token::LParen,
+        ')' => Token::RParen,
+        ',' => Token::Comma,
+        _ => return Err(bad(format!("unexpected '{}'", c))),
+      });
+      i += 1;
+    }
+  }
+
+  Ok(tokens)
+}
+
+struct Parser<'a> {
+  tokens: Vec<Token>,
+  pos: usize,
+  depth: usize,
+  vars: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let t = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    t
+  }
+
+  fn expect(&mut self, t: Token) -> Result<(), Error> {
+    match self.next() {
+      Some(ref n) if *n == t => Ok(()),
+      Some(n) => Err(bad(format!("expected {:?}, found {:?}", t, n))),
+      None => Err(bad(format!("expected {:?} at the end", t))),
+    }
+  }
+
+  fn nest(&mut self) -> Result<(), Error> {
+    self.depth += 1;
+    if self.depth > MAX_DEPTH {
+      Err(bad("nested too deeply".to_string()))
+    } else {
+      Ok(())
+    }
+  }
+
+  // expr := term (('+' | '-') term)*
+  fn expr(&mut self) -> Result<Expr, Error> {
+    let mut e = self.term()?;
+    loop {
+      let op = match self.peek() {
+        Some(Token::Op('+')) => Op::Add,
+        Some(Token::Op('-')) => Op::Sub,
+        _ => return Ok(e),
+      };
+      self.next();
+      e = Expr::Binary(op, Box::new(e), Box::new(self.term()?));
+    }
+  }
+
+  // term := unary (('*' | '/') unary)*
+  fn term(&mut self) -> Result<Expr, Error> {
+    let mut e = self.unary()?;
+    loop {
+      let op = match self.peek() {
+        Some(Token::Op('*')) => Op::Mul,
+        Some(Token::Op('/')) => Op::Div,
+        _ => return Ok(e),
+      };
+      self.next();
+      e = Expr::Binary(op, Box::new(e), Box::new(self.unary()?));
+    }
+  }
+
+  // unary := '-' unary | power
+  fn unary(&mut self) -> Result<Expr, Error> {
+    self.nest()?;
+    let e = match self.peek() {
+      Some(Token::Op('-')) => {
+        self.next();
+        Expr::Neg(Box::new(self.unary()?))
+      }
+      _ => self.power()?,
+    };
+    self.depth -= 1;
+    Ok(e)
+  }
+
+  // power := primary ('^' unary)?, so 2^3^2 is 2^(3^2) and -2^2 is -(2^2).
+  fn power(&mut self) -> Result<Expr, Error> {
+    let base = self.primary()?;
+    match self.peek() {
+      Some(Token::Op('^')) => {
+        self.next();
+        Ok(Expr::Binary(
+          Op::Pow,
+          Box::new(base),
+          Box::new(self.unary()?),
+        ))
+      }
+      _ => Ok(base),
+    }
+  }
+
+  // primary := number | name | function '(' args ')' | '(' expr ')'
+  fn primary(&mut self) -> Result<Expr, Error> {
+    match self.next() {
+      Some(Token::Number(n)) => Ok(Expr::Number(n)),
+      Some(Token::LParen) => {
+        let e = self.expr()?;
+        self.expect(Token::RParen)?;
+        Ok(e)
+      }
+      Some(Token::Ident(name)) => match Func::lookup(name.as_str()) {
+        Some((f, arity)) => {
+          self.expect(Token::LParen)?;
+          let mut args = Vec::new();
+          for i in 0..arity {
+            if i > 0 {
+              self.expect(Token::Comma)?;
+            }
+            args.push(self.expr()?);
+          }
+          self.expect(Token::RParen)?;
+          Ok(Expr::Call(f, args))
+        }
+        None => match self.vars.iter().position(|v| *v == name) {
+          Some(i) => Ok(Expr::Var(i)),
+          None => Err(bad(format!("unknown input or function '{}'", name))),
+        },
+      },
+      Some(t) => Err(bad(format!("unexpected {:?}", t))),
+      None => Err(bad("unexpected end".to_string())),
+    }
+  }
+}
+
+// a valid input name: a letter or _, then letters, digits or _, and not
+// a function name.
+pub fn check_name(name: &str) -> Result<(), Error> {
+  let mut chars = name.chars();
+  let ok = match chars.next() {
+    Some(c) => {
+      (c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+    None => false,
+  };
+  if !ok || Func::lookup(name).is_some() {
+    Err(Error::BadRequest(format!("bad input name: '{}'", name)))
+  } else {
+    Ok(())
+  }
+}
+
+// parse an expression over the named variables; Var(i) refers to vars[i].
+pub fn parse(src: &str, vars: &[String]) -> Result<Expr, Error> {
+  if src.len() > MAX_EXPRESSION_LEN {
+    return Err(bad(format!(
+      "longer than {} characters",
+      MAX_EXPRESSION_LEN
+    )));
+  }
+
+  let mut parser = Parser {
+    tokens: tokenize(src)?,
+    pos: 0,
+    depth: 0,
+    vars: vars,
+  };
+  let e = parser.expr()?;
+  match parser.next() {
+    None => Ok(e),
+    Some(t) => Err(bad(format!("unexpected {:?}", t))),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn names(vars: &[&str]) -> Vec<String> {
+    vars.iter().map(|v| v.to_string()).collect()
+  }
+
+  fn eval(src: &str) -> f64 {
+    parse(src, &[]).unwrap().eval(&[])
+  }
+
+  fn is_bad(src: &str, vars: &[&str]) -> bool {
+    match parse(src, &names(vars)) {
+      Err(Error::BadRequest(_)) => true,
+      _ => false,
+    }
+  }
+
+  #[test]
+  fn precedence() {
+    assert_eq!(eval("1 + 2 * 3"), 7.0);
+    assert_eq!(eval("(1 + 2) * 3"), 9.0);
+    assert_eq!(eval("10 - 4 - 3"), 3.0);
+    assert_eq!(eval("24 / 4 / 2"), 3.0);
+    assert_eq!(eval("2 ^ 3 ^ 2"), 512.0);
+    assert_eq!(eval("-2 ^ 2"), -4.0);
+    assert_eq!(eval("2 ^ -1"), 0.5);
+    assert_eq!(eval("--3"), 3.0);
+    assert_eq!(eval("2 * -3 + 1"), -5.0);
+    assert_eq!(eval("1.5e1 + 5E-1"), 15.5);
+  }
+
+  #[test]
+  fn functions() {
+    assert_eq!(eval("abs(-2)"), 2.0);
+    assert_eq!(eval("sqrt(16)"), 4.0);
+    assert_eq!(eval("round(2.5)"), 3.0);
+    assert_eq!(eval("min(3, 1 + 1)"), 2.0);
+    assert_eq!(eval("max(3, 1 + 1)"), 3.0);
+    assert_eq!(eval("pow(2, 10)"), 1024.0);
+    assert!((eval("log10(1000)") - 3.0).abs() < 1e-12);
+    assert!((eval("ln(exp(2))") - 2.0).abs() < 1e-12);
+    // at 100% humidity the dew point is the temperature.
+    assert!((eval("dewpoint(20, 100)") - 20.0).abs() < 1e-9);
+    assert!((eval("dewpoint(25, 60)") - 16.69).abs() < 0.01);
+
+    assert!(is_bad("min(1)", &[]));
+    assert!(is_bad("abs(1, 2)", &[]));
+    assert!(is_bad("sqrt", &[]));
+  }
+
+  #[test]
+  fn variables() {
+    let vars = names(&["t", "rh"]);
+    let e = parse("(t * 9 / 5) + 32 + rh * 0", &vars).unwrap();
+    assert_eq!(e.eval(&[100.0, 50.0]), 212.0);
+    let e = parse("rh - t", &vars).unwrap();
+    assert_eq!(e.eval(&[1.0, 3.0]), 2.0);
+  }
+
+  #[test]
+  fn unknown_names() {
+    assert!(is_bad("t + 1", &[]));
+    assert!(is_bad("t + x", &["t"]));
+    assert!(is_bad("sin(t)", &["t"]));
+  }
+
+  #[test]
+  fn syntax_errors() {
+    assert!(is_bad("", &[]));
+    assert!(is_bad("1 +", &[]));
+    assert!(is_bad("(1 + 2", &[]));
+    assert!(is_bad("1 + 2)", &[]));
+    assert!(is_bad("1 2", &[]));
+    assert!(is_bad("1 $ 2", &[]));
+    assert!(is_bad("1..2", &[]));
+    assert!(is_bad("* 2", &[]));
+  }
+
+  #[test]
+  fn limits() {
+    let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+    assert_eq!(
+      parse(nested(MAX_DEPTH - 1).as_str(), &[])
+        .unwrap()
+        .eval(&[]),
+      1.0
+    );
+    assert!(is_bad(nested(MAX_DEPTH + 1).as_str(), &[]));
+    assert!(is_bad(
+      format!("{}1", "-".repeat(MAX_DEPTH + 1)).as_str(),
+      &[]
+    ));
+    assert!(is_bad(
+      format!(
+        "{}1{}",
+        "abs(".repeat(MAX_DEPTH + 1),
+        ")".repeat(MAX_DEPTH + 1)
+      )
+      .as_str(),
+      &[]
+    ));
+    // depth is nesting, not length.
+    let long = vec!["1"; 400].join("+");
+    assert_eq!(parse(long.as_str(), &[]).unwrap().eval(&[]), 400.0);
+    assert!(is_bad(" ".repeat(MAX_EXPRESSION_LEN + 1).as_str(), &[]));
+  }
+
+  #[test]
+  fn non_finite_results() {
+    assert_eq!(eval("1 / 0"), std::f64::INFINITY);
+    assert!(eval("ln(-1)").is_nan());
+    assert!(eval("sqrt(-1)").is_nan());
+    assert!(eval("0 / 0").is_nan());
+  }
+
+  #[test]
+  fn input_names() {
+    assert!(check_name("t").is_ok());
+    assert!(check_name("_in2").is_ok());
+    assert!(check_name("").is_err());
+    assert!(check_name("2t").is_err());
+    assert!(check_name("t-1").is_err());
+    assert!(check_name("max").is_err());
+  }
+}
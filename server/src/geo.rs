@@ -0,0 +1,174 @@
+use error::Error;
+use readings::Reading;
+use schemars::JsonSchema;
+use serde_json::Value;
+
+// device locations and reading positions, in WGS84 degrees and meters.
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct Location {
+  pub lat: f64,
+  pub lon: f64,
+  pub elevation: Option<f64>,
+}
+
+// set or, with no location, clear a device's location.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct DeviceLocation {
+  pub device: i64,
+  pub location: Option<Location>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct BBox {
+  pub minlat: f64,
+  pub minlon: f64,
+  pub maxlat: f64,
+  pub maxlon: f64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct LocatedDeviceQuery {
+  pub bbox: Option<BBox>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct LocatedDevice {
+  pub id: i64,
+  pub name: String,
+  pub description: String,
+  pub location: Location,
+}
+
+pub fn check_location(l: &Location) -> Result<(), Error> {
+  if l.lat < -90.0 || l.lat > 90.0 || l.lon < -180.0 || l.lon > 180.0 {
+    Err(Error::BadRequest(format!(
+      "location out of range: lat {}, lon {}",
+      l.lat, l.lon
+    )))
+  } else {
+    Ok(())
+  }
+}
+
+pub fn check_bbox(b: &BBox) -> Result<(), Error> {
+  if b.minlat > b.maxlat || b.minlon > b.maxlon {
+    Err(Error::BadRequest(
+      "bbox minimums must not exceed its maximums".to_string(),
+    ))
+  } else {
+    Ok(())
+  }
+}
+
+// a bbox in the GeoJSON order, "minlon,minlat,maxlon,maxlat".
+pub fn parse_bbox(s: &str) -> Result<BBox, Error> {
+  let v: Vec<f64> = s
+    .split(',')
+    .map(|n| n.trim().parse::<f64>())
+    .collect::<Result<Vec<f64>, _>>()
+    .map_err(|e| Error::BadRequest(format!("bad bbox '{}': {}", s, e)))?;
+  match v.as_slice() {
+    [minlon, minlat, maxlon, maxlat] => {
+      let b = BBox {
+        minlat: *minlat,
+        minlon: *minlon,
+        maxlat: *maxlat,
+        maxlon: *maxlon,
+      };
+      check_bbox(&b)?;
+      Ok(b)
+    }
+    _ => Err(Error::BadRequest(format!(
+      "bad bbox '{}': expected minlon,minlat,maxlon,maxlat",
+      s
+    ))),
+  }
+}
+
+// the bbox as sql parameters, all null for no bbox.
+pub fn bbox_params(b: &Option<BBox>) -> [Option<f64>; 4] {
+  match b {
+    Some(b) => [
+      Some(b.minlat),
+      Some(b.maxlat),
+      Some(b.minlon),
+      Some(b.maxlon),
+    ],
+    None => [None, None, None, None],
+  }
+}
+
+fn point(l: &Location) -> Value {
+  match l.elevation {
+    Some(e) => json!({ "type": "Point", "coordinates": [l.lon, l.lat, e] }),
+    None => json!({ "type": "Point", "coordinates": [l.lon, l.lat] }),
+  }
+}
+
+pub fn feature(l: &Location, properties: Value) -> Value {
+  json!({
+    "type": "Feature",
+    "geometry": point(l),
+    "properties": properties,
+  })
+}
+
+pub fn feature_collection(features: Vec<Value>) -> Value {
+  json!({
+    "type": "FeatureCollection",
+    "features": features,
+  })
+}
+
+pub fn devices_geojson(devices: &Vec<LocatedDevice>) -> Value {
+  feature_collection(
+    devices
+      .iter()
+      .map(|d| {
+        feature(
+          &d.location,
+          json!({ "id": d.id, "name": d.name, "description": d.description }),
+        )
+      })
+      .collect(),
+  )
+}
+
+// positioned readings; readings without a position are left out.
+pub fn readings_geojson(readings: &Vec<Reading>) -> Value {
+  feature_collection(
+    readings
+      .iter()
+      .filter_map(|r| {
+        r.position.as_ref().map(|p| {
+          feature(
+            p,
+            json!({
+              "id": r.id,
+              "sensor": r.sensor,
+              "value": r.value,
+              "measuredate": r.measuredate,
+            }),
+          )
+        })
+      })
+      .collect(),
+  )
+}
+
+// a location from nullable lat, lon and elevation columns.
+pub fn from_columns(
+  lat: Option<f64>,
+  lon: Option<f64>,
+  elevation: Option<f64>,
+) -> Option<Location> {
+  match (lat, lon) {
+    (Some(lat), Some(lon)) => Some(Location {
+      lat: lat,
+      lon: lon,
+      elevation: elevation,
+    }),
+    _ => None,
+  }
+}
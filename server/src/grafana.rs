@@ -0,0 +1,293 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use error::Error;
+use rest::{authed_user, basic_auth, reply, FutureResponse};
+use sciota_protocol::protocol::Measurement;
+use serde_json::Value;
+use storage::{Db, Storage};
+use time;
+
+// a Grafana datasource, speaking the protocol of Grafana's JSON datasource
+// plugin (simpod-json-datasource).  Point the plugin at
+// http://<server>/grafana with basic auth, using a sciota user name and
+// password.
+//
+// Each of the user's sensors is a metric, named "<device>/<sensor>".  Series
+// come from measurement_listing, so they're calibrated, and virtual sensors
+// work too.  A target's payload can pick an aggregate:
+//
+//   {"aggregate": "avg" | "min" | "max" | "sum" | "count" | "last" | "none"}
+//
+// Measurements are grouped into buckets of Grafana's interval, or wider so
+// there are no more than maxDataPoints, and each bucket is reduced with the
+// aggregate.  Without one, the average is used if there are more
+// measurements than maxDataPoints, and raw measurements are sent otherwise.
+// "none" always sends raw measurements.
+
+#[derive(Deserialize, Debug)]
+pub struct TimeRange {
+  pub from: String,
+  pub to: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Target {
+  pub target: Option<String>,
+  #[serde(rename = "type")]
+  pub kind: Option<String>,
+  pub payload: Option<Value>,
+  pub hide: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct QueryRequest {
+  pub range: TimeRange,
+  #[serde(rename = "intervalMs")]
+  pub interval_ms: Option<i64>,
+  #[serde(rename = "maxDataPoints")]
+  pub max_data_points: Option<i64>,
+  pub targets: Vec<Target>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+  Avg,
+  Min,
+  Max,
+  Sum,
+  Count,
+  Last,
+  None,
+}
+
+const AGGREGATES: [(&str, Aggregate); 7] = [
+  ("avg", Aggregate::Avg),
+  ("min", Aggregate::Min),
+  ("max", Aggregate::Max),
+  ("sum", Aggregate::Sum),
+  ("count", Aggregate::Count),
+  ("last", Aggregate::Last),
+  ("none", Aggregate::None),
+];
+
+// a sensor's metric name and id.
+struct Metric {
+  name: String,
+  sensor: i64,
+}
+
+fn sensor_metrics(db: &dyn Storage, uid: i64) -> Result<Vec<Metric>, Error> {
+  let devices = db.devicelisting(uid)?;
+  let mut metrics = Vec::new();
+  for s in db.sensorlisting(uid, None)? {
+    let device = devices
+      .iter()
+      .find(|d| d.id == s.device)
+      .map(|d| d.name.as_str())
+      .unwrap_or("");
+    metrics.push(Metric {
+      name: format!("{}/{}", device, s.name),
+      sensor: s.id,
+    });
+  }
+  metrics.sort_by(|a, b| a.name.cmp(&b.name));
+  Ok(metrics)
+}
+
+// ms since the epoch from a Grafana time, as in "2020-10-31T06:33:44.866Z".
+fn parse_time(s: &str) -> Result<i64, Error> {
+  let bad = || Error::BadRequest(format!("bad time: '{}'", s));
+  if let Ok(ms) = s.parse::<i64>() {
+    return Ok(ms);
+  }
+  let s = s.trim_end_matches('Z');
+  let (secs, frac) = match s.find('.') {
+    Some(i) => (&s[..i], &s[i + 1..]),
+    None => (s, ""),
+  };
+  let tm = time::strptime(secs, "%Y-%m-%dT%H:%M:%S").map_err(|_| bad())?;
+  let ms = if frac.is_empty() {
+    0
+  } else {
+    format!("{:0<3}", &frac[..frac.len().min(3)])
+      .parse::<i64>()
+      .map_err(|_| bad())?
+  };
+  Ok(tm.to_timespec().sec * 1000 + ms)
+}
+
+fn aggregate(target: &Target) -> Result<Option<Aggregate>, Error> {
+  let name = match &target.payload {
+    Some(Value::Object(p)) => match p.get("aggregate") {
+      Some(Value::String(a)) => a.as_str(),
+      Some(Value::Null) | None => return Ok(None),
+      Some(a) => return Err(Error::BadRequest(format!("bad aggregate: {}", a))),
+    },
+    _ => return Ok(None),
+  };
+  match AGGREGATES.iter().find(|(n, _)| *n == name) {
+    Some((_, a)) => Ok(Some(*a)),
+    None => Err(Error::BadRequest(format!("unknown aggregate: '{}'", name))),
+  }
+}
+
+fn reduce(values: &[f64], agg: Aggregate) -> f64 {
+  match agg {
+    Aggregate::Avg => values.iter().sum::<f64>() / values.len() as f64,
+    Aggregate::Min => values.iter().cloned().fold(std::f64::INFINITY, f64::min),
+    Aggregate::Max => values
+      .iter()
+      .cloned()
+      .fold(std::f64::NEG_INFINITY, f64::max),
+    Aggregate::Sum => values.iter().sum(),
+    Aggregate::Count => values.len() as f64,
+    Aggregate::Last | Aggregate::None => values[values.len() - 1],
+  }
+}
+
+// [value, time] pairs: one per bucket of 'width' ms, timed at the bucket's
+// start, or one per measurement for Aggregate::None.  Measurements are in
+// measuredate order.
+pub fn datapoints(ms: &[Measurement], agg: Aggregate, width: i64) -> Vec<(f64, i64)> {
+  if agg == Aggregate::None || width <= 0 {
+    return ms.iter().map(|m| (m.value, m.measuredate)).collect();
+  }
+
+  let mut points = Vec::new();
+  let mut values = Vec::new();
+  let mut bucket = None;
+  for m in ms.iter() {
+    let b = m.measuredate.div_euclid(width) * width;
+    if bucket != Some(b) {
+      if let Some(start) = bucket {
+        points.push((reduce(&values, agg), start));
+      }
+      values.clear();
+      bucket = Some(b);
+    }
+    values.push(m.value);
+  }
+  if let Some(start) = bucket {
+    points.push((reduce(&values, agg), start));
+  }
+  points
+}
+
+// Grafana's "test connection".
+pub fn test(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    authed_user(&db, creds)?;
+    Ok(json!({ "status": "ok" }))
+  })
+}
+
+// metric names, for the query editor.  'target' filters them.
+pub fn search(db: web::Data<Db>, req: HttpRequest, item: web::Json<Value>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let filter = item["target"].as_str().unwrap_or("").to_lowercase();
+    let found: Vec<Value> = sensor_metrics(&db, user.id)?
+      .into_iter()
+      .filter(|m| m.name.to_lowercase().contains(filter.as_str()))
+      .map(|m| json!({ "text": m.name, "value": m.sensor.to_string() }))
+      .collect();
+    Ok(found)
+  })
+}
+
+// metric names with the aggregate payload option, for newer versions of the
+// plugin.
+pub fn metric_list(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let options: Vec<Value> = AGGREGATES
+      .iter()
+      .map(|(n, _)| json!({ "label": n, "value": n }))
+      .collect();
+    let found: Vec<Value> = sensor_metrics(&db, user.id)?
+      .into_iter()
+      .map(|m| {
+        json!({
+          "label": m.name,
+          "value": m.sensor.to_string(),
+          "payloads": [{
+            "label": "aggregate",
+            "name": "aggregate",
+            "type": "select",
+            "options": options,
+          }],
+        })
+      })
+      .collect();
+    Ok(found)
+  })
+}
+
+pub fn query(db: web::Data<Db>, req: HttpRequest, item: web::Json<QueryRequest>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let q = item.into_inner();
+    let from = parse_time(q.range.from.as_str())?;
+    let to = parse_time(q.range.to.as_str())?;
+    let max_points = q.max_data_points.unwrap_or(1000).max(1);
+    // wide enough buckets for maxDataPoints.
+    let width = q
+      .interval_ms
+      .unwrap_or(0)
+      .max((to - from + max_points - 1) / max_points)
+      .max(1);
+    let names = sensor_metrics(&db, user.id)?;
+
+    let mut results = Vec::new();
+    for t in q.targets.iter() {
+      let target = match &t.target {
+        Some(target) if t.hide != Some(true) => target,
+        _ => continue,
+      };
+      // a sensor id, or a metric name.
+      let metric = match target.parse::<i64>() {
+        Ok(id) => names.iter().find(|m| m.sensor == id),
+        Err(_) => names.iter().find(|m| &m.name == target),
+      }
+      .ok_or_else(|| Error::NotFound(format!("no sensor for target '{}'", target)))?;
+
+      let ms = db.measurement_listing(user.id, metric.sensor, Some(from), Some(to))?;
+      let agg = match aggregate(t)? {
+        Some(a) => a,
+        None if ms.len() as i64 > max_points => Aggregate::Avg,
+        None => Aggregate::None,
+      };
+      let points = datapoints(&ms, agg, width);
+
+      if t.kind.as_ref().map(|k| k.as_str()) == Some("table") {
+        results.push(json!({
+          "type": "table",
+          "columns": [
+            { "text": "time", "type": "time" },
+            { "text": metric.name, "type": "number" },
+          ],
+          "rows": points.iter().map(|(v, t)| json!([t, v])).collect::<Vec<Value>>(),
+        }));
+      } else {
+        results.push(json!({
+          "target": metric.name,
+          "datapoints": points.iter().map(|(v, t)| json!([v, t])).collect::<Vec<Value>>(),
+        }));
+      }
+    }
+    Ok(results)
+  })
+}
+
+// sciota has no annotations, but Grafana asks.
+pub fn annotations(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    authed_user(&db, creds)?;
+    Ok(Vec::<Value>::new())
+  })
+}
@@ -0,0 +1,653 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use base64;
+use config::Config;
+use error::Error;
+use logging;
+use rest::{authed_user, basic_auth, reply, FutureResponse};
+use sqldata;
+use std::collections::HashMap;
+use std::fmt;
+use storage::{Db, Storage};
+
+// InfluxDB line protocol writes, at /write (1.x) and /api/v2/write (2.x), so
+// firmware and agents that speak it (Telegraf, ESPHome bridges, Tasmota
+// rules) can send measurements without changes.
+//
+//   <measurement>[,<tag>=<value>...] <field>=<value>[,<field>=<value>...] [<timestamp>]
+//
+// Each field of a point is a measurement of a sensor:
+//
+//   device   the value of the 'device' tag (or the tag named by device_tag),
+//            or the measurement name if the point doesn't have that tag.
+//   sensor   "<measurement>.<field>", or just "<measurement>" for a field
+//            named 'value'.
+//
+// Other tags are ignored.  Floats, integers and booleans (as 1 or 0) are
+// stored; string fields are refused.  Timestamps are in nanoseconds unless
+// the 'precision' parameter says otherwise, and points without one are
+// measured now.
+//
+// Devices and sensors that don't exist are an error, unless autocreate is on.
+// A request is stored in one transaction: if a line doesn't parse, a name
+// doesn't resolve or a value doesn't fit its sensor, nothing in it is stored,
+// devices and sensors it would have created included.
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct InfluxConfig {
+  // create missing devices and sensors.
+  pub autocreate: Option<bool>,
+  // the tag that names the device.
+  pub device_tag: Option<String>,
+}
+
+// query parameters.  'db', 'org', 'bucket' and the like are accepted and
+// ignored; measurements go to the authenticated user's sensors.
+#[derive(Deserialize)]
+pub struct WriteParams {
+  pub precision: Option<String>,
+  pub autocreate: Option<bool>,
+  pub device_tag: Option<String>,
+  // 1.x credentials.
+  pub u: Option<String>,
+  pub p: Option<String>,
+}
+
+// without the password.
+impl fmt::Debug for WriteParams {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WriteParams")
+      .field("precision", &self.precision)
+      .field("autocreate", &self.autocreate)
+      .field("device_tag", &self.device_tag)
+      .field("u", &self.u)
+      .field("p", &self.p.as_ref().map(|_| logging::MASK))
+      .finish()
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Point {
+  pub measurement: String,
+  pub tags: Vec<(String, String)>,
+  pub fields: Vec<(String, f64)>,
+  pub timestamp: Option<i64>,
+}
+
+// a point's field, as a measurement of a sensor given by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedMeasurement {
+  pub device: String,
+  pub sensor: String,
+  pub value: f64,
+  pub measuredate: i64,
+}
+
+// the description of devices and sensors created by writes.
+pub const AUTOCREATED: &str = "created by an influx write";
+
+// split on unescaped 'sep's, keeping escapes.  With 'quotes', separators in
+// double quoted strings don't count.
+fn split(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+  let mut parts = Vec::new();
+  let mut start = 0;
+  let mut escaped = false;
+  let mut quoted = false;
+  for (i, c) in s.char_indices() {
+    if escaped {
+      escaped = false;
+    } else if c == '\\' {
+      escaped = true;
+    } else if quotes && c == '"' {
+      quoted = !quoted;
+    } else if c == sep && !quoted {
+      parts.push(&s[start..i]);
+      start = i + c.len_utf8();
+    }
+  }
+  parts.push(&s[start..]);
+  parts
+}
+
+// split at the first unescaped 'sep'.
+fn split_once(s: &str, sep: char, quotes: bool) -> Option<(&str, &str)> {
+  let parts = split(s, sep, quotes);
+  if parts.len() < 2 {
+    None
+  } else {
+    let first = parts[0];
+    Some((first, &s[first.len() + sep.len_utf8()..]))
+  }
+}
+
+fn unescape(s: &str) -> String {
+  let mut out = String::new();
+  let mut escaped = false;
+  for c in s.chars() {
+    if escaped {
+      // only these are escapes; a backslash before anything else is kept.
+      if !(c == ',' || c == ' ' || c == '=' || c == '\\' || c == '"') {
+        out.push('\\');
+      }
+      out.push(c);
+      escaped = false;
+    } else if c == '\\' {
+      escaped = true;
+    } else {
+      out.push(c);
+    }
+  }
+  if escaped {
+    out.push('\\');
+  }
+  out
+}
+
+// a field value as a number.  Sensors don't have string measurements, so
+// string fields are refused rather than dropped.
+fn field_value(v: &str) -> Result<f64, String> {
+  let bad = || format!("bad field value '{}'", v);
+  if v.starts_with('"') {
+    return if v.len() >= 2 && v.ends_with('"') {
+      Err(format!("string field values aren't supported: {}", v))
+    } else {
+      Err(bad())
+    };
+  }
+  match v {
+    "t" | "T" | "true" | "True" | "TRUE" => return Ok(1.0),
+    "f" | "F" | "false" | "False" | "FALSE" => return Ok(0.0),
+    _ => (),
+  }
+  let n = if v.ends_with('i') {
+    v[..v.len() - 1]
+      .parse::<i64>()
+      .map(|i| i as f64)
+      .map_err(|_| bad())?
+  } else if v.ends_with('u') {
+    v[..v.len() - 1]
+      .parse::<u64>()
+      .map(|u| u as f64)
+      .map_err(|_| bad())?
+  } else {
+    v.parse::<f64>().map_err(|_| bad())?
+  };
+  if n.is_finite() {
+    Ok(n)
+  } else {
+    Err(bad())
+  }
+}
+
+// a line, or None for blank lines and comments.
+pub fn parse_line(line: &str) -> Result<Option<Point>, String> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('#') {
+    return Ok(None);
+  }
+
+  // quotes only matter in the fields: tags can have '"'s in them.
+  let (key, rest) = match split_once(line, ' ', false) {
+    Some(kr) => kr,
+    None => return Err("missing fields".to_string()),
+  };
+  let sections: Vec<&str> = split(rest, ' ', true)
+    .into_iter()
+    .filter(|s| !s.is_empty())
+    .collect();
+  let (fieldset, timestamp) = match sections.as_slice() {
+    [fields] => (*fields, None),
+    [fields, ts] => (*fields, Some(*ts)),
+    _ => return Err("expected a measurement, fields and an optional timestamp".to_string()),
+  };
+
+  let mut keys = split(key, ',', false).into_iter();
+  let measurement = unescape(keys.next().unwrap_or(""));
+  if measurement.is_empty() {
+    return Err("missing measurement name".to_string());
+  }
+  let mut tags = Vec::new();
+  for tag in keys {
+    match split_once(tag, '=', false) {
+      Some((k, v)) if !k.is_empty() && !v.is_empty() => tags.push((unescape(k), unescape(v))),
+      _ => return Err(format!("bad tag '{}'", tag)),
+    }
+  }
+
+  let mut fields = Vec::new();
+  for field in split(fieldset, ',', true) {
+    match split_once(field, '=', true) {
+      Some((k, v)) if !k.is_empty() => fields.push((unescape(k), field_value(v)?)),
+      _ => return Err(format!("bad field '{}'", field)),
+    }
+  }
+
+  let timestamp = match timestamp {
+    Some(ts) => Some(
+      ts.parse::<i64>()
+        .map_err(|_| format!("bad timestamp '{}'", ts))?,
+    ),
+    None => None,
+  };
+
+  Ok(Some(Point {
+    measurement,
+    tags,
+    fields,
+    timestamp,
+  }))
+}
+
+// a timestamp in 'precision' units to ms.
+pub fn to_ms(ts: i64, precision: &str) -> Result<i64, Error> {
+  let ms = match precision {
+    "ns" | "n" => Some(ts.div_euclid(1_000_000)),
+    "us" | "u" => Some(ts.div_euclid(1_000)),
+    "ms" => Some(ts),
+    "s" => ts.checked_mul(1000),
+    "m" => ts.checked_mul(60_000),
+    "h" => ts.checked_mul(3_600_000),
+    p => return Err(Error::BadRequest(format!("unknown precision '{}'", p))),
+  };
+  ms.ok_or_else(|| Error::BadRequest(format!("timestamp out of range: {}", ts)))
+}
+
+pub fn parse(body: &str) -> Result<Vec<Point>, Error> {
+  let mut points = Vec::new();
+  for (i, line) in body.lines().enumerate() {
+    match parse_line(line) {
+      Ok(Some(p)) => points.push(p),
+      Ok(None) => (),
+      Err(e) => return Err(Error::BadRequest(format!("line {}: {}", i + 1, e))),
+    }
+  }
+  Ok(points)
+}
+
+// the device and sensor names for a point's field.
+fn names(point: &Point, field: &str, device_tag: &str) -> (String, String) {
+  let device = point
+    .tags
+    .iter()
+    .find(|(k, _)| k == device_tag)
+    .map(|(_, v)| v.clone())
+    .unwrap_or_else(|| point.measurement.clone());
+  let sensor = if field == "value" {
+    point.measurement.clone()
+  } else {
+    format!("{}.{}", point.measurement, field)
+  };
+  (device, sensor)
+}
+
+// check that every measurement's device and sensor exists, before anything
+// is written: NotFound for the first that doesn't, unless 'autocreate'.
+// 'devices' are the user's devices by name, 'sensors' their sensors by
+// (device name, sensor name).
+pub fn check_names(
+  measurements: &[NamedMeasurement],
+  devices: &HashMap<String, i64>,
+  sensors: &HashMap<(String, String), i64>,
+  autocreate: bool,
+) -> Result<(), Error> {
+  if autocreate {
+    return Ok(());
+  }
+  for m in measurements.iter() {
+    if !devices.contains_key(&m.device) {
+      return Err(Error::NotFound(format!("no device named '{}'", m.device)));
+    }
+    if !sensors.contains_key(&(m.device.clone(), m.sensor.clone())) {
+      return Err(Error::NotFound(format!(
+        "no sensor named '{}' on device '{}'",
+        m.sensor, m.device
+      )));
+    }
+  }
+  Ok(())
+}
+
+// the points' fields as measurements.
+pub fn measurements(
+  points: &[Point],
+  precision: &str,
+  device_tag: &str,
+  now: i64,
+) -> Result<Vec<NamedMeasurement>, Error> {
+  let mut measurements = Vec::new();
+  for p in points.iter() {
+    let measuredate = match p.timestamp {
+      Some(ts) => to_ms(ts, precision)?,
+      None => now,
+    };
+    for (field, value) in p.fields.iter() {
+      let (device, sensor) = names(p, field, device_tag);
+      measurements.push(NamedMeasurement {
+        device,
+        sensor,
+        value: *value,
+        measuredate,
+      });
+    }
+  }
+  Ok(measurements)
+}
+
+// store the points' measurements for 'uid', all in one transaction.
+// Returns how many were stored.
+pub fn write(
+  db: &dyn Storage,
+  uid: i64,
+  points: &[Point],
+  precision: &str,
+  autocreate: bool,
+  device_tag: &str,
+) -> Result<usize, Error> {
+  let measurements = measurements(points, precision, device_tag, sqldata::now()?)?;
+  if measurements.is_empty() {
+    return Ok(0);
+  }
+  db.add_named_measurements(uid, &measurements, autocreate)
+}
+
+// the user name and password: from basic auth, a 2.x "Token user:password"
+// header, or 1.x 'u' and 'p' parameters.
+fn credentials(req: &HttpRequest, params: &WriteParams) -> Result<(String, String), Error> {
+  if let (Some(u), Some(p)) = (&params.u, &params.p) {
+    return Ok((u.clone(), p.clone()));
+  }
+  let token = req
+    .headers()
+    .get("Authorization")
+    .and_then(|h| h.to_str().ok())
+    .and_then(|h| {
+      if h.starts_with("Token ") {
+        Some(h[6..].to_string())
+      } else {
+        None
+      }
+    });
+  match token {
+    Some(t) => {
+      // some clients base64 the token, like basic auth.
+      let t = match base64::decode(&t)
+        .ok()
+        .and_then(|d| String::from_utf8(d).ok())
+      {
+        Some(d) if d.contains(':') => d,
+        _ => t,
+      };
+      let mut parts = t.splitn(2, ':');
+      match (parts.next(), parts.next()) {
+        (Some(name), Some(pwd)) => Ok((name.to_string(), pwd.to_string())),
+        _ => Err(Error::Unauthorized(
+          "tokens are <user>:<password>".to_string(),
+        )),
+      }
+    }
+    None => basic_auth(req),
+  }
+}
+
+pub fn post_write(
+  config: web::Data<Config>,
+  db: web::Data<Db>,
+  req: HttpRequest,
+  params: web::Query<WriteParams>,
+  body: String,
+) -> FutureResponse {
+  let creds = credentials(&req, &params);
+  let ic = config.influx.clone();
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    let autocreate = params
+      .autocreate
+      .or(ic.as_ref().and_then(|c| c.autocreate))
+      .unwrap_or(false);
+    let device_tag = params
+      .device_tag
+      .clone()
+      .or(ic.as_ref().and_then(|c| c.device_tag.clone()))
+      .unwrap_or("device".to_string());
+    let precision = params.precision.clone().unwrap_or("ns".to_string());
+
+    let points = parse(body.as_str())?;
+    let n = write(
+      &db,
+      user.id,
+      &points,
+      precision.as_str(),
+      autocreate,
+      device_tag.as_str(),
+    )?;
+    info!("influx write: {} measurements for user {}", n, user.id);
+    Ok(())
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn point(line: &str) -> Point {
+    parse_line(line).unwrap().unwrap()
+  }
+
+  fn tags(p: &Point) -> Vec<(&str, &str)> {
+    p.tags
+      .iter()
+      .map(|(k, v)| (k.as_str(), v.as_str()))
+      .collect()
+  }
+
+  fn fields(p: &Point) -> Vec<(&str, f64)> {
+    p.fields.iter().map(|(k, v)| (k.as_str(), *v)).collect()
+  }
+
+  #[test]
+  fn params_debug_hides_the_password() {
+    let params = WriteParams {
+      precision: Some("s".to_string()),
+      autocreate: None,
+      device_tag: None,
+      u: Some("me".to_string()),
+      p: Some("secret".to_string()),
+    };
+    let debug = format!("{:?}", params);
+    assert!(!debug.contains("secret"), "{}", debug);
+    assert!(debug.contains(r#"u: Some("me")"#), "{}", debug);
+  }
+
+  #[test]
+  fn telegraf_lines() {
+    let p =
+      point("cpu,cpu=cpu-total,host=server01 usage_idle=98.5,usage_user=1.25 1465839830100400200");
+    assert_eq!(p.measurement, "cpu");
+    assert_eq!(tags(&p), vec![("cpu", "cpu-total"), ("host", "server01")]);
+    assert_eq!(fields(&p), vec![("usage_idle", 98.5), ("usage_user", 1.25)]);
+    assert_eq!(p.timestamp, Some(1465839830100400200));
+
+    let p = point("mem,host=server01 used=4096i,free=12u,available_percent=62.5");
+    assert_eq!(
+      fields(&p),
+      vec![
+        ("used", 4096.0),
+        ("free", 12.0),
+        ("available_percent", 62.5)
+      ]
+    );
+    assert_eq!(p.timestamp, None);
+
+    let p = point("net,interface=eth0 up=true,down=F,bytes_recv=-3i 1465839830100400200");
+    assert_eq!(
+      fields(&p),
+      vec![("up", 1.0), ("down", 0.0), ("bytes_recv", -3.0)]
+    );
+  }
+
+  #[test]
+  fn string_fields() {
+    // refused, with spaces, commas and equals signs in them not taken for
+    // separators.
+    for line in [
+      r#"system,host=server01 uptime_format="1 day,  2:03",load1=0.5 1465839830"#,
+      r#"system note="a=b \"c\"",load1=0.5"#,
+    ]
+    .iter()
+    {
+      match parse_line(line) {
+        Err(e) => assert!(e.starts_with("string field values"), "{}", e),
+        r => panic!("accepted '{}': {:?}", line, r),
+      }
+    }
+
+    match parse("cpu v=1\nsystem note=\"x\"\n") {
+      Err(Error::BadRequest(msg)) => assert!(msg.starts_with("line 2: string"), "{}", msg),
+      r => panic!("{:?}", r.map(|p| p.len())),
+    }
+  }
+
+  #[test]
+  fn escapes() {
+    let p =
+      point("my\\,weather,location=us\\ midwest,eq=a\\=b temp\\ f=82,x\\,y=1 1465839830100400200");
+    assert_eq!(p.measurement, "my,weather");
+    assert_eq!(tags(&p), vec![("location", "us midwest"), ("eq", "a=b")]);
+    assert_eq!(fields(&p), vec![("temp f", 82.0), ("x,y", 1.0)]);
+
+    // a backslash before anything else is kept.
+    let p = point("path,dir=c:\\temp v=1");
+    assert_eq!(tags(&p), vec![("dir", "c:\\temp")]);
+    // quotes in tags are just characters.
+    let p = point("m,t=\"a v=1");
+    assert_eq!(tags(&p), vec![("t", "\"a")]);
+    assert_eq!(fields(&p), vec![("v", 1.0)]);
+  }
+
+  #[test]
+  fn blank_lines_and_comments() {
+    assert_eq!(parse_line("").unwrap(), None);
+    assert_eq!(parse_line("   ").unwrap(), None);
+    assert_eq!(parse_line("# a comment").unwrap(), None);
+    let points = parse("# header\n\ncpu v=1 1\n  cpu v=2 2  \n").unwrap();
+    assert_eq!(points.len(), 2);
+  }
+
+  #[test]
+  fn bad_lines() {
+    for line in [
+      "cpu",
+      "cpu ",
+      ",host=a v=1",
+      "cpu,host v=1",
+      "cpu,host= v=1",
+      "cpu v",
+      "cpu =1",
+      "cpu v=",
+      "cpu v=abc",
+      "cpu v=1.5i",
+      "cpu v=-1u",
+      "cpu v=\"unterminated",
+      "cpu v=NaN",
+      "cpu v=inf",
+      "cpu v=1 12.5",
+      "cpu v=1 1 2",
+    ]
+    .iter()
+    {
+      assert!(parse_line(line).is_err(), "accepted '{}'", line);
+    }
+
+    match parse("cpu v=1\ncpu v=x\n") {
+      Err(Error::BadRequest(msg)) => assert!(msg.starts_with("line 2:"), "{}", msg),
+      r => panic!("{:?}", r.map(|p| p.len())),
+    }
+  }
+
+  #[test]
+  fn precision() {
+    let ts = 1465839830100400200;
+    assert_eq!(to_ms(ts, "ns").unwrap(), 1465839830100);
+    assert_eq!(to_ms(ts, "n").unwrap(), 1465839830100);
+    assert_eq!(to_ms(1465839830100400, "us").unwrap(), 1465839830100);
+    assert_eq!(to_ms(1465839830100, "ms").unwrap(), 1465839830100);
+    assert_eq!(to_ms(1465839830, "s").unwrap(), 1465839830000);
+    assert_eq!(to_ms(2, "m").unwrap(), 120_000);
+    assert_eq!(to_ms(2, "h").unwrap(), 7_200_000);
+    // before 1970 rounds down, not towards zero.
+    assert_eq!(to_ms(-1, "ns").unwrap(), -1);
+    assert_eq!(to_ms(-1_500_000, "ns").unwrap(), -2);
+
+    assert!(to_ms(i64::max_value(), "s").is_err());
+    assert!(to_ms(i64::min_value(), "h").is_err());
+    assert!(to_ms(1, "d").is_err());
+  }
+
+  #[test]
+  fn sensor_names() {
+    let p = point("climate,device=greenhouse,room=north temp=20,value=1");
+    assert_eq!(
+      names(&p, "temp", "device"),
+      ("greenhouse".to_string(), "climate.temp".to_string())
+    );
+    assert_eq!(
+      names(&p, "value", "device"),
+      ("greenhouse".to_string(), "climate".to_string())
+    );
+    assert_eq!(
+      names(&p, "temp", "room"),
+      ("north".to_string(), "climate.temp".to_string())
+    );
+    assert_eq!(
+      names(&p, "temp", "host"),
+      ("climate".to_string(), "climate.temp".to_string())
+    );
+  }
+
+  #[test]
+  fn named_measurements() {
+    let points = parse("climate,device=greenhouse temp=20,value=1 2\ncpu v=3").unwrap();
+    let ms = measurements(&points, "s", "device", 99).unwrap();
+    let named = |device: &str, sensor: &str, value: f64, measuredate: i64| NamedMeasurement {
+      device: device.to_string(),
+      sensor: sensor.to_string(),
+      value,
+      measuredate,
+    };
+    assert_eq!(
+      ms,
+      vec![
+        named("greenhouse", "climate.temp", 20.0, 2000),
+        named("greenhouse", "climate", 1.0, 2000),
+        named("cpu", "cpu.v", 3.0, 99),
+      ]
+    );
+  }
+
+  #[test]
+  fn names_resolve_before_writing() {
+    let points = parse("a,device=d x=1\na,device=d y=2\nb,device=e x=3").unwrap();
+    let ms = measurements(&points, "ns", "device", 0).unwrap();
+    let mut devices = HashMap::new();
+    devices.insert("d".to_string(), 1);
+    let mut sensors = HashMap::new();
+    sensors.insert(("d".to_string(), "a.x".to_string()), 10);
+
+    // every missing name is found, wherever it is in the request.
+    match check_names(&ms, &devices, &sensors, false) {
+      Err(Error::NotFound(msg)) => assert!(msg.contains("'a.y'"), "{}", msg),
+      r => panic!("{:?}", r),
+    }
+    sensors.insert(("d".to_string(), "a.y".to_string()), 11);
+    match check_names(&ms, &devices, &sensors, false) {
+      Err(Error::NotFound(msg)) => assert!(msg.contains("device named 'e'"), "{}", msg),
+      r => panic!("{:?}", r),
+    }
+    assert!(check_names(&ms, &devices, &sensors, true).is_ok());
+
+    devices.insert("e".to_string(), 2);
+    sensors.insert(("e".to_string(), "b.x".to_string()), 12);
+    assert!(check_names(&ms, &devices, &sensors, false).is_ok());
+  }
+}
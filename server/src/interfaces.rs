@@ -1,15 +1,25 @@
+use archive::Archive;
+use backup;
+use calibration::{SensorCalibration, VirtualSensor};
 use config::Config;
-use crypto_hash::{hex_digest, Algorithm};
 use email;
+use error::Error;
+use geo;
+use geo::{DeviceLocation, LocatedDeviceQuery};
+use metrics;
+use readings::{ReadingQuery, SaveReading, SensorType};
+use retention::{RollupQuery, SensorRetention};
+use schemars::JsonSchema;
 use sciota_protocol::protocol::{
   Device, Measurement, MeasurementQuery, PublicMessage, RegistrationData, SaveDevice,
   SaveMeasurement, SaveSensor, Sensor, ServerResponse, UserMessage,
 };
+use serde::de::DeserializeOwned;
 use serde_json::Value;
-use simple_error;
-use sqldata;
-use std::error::Error;
-use std::path::Path;
+use sqldata::User;
+use storage::Storage;
+use templates::{Instantiate, SaveTemplate};
+use udp;
 use util;
 use uuid::Uuid;
 
@@ -18,12 +28,112 @@ pub struct AppState {
   pub htmlstring: String,
 }
 
-pub fn user_interface(config: &Config, msg: UserMessage) -> Result<ServerResponse, Box<dyn Error>> {
+// admin support: run a read-only message as another user.
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct Impersonate {
+  pub user: String,
+  pub what: String,
+  pub data: Option<Value>,
+}
+
+// every 'what' code the message interface answers, for the metrics labels.
+// Keep in step with user_interface, admin_interface and
+// user_interface_loggedin.
+pub const WHATS: [&str; 46] = [
+  "register",
+  "login",
+  "getuserlisting",
+  "enableuser",
+  "disableuser",
+  "deleteuser",
+  "confirmuser",
+  "impersonate",
+  "backup",
+  "getdevicelisting",
+  "savedevice",
+  "deletedevice",
+  "getsensorlisting",
+  "savesensor",
+  "deletesensor",
+  "savemeasurement",
+  "savemeasurements",
+  "getmeasurementlisting",
+  "exportdata",
+  "importdata",
+  "getsensortype",
+  "savesensortype",
+  "savereading",
+  "getreadinglisting",
+  "getcalibration",
+  "setcalibration",
+  "getcalibrationhistory",
+  "deletecalibration",
+  "getcalibrationaudit",
+  "getvirtualsensor",
+  "setvirtualsensor",
+  "getdevicelocation",
+  "setdevicelocation",
+  "makedevicetoken",
+  "deletedevicetoken",
+  "getlocateddevices",
+  "getdevicegeojson",
+  "getreadinggeojson",
+  "savetemplate",
+  "gettemplate",
+  "gettemplatelisting",
+  "deletetemplate",
+  "instantiate",
+  "getrolluplisting",
+  "getretention",
+  "saveretention",
+];
+
+// the replies to a message whose login was refused.
+pub const LOGIN_FAILURES: [&str; 3] = [
+  "invalid user or pwd",
+  "unregistered user",
+  "account disabled",
+];
+
+// the 'what' codes an admin may run via 'impersonate'.
+pub const READ_ONLY_WHATS: [&str; 17] = [
+  "getdevicelisting",
+  "getsensorlisting",
+  "getmeasurementlisting",
+  "getrolluplisting",
+  "getretention",
+  "getsensortype",
+  "getreadinglisting",
+  "getdevicelocation",
+  "getlocateddevices",
+  "getdevicegeojson",
+  "getreadinggeojson",
+  "gettemplate",
+  "gettemplatelisting",
+  "getcalibration",
+  "getcalibrationhistory",
+  "getcalibrationaudit",
+  "getvirtualsensor",
+];
+
+// deserialize a message's 'data' field.
+fn msg_data<T: DeserializeOwned>(data: &Option<Value>) -> Result<T, Error> {
+  match data {
+    Some(d) => Ok(serde_json::from_value(d.clone())?),
+    None => Err(Error::BadRequest("malformed json data".to_string())),
+  }
+}
+
+pub fn user_interface(
+  config: &Config,
+  db: &dyn Storage,
+  msg: UserMessage,
+) -> Result<ServerResponse, Error> {
   info!("got a user message: {}", msg.what);
   if msg.what.as_str() == "register" {
     // do the registration thing.
     // user already exists?
-    match sqldata::read_user(Path::new(&config.db), msg.uid.as_str()) {
+    match db.read_user(msg.uid.as_str()) {
       Ok(_) => {
         // err - user exists.
         Ok(ServerResponse {
@@ -31,23 +141,18 @@ pub fn user_interface(config: &Config, msg: UserMessage) -> Result<ServerRespons
           content: serde_json::Value::Null,
         })
       }
-      Err(_) => {
+      Err(Error::NotFound(_)) => {
         // user does not exist, which is what we want for a new user.
         // get email from 'data'.
-        let msgdata = Option::ok_or(msg.data, "malformed registration data")?;
-        let rd: RegistrationData = serde_json::from_value(msgdata)?;
+        let rd: RegistrationData = msg_data(&msg.data)?;
         // TODO: make a real registration key
         let registration_key = Uuid::new_v4().to_string();
         let salt = util::salt_string();
 
         // write a user record.
-        sqldata::new_user(
-          Path::new(&config.db),
+        db.new_user(
           msg.uid.clone(),
-          hex_digest(
-            Algorithm::SHA256,
-            (msg.pwd + salt.as_str()).into_bytes().as_slice(),
-          ),
+          util::hash_password(msg.pwd.as_str(), salt.as_str()),
           salt,
           rd.email.clone(),
           registration_key.clone().to_string(),
@@ -61,7 +166,8 @@ pub fn user_interface(config: &Config, msg: UserMessage) -> Result<ServerRespons
           rd.email.as_str(),
           msg.uid.as_str(),
           registration_key.as_str(),
-        )?;
+        )
+        .map_err(|e| Error::Email(e.to_string()))?;
 
         // notify the admin.
         email::send_registration_notification(
@@ -71,137 +177,505 @@ pub fn user_interface(config: &Config, msg: UserMessage) -> Result<ServerRespons
           rd.email.as_str(),
           msg.uid.as_str(),
           registration_key.as_str(),
-        )?;
+        )
+        .map_err(|e| Error::Email(e.to_string()))?;
 
         Ok(ServerResponse {
           what: "registration sent".to_string(),
           content: serde_json::Value::Null,
         })
       }
+      Err(e) => Err(e),
     }
   } else {
-    match sqldata::read_user(Path::new(&config.db), msg.uid.as_str()) {
-      Err(_) => Ok(ServerResponse {
-        what: "invalid user or pwd".to_string(),
-        content: serde_json::Value::Null,
-      }),
+    match login(db, msg.uid.as_str(), msg.pwd.as_str()) {
       Ok(userdata) => {
-        // let userdata: User = serde_json::from_value(serde_json::from_str(udata.as_str())?)?;
-        match userdata.registration_key {
-          Some(_reg_key) => Ok(ServerResponse {
-            what: "unregistered user".to_string(),
-            content: serde_json::Value::Null,
-          }),
-          None => {
-            if hex_digest(
-              Algorithm::SHA256,
-              (msg.pwd.clone() + userdata.salt.as_str())
-                .into_bytes()
-                .as_slice(),
-            ) != userdata.hashwd
-            {
-              // don't distinguish between bad user id and bad pwd!
-              Ok(ServerResponse {
-                what: "invalid user or pwd".to_string(),
-                content: serde_json::Value::Null,
-              })
-            } else {
-              // finally!  processing messages as logged in user.
-              user_interface_loggedin(&config, userdata.id, &msg)
-            }
-          }
+        if userdata.admin {
+          admin_interface(config, db, userdata.id, &msg)
+        } else {
+          // finally!  processing messages as logged in user.
+          user_interface_loggedin(db, userdata.id, &msg)
         }
       }
+      // login failures are regular replies in the message protocol.
+      Err(Error::Unauthorized(what)) | Err(Error::Forbidden(what)) => Ok(ServerResponse {
+        what: what,
+        content: serde_json::Value::Null,
+      }),
+      Err(e) => Err(e),
     }
   }
 }
 
-fn user_interface_loggedin(
+// check user name and password, returning the user record if they're allowed in.
+pub fn login(db: &dyn Storage, name: &str, pwd: &str) -> Result<User, Error> {
+  let res = check_login(db, name, pwd);
+  match &res {
+    Ok(user) => metrics::user_seen(user.id),
+    Err(Error::Unauthorized(e)) | Err(Error::Forbidden(e)) => {
+      warn!("login failed for '{}': {}", name, e);
+      metrics::login_failed()
+    }
+    Err(_) => (),
+  }
+  res
+}
+
+fn check_login(db: &dyn Storage, name: &str, pwd: &str) -> Result<User, Error> {
+  let userdata = match db.read_user(name) {
+    Ok(userdata) => userdata,
+    Err(Error::NotFound(_)) => return Err(Error::Unauthorized("invalid user or pwd".to_string())),
+    Err(e) => return Err(e),
+  };
+
+  if userdata.registration_key.is_some() {
+    Err(Error::Unauthorized("unregistered user".to_string()))
+  } else if util::hash_password(pwd, userdata.salt.as_str()) != userdata.hashwd {
+    // don't distinguish between bad user id and bad pwd!
+    Err(Error::Unauthorized("invalid user or pwd".to_string()))
+  } else if userdata.disabled {
+    Err(Error::Forbidden("account disabled".to_string()))
+  } else {
+    Ok(userdata)
+  }
+}
+
+// admin messages.  anything that isn't an admin 'what' is processed as a
+// regular logged in user message.
+fn admin_interface(
   config: &Config,
+  db: &dyn Storage,
+  uid: i64,
+  msg: &UserMessage,
+) -> Result<ServerResponse, Error> {
+  match msg.what.as_str() {
+    "getuserlisting" => {
+      let entries = db.user_listing()?;
+      Ok(ServerResponse {
+        what: "userlisting".to_string(),
+        content: serde_json::to_value(entries)?,
+      })
+    }
+    "enableuser" => {
+      let name: String = msg_data(&msg.data)?;
+
+      db.set_user_disabled(name.as_str(), false)?;
+      Ok(ServerResponse {
+        what: "enableduser".to_string(),
+        content: serde_json::to_value(name)?,
+      })
+    }
+    "disableuser" => {
+      let name: String = msg_data(&msg.data)?;
+
+      db.set_user_disabled(name.as_str(), true)?;
+      Ok(ServerResponse {
+        what: "disableduser".to_string(),
+        content: serde_json::to_value(name)?,
+      })
+    }
+    "deleteuser" => {
+      let name: String = msg_data(&msg.data)?;
+
+      if name == msg.uid {
+        return Err(Error::BadRequest(
+          "admins can't delete themselves".to_string(),
+        ));
+      }
+
+      db.delete_user(name.as_str())?;
+      Ok(ServerResponse {
+        what: "deleteduser".to_string(),
+        content: serde_json::to_value(name)?,
+      })
+    }
+    "confirmuser" => {
+      let name: String = msg_data(&msg.data)?;
+
+      db.confirm_user(name.as_str())?;
+      Ok(ServerResponse {
+        what: "confirmeduser".to_string(),
+        content: serde_json::to_value(name)?,
+      })
+    }
+    "impersonate" => {
+      let imp: Impersonate = msg_data(&msg.data)?;
+
+      if !READ_ONLY_WHATS.contains(&imp.what.as_str()) {
+        return Err(Error::Forbidden(format!(
+          "'what' code not allowed for impersonate:'{}'",
+          imp.what
+        )));
+      }
+
+      let user = db.read_user(imp.user.as_str())?;
+      info!(
+        "admin '{}' impersonating '{}': {}",
+        msg.uid, user.name, imp.what
+      );
+
+      user_interface_loggedin(
+        db,
+        user.id,
+        &UserMessage {
+          uid: user.name,
+          pwd: String::new(),
+          what: imp.what,
+          data: imp.data,
+        },
+      )
+    }
+    "backup" => {
+      let bc = config.backup.as_ref().ok_or(Error::BadRequest(
+        "no [backup] section in the server config".to_string(),
+      ))?;
+
+      let path = backup::snapshot(db, config.db.as_path(), bc)?;
+      info!("admin '{}' made backup {:?}", msg.uid, path);
+      Ok(ServerResponse {
+        what: "backedup".to_string(),
+        content: serde_json::to_value(path.to_string_lossy())?,
+      })
+    }
+    _ => user_interface_loggedin(db, uid, &msg),
+  }
+}
+
+fn user_interface_loggedin(
+  db: &dyn Storage,
   uid: i64,
   msg: &UserMessage,
-) -> Result<ServerResponse, Box<dyn Error>> {
+) -> Result<ServerResponse, Error> {
   match msg.what.as_str() {
     "login" => Ok(ServerResponse {
       what: "logged in".to_string(),
       content: serde_json::Value::Null, // return api token that expires?
     }),
     "getdevicelisting" => {
-      let entries = sqldata::devicelisting(Path::new(&config.db), uid)?;
+      let entries = db.devicelisting(uid)?;
       Ok(ServerResponse {
         what: "devicelisting".to_string(),
         content: serde_json::to_value(entries)?,
       })
     }
     "savedevice" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let sz: SaveDevice = serde_json::from_value(msgdata.clone())?;
+      let sz: SaveDevice = msg_data(&msg.data)?;
 
-      let deviceid = sqldata::save_device(&config.db.as_path(), uid, &sz)?;
+      let deviceid = db.save_device(uid, &sz)?;
       Ok(ServerResponse {
         what: "saveddevice".to_string(),
         content: serde_json::to_value(deviceid)?,
       })
     }
     "deletedevice" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let deviceid: i64 = serde_json::from_value(msgdata.clone())?;
+      let deviceid: i64 = msg_data(&msg.data)?;
 
-      sqldata::delete_device(&config.db.as_path(), uid, deviceid)?;
+      db.delete_device(uid, deviceid)?;
       Ok(ServerResponse {
         what: "deleteddevice".to_string(),
         content: serde_json::to_value(deviceid)?,
       })
     }
     "getsensorlisting" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let deviceid: i64 = serde_json::from_value(msgdata.clone())?;
+      let deviceid: i64 = msg_data(&msg.data)?;
 
-      let entries = sqldata::sensorlisting(Path::new(&config.db), uid, Some(deviceid))?;
+      let entries = db.sensorlisting(uid, Some(deviceid))?;
       Ok(ServerResponse {
         what: "sensorlisting".to_string(),
         content: serde_json::to_value(entries)?, // return api token that expires?
       })
     }
     "savesensor" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let sbe: SaveSensor = serde_json::from_value(msgdata.clone())?;
+      let sbe: SaveSensor = msg_data(&msg.data)?;
 
-      let s = sqldata::save_sensor(&config.db.as_path(), uid, &sbe)?;
+      let s = db.save_sensor(uid, &sbe)?;
       Ok(ServerResponse {
         what: "savedsensor".to_string(),
         content: serde_json::to_value(s)?,
       })
     }
     "deletesensor" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let id: i64 = serde_json::from_value(msgdata.clone())?;
+      let id: i64 = msg_data(&msg.data)?;
 
-      sqldata::delete_sensor(&config.db.as_path(), uid, id)?;
+      db.delete_sensor(uid, id)?;
       Ok(ServerResponse {
         what: "deletedsensor".to_string(),
         content: serde_json::to_value(id)?,
       })
     }
     "savemeasurement" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let m: SaveMeasurement = serde_json::from_value(msgdata.clone())?;
-      let s = sqldata::add_measurement(&config.db.as_path(), uid, &m)?;
+      let m: SaveMeasurement = msg_data(&msg.data)?;
+      let s = db.add_measurement(uid, &m)?;
       Ok(ServerResponse {
         what: "savedmeasurement".to_string(),
         content: serde_json::to_value(s)?,
       })
     }
+    "savemeasurements" => {
+      let ms: Vec<SaveMeasurement> = msg_data(&msg.data)?;
+      let ids = db.add_measurements(uid, &ms)?;
+      Ok(ServerResponse {
+        what: "savedmeasurements".to_string(),
+        content: serde_json::to_value(ids)?,
+      })
+    }
     "getmeasurementlisting" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let mq: MeasurementQuery = serde_json::from_value(msgdata.clone())?;
+      let mq: MeasurementQuery = msg_data(&msg.data)?;
 
-      let entries = sqldata::measurement_listing(Path::new(&config.db), uid, mq.sensor)?;
+      let entries = db.measurement_listing(uid, mq.sensor, None, None)?;
       Ok(ServerResponse {
         what: "measurementlisting".to_string(),
         content: serde_json::to_value(entries)?, // return api token that expires?
       })
     }
+    "exportdata" => {
+      let archive = db.export_user(uid)?;
+      Ok(ServerResponse {
+        what: "exporteddata".to_string(),
+        content: serde_json::to_value(archive)?,
+      })
+    }
+    "importdata" => {
+      let archive: Archive = msg_data(&msg.data)?;
+
+      let summary = db.import_user(uid, &archive)?;
+      Ok(ServerResponse {
+        what: "importeddata".to_string(),
+        content: serde_json::to_value(summary)?,
+      })
+    }
+    "getsensortype" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let st = db.get_sensor_type(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "sensortype".to_string(),
+        content: serde_json::to_value(st)?,
+      })
+    }
+    "savesensortype" => {
+      let st: SensorType = msg_data(&msg.data)?;
+
+      db.set_sensor_type(uid, &st)?;
+      Ok(ServerResponse {
+        what: "savedsensortype".to_string(),
+        content: serde_json::to_value(st)?,
+      })
+    }
+    "savereading" => {
+      let reading: SaveReading = msg_data(&msg.data)?;
+
+      let id = db.add_reading(uid, &reading)?;
+      Ok(ServerResponse {
+        what: "savedreading".to_string(),
+        content: serde_json::to_value(id)?,
+      })
+    }
+    "getreadinglisting" => {
+      let rq: ReadingQuery = msg_data(&msg.data)?;
+
+      let entries = db.reading_listing(uid, &rq)?;
+      Ok(ServerResponse {
+        what: "readinglisting".to_string(),
+        content: serde_json::to_value(entries)?,
+      })
+    }
+    "getcalibration" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let sc = db.get_calibration(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "calibration".to_string(),
+        content: serde_json::to_value(sc)?,
+      })
+    }
+    "setcalibration" => {
+      let sc: SensorCalibration = msg_data(&msg.data)?;
+
+      db.set_calibration(uid, &sc)?;
+      Ok(ServerResponse {
+        what: "savedcalibration".to_string(),
+        content: serde_json::to_value(sc)?,
+      })
+    }
+    "getcalibrationhistory" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let history = db.calibration_history(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "calibrationhistory".to_string(),
+        content: serde_json::to_value(history)?,
+      })
+    }
+    "deletecalibration" => {
+      let id: i64 = msg_data(&msg.data)?;
+
+      db.delete_calibration(uid, id)?;
+      Ok(ServerResponse {
+        what: "deletedcalibration".to_string(),
+        content: serde_json::to_value(id)?,
+      })
+    }
+    "getcalibrationaudit" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let audit = db.calibration_audit(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "calibrationaudit".to_string(),
+        content: serde_json::to_value(audit)?,
+      })
+    }
+    "getvirtualsensor" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let vs = db.get_virtual_sensor(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "virtualsensor".to_string(),
+        content: serde_json::to_value(vs)?,
+      })
+    }
+    "setvirtualsensor" => {
+      let vs: VirtualSensor = msg_data(&msg.data)?;
+
+      db.set_virtual_sensor(uid, &vs)?;
+      Ok(ServerResponse {
+        what: "savedvirtualsensor".to_string(),
+        content: serde_json::to_value(vs)?,
+      })
+    }
+    "getdevicelocation" => {
+      let device: i64 = msg_data(&msg.data)?;
+
+      let dl = db.get_device_location(uid, device)?;
+      Ok(ServerResponse {
+        what: "devicelocation".to_string(),
+        content: serde_json::to_value(dl)?,
+      })
+    }
+    "setdevicelocation" => {
+      let dl: DeviceLocation = msg_data(&msg.data)?;
+
+      db.set_device_location(uid, &dl)?;
+      Ok(ServerResponse {
+        what: "saveddevicelocation".to_string(),
+        content: serde_json::to_value(dl)?,
+      })
+    }
+    "makedevicetoken" => {
+      let device: i64 = msg_data(&msg.data)?;
+
+      let dt = udp::make_token(db, uid, device)?;
+      Ok(ServerResponse {
+        what: "devicetoken".to_string(),
+        content: serde_json::to_value(dt)?,
+      })
+    }
+    "deletedevicetoken" => {
+      let device: i64 = msg_data(&msg.data)?;
+
+      db.set_device_token(uid, device, None)?;
+      Ok(ServerResponse {
+        what: "deleteddevicetoken".to_string(),
+        content: serde_json::to_value(device)?,
+      })
+    }
+    "getlocateddevices" => {
+      let q: LocatedDeviceQuery = msg_data(&msg.data)?;
+
+      let devices = db.located_devices(uid, &q.bbox)?;
+      Ok(ServerResponse {
+        what: "locateddevices".to_string(),
+        content: serde_json::to_value(devices)?,
+      })
+    }
+    "getdevicegeojson" => {
+      let q: LocatedDeviceQuery = msg_data(&msg.data)?;
+
+      let devices = db.located_devices(uid, &q.bbox)?;
+      Ok(ServerResponse {
+        what: "devicegeojson".to_string(),
+        content: geo::devices_geojson(&devices),
+      })
+    }
+    "getreadinggeojson" => {
+      let rq: ReadingQuery = msg_data(&msg.data)?;
+
+      let entries = db.reading_listing(uid, &rq)?;
+      Ok(ServerResponse {
+        what: "readinggeojson".to_string(),
+        content: geo::readings_geojson(&entries),
+      })
+    }
+    "savetemplate" => {
+      let template: SaveTemplate = msg_data(&msg.data)?;
+
+      let id = db.save_template(uid, &template)?;
+      Ok(ServerResponse {
+        what: "savedtemplate".to_string(),
+        content: serde_json::to_value(id)?,
+      })
+    }
+    "gettemplate" => {
+      let id: i64 = msg_data(&msg.data)?;
+
+      let template = db.read_template(uid, id)?;
+      Ok(ServerResponse {
+        what: "template".to_string(),
+        content: serde_json::to_value(template)?,
+      })
+    }
+    "gettemplatelisting" => {
+      let templates = db.template_listing(uid)?;
+      Ok(ServerResponse {
+        what: "templatelisting".to_string(),
+        content: serde_json::to_value(templates)?,
+      })
+    }
+    "deletetemplate" => {
+      let id: i64 = msg_data(&msg.data)?;
+
+      db.delete_template(uid, id)?;
+      Ok(ServerResponse {
+        what: "deletedtemplate".to_string(),
+        content: serde_json::to_value(id)?,
+      })
+    }
+    "instantiate" => {
+      let inst: Instantiate = msg_data(&msg.data)?;
+
+      let devices = db.instantiate_template(uid, &inst)?;
+      Ok(ServerResponse {
+        what: "instantiated".to_string(),
+        content: serde_json::to_value(devices)?,
+      })
+    }
+    "getrolluplisting" => {
+      let rq: RollupQuery = msg_data(&msg.data)?;
+
+      let entries = db.rollup_listing(uid, &rq)?;
+      Ok(ServerResponse {
+        what: "rolluplisting".to_string(),
+        content: serde_json::to_value(entries)?,
+      })
+    }
+    "getretention" => {
+      let sensor: i64 = msg_data(&msg.data)?;
+
+      let sr = db.get_retention(uid, sensor)?;
+      Ok(ServerResponse {
+        what: "retention".to_string(),
+        content: serde_json::to_value(sr)?,
+      })
+    }
+    "saveretention" => {
+      let sr: SensorRetention = msg_data(&msg.data)?;
+
+      db.set_retention(uid, &sr)?;
+      Ok(ServerResponse {
+        what: "savedretention".to_string(),
+        content: serde_json::to_value(sr)?,
+      })
+    }
     /*
         "getzklisting" => {
           let entries = sqldata::zklisting(Path::new(&config.db), uid)?;
@@ -211,8 +685,7 @@ fn user_interface_loggedin(
           })
         }
         "getzk" => {
-          let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-          let id: i64 = serde_json::from_value(msgdata.clone())?;
+          let id: i64 = msg_data(&msg.data)?;
 
           let zk = sqldata::read_zk(Path::new(&config.db), id)?;
           Ok(ServerResponse {
@@ -221,8 +694,7 @@ fn user_interface_loggedin(
           })
         }
         "getzknote" => {
-          let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-          let id: i64 = serde_json::from_value(msgdata.clone())?;
+          let id: i64 = msg_data(&msg.data)?;
 
           let note = sqldata::read_zknote(Path::new(&config.db), id)?;
           Ok(ServerResponse {
@@ -231,8 +703,7 @@ fn user_interface_loggedin(
           })
         }
         "deletezknote" => {
-          let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-          let id: i64 = serde_json::from_value(msgdata.clone())?;
+          let id: i64 = msg_data(&msg.data)?;
 
           sqldata::delete_zknote(Path::new(&config.db), uid, id)?;
           Ok(ServerResponse {
@@ -241,23 +712,16 @@ fn user_interface_loggedin(
           })
         }
     */
-    wat => Err(Box::new(simple_error::SimpleError::new(format!(
-      "invalid 'what' code:'{}'",
-      wat
-    )))),
+    wat => Err(Error::InvalidWhat(wat.to_string())),
   }
 }
 
 // public json msgs don't require login.
-pub fn public_interface(
-  config: &Config,
-  msg: PublicMessage,
-) -> Result<ServerResponse, Box<dyn Error>> {
+pub fn public_interface(config: &Config, msg: PublicMessage) -> Result<ServerResponse, Error> {
   info!("process_public_json, what={}", msg.what.as_str());
   match msg.what.as_str() {
     /*    "getzknote" => {
-      let msgdata = Option::ok_or(msg.data.as_ref(), "malformed json data")?;
-      let id: i64 = serde_json::from_value(msgdata.clone())?;
+      let id: i64 = msg_data(&msg.data)?;
 
       let note = sqldata::read_zknote(&config.db.as_path(), id)?;
       Ok(ServerResponse {
@@ -265,9 +729,72 @@ pub fn public_interface(
         content: serde_json::to_value(note)?,
       })
     }*/
-    wat => Err(Box::new(simple_error::SimpleError::new(format!(
-      "invalid 'what' code:'{}'",
-      wat
-    )))),
+    wat => Err(Error::InvalidWhat(wat.to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use storage_tests;
+
+  fn message(u: &storage_tests::TestUser, what: &str) -> UserMessage {
+    UserMessage {
+      uid: u.name.clone(),
+      pwd: String::new(),
+      what: what.to_string(),
+      data: None,
+    }
+  }
+
+  // each of WHATS reaches a handler; without data most are bad requests.
+  #[test]
+  fn whats_are_answered() {
+    let (tmp, db) = storage_tests::sqlite();
+    let config = storage_tests::config(tmp.path.as_path());
+    let u = storage_tests::user(&db);
+
+    for what in WHATS.iter() {
+      let res = if *what == "register" {
+        let mut msg = message(&u, what);
+        msg.uid = format!("{}-new", u.name);
+        user_interface(&config, &db, msg)
+      } else {
+        admin_interface(&config, &db, u.id, &message(&u, what))
+      };
+      match res {
+        Err(Error::InvalidWhat(_)) => panic!("'{}' isn't answered", what),
+        _ => (),
+      }
+    }
+    match admin_interface(&config, &db, u.id, &message(&u, "nosuchwhat")) {
+      Err(Error::InvalidWhat(_)) => (),
+      r => panic!("{:?}", r.map(|sr| sr.what)),
+    }
+  }
+
+  // admins can read as another user, but not export or change their data.
+  #[test]
+  fn impersonate_is_read_only() {
+    let (tmp, db) = storage_tests::sqlite();
+    let config = storage_tests::config(tmp.path.as_path());
+    let admin = storage_tests::user(&db);
+    let u = storage_tests::user(&db);
+    let impersonate = |what: &str| {
+      let mut msg = message(&admin, "impersonate");
+      msg.data = Some(json!({ "user": u.name, "what": what, "data": null }));
+      admin_interface(&config, &db, admin.id, &msg)
+    };
+
+    assert_eq!(
+      impersonate("getdevicelisting").unwrap().what,
+      "devicelisting"
+    );
+    for what in ["exportdata", "importdata", "savedevice", "deleteuser"].iter() {
+      match impersonate(what) {
+        Err(Error::Forbidden(_)) => (),
+        r => panic!("{}: {:?}", what, r.map(|sr| sr.what)),
+      }
+    }
   }
 }
@@ -9,6 +9,8 @@ extern crate lettre;
 extern crate lettre_email;
 extern crate rand;
 extern crate reqwest;
+extern crate rpassword;
+#[macro_use]
 extern crate serde_json;
 extern crate simple_error;
 extern crate time;
@@ -16,33 +18,64 @@ extern crate toml;
 extern crate uuid;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 extern crate rusqlite;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate barrel;
 extern crate base64;
+extern crate postgres;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate r2d2_sqlite;
+extern crate schemars;
 extern crate sciota_protocol;
 
+mod archive;
+mod backup;
+mod calibration;
+mod commands;
 mod config;
+mod dbbench;
 mod email;
+mod error;
+mod expr;
+mod geo;
+mod grafana;
+mod influx;
 mod interfaces;
+mod logging;
+mod metrics;
+mod openapi;
+mod pgdata;
+mod readings;
+mod rest;
+mod retention;
 mod sqldata;
+mod storage;
+#[cfg(test)]
+mod storage_tests;
+mod templates;
+mod udp;
 mod util;
 
 use actix_files::NamedFile;
+use actix_web::dev::Service;
 // use actix_web::http::{Method, StatusCode};
-use actix_web::middleware::Logger;
 use actix_web::{
-  http, middleware, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer,
-  Responder, Result,
+  http, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+  ResponseError, Result,
 };
 use config::Config;
 use futures::future::Future;
-use sciota_protocol::protocol::{PublicMessage, ServerResponse, UserMessage};
+use sciota_protocol::protocol::{PublicMessage, UserMessage};
 use std::error::Error;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
-use std::time::SystemTime;
+use std::time::{Instant, SystemTime};
+use storage::{Db, Storage};
 
 fn favicon(_req: &HttpRequest) -> Result<NamedFile> {
   let stpath = Path::new("static/favicon.ico");
@@ -70,7 +103,7 @@ fn mainpage(_state: web::Data<Config>, req: HttpRequest) -> HttpResponse {
         .body(s)
     }
     Err(e) => {
-      println!("err");
+      error!("couldn't load index.html: {:?}", e);
       HttpResponse::from_error(actix_web::error::ErrorImATeapot(e))
     }
   }
@@ -80,134 +113,432 @@ fn public(
   state: web::Data<Config>,
   item: web::Json<PublicMessage>,
   _req: HttpRequest,
-) -> HttpResponse {
-  println!("public msg: {:?}", &item);
+) -> rest::FutureResponse {
+  info!("public msg: {}", item.what);
 
-  match interfaces::public_interface(&state, item.into_inner()) {
-    Ok(sr) => HttpResponse::Ok().json(sr),
-    Err(e) => {
-      error!("'public' err: {:?}", e);
-      let se = ServerResponse {
-        what: "server error".to_string(),
-        content: serde_json::Value::String(e.to_string()),
-      };
-      HttpResponse::Ok().json(se)
-    }
-  }
+  let what = item.what.clone();
+  rest::reply(http::StatusCode::OK, move || {
+    let start = Instant::now();
+    let res = interfaces::public_interface(&state, item.into_inner());
+    metrics::message(what.as_str(), &res, start.elapsed());
+    res
+  })
 }
 
-fn user(state: web::Data<Config>, item: web::Json<UserMessage>, _req: HttpRequest) -> HttpResponse {
-  println!("user msg: {:?}", &item);
+// messages are held to the request limit, except an importdata message from
+// a client that sent its credentials as basic auth too; see rest::import_body.
+fn user(
+  state: web::Data<Config>,
+  db: web::Data<Db>,
+  req: HttpRequest,
+  payload: web::Payload,
+) -> rest::FutureResponse {
+  let limit = state.request_limit();
+  Box::new(
+    rest::import_body(&state, db.clone(), &req, payload).and_then(
+      move |(authed, body)| -> rest::FutureResponse {
+        let item: UserMessage = match serde_json::from_slice(&body) {
+          Ok(item) => item,
+          Err(e) => return Box::new(futures::future::ok(error::Error::from(e).error_response())),
+        };
+        if body.len() > limit && (item.what != "importdata" || authed.as_ref() != Some(&item.uid)) {
+          return Box::new(futures::future::ok(
+            error::Error::TooLarge(format!(
+              "messages other than importdata are limited to {} bytes",
+              limit
+            ))
+            .error_response(),
+          ));
+        }
+        info!("user msg: {} from {}", item.what, item.uid);
 
-  match interfaces::user_interface(&state, item.into_inner()) {
-    Ok(sr) => HttpResponse::Ok().json(sr),
-    Err(e) => {
-      error!("'user' err: {:?}", e);
-      let se = ServerResponse {
-        what: "server error".to_string(),
-        content: serde_json::Value::String(e.to_string()),
-      };
-      HttpResponse::Ok().json(se)
-    }
-  }
+        let what = item.what.clone();
+        rest::reply(http::StatusCode::OK, move || {
+          let start = Instant::now();
+          let res = interfaces::user_interface(&state, &db, item);
+          metrics::message(what.as_str(), &res, start.elapsed());
+          res
+        })
+      },
+    ),
+  )
 }
 
-fn register(state: web::Data<Config>, req: HttpRequest) -> HttpResponse {
+fn register(state: web::Data<Config>, db: web::Data<Db>, req: HttpRequest) -> rest::FutureResponse {
   info!("registration: uid: {:?}", req.match_info().get("uid"));
-  match (req.match_info().get("uid"), req.match_info().get("key")) {
-    (Some(uid), Some(key)) => {
-      // read user record.  does the reg key match?
-      match sqldata::read_user(state.db.as_path(), uid) {
-        Ok(user) => {
-          println!("user {:?}", user);
-          println!("user.registration_key {:?}", user.registration_key);
-          println!("key {}", key);
-          if user.registration_key == Some(key.to_string()) {
-            let mut mu = user;
-            mu.registration_key = None;
-            match sqldata::update_user(state.db.as_path(), &mu) {
-              Ok(_) => HttpResponse::Ok().body(
-                format!(
-                  "<h1>You are registered!<h1> <a href=\"{}\">\
+  let (uid, key) = match (req.match_info().get("uid"), req.match_info().get("key")) {
+    (Some(uid), Some(key)) => (uid.to_string(), key.to_string()),
+    _ => {
+      return Box::new(futures::future::ok::<_, actix_web::Error>(
+        HttpResponse::Ok().body("Uid, key not found!".to_string()),
+      ))
+    }
+  };
+
+  let request = logging::request_id();
+  Box::new(
+    web::block(move || -> Result<String, error::Error> {
+      logging::with_request(request, || {
+        // read user record.  does the reg key match?
+        match db.read_user(uid.as_str()) {
+          Ok(user) => {
+            if user.registration_key == Some(key.to_string()) {
+              let mut mu = user;
+              mu.registration_key = None;
+              match db.update_user(&mu) {
+                Ok(_) => Ok(
+                  format!(
+                    "<h1>You are registered!<h1> <a href=\"{}\">\
                  Proceed to the main site</a>",
-                  state.mainsite
-                )
-                .to_string(),
-              ),
-              Err(_e) => HttpResponse::Ok().body("<h1>registration failed</h1>".to_string()),
+                    state.mainsite
+                  )
+                  .to_string(),
+                ),
+                Err(e) => {
+                  error!("registration of '{}' failed: {:?}", uid, e);
+                  Ok("<h1>registration failed</h1>".to_string())
+                }
+              }
+            } else {
+              info!("registration of '{}' refused: wrong key", uid);
+              Ok("<h1>registration failed</h1>".to_string())
             }
-          } else {
-            HttpResponse::Ok().body("<h1>registration failed</h1>".to_string())
           }
+          Err(_e) => Ok("registration key or user doesn't match".to_string()),
         }
-        Err(_e) => HttpResponse::Ok().body("registration key or user doesn't match".to_string()),
+      })
+    })
+    .then(|res| -> Result<HttpResponse, actix_web::Error> {
+      match res {
+        Ok(body) => Ok(HttpResponse::Ok().body(body)),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(format!("{}", e))),
       }
-    }
-    _ => HttpResponse::Ok().body("Uid, key not found!".to_string()),
-  }
-}
-
-fn defcon() -> Config {
-  Config {
-    ip: "127.0.0.1".to_string(),
-    port: 8000,
-    db: PathBuf::from("./mahbloag.db"),
-    mainsite: "https:://mahbloag.practica.site/".to_string(),
-    appname: "mahbloag".to_string(),
-    domain: "practica.site".to_string(),
-  }
+    }),
+  )
 }
 
-fn load_config() -> Config {
-  match util::load_string("config.toml") {
-    Err(e) => {
-      error!("error loading config.toml: {:?}", e);
-      defcon()
+// the args, less '--config <file>', and the file.
+fn config_arg(args: Vec<String>) -> Result<(Vec<String>, Option<PathBuf>), Box<dyn Error>> {
+  let mut rest = Vec::new();
+  let mut file = None;
+  let mut iter = args.into_iter();
+  while let Some(a) = iter.next() {
+    if a == "--config" {
+      let f = iter.next().ok_or("--config needs a file")?;
+      file = Some(PathBuf::from(f));
+    } else if a.starts_with("--config=") {
+      file = Some(PathBuf::from(&a["--config=".len()..]));
+    } else {
+      rest.push(a);
     }
-    Ok(config_str) => match toml::from_str(config_str.as_str()) {
-      Ok(c) => c,
-      Err(e) => {
-        error!("error loading config.toml: {:?}", e);
-        defcon()
-      }
-    },
   }
+  Ok((rest, file))
 }
 
 fn main() {
   match err_main() {
-    Err(e) => println!("error: {:?}", e),
+    // logging may not have started.
+    Err(e) => {
+      eprintln!("error: {}", logging::redact(format!("{}", e).as_str()));
+      std::process::exit(1);
+    }
     Ok(_) => (),
   }
 }
 
 fn err_main() -> Result<(), Box<dyn Error>> {
-  env_logger::init();
+  let (args, config_file) = config_arg(std::env::args().collect())?;
+  let command = args.get(1).map(|a| a.as_str());
+
+  match command {
+    Some("help") | Some("--help") | Some("-h") => {
+      println!("{}", commands::USAGE);
+      return Ok(());
+    }
+    // 'config check' reports problems itself, without logging.
+    Some("config") => {
+      return match args.get(2).map(|a| a.as_str()) {
+        Some("check") => config::check(config_file.as_ref().map(|f| f.as_path())),
+        _ => Err("usage: server [--config <file>] config check".into()),
+      };
+    }
+    _ => (),
+  }
+
+  let loaded = config::load(config_file.as_ref().map(|f| f.as_path()))?;
+  let config = loaded.config;
+
+  logging::init(&config.log)?;
+  match &loaded.file {
+    Some(f) => info!("config from {}", f.display()),
+    None => info!("no config file"),
+  }
+  if !loaded.env.is_empty() {
+    info!("config from the environment: {}", loaded.env.join(", "));
+  }
 
   info!("server init!");
+  metrics::init();
+
+  // 'restore' has to run with the server stopped, before the database is
+  // opened.  'bench' uses its own database.
+  match command {
+    Some("migrate") => {
+      commands::migrate(&config)?;
+      return Ok(());
+    }
+    Some("create-user") => {
+      let name = args
+        .get(2)
+        .ok_or("usage: server create-user <name> [email]")?;
+      commands::create_user(&config, name, args.get(3).map(|e| e.as_str()))?;
+      return Ok(());
+    }
+    Some("reset-password") => {
+      let name = args.get(2).ok_or("usage: server reset-password <name>")?;
+      commands::reset_password(&config, name)?;
+      return Ok(());
+    }
+    Some("check-db") => {
+      commands::check_db(&config)?;
+      return Ok(());
+    }
+    Some("restore") => {
+      let snapshot = args.get(2).ok_or("usage: server restore <snapshot file>")?;
+      backup::restore(config.db.as_path(), Path::new(snapshot))?;
+      return Ok(());
+    }
+    Some("bench") => {
+      let dbfile = args
+        .get(2)
+        .ok_or("usage: server bench <new db file> [measurements] [sensors]")?;
+      let count = match args.get(3) {
+        Some(c) => c.parse::<i64>()?,
+        None => 10_000_000,
+      };
+      let sensors = match args.get(4) {
+        Some(s) => s.parse::<i64>()?,
+        None => 100,
+      };
+      dbbench::run(Path::new(dbfile), count, sensors)?;
+      return Ok(());
+    }
+    Some("backup") | Some("serve") | None => (),
+    Some(a) => return Err(format!("unknown command: {}\n{}", a, commands::USAGE).into()),
+  }
+
+  let db = storage::open_storage(&config)?;
 
-  let config = load_config();
+  if command == Some("backup") {
+    let bc = config
+      .backup
+      .as_ref()
+      .ok_or("no [backup] section in the server config")?;
+    let path = backup::snapshot(&*db, config.db.as_path(), bc)?;
+    info!("wrote backup: {:?}", path);
+    return Ok(());
+  }
 
-  if !config.db.as_path().exists() {
-    sqldata::dbinit(config.db.as_path())?;
+  match &config.admins {
+    Some(admins) => db.set_admins(admins)?,
+    None => (),
   }
 
   let staticF = Path::new("static").exists();
 
-  println!("config: {:?}", config);
+  info!("config: {:?}", config);
 
   // let sys = actix_rt::System::new("pdf-server");
 
   let c = web::Data::new(config.clone());
+  let d = web::Data::new(db);
+
+  match &config.retention {
+    Some(rc) => retention::start(d.clone(), rc.clone()),
+    None => (),
+  }
+  match &config.backup {
+    Some(bc) => backup::start(d.clone(), config.db.clone(), bc.clone()),
+    None => (),
+  }
+  match &config.udp {
+    Some(uc) => udp::start(d.clone(), config.ip.as_str(), uc.clone())?,
+    None => (),
+  }
+
+  // imports get their own, bigger limit; see rest::import_body.
+  let json_limit = config.request_limit();
+
   HttpServer::new(move || {
     let mut app = App::new()
       .register_data(c.clone()) // <- create app with shared state
-      // enable logger
-      .wrap(middleware::Logger::default())
+      .register_data(d.clone())
+      // request ids, the access log, and request counts and latencies by
+      // route.  The query string isn't logged: it can have passwords.  Nor
+      // are registration paths, which have the registration key.
+      .wrap_fn(|req, srv| {
+        let start = Instant::now();
+        let id = logging::next_request_id();
+        let route = metrics::route(req.path());
+        let method = req.method().to_string();
+        let path = if route == "/register/{uid}/{key}" {
+          route.to_string()
+        } else {
+          req.path().to_string()
+        };
+        let remote = req.connection_info().remote().unwrap_or("-").to_string();
+        let call = logging::with_request(Some(id), || srv.call(req));
+        logging::scoped(
+          Some(id),
+          call.map(move |mut res| {
+            let status = res.status().as_u16();
+            let elapsed = start.elapsed();
+            metrics::request(route, method.as_str(), status, elapsed);
+            info!(
+              "{} {} {} {} {:.3}ms",
+              remote,
+              method,
+              path,
+              status,
+              elapsed.as_secs() as f64 * 1e3 + f64::from(elapsed.subsec_nanos()) / 1e6
+            );
+            res.headers_mut().insert(
+              http::header::HeaderName::from_static("x-request-id"),
+              http::header::HeaderValue::from(id),
+            );
+            res
+          }),
+        )
+      })
       //      .route("/", web::get().to(mainpage))
-      .service(web::resource("/public").route(web::post().to(public)))
-      .service(web::resource("/user").route(web::post().to(user)))
-      .service(web::resource(r"/register/{uid}/{key}").route(web::get().to(register)));
+      .service(web::resource("/public").route(web::post().to_async(public)))
+      .service(web::resource("/user").route(web::post().to_async(user)))
+      .service(web::resource(r"/register/{uid}/{key}").route(web::get().to_async(register)))
+      .service(web::resource("/openapi.json").route(web::get().to(openapi::openapi_json)))
+      .service(web::resource("/metrics").route(web::get().to(metrics::metrics)))
+      .service(
+        web::scope("/grafana")
+          .data(web::JsonConfig::default().limit(json_limit))
+          .service(web::resource("/").route(web::get().to_async(grafana::test)))
+          .service(web::resource("/search").route(web::post().to_async(grafana::search)))
+          .service(web::resource("/metrics").route(web::post().to_async(grafana::metric_list)))
+          .service(web::resource("/query").route(web::post().to_async(grafana::query)))
+          .service(web::resource("/annotations").route(web::post().to_async(grafana::annotations))),
+      )
+      // influxdb line protocol, 1.x and 2.x.
+      .service(
+        web::resource("/write")
+          .data(web::PayloadConfig::new(json_limit))
+          .route(web::post().to_async(influx::post_write)),
+      )
+      .service(
+        web::resource("/api/v2/write")
+          .data(web::PayloadConfig::new(json_limit))
+          .route(web::post().to_async(influx::post_write)),
+      )
+      .service(
+        web::scope("/api")
+          .data(web::JsonConfig::default().limit(json_limit))
+          .service(web::resource("/export").route(web::get().to_async(rest::get_export)))
+          .service(web::resource("/import").route(web::post().to_async(rest::post_import)))
+          .service(
+            web::resource("/devices")
+              .route(web::get().to_async(rest::get_devices))
+              .route(web::post().to_async(rest::post_device)),
+          )
+          // before /devices/{id}, which would take 'geojson' as an id.
+          .service(
+            web::resource("/devices/geojson").route(web::get().to_async(rest::get_devices_geojson)),
+          )
+          .service(
+            web::resource("/devices/{id}")
+              .route(web::get().to_async(rest::get_device))
+              .route(web::put().to_async(rest::put_device))
+              .route(web::delete().to_async(rest::delete_device)),
+          )
+          .service(
+            web::resource("/devices/{id}/sensors")
+              .route(web::get().to_async(rest::get_device_sensors))
+              .route(web::post().to_async(rest::post_device_sensor)),
+          )
+          .service(
+            web::resource("/devices/{id}/location")
+              .route(web::get().to_async(rest::get_device_location))
+              .route(web::put().to_async(rest::put_device_location)),
+          )
+          .service(
+            web::resource("/devices/{id}/token")
+              .route(web::post().to_async(rest::post_device_token))
+              .route(web::delete().to_async(rest::delete_device_token)),
+          )
+          .service(
+            web::resource("/templates")
+              .route(web::get().to_async(rest::get_templates))
+              .route(web::post().to_async(rest::post_template)),
+          )
+          .service(
+            web::resource("/templates/{id}")
+              .route(web::get().to_async(rest::get_template))
+              .route(web::put().to_async(rest::put_template))
+              .route(web::delete().to_async(rest::delete_template)),
+          )
+          .service(
+            web::resource("/templates/{id}/instantiate")
+              .route(web::post().to_async(rest::post_instantiate)),
+          )
+          .service(web::resource("/sensors").route(web::get().to_async(rest::get_sensors)))
+          .service(
+            web::resource("/sensors/{id}")
+              .route(web::get().to_async(rest::get_sensor))
+              .route(web::put().to_async(rest::put_sensor))
+              .route(web::delete().to_async(rest::delete_sensor)),
+          )
+          .service(
+            web::resource("/sensors/{id}/measurements")
+              .route(web::get().to_async(rest::get_measurements))
+              .route(web::post().to_async(rest::post_measurement)),
+          )
+          .service(
+            web::resource("/sensors/{id}/calibration")
+              .route(web::get().to_async(rest::get_calibration))
+              .route(web::put().to_async(rest::put_calibration)),
+          )
+          .service(
+            web::resource("/sensors/{id}/calibrations")
+              .route(web::get().to_async(rest::get_calibration_history)),
+          )
+          .service(
+            web::resource("/sensors/{id}/calibrations/audit")
+              .route(web::get().to_async(rest::get_calibration_audit)),
+          )
+          .service(
+            web::resource("/calibrations/{id}")
+              .route(web::delete().to_async(rest::delete_calibration)),
+          )
+          .service(
+            web::resource("/sensors/{id}/virtual")
+              .route(web::get().to_async(rest::get_virtual_sensor))
+              .route(web::put().to_async(rest::put_virtual_sensor)),
+          )
+          .service(
+            web::resource("/sensors/{id}/type")
+              .route(web::get().to_async(rest::get_sensor_type))
+              .route(web::put().to_async(rest::put_sensor_type)),
+          )
+          .service(
+            web::resource("/sensors/{id}/readings")
+              .route(web::get().to_async(rest::get_readings))
+              .route(web::post().to_async(rest::post_reading)),
+          )
+          .service(
+            web::resource("/sensors/{id}/readings/geojson")
+              .route(web::get().to_async(rest::get_readings_geojson)),
+          )
+          .service(
+            web::resource("/sensors/{id}/rollups/{rollup}")
+              .route(web::get().to_async(rest::get_rollups)),
+          ),
+      );
     if staticF {
       app
         .service(actix_files::Files::new("/static/", "static/"))
@@ -0,0 +1,516 @@
+use actix_web::HttpResponse;
+use error::Error;
+use interfaces;
+use sciota_protocol::protocol::ServerResponse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// prometheus metrics, served at /metrics in the text exposition format:
+// requests per route, messages per 'what' code, database operations, ingest,
+// emails and active users.  Everything is kept in one registry behind a
+// mutex, updated as things happen and rendered on each scrape.
+
+// histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+  0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// the server has no sessions; a user counts as active for this long after
+// their last login check, which every message and REST request makes.
+const ACTIVE_WINDOW: Duration = Duration::from_secs(300);
+
+// the routes in main.rs, for the 'route' label.  Paths that don't match one
+// are counted as "other", so that scanners and typos can't grow the label
+// set without bound.
+const ROUTES: [&str; 38] = [
+  "/public",
+  "/user",
+  "/register/{uid}/{key}",
+  "/openapi.json",
+  "/metrics",
+  "/grafana",
+  "/grafana/search",
+  "/grafana/metrics",
+  "/grafana/query",
+  "/grafana/annotations",
+  "/write",
+  "/api/v2/write",
+  "/api/export",
+  "/api/import",
+  "/api/devices",
+  "/api/devices/geojson",
+  "/api/devices/{id}",
+  "/api/devices/{id}/sensors",
+  "/api/devices/{id}/location",
+  "/api/devices/{id}/token",
+  "/api/templates",
+  "/api/templates/{id}",
+  "/api/templates/{id}/instantiate",
+  "/api/sensors",
+  "/api/sensors/{id}",
+  "/api/sensors/{id}/measurements",
+  "/api/sensors/{id}/calibration",
+  "/api/sensors/{id}/calibrations",
+  "/api/sensors/{id}/calibrations/audit",
+  "/api/calibrations/{id}",
+  "/api/sensors/{id}/virtual",
+  "/api/sensors/{id}/type",
+  "/api/sensors/{id}/readings",
+  "/api/sensors/{id}/readings/geojson",
+  "/api/sensors/{id}/rollups/{rollup}",
+  "/",
+  "/static/{file}",
+  "/static/{dir}/{file}",
+];
+
+#[derive(Default)]
+struct Histogram {
+  buckets: [u64; 12],
+  count: u64,
+  sum: f64,
+}
+
+impl Histogram {
+  fn observe(&mut self, d: Duration) {
+    let secs = d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9;
+    for (i, b) in BUCKETS.iter().enumerate() {
+      if secs <= *b {
+        self.buckets[i] += 1;
+      }
+    }
+    self.count += 1;
+    self.sum += secs;
+  }
+}
+
+#[derive(Default)]
+struct Registry {
+  // by (route, method, status).
+  requests: BTreeMap<(String, String, u16), u64>,
+  // by (route, method).
+  request_durations: BTreeMap<(String, String), Histogram>,
+  // by (what, result).
+  messages: BTreeMap<(String, String), u64>,
+  message_durations: BTreeMap<String, Histogram>,
+  login_failures: u64,
+  // by (op, ok).
+  db_operations: BTreeMap<(&'static str, bool), u64>,
+  db_durations: BTreeMap<&'static str, Histogram>,
+  measurements: u64,
+  readings: u64,
+  emails_sent: u64,
+  emails_failed: u64,
+  // udp ingest packets, by status.
+  udp_packets: BTreeMap<&'static str, u64>,
+  // last login check, by user id.
+  users: HashMap<i64, Instant>,
+}
+
+lazy_static! {
+  static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+  static ref START: SystemTime = SystemTime::now();
+}
+
+fn update<F: FnOnce(&mut Registry)>(f: F) {
+  // a panic while holding the lock leaves counters that are still good.
+  let mut r = match REGISTRY.lock() {
+    Ok(r) => r,
+    Err(poisoned) => poisoned.into_inner(),
+  };
+  f(&mut r)
+}
+
+// start the clock for sciota_start_time_seconds.
+pub fn init() {
+  lazy_static::initialize(&START);
+}
+
+// the route pattern a request path matched.
+pub fn route(path: &str) -> &'static str {
+  let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+  for r in ROUTES.iter() {
+    let pattern: Vec<&str> = r.trim_end_matches('/').split('/').collect();
+    if pattern.len() == segments.len()
+      && pattern
+        .iter()
+        .zip(segments.iter())
+        .all(|(p, s)| p == s || (p.starts_with('{') && !s.is_empty()))
+    {
+      return r;
+    }
+  }
+  "other"
+}
+
+pub fn request(route: &str, method: &str, status: u16, elapsed: Duration) {
+  update(|r| {
+    *r.requests
+      .entry((route.to_string(), method.to_string(), status))
+      .or_insert(0) += 1;
+    r.request_durations
+      .entry((route.to_string(), method.to_string()))
+      .or_insert_with(Histogram::default)
+      .observe(elapsed);
+  })
+}
+
+// the 'what' and result labels for a message.  Like routes, 'what' codes
+// that aren't in interfaces::WHATS are counted as "other", and so are
+// messages whose login was refused: those come from anyone, with any 'what'.
+fn message_labels(what: &str, res: &Result<ServerResponse, Error>) -> (&'static str, &'static str) {
+  let known = interfaces::WHATS.iter().find(|w| **w == what);
+  match (known, res) {
+    (_, Ok(sr)) if interfaces::LOGIN_FAILURES.contains(&sr.what.as_str()) => {
+      ("other", "login_failed")
+    }
+    (_, Err(Error::InvalidWhat(_))) => ("other", "invalid_what"),
+    (None, Ok(_)) => ("other", "ok"),
+    (None, Err(e)) => ("other", e.code()),
+    (Some(w), Ok(_)) => (w, "ok"),
+    (Some(w), Err(e)) => (w, e.code()),
+  }
+}
+
+// a message on the message interface.
+pub fn message(what: &str, res: &Result<ServerResponse, Error>, elapsed: Duration) {
+  let (what, result) = message_labels(what, res);
+  update(|r| {
+    *r.messages
+      .entry((what.to_string(), result.to_string()))
+      .or_insert(0) += 1;
+    r.message_durations
+      .entry(what.to_string())
+      .or_insert_with(Histogram::default)
+      .observe(elapsed);
+  })
+}
+
+pub fn login_failed() {
+  update(|r| r.login_failures += 1)
+}
+
+pub fn user_seen(uid: i64) {
+  update(|r| {
+    r.users.insert(uid, Instant::now());
+  })
+}
+
+pub fn db_operation(op: &'static str, ok: bool, elapsed: Duration) {
+  update(|r| {
+    *r.db_operations.entry((op, ok)).or_insert(0) += 1;
+    r.db_durations
+      .entry(op)
+      .or_insert_with(Histogram::default)
+      .observe(elapsed);
+  })
+}
+
+pub fn ingested(measurements: usize) {
+  update(|r| r.measurements += measurements as u64)
+}
+
+pub fn readings_ingested(readings: usize) {
+  update(|r| r.readings += readings as u64)
+}
+
+pub fn udp_packet(status: &'static str) {
+  update(|r| *r.udp_packets.entry(status).or_insert(0) += 1)
+}
+
+pub fn email(sent: bool) {
+  update(|r| {
+    if sent {
+      r.emails_sent += 1
+    } else {
+      r.emails_failed += 1
+    }
+  })
+}
+
+// label values are escaped per the exposition format.
+fn escape(v: &str) -> String {
+  v.replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+  let ls: Vec<String> = pairs
+    .iter()
+    .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+    .collect();
+  ls.join(",")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+  let sep = if labels.is_empty() { "" } else { "," };
+  for (i, b) in BUCKETS.iter().enumerate() {
+    let _ = writeln!(
+      out,
+      "{}_bucket{{{}{}le=\"{}\"}} {}",
+      name, labels, sep, b, h.buckets[i]
+    );
+  }
+  let _ = writeln!(
+    out,
+    "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+    name, labels, sep, h.count
+  );
+  let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum);
+  let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, h.count);
+}
+
+pub fn render() -> String {
+  let mut out = String::new();
+  let start = START
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_secs())
+    .unwrap_or(0);
+
+  update(|r| {
+    let now = Instant::now();
+    r.users
+      .retain(|_, seen| now.duration_since(*seen) < ACTIVE_WINDOW);
+
+    header(
+      &mut out,
+      "sciota_start_time_seconds",
+      "gauge",
+      "When the server started, in seconds since the epoch.",
+    );
+    let _ = writeln!(out, "sciota_start_time_seconds {}", start);
+
+    header(
+      &mut out,
+      "sciota_http_requests_total",
+      "counter",
+      "HTTP requests, by route, method and status.",
+    );
+    for ((route, method, status), n) in r.requests.iter() {
+      let _ = writeln!(
+        out,
+        "sciota_http_requests_total{{{}}} {}",
+        labels(&[
+          ("route", route),
+          ("method", method),
+          ("status", status.to_string().as_str())
+        ]),
+        n
+      );
+    }
+    header(
+      &mut out,
+      "sciota_http_request_duration_seconds",
+      "histogram",
+      "HTTP request latency, by route and method.",
+    );
+    for ((route, method), h) in r.request_durations.iter() {
+      histogram(
+        &mut out,
+        "sciota_http_request_duration_seconds",
+        labels(&[("route", route), ("method", method)]).as_str(),
+        h,
+      );
+    }
+
+    header(
+      &mut out,
+      "sciota_messages_total",
+      "counter",
+      "Message interface requests, by 'what' code and result: ok or an error code.",
+    );
+    for ((what, result), n) in r.messages.iter() {
+      let _ = writeln!(
+        out,
+        "sciota_messages_total{{{}}} {}",
+        labels(&[("what", what), ("result", result)]),
+        n
+      );
+    }
+    header(
+      &mut out,
+      "sciota_message_duration_seconds",
+      "histogram",
+      "Message handling time, by 'what' code.",
+    );
+    for (what, h) in r.message_durations.iter() {
+      histogram(
+        &mut out,
+        "sciota_message_duration_seconds",
+        labels(&[("what", what)]).as_str(),
+        h,
+      );
+    }
+    header(
+      &mut out,
+      "sciota_login_failures_total",
+      "counter",
+      "Refused logins: bad user or password, unregistered or disabled.",
+    );
+    let _ = writeln!(out, "sciota_login_failures_total {}", r.login_failures);
+    header(
+      &mut out,
+      "sciota_active_users",
+      "gauge",
+      "Users who've made a request in the last five minutes.",
+    );
+    let _ = writeln!(out, "sciota_active_users {}", r.users.len());
+
+    header(
+      &mut out,
+      "sciota_measurements_ingested_total",
+      "counter",
+      "Measurements stored.",
+    );
+    let _ = writeln!(out, "sciota_measurements_ingested_total {}", r.measurements);
+    header(
+      &mut out,
+      "sciota_readings_ingested_total",
+      "counter",
+      "Typed readings stored.",
+    );
+    let _ = writeln!(out, "sciota_readings_ingested_total {}", r.readings);
+    header(
+      &mut out,
+      "sciota_udp_packets_total",
+      "counter",
+      "UDP ingest packets, by result: ok, duplicate, or the error status.",
+    );
+    for (status, n) in r.udp_packets.iter() {
+      let _ = writeln!(
+        out,
+        "sciota_udp_packets_total{{{}}} {}",
+        labels(&[("result", status)]),
+        n
+      );
+    }
+
+    header(
+      &mut out,
+      "sciota_db_operations_total",
+      "counter",
+      "Database operations, by operation and result.",
+    );
+    for ((op, ok), n) in r.db_operations.iter() {
+      let result = if *ok { "ok" } else { "error" };
+      let _ = writeln!(
+        out,
+        "sciota_db_operations_total{{{}}} {}",
+        labels(&[("op", op), ("result", result)]),
+        n
+      );
+    }
+    header(
+      &mut out,
+      "sciota_db_operation_duration_seconds",
+      "histogram",
+      "Database operation time, by operation.",
+    );
+    for (op, h) in r.db_durations.iter() {
+      histogram(
+        &mut out,
+        "sciota_db_operation_duration_seconds",
+        labels(&[("op", op)]).as_str(),
+        h,
+      );
+    }
+
+    header(
+      &mut out,
+      "sciota_emails_total",
+      "counter",
+      "Registration emails, by result.",
+    );
+    let _ = writeln!(
+      out,
+      "sciota_emails_total{{result=\"sent\"}} {}",
+      r.emails_sent
+    );
+    let _ = writeln!(
+      out,
+      "sciota_emails_total{{result=\"failed\"}} {}",
+      r.emails_failed
+    );
+  });
+
+  out
+}
+
+pub fn metrics() -> HttpResponse {
+  HttpResponse::Ok()
+    .content_type("text/plain; version=0.0.4")
+    .body(render())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value;
+
+  fn reply(what: &str) -> Result<ServerResponse, Error> {
+    Ok(ServerResponse {
+      what: what.to_string(),
+      content: Value::Null,
+    })
+  }
+
+  #[test]
+  fn message_labels_are_known_whats() {
+    assert_eq!(
+      message_labels("getdevicelisting", &reply("devicelisting")),
+      ("getdevicelisting", "ok")
+    );
+    assert_eq!(
+      message_labels("savedevice", &Err(Error::NotFound("no device".to_string()))),
+      ("savedevice", "not_found")
+    );
+    assert_eq!(
+      message_labels("made-up", &reply("whatever")),
+      ("other", "ok")
+    );
+    assert_eq!(
+      message_labels("made-up", &Err(Error::InvalidWhat("made-up".to_string()))),
+      ("other", "invalid_what")
+    );
+    // a refused login is "other", whatever the 'what'.
+    for what in ["made-up", "getdevicelisting"].iter() {
+      for failure in interfaces::LOGIN_FAILURES.iter() {
+        assert_eq!(
+          message_labels(what, &reply(failure)),
+          ("other", "login_failed")
+        );
+      }
+    }
+  }
+
+  // unauthenticated callers can't grow the label set.
+  #[test]
+  fn messages_are_bounded() {
+    for i in 0..1000 {
+      let what = format!("random-{}", i);
+      message(
+        what.as_str(),
+        &reply("invalid user or pwd"),
+        Duration::from_millis(1),
+      );
+      message(
+        what.as_str(),
+        &Err(Error::InvalidWhat(what.clone())),
+        Duration::from_millis(1),
+      );
+    }
+    let known = |w: &str| w == "other" || interfaces::WHATS.contains(&w);
+    update(|r| {
+      assert!(r.messages.keys().all(|(w, _)| known(w.as_str())));
+      assert!(r.message_durations.keys().all(|w| known(w.as_str())));
+    });
+    assert!(!render().contains("random-"));
+  }
+}
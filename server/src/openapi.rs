@@ -0,0 +1,688 @@
+use actix_web::{web, HttpResponse};
+use archive::{Archive, ImportSummary};
+use calibration::{CalibrationAudit, CalibrationRecord, SensorCalibration, VirtualSensor};
+use config::Config;
+use error::ErrorContent;
+use geo::{DeviceLocation, LocatedDevice, LocatedDeviceQuery};
+use interfaces::Impersonate;
+use readings::{Reading, ReadingQuery, SaveReading, SensorType};
+use rest::{
+  CalibrationBody, DeviceBody, FormulaBody, InstantiateBody, LocationBody, MeasurementBody,
+  ReadingBody, SensorBody, SensorTypeBody, TemplateBody,
+};
+use retention::{RollupEntry, RollupQuery, SensorRetention};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{InstanceType, Schema, SchemaObject};
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use sqldata::UserListEntry;
+use std::any::TypeId;
+use templates::{DeviceTemplate, Instantiate, InstantiatedDevice, SaveTemplate};
+use udp::DeviceToken;
+
+// sciota-protocol types don't derive JsonSchema, so their wire format is
+// described here, as serde remote definitions: the derived (de)serializers
+// name every field of the real type with its type, so these don't compile if
+// they fall out of step with the protocol crate.  The tests check the renames.
+#[allow(dead_code)]
+mod protocol {
+  use schemars::JsonSchema;
+  use sciota_protocol::protocol;
+  use serde_json::Value;
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::UserMessage")]
+  pub struct UserMessage {
+    pub uid: String,
+    pub pwd: String,
+    pub what: String,
+    pub data: Option<Value>,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::PublicMessage")]
+  pub struct PublicMessage {
+    pub what: String,
+    pub data: Option<Value>,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::ServerResponse")]
+  pub struct ServerResponse {
+    pub what: String,
+    pub content: Value,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::RegistrationData")]
+  pub struct RegistrationData {
+    pub email: String,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::Device")]
+  pub struct Device {
+    pub id: i64,
+    pub user: i64,
+    pub name: String,
+    pub description: String,
+    pub createdate: i64,
+    pub changeddate: i64,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::SaveDevice")]
+  pub struct SaveDevice {
+    pub id: Option<i64>,
+    pub name: String,
+    pub description: String,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::Sensor")]
+  pub struct Sensor {
+    pub id: i64,
+    pub device: i64,
+    pub name: String,
+    pub description: String,
+    pub createdate: i64,
+    pub changeddate: i64,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::SaveSensor")]
+  pub struct SaveSensor {
+    pub id: Option<i64>,
+    pub device: i64,
+    pub name: String,
+    pub description: String,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::Measurement")]
+  pub struct Measurement {
+    pub id: i64,
+    pub sensor: i64,
+    pub value: f64,
+    pub createdate: i64,
+    pub measuredate: i64,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::SaveMeasurement")]
+  pub struct SaveMeasurement {
+    pub value: f64,
+    pub sensor: i64,
+    pub measuredate: i64,
+  }
+
+  #[derive(Serialize, Deserialize, JsonSchema)]
+  #[serde(remote = "protocol::MeasurementQuery")]
+  pub struct MeasurementQuery {
+    pub sensor: i64,
+    pub enddate: Option<i64>,
+    #[serde(rename = "lengthOfTime")]
+    pub length_of_time: Option<i64>,
+  }
+}
+
+use self::protocol::*;
+
+// a 'what' code in the message interface: the type of the message 'data',
+// and the 'what' and 'content' of the reply.
+struct WhatCode {
+  what: &'static str,
+  data: Option<Value>,
+  reply: &'static str,
+  content: Option<Value>,
+  admin: bool,
+}
+
+fn schema<T: JsonSchema>(gen: &mut SchemaGenerator) -> Option<Value> {
+  serde_json::to_value(gen.subschema_for::<T>()).ok()
+}
+
+// a what code's data or content: None for ().
+fn what_schema<T: JsonSchema + 'static>(gen: &mut SchemaGenerator) -> Option<Value> {
+  if TypeId::of::<T>() == TypeId::of::<()>() {
+    None
+  } else {
+    schema::<T>(gen)
+  }
+}
+
+// GeoJSON isn't described in detail.
+struct GeoJson;
+
+impl JsonSchema for GeoJson {
+  fn schema_name() -> String {
+    "GeoJson".to_string()
+  }
+  fn is_referenceable() -> bool {
+    false
+  }
+  fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+    let mut schema = SchemaObject::default();
+    schema.instance_type = Some(InstanceType::Object.into());
+    schema.metadata().description = Some("a GeoJSON FeatureCollection".to_string());
+    schema.into()
+  }
+}
+
+// the what codes, one a line:
+//
+//   <what>, <data type> => <reply what>, <content type>;
+//
+// with () for no data or content.
+macro_rules! what_codes {
+  ($gen:ident, $admin:expr; $($what:expr, $data:ty => $reply:expr, $content:ty;)*) => {
+    vec![$(WhatCode {
+      what: $what,
+      data: what_schema::<$data>($gen),
+      reply: $reply,
+      content: what_schema::<$content>($gen),
+      admin: $admin,
+    },)*]
+  };
+}
+
+fn user_whats(gen: &mut SchemaGenerator) -> Vec<WhatCode> {
+  let mut whats = what_codes![gen, false;
+    "register", RegistrationData => "registration sent", ();
+    "login", () => "logged in", ();
+    "getdevicelisting", () => "devicelisting", Vec<Device>;
+    "savedevice", SaveDevice => "saveddevice", i64;
+    "deletedevice", i64 => "deleteddevice", i64;
+    "getsensorlisting", i64 => "sensorlisting", Vec<Sensor>;
+    "savesensor", SaveSensor => "savedsensor", Sensor;
+    "deletesensor", i64 => "deletedsensor", i64;
+    "savemeasurement", SaveMeasurement => "savedmeasurement", i64;
+    "savemeasurements", Vec<SaveMeasurement> => "savedmeasurements", Vec<i64>;
+    "getmeasurementlisting", MeasurementQuery => "measurementlisting", Vec<Measurement>;
+    "getsensortype", i64 => "sensortype", SensorType;
+    "savesensortype", SensorType => "savedsensortype", SensorType;
+    "savereading", SaveReading => "savedreading", i64;
+    "getreadinglisting", ReadingQuery => "readinglisting", Vec<Reading>;
+    "getcalibration", i64 => "calibration", SensorCalibration;
+    "setcalibration", SensorCalibration => "savedcalibration", SensorCalibration;
+    "getcalibrationhistory", i64 => "calibrationhistory", Vec<CalibrationRecord>;
+    "deletecalibration", i64 => "deletedcalibration", i64;
+    "getcalibrationaudit", i64 => "calibrationaudit", Vec<CalibrationAudit>;
+    "getvirtualsensor", i64 => "virtualsensor", VirtualSensor;
+    "setvirtualsensor", VirtualSensor => "savedvirtualsensor", VirtualSensor;
+    "getdevicelocation", i64 => "devicelocation", DeviceLocation;
+    "setdevicelocation", DeviceLocation => "saveddevicelocation", DeviceLocation;
+    "makedevicetoken", i64 => "devicetoken", DeviceToken;
+    "deletedevicetoken", i64 => "deleteddevicetoken", i64;
+    "getlocateddevices", LocatedDeviceQuery => "locateddevices", Vec<LocatedDevice>;
+    "getdevicegeojson", LocatedDeviceQuery => "devicegeojson", GeoJson;
+    "getreadinggeojson", ReadingQuery => "readinggeojson", GeoJson;
+    "savetemplate", SaveTemplate => "savedtemplate", i64;
+    "gettemplate", i64 => "template", DeviceTemplate;
+    "gettemplatelisting", () => "templatelisting", Vec<DeviceTemplate>;
+    "deletetemplate", i64 => "deletedtemplate", i64;
+    "instantiate", Instantiate => "instantiated", Vec<InstantiatedDevice>;
+    "getrolluplisting", RollupQuery => "rolluplisting", Vec<RollupEntry>;
+    "getretention", i64 => "retention", SensorRetention;
+    "saveretention", SensorRetention => "savedretention", SensorRetention;
+    "exportdata", () => "exporteddata", Archive;
+    "importdata", Archive => "importeddata", ImportSummary;
+  ];
+  whats.extend(what_codes![gen, true;
+    "getuserlisting", () => "userlisting", Vec<UserListEntry>;
+    "enableuser", String => "enableduser", String;
+    "disableuser", String => "disableduser", String;
+    "deleteuser", String => "deleteduser", String;
+    "confirmuser", String => "confirmeduser", String;
+    "backup", () => "backedup", String;
+    // the reply is whatever the impersonated 'what' code replies.
+    "impersonate", Impersonate => "", ();
+  ]);
+  whats
+}
+
+fn what_json(w: &WhatCode) -> Value {
+  json!({
+    "what": w.what,
+    "data": w.data,
+    "reply": w.reply,
+    "content": w.content,
+    "admin": w.admin,
+  })
+}
+
+// an operation on one of the /api routes.
+fn rest_op(summary: &str, body: Option<Value>, status: &str, response: Option<Value>) -> Value {
+  let mut op = Map::new();
+  op.insert("summary".to_string(), json!(summary));
+  op.insert("security".to_string(), json!([{ "basic": [] }]));
+  match body {
+    Some(b) => {
+      op.insert(
+        "requestBody".to_string(),
+        json!({ "required": true, "content": { "application/json": { "schema": b } } }),
+      );
+    }
+    None => (),
+  }
+  let mut responses = Map::new();
+  responses.insert(
+    status.to_string(),
+    match response {
+      Some(r) => json!({
+        "description": summary,
+        "content": { "application/json": { "schema": r } }
+      }),
+      None => json!({ "description": summary }),
+    },
+  );
+  responses.insert("default".to_string(), error_response());
+  op.insert("responses".to_string(), Value::Object(responses));
+  Value::Object(op)
+}
+
+fn error_response() -> Value {
+  json!({
+    "description": "error, with 'what' = \"server error\" and an ErrorContent 'content'",
+    "content": {
+      "application/json": { "schema": { "$ref": "#/components/schemas/ServerResponse" } }
+    }
+  })
+}
+
+fn id_param(name: &str) -> Value {
+  json!({ "name": name, "in": "path", "required": true, "schema": { "type": "integer" } })
+}
+
+fn with_params(mut op: Value, params: Vec<Value>) -> Value {
+  op["parameters"] = Value::Array(params);
+  op
+}
+
+pub fn openapi(config: &Config) -> Value {
+  let mut gen = SchemaSettings::openapi3().into_generator();
+
+  let whats: Vec<Value> = user_whats(&mut gen).iter().map(what_json).collect();
+
+  let user_message = schema::<UserMessage>(&mut gen);
+  let public_message = schema::<PublicMessage>(&mut gen);
+  let server_response = schema::<ServerResponse>(&mut gen);
+  schema::<ErrorContent>(&mut gen);
+
+  let device_body = schema::<DeviceBody>(&mut gen);
+  let sensor_body = schema::<SensorBody>(&mut gen);
+  let measurement_body = schema::<MeasurementBody>(&mut gen);
+  let device = schema::<Device>(&mut gen);
+  let devices = schema::<Vec<Device>>(&mut gen);
+  let sensor = schema::<Sensor>(&mut gen);
+  let sensors = schema::<Vec<Sensor>>(&mut gen);
+  let measurements = schema::<Vec<Measurement>>(&mut gen);
+  let rollups = schema::<Vec<RollupEntry>>(&mut gen);
+  let archive = schema::<Archive>(&mut gen);
+  let sensor_type = schema::<SensorType>(&mut gen);
+  let sensor_type_body = schema::<SensorTypeBody>(&mut gen);
+  let reading_body = schema::<ReadingBody>(&mut gen);
+  let readings = schema::<Vec<Reading>>(&mut gen);
+  let location_body = schema::<LocationBody>(&mut gen);
+  let calibration_body = schema::<CalibrationBody>(&mut gen);
+  let sensor_calibration = schema::<SensorCalibration>(&mut gen);
+  let calibration_history = schema::<Vec<CalibrationRecord>>(&mut gen);
+  let calibration_audit = schema::<Vec<CalibrationAudit>>(&mut gen);
+  let formula_body = schema::<FormulaBody>(&mut gen);
+  let virtual_sensor = schema::<VirtualSensor>(&mut gen);
+  let device_location = schema::<DeviceLocation>(&mut gen);
+  let device_token = schema::<DeviceToken>(&mut gen);
+  let import_summary = schema::<ImportSummary>(&mut gen);
+  let template_body = schema::<TemplateBody>(&mut gen);
+  let template = schema::<DeviceTemplate>(&mut gen);
+  let templates = schema::<Vec<DeviceTemplate>>(&mut gen);
+  let instantiate_body = schema::<InstantiateBody>(&mut gen);
+  let instantiated = schema::<Vec<InstantiatedDevice>>(&mut gen);
+  let id = schema::<i64>(&mut gen);
+  let geojson = schema::<GeoJson>(&mut gen);
+
+  let range_params = vec![
+    id_param("id"),
+    json!({ "name": "from", "in": "query", "required": false, "schema": { "type": "integer" },
+            "description": "earliest measuredate, in ms since the epoch" }),
+    json!({ "name": "to", "in": "query", "required": false, "schema": { "type": "integer" },
+            "description": "measuredates before this, in ms since the epoch" }),
+  ];
+  let bbox_param = json!({ "name": "bbox", "in": "query", "required": false,
+                           "schema": { "type": "string" },
+                           "description": "minlon,minlat,maxlon,maxlat; only positions inside this" });
+  let mut reading_params = range_params.clone();
+  reading_params.push(bbox_param.clone());
+  let mut rollup_params = range_params.clone();
+  rollup_params.push(json!({ "name": "rollup", "in": "path", "required": true,
+                             "schema": { "type": "string", "enum": ["hourly", "daily"] } }));
+
+  json!({
+    "openapi": "3.0.3",
+    "info": {
+      "title": config.appname,
+      "version": env!("CARGO_PKG_VERSION"),
+    },
+    "servers": [{ "url": config.mainsite }],
+    "paths": {
+      "/user": {
+        "post": {
+          "summary": "message interface for logged in users; see x-what-codes",
+          "requestBody": {
+            "required": true,
+            "content": { "application/json": { "schema": user_message } }
+          },
+          "responses": {
+            "200": {
+              "description": "reply, with 'what' and 'content' depending on the message 'what'",
+              "content": { "application/json": { "schema": server_response } }
+            },
+            "default": error_response(),
+          },
+          "x-what-codes": whats,
+        }
+      },
+      "/public": {
+        "post": {
+          "summary": "message interface not requiring login",
+          "requestBody": {
+            "required": true,
+            "content": { "application/json": { "schema": public_message } }
+          },
+          "responses": {
+            "200": {
+              "description": "reply",
+              "content": { "application/json": { "schema": server_response } }
+            },
+            "default": error_response(),
+          },
+          "x-what-codes": [],
+        }
+      },
+      "/api/devices": {
+        "get": rest_op("list devices", None, "200", devices.clone()),
+        "post": rest_op("create a device", device_body.clone(), "201", device.clone()),
+      },
+      "/api/devices/{id}": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a device", None, "200", device.clone()),
+        "put": rest_op("update a device", device_body, "200", device),
+        "delete": rest_op("delete a device", None, "204", None),
+      },
+      "/api/devices/geojson": {
+        "get": with_params(
+          rest_op("located devices as GeoJSON", None, "200", geojson.clone()),
+          vec![bbox_param],
+        ),
+      },
+      "/api/devices/{id}/location": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a device's location", None, "200", device_location.clone()),
+        "put": rest_op("set or, with no location, clear a device's location",
+                       location_body, "200", device_location),
+      },
+      "/api/devices/{id}/token": {
+        "parameters": [id_param("id")],
+        "post": rest_op("make a udp ingest token, replacing the device's old one",
+                        None, "201", device_token),
+        "delete": rest_op("delete a device's udp ingest token", None, "204", None),
+      },
+      "/api/devices/{id}/sensors": {
+        "parameters": [id_param("id")],
+        "get": rest_op("list a device's sensors", None, "200", sensors.clone()),
+        "post": rest_op("add a sensor to a device", sensor_body.clone(), "201", sensor.clone()),
+      },
+      "/api/templates": {
+        "get": rest_op("list device templates", None, "200", templates),
+        "post": rest_op("create a device template", template_body.clone(), "201", template.clone()),
+      },
+      "/api/templates/{id}": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a device template", None, "200", template.clone()),
+        "put": rest_op("replace a device template", template_body, "200", template),
+        "delete": rest_op("delete a device template", None, "204", None),
+      },
+      "/api/templates/{id}/instantiate": {
+        "parameters": [id_param("id")],
+        "post": rest_op("make devices and their sensors from a template; with 'count', \
+                         devices are named \"<name> <number>\"",
+                        instantiate_body, "201", instantiated),
+      },
+      "/api/sensors": {
+        "get": rest_op("list sensors", None, "200", sensors),
+      },
+      "/api/sensors/{id}": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a sensor", None, "200", sensor.clone()),
+        "put": rest_op("update a sensor", sensor_body, "200", sensor),
+        "delete": rest_op("delete a sensor", None, "204", None),
+      },
+      "/api/sensors/{id}/measurements": {
+        "get": with_params(
+          rest_op("list a sensor's measurements", None, "200", measurements),
+          range_params.clone(),
+        ),
+        "post": with_params(
+          rest_op("add a measurement", measurement_body, "201", id.clone()),
+          vec![id_param("id")],
+        ),
+      },
+      "/api/export": {
+        "get": rest_op("export all your devices, sensors and measurements", None, "200", archive.clone()),
+      },
+      "/api/import": {
+        "post": rest_op("import an archive from /api/export, with new ids", archive, "201", import_summary),
+      },
+      "/api/sensors/{id}/calibration": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read the calibration in force now", None, "200", sensor_calibration.clone()),
+        "put": rest_op("set a sensor's calibration from 'effective' (default now) on or, with no \
+                        calibration, leave its measurements raw from then on",
+                       calibration_body, "200", sensor_calibration),
+      },
+      "/api/sensors/{id}/calibrations": {
+        "parameters": [id_param("id")],
+        "get": rest_op("list a sensor's calibration history, in effective order",
+                       None, "200", calibration_history),
+      },
+      "/api/sensors/{id}/calibrations/audit": {
+        "parameters": [id_param("id")],
+        "get": rest_op("list the changes to a sensor's calibration history: who set, deleted \
+                        or imported which calibration, and when",
+                       None, "200", calibration_audit),
+      },
+      "/api/calibrations/{id}": {
+        "parameters": [id_param("id")],
+        "delete": rest_op("delete an entry from a calibration history", None, "204", None),
+      },
+      "/api/sensors/{id}/virtual": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a sensor's formula, if it's virtual", None, "200", virtual_sensor.clone()),
+        "put": rest_op("make a sensor virtual or, with no formula, a regular sensor again",
+                       formula_body, "200", virtual_sensor),
+      },
+      "/api/sensors/{id}/type": {
+        "parameters": [id_param("id")],
+        "get": rest_op("read a sensor's value kind", None, "200", sensor_type.clone()),
+        "put": rest_op("set a sensor's value kind", sensor_type_body, "200", sensor_type),
+      },
+      "/api/sensors/{id}/readings": {
+        "get": with_params(
+          rest_op("list a sensor's typed readings", None, "200", readings),
+          reading_params.clone(),
+        ),
+        "post": with_params(
+          rest_op("add a typed reading", reading_body, "201", id),
+          vec![id_param("id")],
+        ),
+      },
+      "/api/sensors/{id}/readings/geojson": {
+        "get": with_params(
+          rest_op("a sensor's positioned readings as GeoJSON", None, "200", geojson),
+          reading_params,
+        ),
+      },
+      "/api/sensors/{id}/rollups/{rollup}": {
+        "get": with_params(
+          rest_op("list a sensor's hourly or daily rollups", None, "200", rollups),
+          rollup_params,
+        ),
+      },
+    },
+    "components": {
+      "schemas": gen.definitions(),
+      "securitySchemes": {
+        "basic": { "type": "http", "scheme": "basic" }
+      }
+    }
+  })
+}
+
+pub fn openapi_json(state: web::Data<Config>) -> HttpResponse {
+  HttpResponse::Ok().json(openapi(&state))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use interfaces::WHATS;
+  use sciota_protocol::protocol;
+  use serde::Serialize;
+  use serde_json::value::Serializer;
+  use std::collections::BTreeSet;
+  use std::path::Path;
+  use storage_tests;
+
+  type Remote<T> = fn(&T, Serializer) -> Result<Value, serde_json::Error>;
+
+  // the description serializes 'v' as the protocol crate does.
+  fn same_wire<T: Serialize>(v: T, remote: Remote<T>) {
+    assert_eq!(
+      remote(&v, Serializer).unwrap(),
+      serde_json::to_value(&v).unwrap()
+    );
+  }
+
+  #[test]
+  fn protocol_descriptions() {
+    let data = Some(json!({ "a": 1 }));
+    same_wire(
+      protocol::UserMessage {
+        uid: "u".to_string(),
+        pwd: "p".to_string(),
+        what: "w".to_string(),
+        data: data.clone(),
+      },
+      UserMessage::serialize,
+    );
+    same_wire(
+      protocol::PublicMessage {
+        what: "w".to_string(),
+        data: data.clone(),
+      },
+      PublicMessage::serialize,
+    );
+    same_wire(
+      protocol::ServerResponse {
+        what: "w".to_string(),
+        content: json!([1, 2]),
+      },
+      ServerResponse::serialize,
+    );
+    same_wire(
+      protocol::RegistrationData {
+        email: "e".to_string(),
+      },
+      RegistrationData::serialize,
+    );
+    same_wire(
+      protocol::Device {
+        id: 1,
+        user: 2,
+        name: "n".to_string(),
+        description: "d".to_string(),
+        createdate: 3,
+        changeddate: 4,
+      },
+      Device::serialize,
+    );
+    same_wire(
+      protocol::SaveDevice {
+        id: Some(1),
+        name: "n".to_string(),
+        description: "d".to_string(),
+      },
+      SaveDevice::serialize,
+    );
+    same_wire(
+      protocol::Sensor {
+        id: 1,
+        device: 2,
+        name: "n".to_string(),
+        description: "d".to_string(),
+        createdate: 3,
+        changeddate: 4,
+      },
+      Sensor::serialize,
+    );
+    same_wire(
+      protocol::SaveSensor {
+        id: None,
+        device: 2,
+        name: "n".to_string(),
+        description: "d".to_string(),
+      },
+      SaveSensor::serialize,
+    );
+    same_wire(
+      protocol::Measurement {
+        id: 1,
+        sensor: 2,
+        value: 2.5,
+        createdate: 3,
+        measuredate: 4,
+      },
+      Measurement::serialize,
+    );
+    same_wire(
+      protocol::SaveMeasurement {
+        value: 2.5,
+        sensor: 2,
+        measuredate: 4,
+      },
+      SaveMeasurement::serialize,
+    );
+    same_wire(
+      protocol::MeasurementQuery {
+        sensor: 2,
+        enddate: Some(5),
+        length_of_time: Some(6),
+      },
+      MeasurementQuery::serialize,
+    );
+  }
+
+  #[test]
+  fn every_what_is_described() {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let whats = user_whats(&mut gen);
+    let described: BTreeSet<&str> = whats.iter().map(|w| w.what).collect();
+    assert_eq!(described.len(), whats.len(), "a what is described twice");
+    assert_eq!(described, WHATS.iter().cloned().collect::<BTreeSet<&str>>());
+
+    let spec = openapi(&storage_tests::config(Path::new("unused.db")));
+    assert_eq!(
+      spec["paths"]["/user"]["post"]["x-what-codes"]
+        .as_array()
+        .map(|w| w.len()),
+      Some(WHATS.len())
+    );
+    for name in ["UserMessage", "Device", "MeasurementQuery"].iter() {
+      assert!(
+        spec["components"]["schemas"][*name].is_object(),
+        "no {} schema",
+        name
+      );
+    }
+  }
+}
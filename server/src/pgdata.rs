@@ -0,0 +1,2509 @@
+use archive;
+use archive::{
+  check_version, Archive, ArchiveCalibration, ArchiveDevice, ArchiveMeasurement, ArchiveRollup,
+  ArchiveSensor, ImportSummary, ARCHIVE_VERSION,
+};
+use barrel::backend::Pg;
+use barrel::types;
+use barrel::types::Type;
+use calibration;
+use calibration::{
+  Calibration, CalibrationAudit, CalibrationRecord, Formula, SensorCalibration, VirtualSensor,
+};
+use error::Error;
+use geo;
+use geo::{BBox, DeviceLocation, LocatedDevice, Location};
+use influx;
+use influx::NamedMeasurement;
+use postgres::rows::{Row, Rows};
+use postgres::GenericConnection;
+use r2d2::Pool;
+use r2d2_postgres::{PostgresConnectionManager, TlsMode};
+use readings;
+use readings::{Reading, ReadingQuery, SaveReading, SensorType, ValueKind};
+use retention::{Rollup, RollupEntry, RollupQuery, SensorRetention};
+use sciota_protocol::protocol::{
+  Device, Measurement, SaveDevice, SaveMeasurement, SaveSensor, Sensor,
+};
+use serde_json;
+use sqldata;
+use sqldata::{now, Columns, User, UserListEntry};
+use std::collections::HashMap;
+use templates;
+use templates::{
+  DeviceTemplate, Instantiate, InstantiatedDevice, InstantiatedSensor, SaveTemplate, TemplateSensor,
+};
+use udp::TokenDevice;
+
+// postgres (or timescaledb) versions of the sqldata functions.
+
+pub type PgPool = Pool<PostgresConnectionManager>;
+
+pub fn connection_pool(url: &str, pool_size: u32) -> Result<PgPool, Error> {
+  let manager = PostgresConnectionManager::new(url, TlsMode::None)?;
+  Ok(Pool::builder().max_size(pool_size).build(manager)?)
+}
+
+// see sqldata::UPDATE4.
+const UPDATE4: &str = "
+  CREATE INDEX measurement_sensor_measuredate ON measurement (sensor, measuredate);
+  CREATE INDEX sensor_device ON sensor (device);
+  CREATE INDEX device_user ON device (\"user\");";
+
+// postgres can add the columns in one ALTER TABLE; see sqldata::update6.
+const UPDATE6_POSITIONS: &str = "
+  ALTER TABLE measurement
+    ADD COLUMN lat DOUBLE PRECISION,
+    ADD COLUMN lon DOUBLE PRECISION,
+    ADD COLUMN elevation DOUBLE PRECISION;";
+
+// see sqldata::UPDATE9.
+const UPDATE9: &str = "
+  CREATE TABLE calibrationrecord (
+    id BIGSERIAL PRIMARY KEY,
+    sensor BIGINT NOT NULL REFERENCES sensor(id),
+    effective BIGINT NOT NULL,
+    transform TEXT,
+    createdate BIGINT NOT NULL,
+    \"user\" BIGINT NOT NULL REFERENCES \"user\"(id),
+    UNIQUE (sensor, effective)
+  );
+
+  CREATE TABLE calibrationaudit (
+    id BIGSERIAL PRIMARY KEY,
+    sensor BIGINT NOT NULL REFERENCES sensor(id),
+    \"user\" BIGINT NOT NULL REFERENCES \"user\"(id),
+    action TEXT NOT NULL,
+    effective BIGINT NOT NULL,
+    transform TEXT,
+    changedate BIGINT NOT NULL
+  );
+  CREATE INDEX calibrationaudit_sensor ON calibrationaudit (sensor);
+
+  INSERT INTO calibrationrecord (sensor, effective, transform, createdate, \"user\")
+    SELECT calibration.sensor, -9223372036854775808, calibration.transform,
+      (extract(epoch FROM now()) * 1000)::BIGINT, device.\"user\"
+    FROM calibration, sensor, device
+    WHERE calibration.sensor = sensor.id AND sensor.device = device.id;
+
+  DROP TABLE calibration;";
+
+// see sqldata::UPDATE10.
+const UPDATE10: &str = "
+  CREATE TABLE devicetoken (
+    device BIGINT NOT NULL UNIQUE REFERENCES device(id),
+    tokenhash TEXT NOT NULL UNIQUE,
+    createdate BIGINT NOT NULL
+  );";
+
+// see sqldata::UPDATE11.
+const UPDATE11: &str = "
+  ALTER TABLE devicetoken ADD COLUMN nextidx BIGINT NOT NULL DEFAULT 0;
+
+  CREATE TABLE tokensensor (
+    device BIGINT NOT NULL REFERENCES device(id),
+    idx BIGINT NOT NULL,
+    sensor BIGINT NOT NULL UNIQUE REFERENCES sensor(id),
+    UNIQUE (device, idx)
+  );
+
+  INSERT INTO tokensensor (device, idx, sensor)
+    SELECT sensor.device,
+      (SELECT count(*) FROM sensor s WHERE s.device = sensor.device AND s.id < sensor.id),
+      sensor.id
+    FROM sensor WHERE sensor.device IN (SELECT device FROM devicetoken);
+
+  UPDATE devicetoken
+    SET nextidx = (SELECT count(*) FROM sensor WHERE sensor.device = devicetoken.device);";
+
+// the barrel migrations' column types on postgres; see sqldata::Columns.
+struct PgColumns;
+
+impl Columns for PgColumns {
+  fn id() -> Type {
+    types::custom("BIGSERIAL PRIMARY KEY")
+  }
+  fn int() -> Type {
+    types::custom("BIGINT")
+  }
+  fn float() -> Type {
+    types::custom("DOUBLE PRECISION")
+  }
+  fn reference(table: &'static str) -> Type {
+    types::custom(match table {
+      "user" => "BIGINT REFERENCES \"user\"(id)",
+      "device" => "BIGINT REFERENCES device(id)",
+      "sensor" => "BIGINT REFERENCES sensor(id)",
+      "devicetemplate" => "BIGINT REFERENCES devicetemplate(id)",
+      t => unreachable!("no reference column for {}", t),
+    })
+  }
+}
+
+// the sql that brings a database to each migration level, from the one
+// before: level n is migrations()[n - 1].  The barrel migrations are
+// sqldata's; the raw sql is postgres' version of sqldata's.
+fn migrations() -> Vec<Vec<String>> {
+  vec![
+    vec![
+      sqldata::initialdb::<PgColumns>().make::<Pg>(),
+      sqldata::update1::<PgColumns>().make::<Pg>(),
+    ],
+    vec![sqldata::update2::<PgColumns>().make::<Pg>()],
+    vec![
+      sqldata::update3::<PgColumns>().make::<Pg>(),
+      sqldata::UPDATE3_INDEXES.to_string(),
+    ],
+    vec![UPDATE4.to_string()],
+    vec![sqldata::update5::<PgColumns>().make::<Pg>()],
+    vec![
+      sqldata::update6::<PgColumns>().make::<Pg>(),
+      UPDATE6_POSITIONS.to_string(),
+    ],
+    vec![sqldata::update7::<PgColumns>().make::<Pg>()],
+    vec![sqldata::update8::<PgColumns>().make::<Pg>()],
+    vec![UPDATE9.to_string()],
+    vec![UPDATE10.to_string()],
+    vec![UPDATE11.to_string()],
+  ]
+}
+
+fn current_level<C: GenericConnection>(conn: &C) -> Result<i64, Error> {
+  let exists = conn.query(
+    "SELECT 1 FROM information_schema.tables WHERE table_name = 'singlevalue'",
+    &[],
+  )?;
+  if exists.is_empty() {
+    return Ok(0);
+  }
+
+  let rows = conn.query(
+    "SELECT value FROM singlevalue WHERE name = 'migration_level'",
+    &[],
+  )?;
+  match rows.iter().next() {
+    Some(row) => Ok(row.get::<_, String>(0).parse()?),
+    None => Ok(0),
+  }
+}
+
+// migrate one level at a time.  Each level's changes and its migration_level
+// are committed together, so a failed migration leaves the database at the
+// level before, ready to try again.
+pub fn dbinit(pool: &PgPool) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let level = current_level(&*conn)?;
+
+  for (i, step) in migrations().iter().enumerate() {
+    let to = i as i64 + 1;
+    if level < to {
+      info!("update{}", to);
+      let tx = conn.transaction()?;
+      for sql in step.iter() {
+        tx.batch_execute(sql.as_str())?;
+      }
+      tx.execute(
+        "INSERT INTO singlevalue (name, value) VALUES ('migration_level', $1)
+         ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+        &[&to.to_string()],
+      )?;
+      tx.commit()?;
+    }
+  }
+
+  info!("db up to date.");
+
+  Ok(())
+}
+
+pub fn migration_level(pool: &PgPool) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  current_level(&*conn)
+}
+
+// the tables, by the migration level that adds them.
+const LEVEL_TABLES: [(i64, &str); 19] = [
+  (1, "user"),
+  (1, "device"),
+  (1, "sensor"),
+  (1, "measurement"),
+  (1, "singlevalue"),
+  (3, "retention"),
+  (3, "rollup"),
+  (3, "measurement_hourly"),
+  (3, "measurement_daily"),
+  (5, "sensortype"),
+  (6, "devicelocation"),
+  (7, "devicetemplate"),
+  (7, "templatesensor"),
+  (8, "virtualsensor"),
+  (8, "virtualinput"),
+  (9, "calibrationrecord"),
+  (9, "calibrationaudit"),
+  (10, "devicetoken"),
+  (11, "tokensensor"),
+];
+
+// references to other tables' ids besides the sqldata::SENSOR_TABLES' sensor
+// columns: table, column, referenced table.
+const REFERENCES: [(&str, &str, &str); 10] = [
+  ("device", "user", "user"),
+  ("sensor", "device", "device"),
+  ("devicelocation", "device", "device"),
+  ("devicetoken", "device", "device"),
+  ("tokensensor", "device", "device"),
+  ("devicetemplate", "user", "user"),
+  ("templatesensor", "template", "devicetemplate"),
+  ("calibrationrecord", "user", "user"),
+  ("calibrationaudit", "user", "user"),
+  ("virtualinput", "input", "sensor"),
+];
+
+// postgres enforces the foreign keys, but they can be missing or skipped, say
+// in a hand restored dump, and the migration_level can be wrong.  So this
+// checks that the tables are the ones the level says, then looks for rows
+// that refer to missing ones.
+pub fn integrity_check(pool: &PgPool) -> Result<Vec<String>, Error> {
+  let conn = pool.get()?;
+
+  let mut problems = Vec::new();
+
+  let level = match current_level(&*conn) {
+    Ok(level) => level,
+    Err(e) => {
+      problems.push(format!("unreadable migration_level: {}", e));
+      return Ok(problems);
+    }
+  };
+
+  let mut tables = Vec::new();
+  for (added, table) in LEVEL_TABLES.iter() {
+    let exists = !conn
+      .query(
+        "SELECT 1 FROM information_schema.tables
+          WHERE table_schema = current_schema() AND table_name = $1",
+        &[table],
+      )?
+      .is_empty();
+    if exists && level < *added {
+      problems.push(format!(
+        "table {} is from migration level {}, but the database is at {}",
+        table, added, level
+      ));
+    } else if !exists && level >= *added {
+      problems.push(format!(
+        "migration level {} should have a {} table",
+        level, table
+      ));
+    }
+    if exists {
+      tables.push(*table);
+    }
+  }
+
+  let references = sqldata::SENSOR_TABLES
+    .iter()
+    .map(|t| (*t, "sensor", "sensor"))
+    .chain(REFERENCES.iter().cloned());
+  for (table, column, parent) in references {
+    if !tables.contains(&table) || !tables.contains(&parent) {
+      continue;
+    }
+    let rows = conn.query(
+      format!(
+        "SELECT count(*) FROM \"{0}\" WHERE \"{1}\" NOT IN (SELECT id FROM \"{2}\")",
+        table, column, parent
+      )
+      .as_str(),
+      &[],
+    )?;
+    let count: i64 = rows.get(0).get(0);
+    if count > 0 {
+      problems.push(format!(
+        "{} {} rows refer to a missing {}",
+        count, table, parent
+      ));
+    }
+  }
+
+  Ok(problems)
+}
+
+fn not_found(what: &str, id: &dyn ToString) -> Error {
+  Error::NotFound(format!("{} not found: {}", what, id.to_string()))
+}
+
+// --------------------------------------------------------------------------------------
+// user CRUD
+
+pub fn read_user(pool: &PgPool, name: &str) -> Result<User, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id, hashwd, salt, email, registration_key, admin, disabled
+      FROM \"user\" WHERE name = $1",
+    &[&name],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok(User {
+      id: row.get(0),
+      name: name.to_string(),
+      hashwd: row.get(1),
+      salt: row.get(2),
+      email: row.get(3),
+      registration_key: row.get(4),
+      admin: row.get(5),
+      disabled: row.get(6),
+    }),
+    None => Err(Error::NotFound("record not found".to_string())),
+  }
+}
+
+pub fn update_user(pool: &PgPool, user: &User) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  conn.execute(
+    "UPDATE \"user\" SET name = $1, hashwd = $2, salt = $3, email = $4, registration_key = $5,
+       admin = $6, disabled = $7
+     WHERE id = $8",
+    &[
+      &user.name,
+      &user.hashwd,
+      &user.salt,
+      &user.email,
+      &user.registration_key,
+      &user.admin,
+      &user.disabled,
+      &user.id,
+    ],
+  )?;
+
+  Ok(())
+}
+
+pub fn new_user(
+  pool: &PgPool,
+  name: String,
+  hashwd: String,
+  salt: String,
+  email: String,
+  registration_key: String,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  let rows = conn.query(
+    "INSERT INTO \"user\" (name, hashwd, salt, email, registration_key, createdate)
+      VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+    &[&name, &hashwd, &salt, &email, &registration_key, &now],
+  )?;
+
+  Ok(rows.get(0).get(0))
+}
+
+pub fn new_registered_user(
+  pool: &PgPool,
+  name: String,
+  hashwd: String,
+  salt: String,
+  email: String,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  let rows = conn.query(
+    "INSERT INTO \"user\" (name, hashwd, salt, email, createdate)
+      VALUES ($1, $2, $3, $4, $5) RETURNING id",
+    &[&name, &hashwd, &salt, &email, &now],
+  )?;
+
+  Ok(rows.get(0).get(0))
+}
+
+// --------------------------------------------------------------------------------------
+// user admin
+
+pub fn user_listing(pool: &PgPool) -> Result<Vec<UserListEntry>, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT u.id, u.name, u.email, u.registration_key IS NULL,
+       u.admin, u.disabled, u.createdate,
+       (SELECT count(*) FROM device WHERE device.\"user\" = u.id),
+       (SELECT count(*) FROM measurement WHERE measurement.sensor IN
+         (SELECT sensor.id FROM sensor, device
+           WHERE sensor.device = device.id AND device.\"user\" = u.id))
+      FROM \"user\" u ORDER BY u.name",
+    &[],
+  )?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| UserListEntry {
+        id: row.get(0),
+        name: row.get(1),
+        email: row.get(2),
+        registered: row.get(3),
+        admin: row.get(4),
+        disabled: row.get(5),
+        createdate: row.get(6),
+        devices: row.get(7),
+        measurements: row.get(8),
+      })
+      .collect(),
+  )
+}
+
+pub fn set_admins(pool: &PgPool, admins: &Vec<String>) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  conn.execute("UPDATE \"user\" SET admin = name = ANY($1)", &[admins])?;
+
+  Ok(())
+}
+
+pub fn set_user_disabled(pool: &PgPool, name: &str, disabled: bool) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let count = conn.execute(
+    "UPDATE \"user\" SET disabled = $1 WHERE name = $2",
+    &[&disabled, &name],
+  )?;
+
+  if count == 0 {
+    return Err(not_found("user", &name));
+  }
+
+  Ok(())
+}
+
+pub fn confirm_user(pool: &PgPool, name: &str) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let count = conn.execute(
+    "UPDATE \"user\" SET registration_key = NULL WHERE name = $1",
+    &[&name],
+  )?;
+
+  if count == 0 {
+    return Err(not_found("user", &name));
+  }
+
+  Ok(())
+}
+
+pub fn delete_user(pool: &PgPool, name: &str) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  let rows = tx.query("SELECT id FROM \"user\" WHERE name = $1", &[&name])?;
+  let uid: i64 = match rows.iter().next() {
+    Some(row) => row.get(0),
+    None => return Err(not_found("user", &name)),
+  };
+
+  for table in sqldata::SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor IN
+          (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.\"user\" = $1)",
+        table
+      )
+      .as_str(),
+      &[&uid],
+    )?;
+  }
+  tx.execute(
+    "DELETE FROM sensor WHERE device IN (SELECT id FROM device WHERE \"user\" = $1)",
+    &[&uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicelocation WHERE device IN (SELECT id FROM device WHERE \"user\" = $1)",
+    &[&uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetoken WHERE device IN (SELECT id FROM device WHERE \"user\" = $1)",
+    &[&uid],
+  )?;
+  tx.execute("DELETE FROM device WHERE \"user\" = $1", &[&uid])?;
+  tx.execute(
+    "DELETE FROM templatesensor WHERE template IN
+      (SELECT id FROM devicetemplate WHERE \"user\" = $1)",
+    &[&uid],
+  )?;
+  tx.execute("DELETE FROM devicetemplate WHERE \"user\" = $1", &[&uid])?;
+  tx.execute("DELETE FROM \"user\" WHERE id = $1", &[&uid])?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+// --------------------------------------------------------------------------------------
+// ownership checks
+
+pub fn check_device_owner<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  device: i64,
+) -> Result<(), Error> {
+  let rows = conn.query(
+    "SELECT id FROM device WHERE id = $1 AND \"user\" = $2",
+    &[&device, &uid],
+  )?;
+  if rows.is_empty() {
+    Err(not_found("device", &device))
+  } else {
+    Ok(())
+  }
+}
+
+pub fn check_sensor_owner<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  sensor: i64,
+) -> Result<(), Error> {
+  let rows = conn.query(
+    "SELECT sensor.id FROM sensor, device
+      WHERE sensor.id = $1 AND sensor.device = device.id AND device.\"user\" = $2",
+    &[&sensor, &uid],
+  )?;
+  if rows.is_empty() {
+    Err(not_found("sensor", &sensor))
+  } else {
+    Ok(())
+  }
+}
+
+// --------------------------------------------------------------------------------------
+// device CRUD
+
+fn device_row(row: Row) -> Device {
+  Device {
+    id: row.get(0),
+    name: row.get(1),
+    description: row.get(2),
+    user: row.get(3),
+    createdate: row.get(4),
+    changeddate: row.get(5),
+  }
+}
+
+pub fn save_device(pool: &PgPool, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  match savedevice.id {
+    Some(id) => {
+      let count = conn.execute(
+        "UPDATE device SET name = $1, description = $2, changeddate = $3
+         WHERE id = $4 AND \"user\" = $5",
+        &[&savedevice.name, &savedevice.description, &now, &id, &uid],
+      )?;
+      if count == 0 {
+        return Err(not_found("device", &id));
+      }
+      Ok(id)
+    }
+    None => insert_device(&*conn, uid, &savedevice.name, &savedevice.description, now),
+  }
+}
+
+fn insert_device<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  name: &str,
+  description: &str,
+  now: i64,
+) -> Result<i64, Error> {
+  let rows = conn.query(
+    "INSERT INTO device (name, \"user\", description, createdate, changeddate)
+     VALUES ($1, $2, $3, $4, $5) RETURNING id",
+    &[&name, &uid, &description, &now, &now],
+  )?;
+  Ok(rows.get(0).get(0))
+}
+
+pub fn read_device(pool: &PgPool, uid: i64, id: i64) -> Result<Device, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id, name, description, \"user\", createdate, changeddate
+      FROM device WHERE id = $1 AND \"user\" = $2",
+    &[&id, &uid],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok(device_row(row)),
+    None => Err(not_found("device", &id)),
+  }
+}
+
+// delete a device along with its sensors and their measurements.
+pub fn delete_device(pool: &PgPool, uid: i64, id: i64) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  // virtual sensors on other devices would lose their inputs.
+  let rows = tx.query(
+    "SELECT count(DISTINCT virtualinput.sensor) FROM virtualinput, sensor, device
+      WHERE virtualinput.input = sensor.id AND sensor.device = device.id
+      AND device.id = $1 AND device.\"user\" = $2
+      AND virtualinput.sensor NOT IN (SELECT id FROM sensor WHERE device = $1)",
+    &[&id, &uid],
+  )?;
+  let users: i64 = rows.get(0).get(0);
+  if users > 0 {
+    return Err(Error::Conflict(format!(
+      "device {} has inputs to {} virtual sensor(s) on other devices",
+      id, users
+    )));
+  }
+
+  for table in sqldata::SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor IN
+          (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.id = $1 AND device.\"user\" = $2)",
+        table
+      )
+      .as_str(),
+      &[&id, &uid],
+    )?;
+  }
+  tx.execute(
+    "DELETE FROM sensor WHERE device = $1
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&id, &uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicelocation WHERE device = $1
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&id, &uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetoken WHERE device = $1
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&id, &uid],
+  )?;
+  tx.execute(
+    "DELETE FROM device WHERE id = $1 AND \"user\" = $2",
+    &[&id, &uid],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn devicelisting(pool: &PgPool, user: i64) -> Result<Vec<Device>, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id, name, description, \"user\", createdate, changeddate
+      FROM device WHERE \"user\" = $1",
+    &[&user],
+  )?;
+
+  Ok(rows.iter().map(device_row).collect())
+}
+
+// --------------------------------------------------------------------------------------
+// sensor CRUD
+
+fn sensor_row(row: Row) -> Sensor {
+  Sensor {
+    id: row.get(0),
+    device: row.get(1),
+    name: row.get(2),
+    description: row.get(3),
+    createdate: row.get(4),
+    changeddate: row.get(5),
+  }
+}
+
+pub fn save_sensor(pool: &PgPool, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  check_device_owner(&*conn, uid, sensor.device)?;
+
+  let rows = match sensor.id {
+    Some(id) => {
+      let rows = conn.query(
+        "UPDATE sensor SET name = $1, description = $2, changeddate = $3
+         WHERE id = $4 AND device = $5
+         RETURNING id, device, name, description, createdate, changeddate",
+        &[&sensor.name, &sensor.description, &now, &id, &sensor.device],
+      )?;
+      if rows.is_empty() {
+        return Err(not_found("sensor", &id));
+      }
+      rows
+    }
+    None => {
+      let tx = conn.transaction()?;
+      let rows = insert_sensor(&tx, sensor.device, &sensor.name, &sensor.description, now)?;
+      tx.commit()?;
+      rows
+    }
+  };
+
+  Ok(sensor_row(rows.get(0)))
+}
+
+// a new sensor, with its udp index.  The caller checks the device's owner.
+fn insert_sensor<C: GenericConnection>(
+  conn: &C,
+  device: i64,
+  name: &str,
+  description: &str,
+  now: i64,
+) -> Result<Rows, Error> {
+  let rows = conn.query(
+    "INSERT INTO sensor (name, device, description, createdate, changeddate)
+     VALUES ($1, $2, $3, $4, $5)
+     RETURNING id, device, name, description, createdate, changeddate",
+    &[&name, &device, &description, &now, &now],
+  )?;
+  add_token_sensor(conn, device, rows.get(0).get(0))?;
+  Ok(rows)
+}
+
+pub fn read_sensor(pool: &PgPool, uid: i64, id: i64) -> Result<Sensor, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id, device, name, description, createdate, changeddate
+      FROM sensor WHERE id = $1
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&id, &uid],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok(sensor_row(row)),
+    None => Err(not_found("sensor", &id)),
+  }
+}
+
+pub fn delete_sensor(pool: &PgPool, uid: i64, sensorid: i64) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  check_sensor_owner(&tx, uid, sensorid)?;
+
+  let rows = tx.query(
+    "SELECT count(*) FROM virtualinput WHERE input = $1",
+    &[&sensorid],
+  )?;
+  let users: i64 = rows.get(0).get(0);
+  if users > 0 {
+    return Err(Error::Conflict(format!(
+      "sensor {} is an input to {} virtual sensor(s)",
+      sensorid, users
+    )));
+  }
+
+  for table in sqldata::SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor = $1
+          AND sensor IN (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.\"user\" = $2)",
+        table
+      )
+      .as_str(),
+      &[&sensorid, &uid],
+    )?;
+  }
+  tx.execute(
+    "DELETE FROM sensor WHERE id = $1
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&sensorid, &uid],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn sensorlisting(pool: &PgPool, user: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id, device, name, description, createdate, changeddate
+      FROM sensor WHERE ($1::BIGINT IS NULL OR device = $1)
+      AND device IN (SELECT id FROM device WHERE \"user\" = $2)",
+    &[&device, &user],
+  )?;
+
+  Ok(rows.iter().map(sensor_row).collect())
+}
+
+// --------------------------------------------------------------------------------------
+// measurement CRUD
+
+pub fn add_measurement(
+  pool: &PgPool,
+  uid: i64,
+  measurement: &SaveMeasurement,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  check_sensor_owner(&*conn, uid, measurement.sensor)?;
+  check_not_virtual(&*conn, measurement.sensor)?;
+  readings::check_measurement(
+    sensor_kind(&*conn, measurement.sensor)?.0,
+    measurement.value,
+  )?;
+
+  let rows = conn.query(
+    "INSERT INTO measurement (sensor, value, measuredate, createdate)
+     VALUES ($1, $2, $3, $4) RETURNING id",
+    &[
+      &measurement.sensor,
+      &measurement.value,
+      &measurement.measuredate,
+      &now,
+    ],
+  )?;
+
+  Ok(rows.get(0).get(0))
+}
+
+// see sqldata::add_measurements.
+pub fn add_measurements(
+  pool: &PgPool,
+  uid: i64,
+  measurements: &[SaveMeasurement],
+) -> Result<Vec<i64>, Error> {
+  readings::check_batch(measurements.len())?;
+
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+  let mut kinds = HashMap::new();
+  let mut ids = Vec::with_capacity(measurements.len());
+  {
+    let insert = tx.prepare(
+      "INSERT INTO measurement (sensor, value, measuredate, createdate)
+       VALUES ($1, $2, $3, $4) RETURNING id",
+    )?;
+    for m in measurements.iter() {
+      check_measured(&tx, uid, m.sensor, m.value, &mut kinds)?;
+      let rows = insert.query(&[&m.sensor, &m.value, &m.measuredate, &now])?;
+      ids.push(rows.get(0).get(0));
+    }
+  }
+  tx.commit()?;
+
+  Ok(ids)
+}
+
+// see sqldata::check_measured.
+fn check_measured<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  sensor: i64,
+  value: f64,
+  kinds: &mut HashMap<i64, ValueKind>,
+) -> Result<(), Error> {
+  let kind = match kinds.get(&sensor) {
+    Some(k) => *k,
+    None => {
+      check_sensor_owner(conn, uid, sensor)?;
+      check_not_virtual(conn, sensor)?;
+      let (k, _) = sensor_kind(conn, sensor)?;
+      kinds.insert(sensor, k);
+      k
+    }
+  };
+  readings::check_measurement(kind, value)
+}
+
+// see sqldata::add_named_measurements.
+pub fn add_named_measurements(
+  pool: &PgPool,
+  uid: i64,
+  measurements: &[NamedMeasurement],
+  autocreate: bool,
+) -> Result<usize, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let mut devices = HashMap::new();
+  let mut sensors = HashMap::new();
+  let rows = tx.query(
+    "SELECT device.name, device.id, sensor.name, sensor.id
+      FROM device LEFT JOIN sensor ON sensor.device = device.id
+      WHERE device.\"user\" = $1",
+    &[&uid],
+  )?;
+  for row in rows.iter() {
+    let dname: String = row.get(0);
+    let sname: Option<String> = row.get(2);
+    let sid: Option<i64> = row.get(3);
+    if let (Some(sname), Some(sid)) = (sname, sid) {
+      sensors.insert((dname.clone(), sname), sid);
+    }
+    devices.insert(dname, row.get(1));
+  }
+
+  influx::check_names(measurements, &devices, &sensors, autocreate)?;
+
+  let mut kinds = HashMap::new();
+  {
+    let insert = tx.prepare(
+      "INSERT INTO measurement (sensor, value, measuredate, createdate)
+       VALUES ($1, $2, $3, $4)",
+    )?;
+    for m in measurements.iter() {
+      let device = match devices.get(&m.device) {
+        Some(id) => *id,
+        None => {
+          let id = insert_device(&tx, uid, &m.device, influx::AUTOCREATED, now)?;
+          devices.insert(m.device.clone(), id);
+          id
+        }
+      };
+      let key = (m.device.clone(), m.sensor.clone());
+      let sensor = match sensors.get(&key) {
+        Some(id) => *id,
+        None => {
+          let rows = insert_sensor(&tx, device, &m.sensor, influx::AUTOCREATED, now)?;
+          let id = rows.get(0).get(0);
+          sensors.insert(key, id);
+          id
+        }
+      };
+      check_measured(&tx, uid, sensor, m.value, &mut kinds)?;
+      insert.execute(&[&sensor, &m.value, &m.measuredate, &now])?;
+    }
+  }
+  tx.commit()?;
+
+  Ok(measurements.len())
+}
+
+pub fn measurement_listing(
+  pool: &PgPool,
+  uid: i64,
+  sensor: i64,
+  from: Option<i64>,
+  to: Option<i64>,
+) -> Result<Vec<Measurement>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let (kind, _) = sensor_kind(&*conn, sensor)?;
+  if !kind.is_numeric() {
+    return Err(Error::BadRequest(format!(
+      "sensor {} has {} readings; use getreadinglisting",
+      sensor,
+      kind.as_str()
+    )));
+  }
+
+  match sensor_formula(&*conn, sensor)? {
+    Some(formula) => {
+      let mut series = Vec::new();
+      for input in formula.inputs.values() {
+        series.push(calibrated_measurements(&*conn, *input, from, to)?);
+      }
+      calibration::evaluate(sensor, &formula, series)
+    }
+    None => calibrated_measurements(&*conn, sensor, from, to),
+  }
+}
+
+fn calibrated_measurements<C: GenericConnection>(
+  conn: &C,
+  sensor: i64,
+  from: Option<i64>,
+  to: Option<i64>,
+) -> Result<Vec<Measurement>, Error> {
+  let rows = conn.query(
+    "SELECT id, value, measuredate, createdate
+      FROM measurement WHERE sensor = $1
+      AND measuredate >= $2
+      AND measuredate < $3
+      ORDER BY measuredate",
+    &[
+      &sensor,
+      &from.unwrap_or(i64::min_value()),
+      &to.unwrap_or(i64::max_value()),
+    ],
+  )?;
+
+  let mut pv: Vec<Measurement> = rows
+    .iter()
+    .map(|row| Measurement {
+      id: row.get(0),
+      value: row.get(1),
+      sensor: sensor,
+      measuredate: row.get(2),
+      createdate: row.get(3),
+    })
+    .collect();
+
+  calibration::calibrate(&sensor_calibrations(conn, sensor)?, &mut pv);
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// calibration and virtual sensors
+
+// a sensor's calibration history, in effective order.
+pub fn sensor_calibrations<C: GenericConnection>(
+  conn: &C,
+  sensor: i64,
+) -> Result<calibration::History, Error> {
+  let rows = conn.query(
+    "SELECT effective, transform FROM calibrationrecord WHERE sensor = $1
+      ORDER BY effective",
+    &[&sensor],
+  )?;
+
+  let mut history = Vec::new();
+  for row in rows.iter() {
+    history.push((row.get(0), parse_transform(row.get(1))?));
+  }
+  Ok(history)
+}
+
+fn parse_transform(transform: Option<String>) -> Result<Option<Calibration>, Error> {
+  match transform {
+    Some(t) => Ok(Some(serde_json::from_str(t.as_str())?)),
+    None => Ok(None),
+  }
+}
+
+fn transform_string(calibration: &Option<Calibration>) -> Result<Option<String>, Error> {
+  match calibration {
+    Some(c) => Ok(Some(serde_json::to_string(c)?)),
+    None => Ok(None),
+  }
+}
+
+fn add_calibration_audit<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  sensor: i64,
+  action: &str,
+  effective: i64,
+  transform: &Option<String>,
+  now: i64,
+) -> Result<(), Error> {
+  conn.execute(
+    "INSERT INTO calibrationaudit (sensor, \"user\", action, effective, transform, changedate)
+      VALUES ($1, $2, $3, $4, $5, $6)",
+    &[&sensor, &uid, &action, &effective, transform, &now],
+  )?;
+  Ok(())
+}
+
+pub fn sensor_formula<C: GenericConnection>(
+  conn: &C,
+  sensor: i64,
+) -> Result<Option<Formula>, Error> {
+  let rows = conn.query(
+    "SELECT expression FROM virtualsensor WHERE sensor = $1",
+    &[&sensor],
+  )?;
+  let expression: String = match rows.iter().next() {
+    Some(row) => row.get(0),
+    None => return Ok(None),
+  };
+
+  let inputs = conn
+    .query(
+      "SELECT name, input FROM virtualinput WHERE sensor = $1",
+      &[&sensor],
+    )?
+    .iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect();
+
+  Ok(Some(Formula {
+    expression: expression,
+    inputs: inputs,
+  }))
+}
+
+fn check_not_virtual<C: GenericConnection>(conn: &C, sensor: i64) -> Result<(), Error> {
+  match sensor_formula(conn, sensor)? {
+    Some(_) => Err(Error::BadRequest(format!(
+      "sensor {} is virtual; its measurements are computed",
+      sensor
+    ))),
+    None => Ok(()),
+  }
+}
+
+pub fn get_calibration(pool: &PgPool, uid: i64, sensor: i64) -> Result<SensorCalibration, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let rows = conn.query(
+    "SELECT effective, transform FROM calibrationrecord
+      WHERE sensor = $1 AND effective <= $2
+      ORDER BY effective DESC LIMIT 1",
+    &[&sensor, &now()?],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok(SensorCalibration {
+      sensor: sensor,
+      effective: Some(row.get(0)),
+      calibration: parse_transform(row.get(1))?,
+    }),
+    None => Ok(SensorCalibration {
+      sensor: sensor,
+      effective: None,
+      calibration: None,
+    }),
+  }
+}
+
+pub fn set_calibration(pool: &PgPool, uid: i64, sc: &SensorCalibration) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sc.sensor)?;
+
+  match &sc.calibration {
+    Some(c) => {
+      c.check()?;
+      let (kind, _) = sensor_kind(&*conn, sc.sensor)?;
+      if !kind.is_numeric() {
+        return Err(Error::BadRequest(format!(
+          "can't calibrate {} readings",
+          kind.as_str()
+        )));
+      }
+    }
+    None => (),
+  }
+
+  let now = now()?;
+  let effective = sc.effective.unwrap_or(now);
+  let transform = transform_string(&sc.calibration)?;
+
+  let tx = conn.transaction()?;
+  tx.execute(
+    "INSERT INTO calibrationrecord (sensor, effective, transform, createdate, \"user\")
+      VALUES ($1, $2, $3, $4, $5)
+     ON CONFLICT (sensor, effective) DO UPDATE SET
+      transform = excluded.transform,
+      createdate = excluded.createdate,
+      \"user\" = excluded.\"user\"",
+    &[&sc.sensor, &effective, &transform, &now, &uid],
+  )?;
+  add_calibration_audit(
+    &tx,
+    uid,
+    sc.sensor,
+    calibration::AUDIT_SET,
+    effective,
+    &transform,
+    now,
+  )?;
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn calibration_history(
+  pool: &PgPool,
+  uid: i64,
+  sensor: i64,
+) -> Result<Vec<CalibrationRecord>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let rows = conn.query(
+    "SELECT calibrationrecord.id, effective, transform, createdate, \"user\".name
+      FROM calibrationrecord, \"user\"
+      WHERE calibrationrecord.\"user\" = \"user\".id AND sensor = $1
+      ORDER BY effective",
+    &[&sensor],
+  )?;
+
+  let mut pv = Vec::new();
+  for row in rows.iter() {
+    pv.push(CalibrationRecord {
+      id: row.get(0),
+      sensor: sensor,
+      effective: row.get(1),
+      calibration: parse_transform(row.get(2))?,
+      createdate: row.get(3),
+      user: row.get(4),
+    });
+  }
+  Ok(pv)
+}
+
+// see sqldata::delete_calibration.
+pub fn delete_calibration(pool: &PgPool, uid: i64, id: i64) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT sensor, effective, transform FROM calibrationrecord WHERE id = $1",
+    &[&id],
+  )?;
+  let (sensor, effective, transform): (i64, i64, Option<String>) = match rows.iter().next() {
+    Some(row) => (row.get(0), row.get(1), row.get(2)),
+    None => return Err(not_found("calibration", &id)),
+  };
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let tx = conn.transaction()?;
+  tx.execute("DELETE FROM calibrationrecord WHERE id = $1", &[&id])?;
+  add_calibration_audit(
+    &tx,
+    uid,
+    sensor,
+    calibration::AUDIT_DELETE,
+    effective,
+    &transform,
+    now()?,
+  )?;
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn calibration_audit(
+  pool: &PgPool,
+  uid: i64,
+  sensor: i64,
+) -> Result<Vec<CalibrationAudit>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let rows = conn.query(
+    "SELECT \"user\".name, action, effective, transform, changedate
+      FROM calibrationaudit, \"user\"
+      WHERE calibrationaudit.\"user\" = \"user\".id AND sensor = $1
+      ORDER BY changedate, calibrationaudit.id",
+    &[&sensor],
+  )?;
+
+  let mut pv = Vec::new();
+  for row in rows.iter() {
+    pv.push(CalibrationAudit {
+      sensor: sensor,
+      user: row.get(0),
+      action: row.get(1),
+      effective: row.get(2),
+      calibration: parse_transform(row.get(3))?,
+      changedate: row.get(4),
+    });
+  }
+  Ok(pv)
+}
+
+pub fn get_virtual_sensor(pool: &PgPool, uid: i64, sensor: i64) -> Result<VirtualSensor, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  Ok(VirtualSensor {
+    sensor: sensor,
+    formula: sensor_formula(&*conn, sensor)?,
+  })
+}
+
+// see sqldata::set_virtual_sensor.
+pub fn set_virtual_sensor(pool: &PgPool, uid: i64, vs: &VirtualSensor) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  check_sensor_owner(&tx, uid, vs.sensor)?;
+
+  tx.execute("DELETE FROM virtualinput WHERE sensor = $1", &[&vs.sensor])?;
+  tx.execute("DELETE FROM virtualsensor WHERE sensor = $1", &[&vs.sensor])?;
+
+  match &vs.formula {
+    Some(f) => {
+      f.compile()?;
+
+      let rows = tx.query(
+        "SELECT count(*) FROM measurement WHERE sensor = $1",
+        &[&vs.sensor],
+      )?;
+      let count: i64 = rows.get(0).get(0);
+      if count > 0 {
+        return Err(Error::Conflict(format!(
+          "sensor {} has measurements; virtual sensors can't",
+          vs.sensor
+        )));
+      }
+      let rows = tx.query(
+        "SELECT count(*) FROM virtualinput WHERE input = $1",
+        &[&vs.sensor],
+      )?;
+      let users: i64 = rows.get(0).get(0);
+      if users > 0 {
+        return Err(Error::Conflict(format!(
+          "sensor {} is an input to another virtual sensor",
+          vs.sensor
+        )));
+      }
+      if !sensor_kind(&tx, vs.sensor)?.0.is_numeric() {
+        return Err(Error::BadRequest(
+          "virtual sensors must be numeric".to_string(),
+        ));
+      }
+
+      for (name, input) in f.inputs.iter() {
+        check_sensor_owner(&tx, uid, *input)?;
+        if *input == vs.sensor || sensor_formula(&tx, *input)?.is_some() {
+          return Err(Error::BadRequest(format!(
+            "input '{}' is a virtual sensor",
+            name
+          )));
+        }
+        if !sensor_kind(&tx, *input)?.0.is_numeric() {
+          return Err(Error::BadRequest(format!("input '{}' isn't numeric", name)));
+        }
+      }
+
+      insert_formula(&tx, vs.sensor, f)?;
+    }
+    None => (),
+  }
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+fn insert_formula<C: GenericConnection>(conn: &C, sensor: i64, f: &Formula) -> Result<(), Error> {
+  conn.execute(
+    "INSERT INTO virtualsensor (sensor, expression) VALUES ($1, $2)",
+    &[&sensor, &f.expression],
+  )?;
+  for (name, input) in f.inputs.iter() {
+    conn.execute(
+      "INSERT INTO virtualinput (sensor, name, input) VALUES ($1, $2, $3)",
+      &[&sensor, name, input],
+    )?;
+  }
+  Ok(())
+}
+
+// --------------------------------------------------------------------------------------
+// typed readings
+
+pub fn sensor_kind<C: GenericConnection>(
+  conn: &C,
+  sensor: i64,
+) -> Result<(ValueKind, Option<i64>), Error> {
+  let rows = conn.query(
+    "SELECT kind, dims FROM sensortype WHERE sensor = $1",
+    &[&sensor],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok((
+      ValueKind::parse(row.get::<_, String>(0).as_str())?,
+      row.get(1),
+    )),
+    None => Ok((ValueKind::Float, None)),
+  }
+}
+
+pub fn get_sensor_type(pool: &PgPool, uid: i64, sensor: i64) -> Result<SensorType, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let (kind, dims) = sensor_kind(&*conn, sensor)?;
+  let rows = conn.query("SELECT unit FROM sensortype WHERE sensor = $1", &[&sensor])?;
+  Ok(SensorType {
+    sensor: sensor,
+    kind: kind,
+    dims: dims,
+    unit: rows.iter().next().and_then(|row| row.get(0)),
+  })
+}
+
+pub fn set_sensor_type(pool: &PgPool, uid: i64, st: &SensorType) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  readings::check_type(st)?;
+
+  let tx = conn.transaction()?;
+
+  check_sensor_owner(&tx, uid, st.sensor)?;
+
+  let (kind, dims) = sensor_kind(&tx, st.sensor)?;
+  if (kind, dims) != (st.kind, st.dims) {
+    let rows = tx.query(
+      "SELECT count(*) FROM measurement WHERE sensor = $1",
+      &[&st.sensor],
+    )?;
+    let count: i64 = rows.get(0).get(0);
+    if count > 0 {
+      return Err(Error::Conflict(format!(
+        "sensor {} already has {} readings",
+        st.sensor,
+        kind.as_str()
+      )));
+    }
+  }
+
+  tx.execute(
+    "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES ($1, $2, $3, $4)
+     ON CONFLICT (sensor) DO UPDATE SET
+       kind = excluded.kind, dims = excluded.dims, unit = excluded.unit",
+    &[&st.sensor, &st.kind.as_str(), &st.dims, &st.unit],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn add_reading(pool: &PgPool, uid: i64, reading: &SaveReading) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  check_sensor_owner(&*conn, uid, reading.sensor)?;
+  check_not_virtual(&*conn, reading.sensor)?;
+
+  let (kind, dims) = sensor_kind(&*conn, reading.sensor)?;
+  let (value, payload) = readings::encode(kind, dims, &reading.value)?;
+
+  match &reading.position {
+    Some(p) => geo::check_location(p)?,
+    None => (),
+  }
+  let p = reading.position.as_ref();
+
+  let rows = conn.query(
+    "INSERT INTO measurement (sensor, value, payload, measuredate, createdate, lat, lon, elevation)
+     VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING id",
+    &[
+      &reading.sensor,
+      &value,
+      &payload,
+      &reading.measuredate,
+      &now,
+      &p.map(|p| p.lat),
+      &p.map(|p| p.lon),
+      &p.and_then(|p| p.elevation),
+    ],
+  )?;
+
+  Ok(rows.get(0).get(0))
+}
+
+pub fn reading_listing(
+  pool: &PgPool,
+  uid: i64,
+  query: &ReadingQuery,
+) -> Result<Vec<Reading>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, query.sensor)?;
+
+  let (kind, _) = sensor_kind(&*conn, query.sensor)?;
+
+  match &query.bbox {
+    Some(b) => geo::check_bbox(b)?,
+    None => (),
+  }
+  let bbox = geo::bbox_params(&query.bbox);
+
+  let rows = conn.query(
+    "SELECT id, value, payload, measuredate, createdate, lat, lon, elevation
+       FROM measurement WHERE sensor = $1
+       AND measuredate >= $2
+       AND measuredate < $3
+       AND ($4::DOUBLE PRECISION IS NULL
+         OR (lat >= $4 AND lat <= $5 AND lon >= $6 AND lon <= $7))
+       ORDER BY measuredate",
+    &[
+      &query.sensor,
+      &query.from.unwrap_or(i64::min_value()),
+      &query.to.unwrap_or(i64::max_value()),
+      &bbox[0],
+      &bbox[1],
+      &bbox[2],
+      &bbox[3],
+    ],
+  )?;
+
+  let mut pv = Vec::new();
+  for row in rows.iter() {
+    pv.push(Reading {
+      id: row.get(0),
+      sensor: query.sensor,
+      value: readings::decode(kind, row.get(1), row.get(2))?,
+      measuredate: row.get(3),
+      createdate: row.get(4),
+      position: geo::from_columns(row.get(5), row.get(6), row.get(7)),
+    });
+  }
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// device locations
+
+pub fn get_device_location(pool: &PgPool, uid: i64, device: i64) -> Result<DeviceLocation, Error> {
+  let conn = pool.get()?;
+
+  check_device_owner(&*conn, uid, device)?;
+
+  let rows = conn.query(
+    "SELECT lat, lon, elevation FROM devicelocation WHERE device = $1",
+    &[&device],
+  )?;
+
+  Ok(DeviceLocation {
+    device: device,
+    location: rows.iter().next().map(|row| Location {
+      lat: row.get(0),
+      lon: row.get(1),
+      elevation: row.get(2),
+    }),
+  })
+}
+
+pub fn set_device_location(pool: &PgPool, uid: i64, dl: &DeviceLocation) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_device_owner(&*conn, uid, dl.device)?;
+
+  match &dl.location {
+    Some(l) => {
+      geo::check_location(l)?;
+      conn.execute(
+        "INSERT INTO devicelocation (device, lat, lon, elevation) VALUES ($1, $2, $3, $4)
+         ON CONFLICT (device) DO UPDATE SET
+           lat = excluded.lat, lon = excluded.lon, elevation = excluded.elevation",
+        &[&dl.device, &l.lat, &l.lon, &l.elevation],
+      )?;
+    }
+    None => {
+      conn.execute(
+        "DELETE FROM devicelocation WHERE device = $1",
+        &[&dl.device],
+      )?;
+    }
+  }
+
+  Ok(())
+}
+
+pub fn located_devices(
+  pool: &PgPool,
+  uid: i64,
+  bbox: &Option<BBox>,
+) -> Result<Vec<LocatedDevice>, Error> {
+  let conn = pool.get()?;
+
+  match bbox {
+    Some(b) => geo::check_bbox(b)?,
+    None => (),
+  }
+  let b = geo::bbox_params(bbox);
+
+  let rows = conn.query(
+    "SELECT device.id, device.name, device.description,
+            devicelocation.lat, devicelocation.lon, devicelocation.elevation
+       FROM device, devicelocation
+       WHERE devicelocation.device = device.id AND device.\"user\" = $1
+       AND ($2::DOUBLE PRECISION IS NULL
+         OR (lat >= $2 AND lat <= $3 AND lon >= $4 AND lon <= $5))
+       ORDER BY device.id",
+    &[&uid, &b[0], &b[1], &b[2], &b[3]],
+  )?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| LocatedDevice {
+        id: row.get(0),
+        name: row.get(1),
+        description: row.get(2),
+        location: Location {
+          lat: row.get(3),
+          lon: row.get(4),
+          elevation: row.get(5),
+        },
+      })
+      .collect(),
+  )
+}
+
+// --------------------------------------------------------------------------------------
+// device tokens
+
+pub fn set_device_token(
+  pool: &PgPool,
+  uid: i64,
+  device: i64,
+  hash: Option<&str>,
+) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_device_owner(&*conn, uid, device)?;
+
+  let tx = conn.transaction()?;
+  tx.execute("DELETE FROM tokensensor WHERE device = $1", &[&device])?;
+  match hash {
+    Some(h) => {
+      // the sensors are numbered afresh, in id order.
+      tx.execute(
+        "INSERT INTO tokensensor (device, idx, sensor)
+         SELECT $1, row_number() OVER (ORDER BY id) - 1, id FROM sensor WHERE device = $1",
+        &[&device],
+      )?;
+      tx.execute(
+        "INSERT INTO devicetoken (device, tokenhash, createdate, nextidx)
+         VALUES ($1, $2, $3, (SELECT count(*) FROM sensor WHERE device = $1))
+         ON CONFLICT (device) DO UPDATE SET
+           tokenhash = excluded.tokenhash, createdate = excluded.createdate,
+           nextidx = excluded.nextidx",
+        &[&device, &h, &now()?],
+      )?;
+    }
+    None => {
+      tx.execute("DELETE FROM devicetoken WHERE device = $1", &[&device])?;
+    }
+  }
+  tx.commit()?;
+
+  Ok(())
+}
+
+// give a new sensor its device's next udp index, if the device has a token.
+fn add_token_sensor<C: GenericConnection>(conn: &C, device: i64, sensor: i64) -> Result<(), Error> {
+  conn.execute(
+    "WITH t AS (
+       UPDATE devicetoken SET nextidx = nextidx + 1 WHERE device = $1 RETURNING nextidx - 1 AS idx
+     )
+     INSERT INTO tokensensor (device, idx, sensor) SELECT $1, idx, $2 FROM t",
+    &[&device, &sensor],
+  )?;
+  Ok(())
+}
+
+pub fn token_device(pool: &PgPool, hash: &str) -> Result<TokenDevice, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT device.\"user\", device.id FROM devicetoken, device, \"user\"
+      WHERE devicetoken.tokenhash = $1 AND devicetoken.device = device.id
+        AND device.\"user\" = \"user\".id AND \"user\".registration_key IS NULL
+        AND NOT \"user\".disabled",
+    &[&hash],
+  )?;
+  let (user, device): (i64, i64) = match rows.iter().next() {
+    Some(row) => (row.get(0), row.get(1)),
+    None => return Err(Error::NotFound("unknown device token".to_string())),
+  };
+
+  let sensors = conn
+    .query(
+      "SELECT idx, sensor FROM tokensensor WHERE device = $1",
+      &[&device],
+    )?
+    .iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect();
+
+  Ok(TokenDevice {
+    user,
+    device,
+    sensors,
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// device templates
+
+fn template_sensors<C: GenericConnection>(
+  conn: &C,
+  template: i64,
+) -> Result<Vec<TemplateSensor>, Error> {
+  let rows = conn.query(
+    "SELECT name, description, unit, kind, dims FROM templatesensor
+      WHERE template = $1 ORDER BY seq",
+    &[&template],
+  )?;
+
+  let mut pv = Vec::new();
+  for row in rows.iter() {
+    let kind: Option<String> = row.get(3);
+    pv.push(TemplateSensor {
+      name: row.get(0),
+      description: row.get(1),
+      unit: row.get(2),
+      kind: match kind {
+        Some(k) => Some(ValueKind::parse(k.as_str())?),
+        None => None,
+      },
+      dims: row.get(4),
+    });
+  }
+
+  Ok(pv)
+}
+
+pub fn save_template(pool: &PgPool, uid: i64, template: &SaveTemplate) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  templates::check_template(template)?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let id = match template.id {
+    Some(id) => {
+      let count = tx.execute(
+        "UPDATE devicetemplate SET name = $1, description = $2, changeddate = $3
+         WHERE id = $4 AND \"user\" = $5",
+        &[&template.name, &template.description, &now, &id, &uid],
+      )?;
+      if count == 0 {
+        return Err(not_found("template", &id));
+      }
+      tx.execute("DELETE FROM templatesensor WHERE template = $1", &[&id])?;
+      id
+    }
+    None => {
+      let rows = tx.query(
+        "INSERT INTO devicetemplate (\"user\", name, description, createdate, changeddate)
+         VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        &[&uid, &template.name, &template.description, &now, &now],
+      )?;
+      rows.get(0).get(0)
+    }
+  };
+
+  let insert = tx.prepare(
+    "INSERT INTO templatesensor (template, seq, name, description, unit, kind, dims)
+     VALUES ($1, $2, $3, $4, $5, $6, $7)",
+  )?;
+  for (seq, s) in template.sensors.iter().enumerate() {
+    insert.execute(&[
+      &id,
+      &(seq as i64),
+      &s.name,
+      &s.description,
+      &s.unit,
+      &s.kind.map(|k| k.as_str()),
+      &s.dims,
+    ])?;
+  }
+  drop(insert);
+
+  tx.commit()?;
+
+  Ok(id)
+}
+
+fn read_template_conn<C: GenericConnection>(
+  conn: &C,
+  uid: i64,
+  id: i64,
+) -> Result<DeviceTemplate, Error> {
+  let rows = conn.query(
+    "SELECT name, description, createdate, changeddate FROM devicetemplate
+      WHERE id = $1 AND \"user\" = $2",
+    &[&id, &uid],
+  )?;
+  let row = rows.iter().next().ok_or(not_found("template", &id))?;
+
+  Ok(DeviceTemplate {
+    id: id,
+    name: row.get(0),
+    description: row.get(1),
+    createdate: row.get(2),
+    changeddate: row.get(3),
+    sensors: template_sensors(conn, id)?,
+  })
+}
+
+pub fn read_template(pool: &PgPool, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+  let conn = pool.get()?;
+
+  read_template_conn(&*conn, uid, id)
+}
+
+pub fn template_listing(pool: &PgPool, uid: i64) -> Result<Vec<DeviceTemplate>, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT id FROM devicetemplate WHERE \"user\" = $1 ORDER BY id",
+    &[&uid],
+  )?;
+
+  let mut pv = Vec::new();
+  for row in rows.iter() {
+    pv.push(read_template_conn(&*conn, uid, row.get(0))?);
+  }
+
+  Ok(pv)
+}
+
+pub fn delete_template(pool: &PgPool, uid: i64, id: i64) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  tx.execute(
+    "DELETE FROM templatesensor WHERE template = $1
+      AND template IN (SELECT id FROM devicetemplate WHERE \"user\" = $2)",
+    &[&id, &uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetemplate WHERE id = $1 AND \"user\" = $2",
+    &[&id, &uid],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn instantiate_template(
+  pool: &PgPool,
+  uid: i64,
+  inst: &Instantiate,
+) -> Result<Vec<InstantiatedDevice>, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let template = read_template_conn(&tx, uid, inst.template)?;
+  let names = templates::device_names(
+    inst.name.as_ref().unwrap_or(&template.name).as_str(),
+    inst.count,
+    inst.first,
+  )?;
+  let description = inst.description.as_ref().unwrap_or(&template.description);
+
+  let mut devices = Vec::new();
+  for name in names {
+    let rows = tx.query(
+      "INSERT INTO device (name, \"user\", description, createdate, changeddate)
+       VALUES ($1, $2, $3, $4, $5) RETURNING id",
+      &[&name, &uid, description, &now, &now],
+    )?;
+    let deviceid: i64 = rows.get(0).get(0);
+
+    let mut sensors = Vec::new();
+    for ts in template.sensors.iter() {
+      let rows = tx.query(
+        "INSERT INTO sensor (device, name, description, createdate, changeddate)
+         VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        &[&deviceid, &ts.name, &ts.description, &now, &now],
+      )?;
+      let sensorid: i64 = rows.get(0).get(0);
+
+      if templates::needs_type(ts) {
+        tx.execute(
+          "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES ($1, $2, $3, $4)",
+          &[
+            &sensorid,
+            &ts.kind.unwrap_or(ValueKind::Float).as_str(),
+            &ts.dims,
+            &ts.unit,
+          ],
+        )?;
+      }
+
+      sensors.push(InstantiatedSensor {
+        id: sensorid,
+        name: ts.name.clone(),
+      });
+    }
+
+    devices.push(InstantiatedDevice {
+      id: deviceid,
+      name: name,
+      sensors: sensors,
+    });
+  }
+
+  tx.commit()?;
+
+  Ok(devices)
+}
+
+// --------------------------------------------------------------------------------------
+// retention and rollups
+
+fn retention_row(row: Row) -> SensorRetention {
+  SensorRetention {
+    sensor: row.get(0),
+    rawdays: row.get(1),
+    hourlydays: row.get(2),
+    dailydays: row.get(3),
+  }
+}
+
+pub fn get_retention(pool: &PgPool, uid: i64, sensor: i64) -> Result<SensorRetention, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sensor)?;
+
+  let rows = conn.query(
+    "SELECT sensor, rawdays, hourlydays, dailydays FROM retention WHERE sensor = $1",
+    &[&sensor],
+  )?;
+
+  match rows.iter().next() {
+    Some(row) => Ok(retention_row(row)),
+    None => Ok(SensorRetention {
+      sensor: sensor,
+      rawdays: None,
+      hourlydays: None,
+      dailydays: None,
+    }),
+  }
+}
+
+pub fn set_retention(pool: &PgPool, uid: i64, sr: &SensorRetention) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, sr.sensor)?;
+
+  conn.execute(
+    "INSERT INTO retention (sensor, rawdays, hourlydays, dailydays)
+     VALUES ($1, $2, $3, $4)
+     ON CONFLICT (sensor) DO UPDATE SET
+       rawdays = excluded.rawdays,
+       hourlydays = excluded.hourlydays,
+       dailydays = excluded.dailydays",
+    &[&sr.sensor, &sr.rawdays, &sr.hourlydays, &sr.dailydays],
+  )?;
+
+  Ok(())
+}
+
+pub fn sensor_retentions(pool: &PgPool) -> Result<Vec<SensorRetention>, Error> {
+  let conn = pool.get()?;
+
+  let rows = conn.query(
+    "SELECT sensor.id, retention.rawdays, retention.hourlydays, retention.dailydays
+       FROM sensor LEFT JOIN retention ON retention.sensor = sensor.id",
+    &[],
+  )?;
+
+  Ok(rows.iter().map(retention_row).collect())
+}
+
+// as sqldata::rollup_measurements.  The batch's ids are locked first, so the
+// statements after agree on them.
+pub fn rollup_measurements(
+  pool: &PgPool,
+  sensor: i64,
+  before: i64,
+  limit: i64,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  let batch: Vec<(i64, i64)> = tx
+    .query(
+      "SELECT id, measuredate FROM measurement WHERE sensor = $1 AND measuredate < $2
+        ORDER BY measuredate LIMIT $3 FOR UPDATE",
+      &[&sensor, &before, &limit],
+    )?
+    .iter()
+    .map(|row| (row.get(0), row.get(1)))
+    .collect();
+  let rolledupto = match batch.last() {
+    Some((_, last)) if batch.len() as i64 >= limit => *last,
+    Some(_) => before,
+    None => return Ok(0),
+  };
+  let ids: Vec<i64> = batch.iter().map(|(id, _)| *id).collect();
+
+  for rollup in [Rollup::Hourly, Rollup::Daily].iter() {
+    tx.execute(
+      format!(
+        "INSERT INTO {table} (sensor, period, samples, total, minimum, maximum)
+         SELECT sensor, measuredate - ((measuredate % $2) + $2) % $2,
+             count(*), sum(value), min(value), max(value)
+           FROM measurement WHERE id = ANY($1) AND payload IS NULL
+           GROUP BY sensor, 2
+         ON CONFLICT (sensor, period) DO UPDATE SET
+           samples = {table}.samples + excluded.samples,
+           total = {table}.total + excluded.total,
+           minimum = LEAST({table}.minimum, excluded.minimum),
+           maximum = GREATEST({table}.maximum, excluded.maximum)",
+        table = rollup.table()
+      )
+      .as_str(),
+      &[&ids, &rollup.period()],
+    )?;
+  }
+
+  let count = tx.execute("DELETE FROM measurement WHERE id = ANY($1)", &[&ids])?;
+
+  tx.execute(
+    "INSERT INTO rollup (sensor, rolledupto) VALUES ($1, $2)
+     ON CONFLICT (sensor) DO UPDATE SET
+       rolledupto = GREATEST(rollup.rolledupto, excluded.rolledupto)",
+    &[&sensor, &rolledupto],
+  )?;
+
+  tx.commit()?;
+
+  Ok(count as i64)
+}
+
+pub fn prune_rollups(
+  pool: &PgPool,
+  rollup: Rollup,
+  sensor: i64,
+  before: i64,
+  limit: i64,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let count = conn.execute(
+    format!(
+      "DELETE FROM {table} WHERE ctid IN
+        (SELECT ctid FROM {table} WHERE sensor = $1 AND period < $2 LIMIT $3)",
+      table = rollup.table()
+    )
+    .as_str(),
+    &[&sensor, &before, &limit],
+  )?;
+
+  Ok(count as i64)
+}
+
+pub fn rollup_listing(
+  pool: &PgPool,
+  uid: i64,
+  query: &RollupQuery,
+) -> Result<Vec<RollupEntry>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&*conn, uid, query.sensor)?;
+
+  let rows = conn.query(
+    format!(
+      "SELECT period, samples, total, minimum, maximum
+         FROM {} WHERE sensor = $1
+         AND period >= $2
+         AND period < $3
+         ORDER BY period",
+      query.rollup.table()
+    )
+    .as_str(),
+    &[
+      &query.sensor,
+      &query.from.unwrap_or(i64::min_value()),
+      &query.to.unwrap_or(i64::max_value()),
+    ],
+  )?;
+
+  let mut pv: Vec<RollupEntry> = rows
+    .iter()
+    .map(|row| {
+      let samples: i64 = row.get(1);
+      let total: f64 = row.get(2);
+      RollupEntry {
+        sensor: query.sensor,
+        period: row.get(0),
+        samples: samples,
+        mean: total / samples as f64,
+        min: row.get(3),
+        max: row.get(4),
+      }
+    })
+    .collect();
+
+  calibration::calibrate_rollups(&sensor_calibrations(&*conn, query.sensor)?, &mut pv);
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// export and import
+
+fn archive_rollups<C: GenericConnection>(
+  conn: &C,
+  rollup: Rollup,
+  sensor: i64,
+) -> Result<Vec<ArchiveRollup>, Error> {
+  let rows = conn.query(
+    format!(
+      "SELECT period, samples, total, minimum, maximum FROM {} WHERE sensor = $1 ORDER BY period",
+      rollup.table()
+    )
+    .as_str(),
+    &[&sensor],
+  )?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| ArchiveRollup {
+        period: row.get(0),
+        samples: row.get(1),
+        total: row.get(2),
+        minimum: row.get(3),
+        maximum: row.get(4),
+      })
+      .collect(),
+  )
+}
+
+fn archive_sensor<C: GenericConnection>(conn: &C, id: i64) -> Result<ArchiveSensor, Error> {
+  let rows = conn.query(
+    "SELECT sensor.name, sensor.description, sensor.createdate, sensor.changeddate,
+            retention.rawdays, retention.hourlydays, retention.dailydays, rollup.rolledupto,
+            sensortype.dims, sensortype.unit
+       FROM sensor
+       LEFT JOIN retention ON retention.sensor = sensor.id
+       LEFT JOIN rollup ON rollup.sensor = sensor.id
+       LEFT JOIN sensortype ON sensortype.sensor = sensor.id
+       WHERE sensor.id = $1",
+    &[&id],
+  )?;
+  let row = rows.iter().next().ok_or(not_found("sensor", &id))?;
+
+  let measurements = conn.query(
+    "SELECT value, payload, measuredate, createdate, lat, lon, elevation
+       FROM measurement WHERE sensor = $1
+      ORDER BY measuredate",
+    &[&id],
+  )?;
+
+  let history = sensor_calibrations(conn, id)?;
+  let mut calibrations = Vec::new();
+  for c in conn
+    .query(
+      "SELECT effective, transform, createdate FROM calibrationrecord
+        WHERE sensor = $1 ORDER BY effective",
+      &[&id],
+    )?
+    .iter()
+  {
+    calibrations.push(ArchiveCalibration {
+      effective: c.get(0),
+      calibration: parse_transform(c.get(1))?,
+      createdate: c.get(2),
+    });
+  }
+
+  Ok(ArchiveSensor {
+    id: id,
+    name: row.get(0),
+    description: row.get(1),
+    createdate: row.get(2),
+    changeddate: row.get(3),
+    kind: Some(sensor_kind(conn, id)?.0),
+    dims: row.get(8),
+    unit: row.get(9),
+    calibration: None,
+    calibrations: Some(calibrations),
+    formula: sensor_formula(conn, id)?,
+    rawdays: row.get(4),
+    hourlydays: row.get(5),
+    dailydays: row.get(6),
+    rolledupto: row.get(7),
+    measurements: measurements
+      .iter()
+      .map(|m| ArchiveMeasurement {
+        value: m.get(0),
+        payload: m.get(1),
+        measuredate: m.get(2),
+        createdate: m.get(3),
+        position: geo::from_columns(m.get(4), m.get(5), m.get(6)),
+        calibrated: calibration::in_force(&history, m.get(2)).map(|c| c.apply(m.get(0))),
+      })
+      .collect(),
+    hourly: archive_rollups(conn, Rollup::Hourly, id)?,
+    daily: archive_rollups(conn, Rollup::Daily, id)?,
+  })
+}
+
+pub fn export_user(pool: &PgPool, uid: i64) -> Result<Archive, Error> {
+  let conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  let rows = tx.query("SELECT name FROM \"user\" WHERE id = $1", &[&uid])?;
+  let name: String = match rows.iter().next() {
+    Some(row) => row.get(0),
+    None => return Err(not_found("user", &uid)),
+  };
+
+  let mut devices = Vec::new();
+  for row in tx
+    .query(
+      "SELECT device.id, device.name, device.description, device.createdate,
+              device.changeddate, devicelocation.lat, devicelocation.lon, devicelocation.elevation
+         FROM device
+         LEFT JOIN devicelocation ON devicelocation.device = device.id
+        WHERE device.\"user\" = $1 ORDER BY device.id",
+      &[&uid],
+    )?
+    .iter()
+  {
+    let id: i64 = row.get(0);
+    let mut sensors = Vec::new();
+    for srow in tx
+      .query(
+        "SELECT id FROM sensor WHERE device = $1 ORDER BY id",
+        &[&id],
+      )?
+      .iter()
+    {
+      sensors.push(archive_sensor(&tx, srow.get(0))?);
+    }
+    devices.push(ArchiveDevice {
+      id: id,
+      name: row.get(1),
+      description: row.get(2),
+      createdate: row.get(3),
+      changeddate: row.get(4),
+      location: geo::from_columns(row.get(5), row.get(6), row.get(7)),
+      sensors: sensors,
+    });
+  }
+
+  tx.commit()?;
+
+  Ok(Archive {
+    version: ARCHIVE_VERSION,
+    exportdate: now()?,
+    user: name,
+    devices: devices,
+  })
+}
+
+pub fn import_user(pool: &PgPool, uid: i64, archive: &Archive) -> Result<ImportSummary, Error> {
+  let conn = pool.get()?;
+
+  check_version(archive)?;
+
+  let now = now()?;
+  let tx = conn.transaction()?;
+
+  let mut summary = ImportSummary {
+    devices: 0,
+    sensors: 0,
+    measurements: 0,
+  };
+
+  // the archive's sensor ids to the new ones, for the virtual sensor inputs.
+  let mut sensorids = HashMap::new();
+  let mut formulas = Vec::new();
+
+  let insert_measurement = tx.prepare(
+    "INSERT INTO measurement (sensor, value, payload, measuredate, createdate, lat, lon, elevation)
+     VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+  )?;
+
+  for device in archive.devices.iter() {
+    let rows = tx.query(
+      "INSERT INTO device (\"user\", name, description, createdate, changeddate)
+       VALUES ($1, $2, $3, $4, $5) RETURNING id",
+      &[
+        &uid,
+        &device.name,
+        &device.description,
+        &device.createdate,
+        &device.changeddate,
+      ],
+    )?;
+    let deviceid: i64 = rows.get(0).get(0);
+    summary.devices += 1;
+
+    match &device.location {
+      Some(l) => {
+        geo::check_location(l)?;
+        tx.execute(
+          "INSERT INTO devicelocation (device, lat, lon, elevation) VALUES ($1, $2, $3, $4)",
+          &[&deviceid, &l.lat, &l.lon, &l.elevation],
+        )?;
+      }
+      None => (),
+    }
+
+    for sensor in device.sensors.iter() {
+      let rows = tx.query(
+        "INSERT INTO sensor (device, name, description, createdate, changeddate)
+         VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        &[
+          &deviceid,
+          &sensor.name,
+          &sensor.description,
+          &sensor.createdate,
+          &sensor.changeddate,
+        ],
+      )?;
+      let sensorid: i64 = rows.get(0).get(0);
+      summary.sensors += 1;
+      sensorids.insert(sensor.id, sensorid);
+
+      for c in archive::sensor_calibrations(sensor, archive.exportdate).iter() {
+        match &c.calibration {
+          Some(c) => c.check()?,
+          None => (),
+        }
+        let transform = transform_string(&c.calibration)?;
+        tx.execute(
+          "INSERT INTO calibrationrecord (sensor, effective, transform, createdate, \"user\")
+           VALUES ($1, $2, $3, $4, $5)",
+          &[&sensorid, &c.effective, &transform, &c.createdate, &uid],
+        )?;
+        add_calibration_audit(
+          &tx,
+          uid,
+          sensorid,
+          calibration::AUDIT_IMPORT,
+          c.effective,
+          &transform,
+          now,
+        )?;
+      }
+      match &sensor.formula {
+        Some(f) => formulas.push((sensorid, f)),
+        None => (),
+      }
+
+      if sensor.rawdays.is_some() || sensor.hourlydays.is_some() || sensor.dailydays.is_some() {
+        tx.execute(
+          "INSERT INTO retention (sensor, rawdays, hourlydays, dailydays)
+           VALUES ($1, $2, $3, $4)",
+          &[
+            &sensorid,
+            &sensor.rawdays,
+            &sensor.hourlydays,
+            &sensor.dailydays,
+          ],
+        )?;
+      }
+      match sensor.rolledupto {
+        Some(to) => {
+          tx.execute(
+            "INSERT INTO rollup (sensor, rolledupto) VALUES ($1, $2)",
+            &[&sensorid, &to],
+          )?;
+        }
+        None => (),
+      }
+      match (sensor.kind, &sensor.unit) {
+        (Some(ValueKind::Float), None) | (None, None) => (),
+        (kind, unit) => {
+          let kind = kind.unwrap_or(ValueKind::Float);
+          readings::check_kind(kind, sensor.dims)?;
+          tx.execute(
+            "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES ($1, $2, $3, $4)",
+            &[&sensorid, &kind.as_str(), &sensor.dims, unit],
+          )?;
+        }
+      }
+
+      if sensor.formula.is_some() && !sensor.measurements.is_empty() {
+        return Err(Error::BadRequest(format!(
+          "virtual sensor {} can't have measurements",
+          sensor.id
+        )));
+      }
+      let kind = sensor.kind.unwrap_or(ValueKind::Float);
+      for m in sensor.measurements.iter() {
+        let (value, payload) = readings::check_archived(
+          kind,
+          sensor.dims,
+          m.value,
+          m.payload.as_ref().map(|p| p.as_str()),
+        )?;
+        let p = m.position.as_ref();
+        match p {
+          Some(p) => geo::check_location(p)?,
+          None => (),
+        }
+        insert_measurement.execute(&[
+          &sensorid,
+          &value,
+          &payload,
+          &m.measuredate,
+          &m.createdate,
+          &p.map(|p| p.lat),
+          &p.map(|p| p.lon),
+          &p.and_then(|p| p.elevation),
+        ])?;
+        summary.measurements += 1;
+      }
+
+      for (rollup, rows) in [
+        (Rollup::Hourly, &sensor.hourly),
+        (Rollup::Daily, &sensor.daily),
+      ]
+      .iter()
+      {
+        let insert = tx.prepare(
+          format!(
+            "INSERT INTO {} (sensor, period, samples, total, minimum, maximum)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            rollup.table()
+          )
+          .as_str(),
+        )?;
+        for r in rows.iter() {
+          insert.execute(&[
+            &sensorid, &r.period, &r.samples, &r.total, &r.minimum, &r.maximum,
+          ])?;
+        }
+      }
+    }
+  }
+
+  drop(insert_measurement);
+
+  for (sensorid, f) in formulas {
+    insert_formula(&tx, sensorid, &calibration::remap_inputs(f, &sensorids)?)?;
+  }
+
+  tx.commit()?;
+
+  Ok(summary)
+}
@@ -0,0 +1,251 @@
+use error::Error;
+use geo::{BBox, Location};
+use schemars::JsonSchema;
+use serde_json;
+use serde_json::Value;
+
+// typed measurements.  Each sensor declares the kind of value it produces;
+// sensors without a declaration are 'float', which is all the original
+// savemeasurement/getmeasurementlisting messages handle.
+//
+// float, integer and boolean readings are stored in measurement.value (a
+// boolean as 0 or 1), so they can be rolled up.  The others are stored as json
+// in measurement.payload, with value 0.
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueKind {
+  Float,
+  Integer,
+  Boolean,
+  String,
+  Vector,
+  Json,
+}
+
+impl ValueKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ValueKind::Float => "float",
+      ValueKind::Integer => "integer",
+      ValueKind::Boolean => "boolean",
+      ValueKind::String => "string",
+      ValueKind::Vector => "vector",
+      ValueKind::Json => "json",
+    }
+  }
+
+  pub fn parse(s: &str) -> Result<ValueKind, Error> {
+    match s {
+      "float" => Ok(ValueKind::Float),
+      "integer" => Ok(ValueKind::Integer),
+      "boolean" => Ok(ValueKind::Boolean),
+      "string" => Ok(ValueKind::String),
+      "vector" => Ok(ValueKind::Vector),
+      "json" => Ok(ValueKind::Json),
+      _ => Err(Error::Internal(format!("unknown value kind: {}", s))),
+    }
+  }
+
+  // stored in measurement.value rather than payload.
+  pub fn is_numeric(&self) -> bool {
+    match self {
+      ValueKind::Float | ValueKind::Integer | ValueKind::Boolean => true,
+      _ => false,
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SensorType {
+  pub sensor: i64,
+  pub kind: ValueKind,
+  // number of elements, for vectors.
+  pub dims: Option<i64>,
+  // unit of measure, for display.
+  pub unit: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SaveReading {
+  pub sensor: i64,
+  pub value: Value,
+  pub measuredate: i64,
+  pub position: Option<Location>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct Reading {
+  pub id: i64,
+  pub sensor: i64,
+  pub value: Value,
+  pub measuredate: i64,
+  pub createdate: i64,
+  pub position: Option<Location>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct ReadingQuery {
+  pub sensor: i64,
+  pub from: Option<i64>,
+  pub to: Option<i64>,
+  // only readings with a position inside this.
+  pub bbox: Option<BBox>,
+}
+
+// largest json object accepted for a 'json' sensor.
+pub const MAX_JSON_BYTES: usize = 4096;
+
+// integers beyond this don't survive the trip through a float.
+const MAX_EXACT_INT: i64 = 1 << 53;
+
+pub fn check_type(st: &SensorType) -> Result<(), Error> {
+  check_kind(st.kind, st.dims)
+}
+
+pub fn check_kind(kind: ValueKind, dims: Option<i64>) -> Result<(), Error> {
+  match (kind, dims) {
+    (ValueKind::Vector, Some(d)) if d > 0 => Ok(()),
+    (ValueKind::Vector, _) => Err(Error::BadRequest(
+      "vector sensors need 'dims' of at least 1".to_string(),
+    )),
+    (_, None) => Ok(()),
+    (_, Some(_)) => Err(Error::BadRequest(
+      "'dims' is only for vector sensors".to_string(),
+    )),
+  }
+}
+
+fn wrong_kind(kind: ValueKind, value: &Value) -> Error {
+  Error::BadRequest(format!(
+    "expected a {} reading, got: {}",
+    kind.as_str(),
+    value
+  ))
+}
+
+// check a reading against the sensor's kind, and convert it to the value and
+// payload columns.
+pub fn encode(
+  kind: ValueKind,
+  dims: Option<i64>,
+  value: &Value,
+) -> Result<(f64, Option<String>), Error> {
+  match kind {
+    ValueKind::Float => match value.as_f64() {
+      Some(f) => Ok((f, None)),
+      None => Err(wrong_kind(kind, value)),
+    },
+    ValueKind::Integer => match value.as_i64() {
+      Some(i) if i.abs() <= MAX_EXACT_INT => Ok((i as f64, None)),
+      _ => Err(wrong_kind(kind, value)),
+    },
+    ValueKind::Boolean => match value.as_bool() {
+      Some(b) => Ok((if b { 1.0 } else { 0.0 }, None)),
+      None => Err(wrong_kind(kind, value)),
+    },
+    ValueKind::String => match value {
+      Value::String(_) => Ok((0.0, Some(serde_json::to_string(value)?))),
+      _ => Err(wrong_kind(kind, value)),
+    },
+    ValueKind::Vector => match value {
+      Value::Array(a) if Some(a.len() as i64) == dims && a.iter().all(|e| e.as_f64().is_some()) => {
+        Ok((0.0, Some(serde_json::to_string(value)?)))
+      }
+      _ => Err(Error::BadRequest(format!(
+        "expected a vector of {} numbers, got: {}",
+        dims.unwrap_or(0),
+        value
+      ))),
+    },
+    ValueKind::Json => match value {
+      Value::Object(_) => {
+        let s = serde_json::to_string(value)?;
+        if s.len() > MAX_JSON_BYTES {
+          Err(Error::BadRequest(format!(
+            "json readings are limited to {} bytes",
+            MAX_JSON_BYTES
+          )))
+        } else {
+          Ok((0.0, Some(s)))
+        }
+      }
+      _ => Err(wrong_kind(kind, value)),
+    },
+  }
+}
+
+// the reading value from the value and payload columns.
+pub fn decode(kind: ValueKind, value: f64, payload: Option<String>) -> Result<Value, Error> {
+  match (kind, payload) {
+    (ValueKind::Float, _) => Ok(json!(value)),
+    (ValueKind::Integer, _) => Ok(json!(value as i64)),
+    (ValueKind::Boolean, _) => Ok(json!(value != 0.0)),
+    (_, Some(p)) => serde_json::from_str(p.as_str()).map_err(|e| Error::Internal(e.to_string())),
+    (_, None) => Err(Error::Internal(format!(
+      "{} reading with no payload",
+      kind.as_str()
+    ))),
+  }
+}
+
+// most measurements in one 'savemeasurements' batch.
+pub const MAX_MEASUREMENT_BATCH: usize = 10000;
+
+pub fn check_batch(len: usize) -> Result<(), Error> {
+  if len == 0 || len > MAX_MEASUREMENT_BATCH {
+    Err(Error::BadRequest(format!(
+      "batches need between 1 and {} measurements",
+      MAX_MEASUREMENT_BATCH
+    )))
+  } else {
+    Ok(())
+  }
+}
+
+// the plain float measurement messages only work for numeric sensors.
+pub fn check_measurement(kind: ValueKind, value: f64) -> Result<(), Error> {
+  match kind {
+    ValueKind::Float => Ok(()),
+    ValueKind::Integer if value.fract() == 0.0 => Ok(()),
+    ValueKind::Boolean if value == 0.0 || value == 1.0 => Ok(()),
+    _ => Err(Error::BadRequest(format!(
+      "{} is not a valid {} reading; use 'savereading' for typed sensors",
+      value,
+      kind.as_str()
+    ))),
+  }
+}
+
+// check an archived measurement's value and payload columns against the
+// sensor's kind, as savemeasurement or savereading would have, and return the
+// columns as they'd have stored them.
+pub fn check_archived(
+  kind: ValueKind,
+  dims: Option<i64>,
+  value: f64,
+  payload: Option<&str>,
+) -> Result<(f64, Option<String>), Error> {
+  match payload {
+    None if kind.is_numeric() => {
+      check_measurement(kind, value)?;
+      Ok((value, None))
+    }
+    Some(p) if !kind.is_numeric() => match serde_json::from_str::<Value>(p) {
+      Ok(v) => encode(kind, dims, &v),
+      Err(_) => Err(Error::BadRequest(format!(
+        "invalid {} reading payload: {}",
+        kind.as_str(),
+        p
+      ))),
+    },
+    Some(_) => Err(Error::BadRequest(format!(
+      "{} readings don't have a payload",
+      kind.as_str()
+    ))),
+    None => Err(Error::BadRequest(format!(
+      "{} readings need a payload",
+      kind.as_str()
+    ))),
+  }
+}
@@ -0,0 +1,861 @@
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
+use actix_web::web::BytesMut;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use archive::Archive;
+use base64;
+use calibration::{Calibration, Formula, SensorCalibration, VirtualSensor};
+use config::Config;
+use error::Error;
+use futures::future::Future;
+use futures::stream::Stream;
+use geo;
+use geo::{BBox, DeviceLocation, Location};
+use interfaces;
+use logging;
+use readings::{ReadingQuery, SaveReading, SensorType, ValueKind};
+use retention::{Rollup, RollupQuery};
+use schemars::JsonSchema;
+use sciota_protocol::protocol::{SaveDevice, SaveMeasurement, SaveSensor};
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+use sqldata;
+use sqldata::User;
+use storage::{Db, Storage};
+use templates::{Instantiate, SaveTemplate, TemplateSensor};
+use udp;
+
+// resource oriented api, alongside the 'what' messages.  Requests are
+// authenticated with http basic auth, using the same user name and password
+// as the message interface.
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct DeviceBody {
+  pub name: String,
+  pub description: String,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct SensorBody {
+  pub name: String,
+  pub description: String,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct MeasurementBody {
+  pub value: f64,
+  pub measuredate: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct MeasurementRange {
+  pub from: Option<i64>,
+  pub to: Option<i64>,
+}
+
+// user name and password from an http basic auth header.
+pub fn basic_auth(req: &HttpRequest) -> Result<(String, String), Error> {
+  let malformed = || Error::Unauthorized("malformed credentials".to_string());
+
+  let header = match req.headers().get("Authorization") {
+    Some(h) => h.to_str().map_err(|_| malformed())?,
+    None => return Err(Error::Unauthorized("missing credentials".to_string())),
+  };
+
+  if !header.starts_with("Basic ") {
+    return Err(malformed());
+  }
+
+  let decoded = base64::decode(&header[6..]).map_err(|_| malformed())?;
+  let creds = String::from_utf8(decoded).map_err(|_| malformed())?;
+  let mut parts = creds.splitn(2, ':');
+  match (parts.next(), parts.next()) {
+    (Some(name), Some(pwd)) => Ok((name.to_string(), pwd.to_string())),
+    _ => Err(malformed()),
+  }
+}
+
+pub fn authed_user(
+  db: &dyn Storage,
+  creds: Result<(String, String), Error>,
+) -> Result<User, Error> {
+  let (name, pwd) = creds?;
+  interfaces::login(db, name.as_str(), pwd.as_str())
+}
+
+pub type FutureResponse = Box<dyn Future<Item = HttpResponse, Error = actix_web::Error>>;
+
+pub type FutureBody = Box<dyn Future<Item = (Option<String>, BytesMut), Error = actix_web::Error>>;
+
+// a request body, refused once it's over 'limit' bytes.
+fn read_body(
+  payload: web::Payload,
+  limit: usize,
+) -> impl Future<Item = BytesMut, Error = actix_web::Error> {
+  payload.from_err::<actix_web::Error>().fold(
+    BytesMut::new(),
+    move |mut body, chunk| -> Result<BytesMut, actix_web::Error> {
+      if body.len() + chunk.len() > limit {
+        Err(Error::TooLarge(format!("the request is bigger than {} bytes", limit)).into())
+      } else {
+        body.extend_from_slice(&chunk);
+        Ok(body)
+      }
+    },
+  )
+}
+
+// a request body that may be an import.  The basic auth credentials, if any,
+// are checked before it's read: with good ones it can be up to the import
+// limit, and comes with the user's name; otherwise it's held to the request
+// limit.  So anonymous clients can't make the server buffer big bodies.
+pub fn import_body(
+  config: &Config,
+  db: web::Data<Db>,
+  req: &HttpRequest,
+  payload: web::Payload,
+) -> FutureBody {
+  let (limit, import_limit) = (config.request_limit(), config.import_limit());
+  let creds = basic_auth(req);
+  let request = logging::request_id();
+  Box::new(
+    web::block(move || {
+      logging::with_request(request, || match creds {
+        Ok(creds) => match authed_user(&db, Ok(creds)) {
+          Ok(user) => Ok(Some(user.name)),
+          Err(Error::Unauthorized(_)) | Err(Error::Forbidden(_)) => Ok(None),
+          Err(e) => Err(e),
+        },
+        Err(_) => Ok(None),
+      })
+    })
+    .map_err(|e| match e {
+      BlockingError::Error(e) => e.into(),
+      BlockingError::Canceled => Error::Internal("request canceled".to_string()).into(),
+    })
+    .and_then(move |user: Option<String>| {
+      let max = if user.is_some() { import_limit } else { limit };
+      read_body(payload, max).map(move |body| (user, body))
+    }),
+  )
+}
+
+// run database work on the blocking thread pool, replying with its result as
+// json or with the error's status code.  The work is logged as part of the
+// current request.
+pub fn reply<F, T>(status: StatusCode, f: F) -> FutureResponse
+where
+  F: FnOnce() -> Result<T, Error> + Send + 'static,
+  T: Serialize + Send + 'static,
+{
+  let request = logging::request_id();
+  Box::new(web::block(move || logging::with_request(request, f)).then(
+    move |res| -> Result<HttpResponse, actix_web::Error> {
+      match res {
+        Ok(val) => {
+          if status == StatusCode::NO_CONTENT {
+            Ok(HttpResponse::build(status).finish())
+          } else {
+            Ok(HttpResponse::build(status).json(val))
+          }
+        }
+        Err(BlockingError::Error(e)) => {
+          error!("request err: {:?}", e);
+          Ok(e.error_response())
+        }
+        Err(BlockingError::Canceled) => {
+          Ok(Error::Internal("request canceled".to_string()).error_response())
+        }
+      }
+    },
+  ))
+}
+
+// --------------------------------------------------------------------------------------
+// devices
+
+pub fn get_devices(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.devicelisting(user.id)
+  })
+}
+
+pub fn post_device(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  item: web::Json<DeviceBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    let id = db.save_device(
+      user.id,
+      &SaveDevice {
+        id: None,
+        name: body.name,
+        description: body.description,
+      },
+    )?;
+    db.read_device(user.id, id)
+  })
+}
+
+pub fn get_device(db: web::Data<Db>, req: HttpRequest, path: web::Path<i64>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.read_device(user.id, *path)
+  })
+}
+
+pub fn put_device(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<DeviceBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    let id = db.save_device(
+      user.id,
+      &SaveDevice {
+        id: Some(*path),
+        name: body.name,
+        description: body.description,
+      },
+    )?;
+    db.read_device(user.id, id)
+  })
+}
+
+pub fn delete_device(db: web::Data<Db>, req: HttpRequest, path: web::Path<i64>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    // 404 if the device doesn't exist or isn't ours.
+    db.read_device(user.id, *path)?;
+    db.delete_device(user.id, *path)
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// sensors
+
+pub fn get_device_sensors(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.read_device(user.id, *path)?;
+    db.sensorlisting(user.id, Some(*path))
+  })
+}
+
+pub fn post_device_sensor(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<SensorBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    db.save_sensor(
+      user.id,
+      &SaveSensor {
+        id: None,
+        device: *path,
+        name: body.name,
+        description: body.description,
+      },
+    )
+  })
+}
+
+pub fn get_sensors(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.sensorlisting(user.id, None)
+  })
+}
+
+pub fn get_sensor(db: web::Data<Db>, req: HttpRequest, path: web::Path<i64>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.read_sensor(user.id, *path)
+  })
+}
+
+pub fn put_sensor(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<SensorBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    let current = db.read_sensor(user.id, *path)?;
+    db.save_sensor(
+      user.id,
+      &SaveSensor {
+        id: Some(*path),
+        device: current.device,
+        name: body.name,
+        description: body.description,
+      },
+    )?;
+    db.read_sensor(user.id, *path)
+  })
+}
+
+pub fn delete_sensor(db: web::Data<Db>, req: HttpRequest, path: web::Path<i64>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    db.read_sensor(user.id, *path)?;
+    db.delete_sensor(user.id, *path)
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// measurements
+
+pub fn get_measurements(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  query: web::Query<MeasurementRange>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.measurement_listing(user.id, *path, query.from, query.to)
+  })
+}
+
+pub fn post_measurement(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<MeasurementBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let measuredate = match item.measuredate {
+      Some(md) => md,
+      None => sqldata::now()?,
+    };
+    db.add_measurement(
+      user.id,
+      &SaveMeasurement {
+        value: item.value,
+        sensor: *path,
+        measuredate: measuredate,
+      },
+    )
+  })
+}
+
+pub fn get_rollups(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<(i64, Rollup)>,
+  query: web::Query<MeasurementRange>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.rollup_listing(
+      user.id,
+      &RollupQuery {
+        sensor: path.0,
+        rollup: path.1,
+        from: query.from,
+        to: query.to,
+      },
+    )
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// export and import
+
+pub fn get_export(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.export_user(user.id)
+  })
+}
+
+// archives can be big, so this takes the raw body; see import_body.
+pub fn post_import(
+  config: web::Data<Config>,
+  db: web::Data<Db>,
+  req: HttpRequest,
+  payload: web::Payload,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  Box::new(
+    import_body(&config, db.clone(), &req, payload).and_then(move |(_, body)| {
+      reply(StatusCode::CREATED, move || {
+        let user = authed_user(&db, creds)?;
+        let archive: Archive = serde_json::from_slice(&body)?;
+        db.import_user(user.id, &archive)
+      })
+    }),
+  )
+}
+
+// --------------------------------------------------------------------------------------
+// typed readings
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct SensorTypeBody {
+  pub kind: ValueKind,
+  pub dims: Option<i64>,
+  pub unit: Option<String>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct ReadingBody {
+  pub value: Value,
+  pub measuredate: Option<i64>,
+  pub position: Option<Location>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct ReadingRange {
+  pub from: Option<i64>,
+  pub to: Option<i64>,
+  // minlon,minlat,maxlon,maxlat
+  pub bbox: Option<String>,
+}
+
+fn bbox_param(bbox: &Option<String>) -> Result<Option<BBox>, Error> {
+  match bbox {
+    Some(b) => Ok(Some(geo::parse_bbox(b.as_str())?)),
+    None => Ok(None),
+  }
+}
+
+fn reading_query(sensor: i64, range: &ReadingRange) -> Result<ReadingQuery, Error> {
+  Ok(ReadingQuery {
+    sensor: sensor,
+    from: range.from,
+    to: range.to,
+    bbox: bbox_param(&range.bbox)?,
+  })
+}
+
+pub fn get_sensor_type(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.get_sensor_type(user.id, *path)
+  })
+}
+
+pub fn put_sensor_type(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<SensorTypeBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let item = item.into_inner();
+    let st = SensorType {
+      sensor: *path,
+      kind: item.kind,
+      dims: item.dims,
+      unit: item.unit,
+    };
+    db.set_sensor_type(user.id, &st)?;
+    Ok(st)
+  })
+}
+
+pub fn get_readings(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  query: web::Query<ReadingRange>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.reading_listing(user.id, &reading_query(*path, &query)?)
+  })
+}
+
+pub fn get_readings_geojson(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  query: web::Query<ReadingRange>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let entries = db.reading_listing(user.id, &reading_query(*path, &query)?)?;
+    Ok(geo::readings_geojson(&entries))
+  })
+}
+
+pub fn post_reading(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<ReadingBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let item = item.into_inner();
+    let measuredate = match item.measuredate {
+      Some(md) => md,
+      None => sqldata::now()?,
+    };
+    db.add_reading(
+      user.id,
+      &SaveReading {
+        sensor: *path,
+        value: item.value,
+        measuredate: measuredate,
+        position: item.position,
+      },
+    )
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// calibration and virtual sensors
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct CalibrationBody {
+  // now if missing.
+  pub effective: Option<i64>,
+  pub calibration: Option<Calibration>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct FormulaBody {
+  pub formula: Option<Formula>,
+}
+
+pub fn get_calibration(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.get_calibration(user.id, *path)
+  })
+}
+
+pub fn put_calibration(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<CalibrationBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let item = item.into_inner();
+    let sc = SensorCalibration {
+      sensor: *path,
+      effective: item.effective,
+      calibration: item.calibration,
+    };
+    db.set_calibration(user.id, &sc)?;
+    Ok(sc)
+  })
+}
+
+pub fn get_calibration_history(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.calibration_history(user.id, *path)
+  })
+}
+
+pub fn get_calibration_audit(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.calibration_audit(user.id, *path)
+  })
+}
+
+pub fn delete_calibration(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    db.delete_calibration(user.id, *path)
+  })
+}
+
+pub fn get_virtual_sensor(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.get_virtual_sensor(user.id, *path)
+  })
+}
+
+pub fn put_virtual_sensor(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<FormulaBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let vs = VirtualSensor {
+      sensor: *path,
+      formula: item.into_inner().formula,
+    };
+    db.set_virtual_sensor(user.id, &vs)?;
+    Ok(vs)
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// locations
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct LocationBody {
+  pub location: Option<Location>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct BBoxParam {
+  // minlon,minlat,maxlon,maxlat
+  pub bbox: Option<String>,
+}
+
+pub fn get_device_location(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.get_device_location(user.id, *path)
+  })
+}
+
+pub fn put_device_location(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<LocationBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let dl = DeviceLocation {
+      device: *path,
+      location: item.into_inner().location,
+    };
+    db.set_device_location(user.id, &dl)?;
+    Ok(dl)
+  })
+}
+
+// a new udp ingest token, replacing the device's old one.
+pub fn post_device_token(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    udp::make_token(&db, user.id, *path)
+  })
+}
+
+pub fn delete_device_token(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    db.set_device_token(user.id, *path, None)
+  })
+}
+
+pub fn get_devices_geojson(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  query: web::Query<BBoxParam>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let devices = db.located_devices(user.id, &bbox_param(&query.bbox)?)?;
+    Ok(geo::devices_geojson(&devices))
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// device templates
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct TemplateBody {
+  pub name: String,
+  pub description: String,
+  pub sensors: Vec<TemplateSensor>,
+}
+
+#[derive(Deserialize, Debug, JsonSchema)]
+pub struct InstantiateBody {
+  pub name: Option<String>,
+  pub description: Option<String>,
+  pub count: Option<i64>,
+  pub first: Option<i64>,
+}
+
+pub fn get_templates(db: web::Data<Db>, req: HttpRequest) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.template_listing(user.id)
+  })
+}
+
+pub fn post_template(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  item: web::Json<TemplateBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    let id = db.save_template(
+      user.id,
+      &SaveTemplate {
+        id: None,
+        name: body.name,
+        description: body.description,
+        sensors: body.sensors,
+      },
+    )?;
+    db.read_template(user.id, id)
+  })
+}
+
+pub fn get_template(db: web::Data<Db>, req: HttpRequest, path: web::Path<i64>) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    db.read_template(user.id, *path)
+  })
+}
+
+pub fn put_template(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<TemplateBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::OK, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    let id = db.save_template(
+      user.id,
+      &SaveTemplate {
+        id: Some(*path),
+        name: body.name,
+        description: body.description,
+        sensors: body.sensors,
+      },
+    )?;
+    db.read_template(user.id, id)
+  })
+}
+
+pub fn delete_template(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::NO_CONTENT, move || {
+    let user = authed_user(&db, creds)?;
+    // 404 if the template doesn't exist or isn't ours.
+    db.read_template(user.id, *path)?;
+    db.delete_template(user.id, *path)
+  })
+}
+
+pub fn post_instantiate(
+  db: web::Data<Db>,
+  req: HttpRequest,
+  path: web::Path<i64>,
+  item: web::Json<InstantiateBody>,
+) -> FutureResponse {
+  let creds = basic_auth(&req);
+  reply(StatusCode::CREATED, move || {
+    let user = authed_user(&db, creds)?;
+    let body = item.into_inner();
+    db.instantiate_template(
+      user.id,
+      &Instantiate {
+        template: *path,
+        name: body.name,
+        description: body.description,
+        count: body.count,
+        first: body.first,
+      },
+    )
+  })
+}
@@ -0,0 +1,251 @@
+use actix_web::web;
+use error::Error;
+use schemars::JsonSchema;
+use sqldata;
+use std::thread;
+use std::time::Duration;
+use storage::{Db, Storage};
+
+pub const HOUR: i64 = 3_600_000;
+pub const DAY: i64 = 24 * HOUR;
+
+// global retention settings, from the config.  Days are counted back from
+// now; a missing setting means keep forever.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+  pub rawdays: Option<i64>,
+  pub hourlydays: Option<i64>,
+  pub dailydays: Option<i64>,
+  pub interval_secs: Option<u64>,
+  pub batch_size: Option<i64>,
+}
+
+// per-sensor overrides of the global settings.
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SensorRetention {
+  pub sensor: i64,
+  pub rawdays: Option<i64>,
+  pub hourlydays: Option<i64>,
+  pub dailydays: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Rollup {
+  Hourly,
+  Daily,
+}
+
+impl Rollup {
+  pub fn table(&self) -> &'static str {
+    match self {
+      Rollup::Hourly => "measurement_hourly",
+      Rollup::Daily => "measurement_daily",
+    }
+  }
+  pub fn period(&self) -> i64 {
+    match self {
+      Rollup::Hourly => HOUR,
+      Rollup::Daily => DAY,
+    }
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct RollupQuery {
+  pub sensor: i64,
+  pub rollup: Rollup,
+  pub from: Option<i64>,
+  pub to: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct RollupEntry {
+  pub sensor: i64,
+  pub period: i64,
+  pub samples: i64,
+  pub mean: f64,
+  pub min: f64,
+  pub max: f64,
+}
+
+// delete up to batch_size rows at a time until there are none left, so
+// ingestion only ever waits on one small delete.
+fn prune<F>(batch_size: i64, mut f: F) -> Result<i64, Error>
+where
+  F: FnMut(i64) -> Result<i64, Error>,
+{
+  let mut total = 0;
+  loop {
+    let count = f(batch_size)?;
+    total += count;
+    if count < batch_size {
+      return Ok(total);
+    }
+  }
+}
+
+// a sensor's settings, with the global ones where it has none.  None if
+// everything is kept forever.
+fn in_force(sr: &SensorRetention, config: &RetentionConfig) -> Option<SensorRetention> {
+  let sr = SensorRetention {
+    sensor: sr.sensor,
+    rawdays: sr.rawdays.or(config.rawdays),
+    hourlydays: sr.hourlydays.or(config.hourlydays),
+    dailydays: sr.dailydays.or(config.dailydays),
+  };
+  if sr.rawdays.is_none() && sr.hourlydays.is_none() && sr.dailydays.is_none() {
+    None
+  } else {
+    Some(sr)
+  }
+}
+
+// roll up and prune expired data for every sensor.
+pub fn enforce(db: &dyn Storage, config: &RetentionConfig) -> Result<(), Error> {
+  let now = sqldata::now()?;
+  let batch_size = config.batch_size.unwrap_or(1000);
+
+  for sr in db.sensor_retentions()? {
+    let sr = match in_force(&sr, config) {
+      Some(sr) => sr,
+      None => continue,
+    };
+    match sr.rawdays {
+      Some(rawdays) => {
+        let cutoff = (now - rawdays * DAY) / DAY * DAY;
+
+        // each batch is added to the rollups and deleted together, so every
+        // measurement older than the cutoff is counted exactly once, including
+        // ones that arrive late, from spools, imports or devices' clocks.
+        let count = prune(batch_size, |limit| {
+          db.rollup_measurements(sr.sensor, cutoff, limit)
+        })?;
+        if count > 0 {
+          info!(
+            "sensor {}: rolled up and pruned {} measurements",
+            sr.sensor, count
+          );
+        }
+      }
+      None => (),
+    }
+
+    for (rollup, days) in [
+      (Rollup::Hourly, sr.hourlydays),
+      (Rollup::Daily, sr.dailydays),
+    ]
+    .iter()
+    {
+      match days {
+        Some(days) => {
+          let before = now - days * DAY;
+          let count = prune(batch_size, |limit| {
+            db.prune_rollups(*rollup, sr.sensor, before, limit)
+          })?;
+          if count > 0 {
+            info!(
+              "sensor {}: pruned {} {} rows",
+              sr.sensor,
+              count,
+              rollup.table()
+            );
+          }
+        }
+        None => (),
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// run the retention job periodically in a background thread.
+pub fn start(db: web::Data<Db>, config: RetentionConfig) {
+  let interval = Duration::from_secs(config.interval_secs.unwrap_or(3600));
+  thread::spawn(move || loop {
+    match enforce(&**db, &config) {
+      Ok(()) => (),
+      Err(e) => error!("retention job error: {:?}", e),
+    }
+    thread::sleep(interval);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use sciota_protocol::protocol::SaveMeasurement;
+  use storage_tests::{device, sensor, sqlite, user};
+
+  fn config(rawdays: Option<i64>) -> RetentionConfig {
+    RetentionConfig {
+      rawdays,
+      hourlydays: None,
+      dailydays: None,
+      interval_secs: None,
+      batch_size: Some(2),
+    }
+  }
+
+  fn retention(sensor: i64, rawdays: Option<i64>, dailydays: Option<i64>) -> SensorRetention {
+    SensorRetention {
+      sensor,
+      rawdays,
+      hourlydays: None,
+      dailydays,
+    }
+  }
+
+  #[test]
+  fn sensor_settings_override_global_ones() {
+    let none = retention(1, None, None);
+    assert!(in_force(&none, &config(None)).is_none());
+
+    let global = in_force(&none, &config(Some(30))).unwrap();
+    assert_eq!((global.rawdays, global.dailydays), (Some(30), None));
+
+    let own = in_force(&retention(1, Some(7), Some(365)), &config(Some(30))).unwrap();
+    assert_eq!((own.rawdays, own.dailydays), (Some(7), Some(365)));
+
+    let daily = in_force(&retention(1, None, Some(365)), &config(None)).unwrap();
+    assert_eq!((daily.rawdays, daily.dailydays), (None, Some(365)));
+  }
+
+  #[test]
+  fn enforce_skips_sensors_without_retention() {
+    let (_tmp, db) = sqlite();
+    let u = user(&db);
+    let dev = device(&db, u.id, "dev");
+    let kept = sensor(&db, u.id, dev, "kept");
+    let pruned = sensor(&db, u.id, dev, "pruned");
+    db.set_retention(u.id, &retention(pruned, Some(1), None))
+      .unwrap();
+    let old = (0..5)
+      .flat_map(|i| {
+        vec![
+          SaveMeasurement {
+            value: 1.0,
+            sensor: kept,
+            measuredate: i * HOUR,
+          },
+          SaveMeasurement {
+            value: 1.0,
+            sensor: pruned,
+            measuredate: i * HOUR,
+          },
+        ]
+      })
+      .collect::<Vec<SaveMeasurement>>();
+    db.add_measurements(u.id, &old).unwrap();
+    let count = |s| db.measurement_listing(u.id, s, None, None).unwrap().len();
+
+    // in batches of 2, until there are none.
+    enforce(&db, &config(None)).unwrap();
+    assert_eq!((count(kept), count(pruned)), (5, 0));
+
+    enforce(&db, &config(Some(1))).unwrap();
+    assert_eq!(count(kept), 0);
+  }
+}
@@ -1,17 +1,46 @@
+use archive;
+use archive::{
+  check_version, Archive, ArchiveCalibration, ArchiveDevice, ArchiveMeasurement, ArchiveRollup,
+  ArchiveSensor, ImportSummary, ARCHIVE_VERSION,
+};
 use barrel::backend::Sqlite;
+use barrel::types::Type;
 use barrel::{types, Migration};
-use rusqlite::{params, Connection};
+use calibration;
+use calibration::{
+  Calibration, CalibrationAudit, CalibrationRecord, Formula, SensorCalibration, VirtualSensor,
+};
+use error::Error;
+use geo;
+use geo::{BBox, DeviceLocation, LocatedDevice, Location};
+use influx;
+use influx::NamedMeasurement;
+use logging;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use readings;
+use readings::{Reading, ReadingQuery, SaveReading, SensorType, ValueKind};
+use retention::{Rollup, RollupEntry, RollupQuery, SensorRetention};
+use rusqlite::backup::Backup;
+use rusqlite::{params, Connection, TransactionBehavior};
+use schemars::JsonSchema;
 use sciota_protocol::protocol::{
   Device, Measurement, MeasurementQuery, PublicMessage, RegistrationData, SaveDevice,
   SaveMeasurement, SaveSensor, Sensor, ServerResponse, UserMessage,
 };
 use serde_json;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
-use std::error::Error;
+use std::fmt;
 use std::path::Path;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+use templates;
+use templates::{
+  DeviceTemplate, Instantiate, InstantiatedDevice, InstantiatedSensor, SaveTemplate, TemplateSensor,
+};
+use udp::TokenDevice;
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize)]
 pub struct User {
   pub id: i64,
   pub name: String,
@@ -19,8 +48,44 @@ pub struct User {
   pub salt: String,
   pub email: String,
   pub registration_key: Option<String>,
+  pub admin: bool,
+  pub disabled: bool,
+}
+
+// without the password hash, salt or registration key.
+impl fmt::Debug for User {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("User")
+      .field("id", &self.id)
+      .field("name", &self.name)
+      .field("hashwd", &logging::MASK)
+      .field("salt", &logging::MASK)
+      .field("email", &self.email)
+      .field(
+        "registration_key",
+        &self.registration_key.as_ref().map(|_| logging::MASK),
+      )
+      .field("admin", &self.admin)
+      .field("disabled", &self.disabled)
+      .finish()
+  }
+}
+
+#[derive(Deserialize, Serialize, Debug, JsonSchema)]
+pub struct UserListEntry {
+  pub id: i64,
+  pub name: String,
+  pub email: String,
+  pub registered: bool,
+  pub admin: bool,
+  pub disabled: bool,
+  pub createdate: i64,
+  pub devices: i64,
+  pub measurements: i64,
 }
 
+pub type DbPool = Pool<SqliteConnectionManager>;
+
 // use this to open connections so we'll get foreign key checks
 pub fn connection_open(dbfile: &Path) -> rusqlite::Result<Connection> {
   let conn = Connection::open(dbfile)?;
@@ -30,76 +95,99 @@ pub fn connection_open(dbfile: &Path) -> rusqlite::Result<Connection> {
   Ok(conn)
 }
 
-pub fn initialdb() -> Migration {
+// the shared connection pool.  Every pooled connection gets foreign key checks
+// and a busy timeout; the database itself is switched to WAL mode so readers
+// don't block the writer.
+pub fn connection_pool(
+  dbfile: &Path,
+  pool_size: u32,
+  busy_timeout: Duration,
+) -> Result<DbPool, Error> {
+  let conn = connection_open(dbfile)?;
+  conn.query_row("PRAGMA journal_mode = WAL;", params![], |row| {
+    row.get::<_, String>(0)
+  })?;
+
+  let manager = SqliteConnectionManager::file(dbfile).with_init(move |c| {
+    c.execute_batch("PRAGMA foreign_keys = true;")?;
+    c.busy_timeout(busy_timeout)
+  });
+
+  Ok(Pool::builder().max_size(pool_size).build(manager)?)
+}
+
+// column types that differ between the backends.  Dates are ms since the
+// epoch and ids are i64s: sqlite's INTEGER holds them, but postgres needs
+// BIGINT.  The barrel migrations below use these, so they can make either
+// backend's schema; see pgdata::dbinit.
+pub trait Columns {
+  fn id() -> Type;
+  fn int() -> Type;
+  fn float() -> Type;
+  // a reference to 'table's id.
+  fn reference(table: &'static str) -> Type;
+}
+
+pub struct SqliteColumns;
+
+impl Columns for SqliteColumns {
+  fn id() -> Type {
+    types::integer().primary(true).increments(true)
+  }
+  fn int() -> Type {
+    types::integer()
+  }
+  fn float() -> Type {
+    types::float()
+  }
+  fn reference(table: &'static str) -> Type {
+    types::foreign(table, "id")
+  }
+}
+
+pub fn initialdb<C: Columns>() -> Migration {
   let mut m = Migration::new();
 
   m.create_table("user", |t| {
-    t.add_column(
-      "id",
-      types::integer()
-        .primary(true)
-        .increments(true)
-        .nullable(false),
-    );
+    t.add_column("id", C::id().nullable(false));
     t.add_column("name", types::text().nullable(false).unique(true));
     t.add_column("hashwd", types::text().nullable(false));
     t.add_column("salt", types::text().nullable(false));
     t.add_column("email", types::text().nullable(false));
     t.add_column("registration_key", types::text().nullable(true));
-    t.add_column("createdate", types::integer().nullable(false));
+    t.add_column("createdate", C::int().nullable(false));
   });
 
   m.create_table("device", |t| {
-    t.add_column(
-      "id",
-      types::integer()
-        .primary(true)
-        .increments(true)
-        .nullable(false),
-    );
-    t.add_column("user", types::foreign("user", "id").nullable(false));
+    t.add_column("id", C::id().nullable(false));
+    t.add_column("user", C::reference("user").nullable(false));
     t.add_column("name", types::text().nullable(false));
     t.add_column("description", types::text().nullable(false));
-    t.add_column("createdate", types::integer().nullable(false));
-    t.add_column("changeddate", types::integer().nullable(false));
+    t.add_column("createdate", C::int().nullable(false));
+    t.add_column("changeddate", C::int().nullable(false));
   });
 
   m.create_table("sensor", |t| {
-    t.add_column(
-      "id",
-      types::integer()
-        .primary(true)
-        .increments(true)
-        .nullable(false),
-    );
-    t.add_column(
-      "device",
-      types::foreign("device", vec!["id"]).nullable(false),
-    );
+    t.add_column("id", C::id().nullable(false));
+    t.add_column("device", C::reference("device").nullable(false));
     t.add_column("name", types::text().nullable(false));
     t.add_column("description", types::text().nullable(false));
-    t.add_column("createdate", types::integer().nullable(false));
-    t.add_column("changeddate", types::integer().nullable(false));
+    t.add_column("createdate", C::int().nullable(false));
+    t.add_column("changeddate", C::int().nullable(false));
   });
 
   m.create_table("measurement", |t| {
-    t.add_column(
-      "id",
-      types::integer()
-        .primary(true)
-        .increments(true)
-        .nullable(false),
-    );
-    t.add_column("sensor", types::foreign("sensor", "id").nullable(false));
-    t.add_column("value", types::float().nullable(false));
-    t.add_column("measuredate", types::integer().nullable(false));
-    t.add_column("createdate", types::integer().nullable(false));
+    t.add_column("id", C::id().nullable(false));
+    t.add_column("sensor", C::reference("sensor").nullable(false));
+    t.add_column("value", C::float().nullable(false));
+    t.add_column("measuredate", C::int().nullable(false));
+    t.add_column("createdate", C::int().nullable(false));
   });
 
   m
 }
 
-pub fn update1() -> Migration {
+pub fn update1<C: Columns>() -> Migration {
   let mut m = Migration::new();
 
   // table for storing single values.  We'll store our migration level here.
@@ -110,7 +198,241 @@ pub fn update1() -> Migration {
 
   m
 }
-pub fn get_single_value(conn: &Connection, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+
+pub fn update2<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // admin role, and a switch for locking out misbehaving accounts.
+  m.change_table("user", |t| {
+    t.add_column("admin", types::boolean().nullable(false).default(false));
+    t.add_column("disabled", types::boolean().nullable(false).default(false));
+  });
+
+  m
+}
+
+pub fn update3<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // per-sensor retention overrides.
+  m.create_table("retention", |t| {
+    t.add_column(
+      "sensor",
+      C::reference("sensor").nullable(false).unique(true),
+    );
+    t.add_column("rawdays", C::int().nullable(true));
+    t.add_column("hourlydays", C::int().nullable(true));
+    t.add_column("dailydays", C::int().nullable(true));
+  });
+
+  // measurements before 'rolledupto' have been added to the rollup tables.
+  m.create_table("rollup", |t| {
+    t.add_column(
+      "sensor",
+      C::reference("sensor").nullable(false).unique(true),
+    );
+    t.add_column("rolledupto", C::int().nullable(false));
+  });
+
+  for table in ["measurement_hourly", "measurement_daily"].iter() {
+    m.create_table(*table, |t| {
+      t.add_column("sensor", C::reference("sensor").nullable(false));
+      t.add_column("period", C::int().nullable(false));
+      t.add_column("samples", C::int().nullable(false));
+      t.add_column("total", C::float().nullable(false));
+      t.add_column("minimum", C::float().nullable(false));
+      t.add_column("maximum", C::float().nullable(false));
+    });
+  }
+
+  m
+}
+
+// barrel doesn't do multi column indexes.
+pub const UPDATE3_INDEXES: &str = "
+  CREATE UNIQUE INDEX measurement_hourly_sensor_period ON measurement_hourly (sensor, period);
+  CREATE UNIQUE INDEX measurement_daily_sensor_period ON measurement_daily (sensor, period);";
+
+// indexes for the per-sensor, per-device and per-user lookups that every
+// listing and ownership check does.  (sensor, measuredate) also serves plain
+// 'sensor = ?' lookups.
+const UPDATE4: &str = "
+  CREATE INDEX measurement_sensor_measuredate ON measurement (sensor, measuredate);
+  CREATE INDEX sensor_device ON sensor (device);
+  CREATE INDEX device_user ON device (user);";
+
+pub fn update5<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // the kind of value each sensor produces; see readings.rs.  No row means
+  // 'float'.
+  m.create_table("sensortype", |t| {
+    t.add_column(
+      "sensor",
+      C::reference("sensor").nullable(false).unique(true),
+    );
+    t.add_column("kind", types::text().nullable(false));
+    t.add_column("dims", C::int().nullable(true));
+  });
+
+  // json for readings that don't fit in 'value'.
+  m.change_table("measurement", |t| {
+    t.add_column("payload", types::text().nullable(true));
+  });
+
+  m
+}
+
+pub fn update6<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // fixed device locations.  Per-reading positions go in measurement.
+  m.create_table("devicelocation", |t| {
+    t.add_column(
+      "device",
+      C::reference("device").nullable(false).unique(true),
+    );
+    t.add_column("lat", C::float().nullable(false));
+    t.add_column("lon", C::float().nullable(false));
+    t.add_column("elevation", C::float().nullable(true));
+  });
+
+  m
+}
+
+// sqlite adds one column per ALTER TABLE.
+const UPDATE6_POSITIONS: &str = "
+  ALTER TABLE measurement ADD COLUMN lat REAL;
+  ALTER TABLE measurement ADD COLUMN lon REAL;
+  ALTER TABLE measurement ADD COLUMN elevation REAL;";
+
+pub fn update7<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // device templates; see templates.rs.
+  m.create_table("devicetemplate", |t| {
+    t.add_column("id", C::id().nullable(false));
+    t.add_column("user", C::reference("user").nullable(false));
+    t.add_column("name", types::text().nullable(false));
+    t.add_column("description", types::text().nullable(false));
+    t.add_column("createdate", C::int().nullable(false));
+    t.add_column("changeddate", C::int().nullable(false));
+  });
+
+  // a template's sensors, in 'seq' order.
+  m.create_table("templatesensor", |t| {
+    t.add_column("template", C::reference("devicetemplate").nullable(false));
+    t.add_column("seq", C::int().nullable(false));
+    t.add_column("name", types::text().nullable(false));
+    t.add_column("description", types::text().nullable(false));
+    t.add_column("unit", types::text().nullable(true));
+    t.add_column("kind", types::text().nullable(true));
+    t.add_column("dims", C::int().nullable(true));
+  });
+
+  // units of measure.
+  m.change_table("sensortype", |t| {
+    t.add_column("unit", types::text().nullable(true));
+  });
+
+  m
+}
+
+pub fn update8<C: Columns>() -> Migration {
+  let mut m = Migration::new();
+
+  // calibrations and virtual sensors; see calibration.rs.  The calibration is
+  // stored as json.
+  m.create_table("calibration", |t| {
+    t.add_column(
+      "sensor",
+      C::reference("sensor").nullable(false).unique(true),
+    );
+    t.add_column("transform", types::text().nullable(false));
+  });
+
+  m.create_table("virtualsensor", |t| {
+    t.add_column(
+      "sensor",
+      C::reference("sensor").nullable(false).unique(true),
+    );
+    t.add_column("expression", types::text().nullable(false));
+  });
+
+  // a virtual sensor's inputs, by name.
+  m.create_table("virtualinput", |t| {
+    t.add_column("sensor", C::reference("sensor").nullable(false));
+    t.add_column("name", types::text().nullable(false));
+    t.add_column("input", C::reference("sensor").nullable(false));
+  });
+
+  m
+}
+
+// calibration histories and their audit trail, replacing the single
+// calibration per sensor; see calibration.rs.  Existing calibrations are kept
+// as effective from the start, so listings don't change.
+const UPDATE9: &str = "
+  CREATE TABLE calibrationrecord (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    sensor INTEGER NOT NULL REFERENCES sensor(id),
+    effective INTEGER NOT NULL,
+    transform TEXT,
+    createdate INTEGER NOT NULL,
+    user INTEGER NOT NULL REFERENCES user(id)
+  );
+  CREATE UNIQUE INDEX calibrationrecord_sensor_effective
+    ON calibrationrecord (sensor, effective);
+
+  CREATE TABLE calibrationaudit (
+    sensor INTEGER NOT NULL REFERENCES sensor(id),
+    user INTEGER NOT NULL REFERENCES user(id),
+    action TEXT NOT NULL,
+    effective INTEGER NOT NULL,
+    transform TEXT,
+    changedate INTEGER NOT NULL
+  );
+  CREATE INDEX calibrationaudit_sensor ON calibrationaudit (sensor);
+
+  INSERT INTO calibrationrecord (sensor, effective, transform, createdate, user)
+    SELECT calibration.sensor, -9223372036854775808, calibration.transform,
+      CAST(strftime('%s', 'now') AS INTEGER) * 1000, device.user
+    FROM calibration, sensor, device
+    WHERE calibration.sensor = sensor.id AND sensor.device = device.id;
+
+  DROP TABLE calibration;";
+
+// device tokens for udp ingest; see udp.rs.  One per device.
+const UPDATE10: &str = "
+  CREATE TABLE devicetoken (
+    device INTEGER NOT NULL UNIQUE REFERENCES device(id),
+    tokenhash TEXT NOT NULL UNIQUE,
+    createdate INTEGER NOT NULL
+  );";
+
+// udp sensor indexes, fixed when a sensor gets one so deleting a sensor
+// doesn't shift the others.  New sensors take the device's nextidx, and
+// indexes aren't reused.
+const UPDATE11: &str = "
+  ALTER TABLE devicetoken ADD COLUMN nextidx INTEGER NOT NULL DEFAULT 0;
+
+  CREATE TABLE tokensensor (
+    device INTEGER NOT NULL REFERENCES device(id),
+    idx INTEGER NOT NULL,
+    sensor INTEGER NOT NULL UNIQUE REFERENCES sensor(id),
+    UNIQUE (device, idx)
+  );
+
+  INSERT INTO tokensensor (device, idx, sensor)
+    SELECT sensor.device,
+      (SELECT count(*) FROM sensor s WHERE s.device = sensor.device AND s.id < sensor.id),
+      sensor.id
+    FROM sensor WHERE sensor.device IN (SELECT device FROM devicetoken);
+
+  UPDATE devicetoken
+    SET nextidx = (SELECT count(*) FROM sensor WHERE sensor.device = devicetoken.device);";
+
+pub fn get_single_value(conn: &Connection, name: &str) -> Result<Option<String>, Error> {
   match conn.query_row(
     "select value from singlevalue where name = ?1",
     params![name],
@@ -118,11 +440,11 @@ pub fn get_single_value(conn: &Connection, name: &str) -> Result<Option<String>,
   ) {
     Ok(v) => Ok(Some(v)),
     Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-    Err(x) => Err(Box::new(x)),
+    Err(x) => Err(x.into()),
   }
 }
 
-pub fn set_single_value(conn: &Connection, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+pub fn set_single_value(conn: &Connection, name: &str, value: &str) -> Result<(), Error> {
   match get_single_value(conn, name) {
     Ok(None) => {
       conn.execute(
@@ -146,7 +468,7 @@ pub fn set_single_value(conn: &Connection, name: &str, value: &str) -> Result<()
   }
 }
 
-pub fn now() -> Result<i64, Box<dyn Error>> {
+pub fn now() -> Result<i64, Error> {
   let nowsecs = SystemTime::now()
     .duration_since(SystemTime::UNIX_EPOCH)
     .map(|n| n.as_millis())?;
@@ -154,58 +476,157 @@ pub fn now() -> Result<i64, Box<dyn Error>> {
   Ok(s)
 }
 
-pub fn dbinit(dbfile: &Path) -> Result<(), Box<dyn Error>> {
-  let exists = dbfile.exists();
+// the schema version dbinit brings a database up to.
+pub const MIGRATION_LEVEL: i64 = 11;
 
-  let conn = connection_open(dbfile)?;
+// the sql for each migration level after the first, from 2 on.
+fn migrations() -> Vec<Vec<String>> {
+  vec![
+    vec![update2::<SqliteColumns>().make::<Sqlite>()],
+    vec![
+      update3::<SqliteColumns>().make::<Sqlite>(),
+      UPDATE3_INDEXES.to_string(),
+    ],
+    vec![UPDATE4.to_string()],
+    vec![update5::<SqliteColumns>().make::<Sqlite>()],
+    vec![
+      update6::<SqliteColumns>().make::<Sqlite>(),
+      UPDATE6_POSITIONS.to_string(),
+    ],
+    vec![update7::<SqliteColumns>().make::<Sqlite>()],
+    vec![update8::<SqliteColumns>().make::<Sqlite>()],
+    vec![UPDATE9.to_string()],
+    vec![UPDATE10.to_string()],
+    vec![UPDATE11.to_string()],
+  ]
+}
 
-  if !exists {
-    println!("initialdb");
-    conn.execute_batch(initialdb().make::<Sqlite>().as_str())?;
+// run a migration level's sql and record the level in one transaction, so a
+// migration that fails leaves the database at the level before it.
+fn migrate(conn: &mut Connection, level: i64, sql: &[String]) -> Result<(), Error> {
+  info!("update{}", level);
+  let tx = conn.transaction()?;
+  for s in sql.iter() {
+    tx.execute_batch(s.as_str())?;
   }
+  set_single_value(&tx, "migration_level", level.to_string().as_str())?;
+  tx.commit()?;
+  Ok(())
+}
+
+pub fn dbinit(dbfile: &Path) -> Result<(), Error> {
+  let exists = dbfile.exists();
 
-  match get_single_value(&conn, "migration_level") {
-    Err(_) => {
-      println!("update1");
-      conn.execute_batch(update1().make::<Sqlite>().as_str())?;
-      set_single_value(&conn, "migration_level", "1")?;
+  let mut conn = connection_open(dbfile)?;
+
+  let level = if !exists {
+    info!("initialdb");
+    migrate(
+      &mut conn,
+      1,
+      &[
+        initialdb::<SqliteColumns>().make::<Sqlite>(),
+        update1::<SqliteColumns>().make::<Sqlite>(),
+      ],
+    )?;
+    1
+  } else {
+    match get_single_value(&conn, "migration_level") {
+      Err(_) => {
+        migrate(&mut conn, 1, &[update1::<SqliteColumns>().make::<Sqlite>()])?;
+        1
+      }
+      Ok(None) => 0,
+      Ok(Some(level)) => level.parse::<i64>()?,
     }
-    Ok(_) => {
-      // nothing beyond update1 yet.
+  };
+
+  for (i, step) in migrations().iter().enumerate() {
+    let to = i as i64 + 2;
+    if level < to {
+      migrate(&mut conn, to, step)?;
     }
   }
-  println!("db up to date.");
+  info!("db up to date.");
+
+  Ok(())
+}
+
+// copy the database to 'dest' with sqlite's online backup api, which doesn't
+// block other connections while it runs.
+pub fn backup(pool: &DbPool, dest: &Path) -> Result<(), Error> {
+  let conn = pool.get()?;
+  let mut dst = Connection::open(dest)?;
 
-  // conn.execute_batch(initialdb().make::<Sqlite>().as_str());
+  let backup = Backup::new(&conn, &mut dst)?;
+  backup.run_to_completion(1024, Duration::from_millis(10), None)?;
 
   Ok(())
 }
 
+pub fn migration_level(pool: &DbPool) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  match get_single_value(&conn, "migration_level")? {
+    Some(level) => Ok(level.parse::<i64>()?),
+    None => Ok(0),
+  }
+}
+
+// sqlite's integrity and foreign key checks.
+pub fn integrity_check(pool: &DbPool) -> Result<Vec<String>, Error> {
+  let conn = pool.get()?;
+
+  let mut problems = Vec::new();
+
+  let mut pstmt = conn.prepare("PRAGMA integrity_check")?;
+  let rec_iter = pstmt.query_map(params![], |row| row.get::<_, String>(0))?;
+  for rsrec in rec_iter {
+    let rec = rsrec?;
+    if rec != "ok" {
+      problems.push(rec);
+    }
+  }
+
+  let mut pstmt = conn.prepare("PRAGMA foreign_key_check")?;
+  let rec_iter = pstmt.query_map(params![], |row| {
+    Ok(format!(
+      "{} row {} refers to a missing {}",
+      row.get::<_, String>(0)?,
+      row.get::<_, Option<i64>>(1)?.unwrap_or(0),
+      row.get::<_, String>(2)?
+    ))
+  })?;
+  for rsrec in rec_iter {
+    problems.push(rsrec?);
+  }
+
+  Ok(problems)
+}
+
 // --------------------------------------------------------------------------------------
 // user CRUD
 
-pub fn add_user(dbfile: &Path, name: &str, hashwd: &str) -> Result<i64, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+pub fn add_user(pool: &DbPool, name: &str, hashwd: &str) -> Result<i64, Error> {
+  let conn = pool.get()?;
 
   let nowi64secs = now()?;
 
-  println!("adding user: {}", name);
-  let wat = conn.execute(
+  info!("adding user: {}", name);
+  conn.execute(
     "INSERT INTO user (name, hashwd, createdate)
                 VALUES (?1, ?2, ?3)",
     params![name, hashwd, nowi64secs],
   )?;
 
-  println!("wat: {}", wat);
-
   Ok(conn.last_insert_rowid())
 }
 
-pub fn read_user(dbfile: &Path, name: &str) -> Result<User, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+pub fn read_user(pool: &DbPool, name: &str) -> Result<User, Error> {
+  let conn = pool.get()?;
 
   let user = conn.query_row(
-    "SELECT id, hashwd, salt, email, registration_key
+    "SELECT id, hashwd, salt, email, registration_key, admin, disabled
       FROM user WHERE name = ?1",
     params![name],
     |row| {
@@ -216,6 +637,8 @@ pub fn read_user(dbfile: &Path, name: &str) -> Result<User, Box<dyn Error>> {
         salt: row.get(2)?,
         email: row.get(3)?,
         registration_key: row.get(4)?,
+        admin: row.get(5)?,
+        disabled: row.get(6)?,
       })
     },
   )?;
@@ -223,18 +646,21 @@ pub fn read_user(dbfile: &Path, name: &str) -> Result<User, Box<dyn Error>> {
   Ok(user)
 }
 
-pub fn update_user(dbfile: &Path, user: &User) -> Result<(), Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+pub fn update_user(pool: &DbPool, user: &User) -> Result<(), Error> {
+  let conn = pool.get()?;
 
   conn.execute(
-    "UPDATE user SET name = ?1, hashwd = ?2, salt = ?3, email = ?4, registration_key = ?5
-     WHERE id = ?6",
+    "UPDATE user SET name = ?1, hashwd = ?2, salt = ?3, email = ?4, registration_key = ?5,
+       admin = ?6, disabled = ?7
+     WHERE id = ?8",
     params![
       user.name,
       user.hashwd,
       user.salt,
       user.email,
       user.registration_key,
+      user.admin,
+      user.disabled,
       user.id
     ],
   )?;
@@ -243,14 +669,14 @@ pub fn update_user(dbfile: &Path, user: &User) -> Result<(), Box<dyn Error>> {
 }
 
 pub fn new_user(
-  dbfile: &Path,
+  pool: &DbPool,
   name: String,
   hashwd: String,
   salt: String,
   email: String,
   registration_key: String,
-) -> Result<i64, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
 
   let now = now()?;
 
@@ -263,95 +689,54 @@ pub fn new_user(
   Ok(conn.last_insert_rowid())
 }
 
-// --------------------------------------------------------------------------------------
-// device CRUD
-
-pub fn save_device(
-  dbfile: &Path,
-  uid: i64,
-  savedevice: &SaveDevice,
-) -> Result<i64, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+// a user with no registration key, so registered already.
+pub fn new_registered_user(
+  pool: &DbPool,
+  name: String,
+  hashwd: String,
+  salt: String,
+  email: String,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
 
   let now = now()?;
 
-  match savedevice.id {
-    Some(id) => {
-      println!("updating device: {}", savedevice.name);
-
-      // TODO ensure user auth here.
-
-      conn.execute(
-        "UPDATE device SET name = ?1, description = ?2, changeddate = ?3
-         WHERE id = ?4",
-        params![savedevice.name, savedevice.description, now, savedevice.id],
-      )?;
-      Ok(id)
-    }
-    None => {
-      println!("adding device: {}", savedevice.name);
-      conn.execute(
-        "INSERT INTO device (name, user, description, createdate, changeddate)
-         VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![savedevice.name, uid, savedevice.description, now, now],
-      )?;
-
-      Ok(conn.last_insert_rowid())
-    }
-  }
-}
-
-pub fn read_device(dbfile: &Path, id: i64) -> Result<Device, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
-
-  let rbe = conn.query_row(
-    "SELECT name, description, createdate, changeddate
-      FROM device WHERE id = ?1",
-    params![id],
-    |row| {
-      Ok(Device {
-        id: id,
-        name: row.get(0)?,
-        description: row.get(1)?,
-        user: row.get(2)?,
-        createdate: row.get(3)?,
-        changeddate: row.get(4)?,
-      })
-    },
+  conn.execute(
+    "INSERT INTO user (name, hashwd, salt, email, createdate)
+      VALUES (?1, ?2, ?3, ?4, ?5)",
+    params![name, hashwd, salt, email, now],
   )?;
 
-  Ok(rbe)
+  Ok(conn.last_insert_rowid())
 }
 
-pub fn delete_device(dbfile: &Path, uid: i64, id: i64) -> Result<(), Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
-
-  // TODO: delete all sensors from this device also.
-  // only delete when user is in the device
-  conn.execute(
-    "DELETE FROM device WHERE id = ?1 and user = ?2",
-    params![id, uid],
-  )?;
-  Ok(())
-}
+// --------------------------------------------------------------------------------------
+// user admin
 
-pub fn devicelisting(dbfile: &Path, user: i64) -> rusqlite::Result<Vec<Device>> {
-  let conn = connection_open(dbfile)?;
+pub fn user_listing(pool: &DbPool) -> Result<Vec<UserListEntry>, Error> {
+  let conn = pool.get()?;
 
   let mut pstmt = conn.prepare(
-    "SELECT id, name, description, user, createdate, changeddate
-      FROM device
-      where device.user = ?1",
+    "SELECT user.id, user.name, user.email, user.registration_key IS NULL,
+       user.admin, user.disabled, user.createdate,
+       (SELECT count(*) FROM device WHERE device.user = user.id),
+       (SELECT count(*) FROM measurement WHERE measurement.sensor IN
+         (SELECT sensor.id FROM sensor, device
+           WHERE sensor.device = device.id AND device.user = user.id))
+      FROM user ORDER BY user.name",
   )?;
 
-  let rec_iter = pstmt.query_map(params![user], |row| {
-    Ok(Device {
+  let rec_iter = pstmt.query_map(params![], |row| {
+    Ok(UserListEntry {
       id: row.get(0)?,
       name: row.get(1)?,
-      description: row.get(2)?,
-      user: row.get(3)?,
-      createdate: row.get(4)?,
-      changeddate: row.get(5)?,
+      email: row.get(2)?,
+      registered: row.get(3)?,
+      admin: row.get(4)?,
+      disabled: row.get(5)?,
+      createdate: row.get(6)?,
+      devices: row.get(7)?,
+      measurements: row.get(8)?,
     })
   })?;
 
@@ -369,60 +754,388 @@ pub fn devicelisting(dbfile: &Path, user: i64) -> rusqlite::Result<Vec<Device>>
   Ok(pv)
 }
 
-// --------------------------------------------------------------------------------------
-// sensor CRUD
+// sync the admin flag with the list of admin names from the config.
+pub fn set_admins(pool: &DbPool, admins: &Vec<String>) -> Result<(), Error> {
+  let conn = pool.get()?;
 
-pub fn save_sensor(dbfile: &Path, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+  conn.execute("UPDATE user SET admin = 0", params![])?;
+  for name in admins {
+    conn.execute("UPDATE user SET admin = 1 WHERE name = ?1", params![name])?;
+  }
 
-  let now = now()?;
+  Ok(())
+}
 
-  // TODO ensure user auth here. does user own the device?
+pub fn set_user_disabled(pool: &DbPool, name: &str, disabled: bool) -> Result<(), Error> {
+  let conn = pool.get()?;
 
-  match sensor.id {
-    Some(id) => {
-      println!("updating sensor: {}", sensor.name);
-      conn.execute(
-        "UPDATE sensor SET name = ?1, description = ?2, changeddate = ?3
-         WHERE id = ?4",
-        params![sensor.name, sensor.description, now, sensor.id],
-      )?;
-      Ok(Sensor {
-        id: id,
-        device: sensor.device.clone(),
-        name: sensor.name.clone(),
-        description: sensor.description.clone(),
-        createdate: now, // TODO: actual createdate
-        changeddate: now,
-      })
-    }
-    None => {
-      println!("adding sensor: {}", sensor.name);
-      conn.execute(
-        "INSERT INTO sensor (name, device, description, createdate, changeddate)
-         VALUES (?1, ?2, ?3, ?4, ?5)",
-        params![sensor.name, sensor.device, sensor.description, now, now],
-      )?;
+  let count = conn.execute(
+    "UPDATE user SET disabled = ?1 WHERE name = ?2",
+    params![disabled, name],
+  )?;
 
-      Ok(Sensor {
-        id: conn.last_insert_rowid(),
-        device: sensor.device.clone(),
-        name: sensor.name.clone(),
-        description: sensor.description.clone(),
-        createdate: now,
-        changeddate: now,
-      })
-    }
+  if count == 0 {
+    return Err(Error::NotFound(format!("user not found: {}", name)));
   }
+
+  Ok(())
 }
 
-pub fn read_sensor(dbfile: &Path, id: i64) -> Result<Sensor, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+// mark a user as registered without going through the email link.
+pub fn confirm_user(pool: &DbPool, name: &str) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  let count = conn.execute(
+    "UPDATE user SET registration_key = NULL WHERE name = ?1",
+    params![name],
+  )?;
+
+  if count == 0 {
+    return Err(Error::NotFound(format!("user not found: {}", name)));
+  }
+
+  Ok(())
+}
+
+// the tables with rows for a sensor, which have to go before the sensor does.
+pub const SENSOR_TABLES: [&str; 11] = [
+  "measurement",
+  "measurement_hourly",
+  "measurement_daily",
+  "retention",
+  "rollup",
+  "sensortype",
+  "calibrationrecord",
+  "calibrationaudit",
+  "virtualsensor",
+  "virtualinput",
+  "tokensensor",
+];
+
+// delete a user along with all their devices, sensors and measurements.
+pub fn delete_user(pool: &DbPool, name: &str) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  let uid: i64 = match tx.query_row(
+    "SELECT id FROM user WHERE name = ?1",
+    params![name],
+    |row| row.get(0),
+  ) {
+    Ok(uid) => uid,
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      return Err(Error::NotFound(format!("user not found: {}", name)))
+    }
+    Err(e) => return Err(e.into()),
+  };
+
+  for table in SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor IN
+          (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.user = ?1)",
+        table
+      )
+      .as_str(),
+      params![uid],
+    )?;
+  }
+  tx.execute(
+    "DELETE FROM sensor WHERE device IN (SELECT id FROM device WHERE user = ?1)",
+    params![uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicelocation WHERE device IN (SELECT id FROM device WHERE user = ?1)",
+    params![uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetoken WHERE device IN (SELECT id FROM device WHERE user = ?1)",
+    params![uid],
+  )?;
+  tx.execute("DELETE FROM device WHERE user = ?1", params![uid])?;
+  tx.execute(
+    "DELETE FROM templatesensor WHERE template IN (SELECT id FROM devicetemplate WHERE user = ?1)",
+    params![uid],
+  )?;
+  tx.execute("DELETE FROM devicetemplate WHERE user = ?1", params![uid])?;
+  tx.execute("DELETE FROM user WHERE id = ?1", params![uid])?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+// --------------------------------------------------------------------------------------
+// ownership checks
+
+pub fn check_device_owner(conn: &Connection, uid: i64, device: i64) -> Result<(), Error> {
+  match conn.query_row(
+    "SELECT id FROM device WHERE id = ?1 AND user = ?2",
+    params![device, uid],
+    |row| row.get::<_, i64>(0),
+  ) {
+    Ok(_) => Ok(()),
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      Err(Error::NotFound(format!("device not found: {}", device)))
+    }
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn check_sensor_owner(conn: &Connection, uid: i64, sensor: i64) -> Result<(), Error> {
+  match conn.query_row(
+    "SELECT sensor.id FROM sensor, device
+      WHERE sensor.id = ?1 AND sensor.device = device.id AND device.user = ?2",
+    params![sensor, uid],
+    |row| row.get::<_, i64>(0),
+  ) {
+    Ok(_) => Ok(()),
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      Err(Error::NotFound(format!("sensor not found: {}", sensor)))
+    }
+    Err(e) => Err(e.into()),
+  }
+}
+
+// --------------------------------------------------------------------------------------
+// device CRUD
+
+pub fn save_device(pool: &DbPool, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  match savedevice.id {
+    Some(id) => {
+      debug!("updating device: {}", savedevice.name);
+
+      let count = conn.execute(
+        "UPDATE device SET name = ?1, description = ?2, changeddate = ?3
+         WHERE id = ?4 AND user = ?5",
+        params![savedevice.name, savedevice.description, now, id, uid],
+      )?;
+      if count == 0 {
+        return Err(Error::NotFound(format!("device not found: {}", id)));
+      }
+      Ok(id)
+    }
+    None => insert_device(&conn, uid, &savedevice.name, &savedevice.description, now),
+  }
+}
+
+fn insert_device(
+  conn: &Connection,
+  uid: i64,
+  name: &str,
+  description: &str,
+  now: i64,
+) -> Result<i64, Error> {
+  debug!("adding device: {}", name);
+  conn.execute(
+    "INSERT INTO device (name, user, description, createdate, changeddate)
+     VALUES (?1, ?2, ?3, ?4, ?5)",
+    params![name, uid, description, now, now],
+  )?;
+
+  Ok(conn.last_insert_rowid())
+}
+
+pub fn read_device(pool: &DbPool, uid: i64, id: i64) -> Result<Device, Error> {
+  let conn = pool.get()?;
+
+  let rbe = conn.query_row(
+    "SELECT name, description, user, createdate, changeddate
+      FROM device WHERE id = ?1 AND user = ?2",
+    params![id, uid],
+    |row| {
+      Ok(Device {
+        id: id,
+        name: row.get(0)?,
+        description: row.get(1)?,
+        user: row.get(2)?,
+        createdate: row.get(3)?,
+        changeddate: row.get(4)?,
+      })
+    },
+  )?;
+
+  Ok(rbe)
+}
+
+// delete a device along with its sensors and their measurements.
+pub fn delete_device(pool: &DbPool, uid: i64, id: i64) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  // virtual sensors on other devices would lose their inputs.
+  let users: i64 = tx.query_row(
+    "SELECT count(DISTINCT virtualinput.sensor) FROM virtualinput, sensor, device
+      WHERE virtualinput.input = sensor.id AND sensor.device = device.id
+      AND device.id = ?1 AND device.user = ?2
+      AND virtualinput.sensor NOT IN (SELECT id FROM sensor WHERE device = ?1)",
+    params![id, uid],
+    |row| row.get(0),
+  )?;
+  if users > 0 {
+    return Err(Error::Conflict(format!(
+      "device {} has inputs to {} virtual sensor(s) on other devices",
+      id, users
+    )));
+  }
+
+  // only delete when user is in the device
+  for table in SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor IN
+          (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.id = ?1 AND device.user = ?2)",
+        table
+      )
+      .as_str(),
+      params![id, uid],
+    )?;
+  }
+  tx.execute(
+    "DELETE FROM sensor WHERE device = ?1
+      AND device IN (SELECT id FROM device WHERE user = ?2)",
+    params![id, uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicelocation WHERE device = ?1
+      AND device IN (SELECT id FROM device WHERE user = ?2)",
+    params![id, uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetoken WHERE device = ?1
+      AND device IN (SELECT id FROM device WHERE user = ?2)",
+    params![id, uid],
+  )?;
+  tx.execute(
+    "DELETE FROM device WHERE id = ?1 and user = ?2",
+    params![id, uid],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn devicelisting(pool: &DbPool, user: i64) -> Result<Vec<Device>, Error> {
+  let conn = pool.get()?;
+
+  let mut pstmt = conn.prepare(
+    "SELECT id, name, description, user, createdate, changeddate
+      FROM device
+      where device.user = ?1",
+  )?;
+
+  let rec_iter = pstmt.query_map(params![user], |row| {
+    Ok(Device {
+      id: row.get(0)?,
+      name: row.get(1)?,
+      description: row.get(2)?,
+      user: row.get(3)?,
+      createdate: row.get(4)?,
+      changeddate: row.get(5)?,
+    })
+  })?;
+
+  let mut pv = Vec::new();
+
+  for rsrec in rec_iter {
+    match rsrec {
+      Ok(rec) => {
+        pv.push(rec);
+      }
+      Err(_) => (),
+    }
+  }
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// sensor CRUD
+
+pub fn save_sensor(pool: &DbPool, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error> {
+  let mut conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  check_device_owner(&tx, uid, sensor.device)?;
+
+  let saved = match sensor.id {
+    Some(id) => {
+      debug!("updating sensor: {}", sensor.name);
+      let count = tx.execute(
+        "UPDATE sensor SET name = ?1, description = ?2, changeddate = ?3
+         WHERE id = ?4 AND device = ?5",
+        params![sensor.name, sensor.description, now, id, sensor.device],
+      )?;
+      if count == 0 {
+        return Err(Error::NotFound(format!("sensor not found: {}", id)));
+      }
+      Sensor {
+        id: id,
+        device: sensor.device.clone(),
+        name: sensor.name.clone(),
+        description: sensor.description.clone(),
+        createdate: now, // TODO: actual createdate
+        changeddate: now,
+      }
+    }
+    None => {
+      let id = insert_sensor(&tx, sensor.device, &sensor.name, &sensor.description, now)?;
+
+      Sensor {
+        id: id,
+        device: sensor.device.clone(),
+        name: sensor.name.clone(),
+        description: sensor.description.clone(),
+        createdate: now,
+        changeddate: now,
+      }
+    }
+  };
+  tx.commit()?;
+
+  Ok(saved)
+}
+
+// a new sensor, with its udp index.  The caller checks the device's owner.
+fn insert_sensor(
+  conn: &Connection,
+  device: i64,
+  name: &str,
+  description: &str,
+  now: i64,
+) -> Result<i64, Error> {
+  debug!("adding sensor: {}", name);
+  conn.execute(
+    "INSERT INTO sensor (name, device, description, createdate, changeddate)
+     VALUES (?1, ?2, ?3, ?4, ?5)",
+    params![name, device, description, now, now],
+  )?;
+  let id = conn.last_insert_rowid();
+  add_token_sensor(conn, device, id)?;
+
+  Ok(id)
+}
+
+pub fn read_sensor(pool: &DbPool, uid: i64, id: i64) -> Result<Sensor, Error> {
+  let conn = pool.get()?;
 
   let rbe = conn.query_row(
     "SELECT device, name, description, createdate, changeddate
-      FROM sensor WHERE id = ?1",
-    params![id],
+      FROM sensor WHERE id = ?1
+      AND device IN (SELECT id FROM device WHERE user = ?2)",
+    params![id, uid],
     |row| {
       Ok(Sensor {
         id: id,
@@ -437,25 +1150,52 @@ pub fn read_sensor(dbfile: &Path, id: i64) -> Result<Sensor, Box<dyn Error>> {
 
   Ok(rbe)
 }
-pub fn delete_sensor(dbfile: &Path, uid: i64, sensorid: i64) -> Result<(), Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+pub fn delete_sensor(pool: &DbPool, uid: i64, sensorid: i64) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  // first, so nothing is said about other users' sensors.
+  check_sensor_owner(&tx, uid, sensorid)?;
+
+  let users: i64 = tx.query_row(
+    "SELECT count(*) FROM virtualinput WHERE input = ?1",
+    params![sensorid],
+    |row| row.get(0),
+  )?;
+  if users > 0 {
+    return Err(Error::Conflict(format!(
+      "sensor {} is an input to {} virtual sensor(s)",
+      sensorid, users
+    )));
+  }
 
   // only delete when user is in the zk
-  conn.execute(
+  for table in SENSOR_TABLES.iter() {
+    tx.execute(
+      format!(
+        "DELETE FROM {} WHERE sensor = ?1
+          AND sensor IN (SELECT sensor.id FROM sensor, device
+            WHERE sensor.device = device.id AND device.user = ?2)",
+        table
+      )
+      .as_str(),
+      params![sensorid, uid],
+    )?;
+  }
+  tx.execute(
     "DELETE FROM sensor WHERE id = ?1 
       AND device IN (SELECT id FROM device WHERE user = ?2)",
     params![sensorid, uid],
   )?;
 
+  tx.commit()?;
+
   Ok(())
 }
 
-pub fn sensorlisting(
-  dbfile: &Path,
-  user: i64,
-  device: Option<i64>,
-) -> rusqlite::Result<Vec<Sensor>> {
-  let conn = connection_open(dbfile)?;
+pub fn sensorlisting(pool: &DbPool, user: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error> {
+  let conn = pool.get()?;
 
   let mut pv = Vec::new();
   match device {
@@ -489,7 +1229,7 @@ pub fn sensorlisting(
     }
     None => {
       let mut pstmt = conn.prepare(
-        "SELECT id, name, description, createdate, changeddate
+        "SELECT id, device, name, description, createdate, changeddate
               FROM sensor where device IN
                 (SELECT id FROM device WHERE user = ?1)",
       )?;
@@ -522,15 +1262,19 @@ pub fn sensorlisting(
 // measurement CRUD
 
 pub fn add_measurement(
-  dbfile: &Path,
+  pool: &DbPool,
   uid: i64,
   measurement: &SaveMeasurement,
-) -> Result<i64, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
 
   let now = now()?;
 
-  println!("adding measurement: {}", measurement.value);
+  check_sensor_owner(&conn, uid, measurement.sensor)?;
+  check_not_virtual(&conn, measurement.sensor)?;
+  readings::check_measurement(sensor_kind(&conn, measurement.sensor)?.0, measurement.value)?;
+
+  trace!("adding measurement: {}", measurement.value);
   conn.execute(
     "INSERT INTO measurement (sensor, value, measuredate, createdate)
      VALUES (?1, ?2, ?3, ?4)",
@@ -545,20 +1289,191 @@ pub fn add_measurement(
   Ok(conn.last_insert_rowid())
 }
 
+// add a batch of measurements in one transaction: all of them, or none if
+// any is invalid.  Returns their ids, in order.
+pub fn add_measurements(
+  pool: &DbPool,
+  uid: i64,
+  measurements: &[SaveMeasurement],
+) -> Result<Vec<i64>, Error> {
+  readings::check_batch(measurements.len())?;
+
+  let mut conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+  let mut kinds = HashMap::new();
+  let mut ids = Vec::with_capacity(measurements.len());
+  {
+    let mut pstmt = tx.prepare(
+      "INSERT INTO measurement (sensor, value, measuredate, createdate)
+       VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for m in measurements.iter() {
+      check_measured(&tx, uid, m.sensor, m.value, &mut kinds)?;
+      pstmt.execute(params![m.sensor, m.value, m.measuredate, now])?;
+      ids.push(tx.last_insert_rowid());
+    }
+  }
+  tx.commit()?;
+
+  Ok(ids)
+}
+
+// check that 'uid' can add 'value' to 'sensor': it's theirs, not virtual,
+// and the value fits its kind.  'kinds' caches the checked sensors.
+fn check_measured(
+  conn: &Connection,
+  uid: i64,
+  sensor: i64,
+  value: f64,
+  kinds: &mut HashMap<i64, ValueKind>,
+) -> Result<(), Error> {
+  let kind = match kinds.get(&sensor) {
+    Some(k) => *k,
+    None => {
+      check_sensor_owner(conn, uid, sensor)?;
+      check_not_virtual(conn, sensor)?;
+      let (k, _) = sensor_kind(conn, sensor)?;
+      kinds.insert(sensor, k);
+      k
+    }
+  };
+  readings::check_measurement(kind, value)
+}
+
+// add measurements of sensors given by device and sensor name, for influx
+// writes.  Every name is resolved before anything is written, and it's all
+// one transaction: the measurements and any devices and sensors created for
+// them are stored, or none are.  Returns how many were stored.
+pub fn add_named_measurements(
+  pool: &DbPool,
+  uid: i64,
+  measurements: &[NamedMeasurement],
+  autocreate: bool,
+) -> Result<usize, Error> {
+  let mut conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let mut devices = HashMap::new();
+  let mut sensors = HashMap::new();
+  {
+    let mut pstmt = tx.prepare(
+      "SELECT device.name, device.id, sensor.name, sensor.id
+        FROM device LEFT JOIN sensor ON sensor.device = device.id
+        WHERE device.user = ?1",
+    )?;
+    let rec_iter = pstmt.query_map(params![uid], |row| {
+      Ok((
+        row.get::<_, String>(0)?,
+        row.get::<_, i64>(1)?,
+        row.get::<_, Option<String>>(2)?,
+        row.get::<_, Option<i64>>(3)?,
+      ))
+    })?;
+    for rsrec in rec_iter {
+      let (dname, did, sname, sid) = rsrec?;
+      if let (Some(sname), Some(sid)) = (sname, sid) {
+        sensors.insert((dname.clone(), sname), sid);
+      }
+      devices.insert(dname, did);
+    }
+  }
+
+  influx::check_names(measurements, &devices, &sensors, autocreate)?;
+
+  let mut kinds = HashMap::new();
+  {
+    let mut pstmt = tx.prepare(
+      "INSERT INTO measurement (sensor, value, measuredate, createdate)
+       VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for m in measurements.iter() {
+      let device = match devices.get(&m.device) {
+        Some(id) => *id,
+        None => {
+          let id = insert_device(&tx, uid, &m.device, influx::AUTOCREATED, now)?;
+          devices.insert(m.device.clone(), id);
+          id
+        }
+      };
+      let key = (m.device.clone(), m.sensor.clone());
+      let sensor = match sensors.get(&key) {
+        Some(id) => *id,
+        None => {
+          let id = insert_sensor(&tx, device, &m.sensor, influx::AUTOCREATED, now)?;
+          sensors.insert(key, id);
+          id
+        }
+      };
+      check_measured(&tx, uid, sensor, m.value, &mut kinds)?;
+      pstmt.execute(params![sensor, m.value, m.measuredate, now])?;
+    }
+  }
+  tx.commit()?;
+
+  Ok(measurements.len())
+}
+
+// list measurements for a sensor, optionally limited to measuredates in
+// [from, to).  Values are calibrated, and computed for virtual sensors.
 pub fn measurement_listing(
-  dbfile: &Path,
+  pool: &DbPool,
   uid: i64,
   sensor: i64,
-) -> Result<Vec<Measurement>, Box<dyn Error>> {
-  let conn = connection_open(dbfile)?;
+  from: Option<i64>,
+  to: Option<i64>,
+) -> Result<Vec<Measurement>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  let (kind, _) = sensor_kind(&conn, sensor)?;
+  if !kind.is_numeric() {
+    return Err(Error::BadRequest(format!(
+      "sensor {} has {} readings; use getreadinglisting",
+      sensor,
+      kind.as_str()
+    )));
+  }
 
-  // TODO: user owns this sensor?
+  match sensor_formula(&conn, sensor)? {
+    Some(formula) => {
+      let mut series = Vec::new();
+      for input in formula.inputs.values() {
+        series.push(calibrated_measurements(&conn, *input, from, to)?);
+      }
+      calibration::evaluate(sensor, &formula, series)
+    }
+    None => calibrated_measurements(&conn, sensor, from, to),
+  }
+}
+
+// a sensor's measurements in [from, to), with its calibration applied.
+fn calibrated_measurements(
+  conn: &Connection,
+  sensor: i64,
+  from: Option<i64>,
+  to: Option<i64>,
+) -> Result<Vec<Measurement>, Error> {
   let mut pstmt = conn.prepare(
     "SELECT id, value, measuredate, createdate
-            FROM measurement where sensor = ?1",
+            FROM measurement where sensor = ?1
+            AND measuredate >= ?2
+            AND measuredate < ?3
+            ORDER BY measuredate",
   )?;
 
-  let mut rec_iter = pstmt.query_map(params![sensor], |row| {
+  // open ends as bounds rather than 'IS NULL OR', so the range can use the
+  // (sensor, measuredate) index.
+  let from = from.unwrap_or(i64::min_value());
+  let to = to.unwrap_or(i64::max_value());
+
+  let rec_iter = pstmt.query_map(params![sensor, from, to], |row| {
     Ok(Measurement {
       id: row.get(0)?,
       value: row.get(1)?,
@@ -579,5 +1494,1606 @@ pub fn measurement_listing(
     }
   }
 
+  calibration::calibrate(&sensor_calibrations(conn, sensor)?, &mut pv);
+
   Ok(pv)
 }
+
+// --------------------------------------------------------------------------------------
+// calibration and virtual sensors
+
+// a sensor's calibration history, in effective order.
+pub fn sensor_calibrations(conn: &Connection, sensor: i64) -> Result<calibration::History, Error> {
+  let mut pstmt = conn.prepare(
+    "SELECT effective, transform FROM calibrationrecord WHERE sensor = ?1
+      ORDER BY effective",
+  )?;
+  let rec_iter = pstmt.query_map(params![sensor], |row| {
+    Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?))
+  })?;
+
+  let mut history = Vec::new();
+  for rec in rec_iter {
+    let (effective, transform) = rec?;
+    history.push((effective, parse_transform(transform)?));
+  }
+  Ok(history)
+}
+
+fn parse_transform(transform: Option<String>) -> Result<Option<Calibration>, Error> {
+  match transform {
+    Some(t) => Ok(Some(serde_json::from_str(t.as_str())?)),
+    None => Ok(None),
+  }
+}
+
+fn transform_string(calibration: &Option<Calibration>) -> Result<Option<String>, Error> {
+  match calibration {
+    Some(c) => Ok(Some(serde_json::to_string(c)?)),
+    None => Ok(None),
+  }
+}
+
+fn add_calibration_audit(
+  conn: &Connection,
+  uid: i64,
+  sensor: i64,
+  action: &str,
+  effective: i64,
+  transform: &Option<String>,
+  now: i64,
+) -> Result<(), Error> {
+  conn.execute(
+    "INSERT INTO calibrationaudit (sensor, user, action, effective, transform, changedate)
+      VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    params![sensor, uid, action, effective, transform, now],
+  )?;
+  Ok(())
+}
+
+pub fn sensor_formula(conn: &Connection, sensor: i64) -> Result<Option<Formula>, Error> {
+  let expression: String = match conn.query_row(
+    "SELECT expression FROM virtualsensor WHERE sensor = ?1",
+    params![sensor],
+    |row| row.get(0),
+  ) {
+    Ok(e) => e,
+    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+
+  let mut pstmt = conn.prepare("SELECT name, input FROM virtualinput WHERE sensor = ?1")?;
+  let rec_iter = pstmt.query_map(params![sensor], |row| {
+    Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+  })?;
+
+  let mut inputs = BTreeMap::new();
+  for rec in rec_iter {
+    let (name, input) = rec?;
+    inputs.insert(name, input);
+  }
+
+  Ok(Some(Formula {
+    expression: expression,
+    inputs: inputs,
+  }))
+}
+
+// virtual sensors only have computed measurements.
+fn check_not_virtual(conn: &Connection, sensor: i64) -> Result<(), Error> {
+  match sensor_formula(conn, sensor)? {
+    Some(_) => Err(Error::BadRequest(format!(
+      "sensor {} is virtual; its measurements are computed",
+      sensor
+    ))),
+    None => Ok(()),
+  }
+}
+
+pub fn get_calibration(pool: &DbPool, uid: i64, sensor: i64) -> Result<SensorCalibration, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  match conn.query_row(
+    "SELECT effective, transform FROM calibrationrecord
+      WHERE sensor = ?1 AND effective <= ?2
+      ORDER BY effective DESC LIMIT 1",
+    params![sensor, now()?],
+    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, Option<String>>(1)?)),
+  ) {
+    Ok((effective, transform)) => Ok(SensorCalibration {
+      sensor: sensor,
+      effective: Some(effective),
+      calibration: parse_transform(transform)?,
+    }),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(SensorCalibration {
+      sensor: sensor,
+      effective: None,
+      calibration: None,
+    }),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn set_calibration(pool: &DbPool, uid: i64, sc: &SensorCalibration) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sc.sensor)?;
+
+  match &sc.calibration {
+    Some(c) => {
+      c.check()?;
+      let (kind, _) = sensor_kind(&conn, sc.sensor)?;
+      if !kind.is_numeric() {
+        return Err(Error::BadRequest(format!(
+          "can't calibrate {} readings",
+          kind.as_str()
+        )));
+      }
+    }
+    None => (),
+  }
+
+  let now = now()?;
+  let effective = sc.effective.unwrap_or(now);
+  let transform = transform_string(&sc.calibration)?;
+
+  let tx = conn.transaction()?;
+  tx.execute(
+    "INSERT INTO calibrationrecord (sensor, effective, transform, createdate, user)
+      VALUES (?1, ?2, ?3, ?4, ?5)
+     ON CONFLICT(sensor, effective) DO UPDATE SET
+      transform = excluded.transform,
+      createdate = excluded.createdate,
+      user = excluded.user",
+    params![sc.sensor, effective, transform, now, uid],
+  )?;
+  add_calibration_audit(
+    &tx,
+    uid,
+    sc.sensor,
+    calibration::AUDIT_SET,
+    effective,
+    &transform,
+    now,
+  )?;
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn calibration_history(
+  pool: &DbPool,
+  uid: i64,
+  sensor: i64,
+) -> Result<Vec<CalibrationRecord>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  let mut pstmt = conn.prepare(
+    "SELECT calibrationrecord.id, effective, transform, createdate, user.name
+      FROM calibrationrecord, user
+      WHERE calibrationrecord.user = user.id AND sensor = ?1
+      ORDER BY effective",
+  )?;
+  let rec_iter = pstmt.query_map(params![sensor], |row| {
+    Ok((
+      row.get::<_, i64>(0)?,
+      row.get::<_, i64>(1)?,
+      row.get::<_, Option<String>>(2)?,
+      row.get::<_, i64>(3)?,
+      row.get::<_, String>(4)?,
+    ))
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    let (id, effective, transform, createdate, user) = rec?;
+    pv.push(CalibrationRecord {
+      id: id,
+      sensor: sensor,
+      effective: effective,
+      calibration: parse_transform(transform)?,
+      createdate: createdate,
+      user: user,
+    });
+  }
+  Ok(pv)
+}
+
+// remove an entry from a calibration history, so the one before it stays in
+// force until the next.
+pub fn delete_calibration(pool: &DbPool, uid: i64, id: i64) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let (sensor, effective, transform) = match conn.query_row(
+    "SELECT sensor, effective, transform FROM calibrationrecord WHERE id = ?1",
+    params![id],
+    |row| {
+      Ok((
+        row.get::<_, i64>(0)?,
+        row.get::<_, i64>(1)?,
+        row.get::<_, Option<String>>(2)?,
+      ))
+    },
+  ) {
+    Ok(r) => r,
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      return Err(Error::NotFound(format!("calibration not found: {}", id)))
+    }
+    Err(e) => return Err(e.into()),
+  };
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  let tx = conn.transaction()?;
+  tx.execute("DELETE FROM calibrationrecord WHERE id = ?1", params![id])?;
+  add_calibration_audit(
+    &tx,
+    uid,
+    sensor,
+    calibration::AUDIT_DELETE,
+    effective,
+    &transform,
+    now()?,
+  )?;
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn calibration_audit(
+  pool: &DbPool,
+  uid: i64,
+  sensor: i64,
+) -> Result<Vec<CalibrationAudit>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  let mut pstmt = conn.prepare(
+    "SELECT user.name, action, effective, transform, changedate
+      FROM calibrationaudit, user
+      WHERE calibrationaudit.user = user.id AND sensor = ?1
+      ORDER BY changedate, calibrationaudit.rowid",
+  )?;
+  let rec_iter = pstmt.query_map(params![sensor], |row| {
+    Ok((
+      row.get::<_, String>(0)?,
+      row.get::<_, String>(1)?,
+      row.get::<_, i64>(2)?,
+      row.get::<_, Option<String>>(3)?,
+      row.get::<_, i64>(4)?,
+    ))
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    let (user, action, effective, transform, changedate) = rec?;
+    pv.push(CalibrationAudit {
+      sensor: sensor,
+      user: user,
+      action: action,
+      effective: effective,
+      calibration: parse_transform(transform)?,
+      changedate: changedate,
+    });
+  }
+  Ok(pv)
+}
+
+pub fn get_virtual_sensor(pool: &DbPool, uid: i64, sensor: i64) -> Result<VirtualSensor, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  Ok(VirtualSensor {
+    sensor: sensor,
+    formula: sensor_formula(&conn, sensor)?,
+  })
+}
+
+// a virtual sensor's inputs must be the user's own regular numeric sensors,
+// and a virtual sensor can't have measurements of its own or be an input
+// itself, so there are no chains or cycles.
+pub fn set_virtual_sensor(pool: &DbPool, uid: i64, vs: &VirtualSensor) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  check_sensor_owner(&tx, uid, vs.sensor)?;
+
+  tx.execute(
+    "DELETE FROM virtualinput WHERE sensor = ?1",
+    params![vs.sensor],
+  )?;
+  tx.execute(
+    "DELETE FROM virtualsensor WHERE sensor = ?1",
+    params![vs.sensor],
+  )?;
+
+  match &vs.formula {
+    Some(f) => {
+      f.compile()?;
+
+      let count: i64 = tx.query_row(
+        "SELECT count(*) FROM measurement WHERE sensor = ?1",
+        params![vs.sensor],
+        |row| row.get(0),
+      )?;
+      if count > 0 {
+        return Err(Error::Conflict(format!(
+          "sensor {} has measurements; virtual sensors can't",
+          vs.sensor
+        )));
+      }
+      let users: i64 = tx.query_row(
+        "SELECT count(*) FROM virtualinput WHERE input = ?1",
+        params![vs.sensor],
+        |row| row.get(0),
+      )?;
+      if users > 0 {
+        return Err(Error::Conflict(format!(
+          "sensor {} is an input to another virtual sensor",
+          vs.sensor
+        )));
+      }
+      if !sensor_kind(&tx, vs.sensor)?.0.is_numeric() {
+        return Err(Error::BadRequest(
+          "virtual sensors must be numeric".to_string(),
+        ));
+      }
+
+      for (name, input) in f.inputs.iter() {
+        check_sensor_owner(&tx, uid, *input)?;
+        if *input == vs.sensor || sensor_formula(&tx, *input)?.is_some() {
+          return Err(Error::BadRequest(format!(
+            "input '{}' is a virtual sensor",
+            name
+          )));
+        }
+        if !sensor_kind(&tx, *input)?.0.is_numeric() {
+          return Err(Error::BadRequest(format!("input '{}' isn't numeric", name)));
+        }
+      }
+
+      insert_formula(&tx, vs.sensor, f)?;
+    }
+    None => (),
+  }
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+fn insert_formula(conn: &Connection, sensor: i64, f: &Formula) -> Result<(), Error> {
+  conn.execute(
+    "INSERT INTO virtualsensor (sensor, expression) VALUES (?1, ?2)",
+    params![sensor, f.expression],
+  )?;
+  for (name, input) in f.inputs.iter() {
+    conn.execute(
+      "INSERT INTO virtualinput (sensor, name, input) VALUES (?1, ?2, ?3)",
+      params![sensor, name, input],
+    )?;
+  }
+  Ok(())
+}
+
+// --------------------------------------------------------------------------------------
+// typed readings
+
+// a sensor's value kind and vector size.
+pub fn sensor_kind(conn: &Connection, sensor: i64) -> Result<(ValueKind, Option<i64>), Error> {
+  match conn.query_row(
+    "SELECT kind, dims FROM sensortype WHERE sensor = ?1",
+    params![sensor],
+    |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+  ) {
+    Ok((kind, dims)) => Ok((ValueKind::parse(kind.as_str())?, dims)),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok((ValueKind::Float, None)),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn get_sensor_type(pool: &DbPool, uid: i64, sensor: i64) -> Result<SensorType, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  let (kind, dims) = sensor_kind(&conn, sensor)?;
+  let unit = match conn.query_row(
+    "SELECT unit FROM sensortype WHERE sensor = ?1",
+    params![sensor],
+    |row| row.get(0),
+  ) {
+    Ok(unit) => unit,
+    Err(rusqlite::Error::QueryReturnedNoRows) => None,
+    Err(e) => return Err(e.into()),
+  };
+  Ok(SensorType {
+    sensor: sensor,
+    kind: kind,
+    dims: dims,
+    unit: unit,
+  })
+}
+
+// the kind can only change while the sensor has no measurements, since the
+// existing ones wouldn't decode as the new kind.
+pub fn set_sensor_type(pool: &DbPool, uid: i64, st: &SensorType) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  readings::check_type(st)?;
+
+  let tx = conn.transaction()?;
+
+  check_sensor_owner(&tx, uid, st.sensor)?;
+
+  let (kind, dims) = sensor_kind(&tx, st.sensor)?;
+  if (kind, dims) != (st.kind, st.dims) {
+    let count: i64 = tx.query_row(
+      "SELECT count(*) FROM measurement WHERE sensor = ?1",
+      params![st.sensor],
+      |row| row.get(0),
+    )?;
+    if count > 0 {
+      return Err(Error::Conflict(format!(
+        "sensor {} already has {} readings",
+        st.sensor,
+        kind.as_str()
+      )));
+    }
+  }
+
+  tx.execute(
+    "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES (?1, ?2, ?3, ?4)
+     ON CONFLICT(sensor) DO UPDATE SET
+       kind = excluded.kind, dims = excluded.dims, unit = excluded.unit",
+    params![st.sensor, st.kind.as_str(), st.dims, st.unit],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+pub fn add_reading(pool: &DbPool, uid: i64, reading: &SaveReading) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let now = now()?;
+
+  check_sensor_owner(&conn, uid, reading.sensor)?;
+  check_not_virtual(&conn, reading.sensor)?;
+
+  let (kind, dims) = sensor_kind(&conn, reading.sensor)?;
+  let (value, payload) = readings::encode(kind, dims, &reading.value)?;
+
+  match &reading.position {
+    Some(p) => geo::check_location(p)?,
+    None => (),
+  }
+  let p = reading.position.as_ref();
+
+  conn.execute(
+    "INSERT INTO measurement (sensor, value, payload, measuredate, createdate, lat, lon, elevation)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    params![
+      reading.sensor,
+      value,
+      payload,
+      reading.measuredate,
+      now,
+      p.map(|p| p.lat),
+      p.map(|p| p.lon),
+      p.and_then(|p| p.elevation)
+    ],
+  )?;
+
+  Ok(conn.last_insert_rowid())
+}
+
+pub fn reading_listing(
+  pool: &DbPool,
+  uid: i64,
+  query: &ReadingQuery,
+) -> Result<Vec<Reading>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, query.sensor)?;
+
+  let (kind, _) = sensor_kind(&conn, query.sensor)?;
+
+  match &query.bbox {
+    Some(b) => geo::check_bbox(b)?,
+    None => (),
+  }
+  let bbox = geo::bbox_params(&query.bbox);
+
+  let mut pstmt = conn.prepare(
+    "SELECT id, value, payload, measuredate, createdate, lat, lon, elevation
+       FROM measurement WHERE sensor = ?1
+       AND measuredate >= ?2
+       AND measuredate < ?3
+       AND (?4 IS NULL OR (lat >= ?4 AND lat <= ?5 AND lon >= ?6 AND lon <= ?7))
+       ORDER BY measuredate",
+  )?;
+
+  let rec_iter = pstmt.query_map(
+    params![
+      query.sensor,
+      query.from.unwrap_or(i64::min_value()),
+      query.to.unwrap_or(i64::max_value()),
+      bbox[0],
+      bbox[1],
+      bbox[2],
+      bbox[3]
+    ],
+    |row| {
+      Ok((
+        row.get::<_, i64>(0)?,
+        row.get::<_, f64>(1)?,
+        row.get::<_, Option<String>>(2)?,
+        row.get::<_, i64>(3)?,
+        row.get::<_, i64>(4)?,
+        geo::from_columns(row.get(5)?, row.get(6)?, row.get(7)?),
+      ))
+    },
+  )?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    let (id, value, payload, measuredate, createdate, position) = rec?;
+    pv.push(Reading {
+      id: id,
+      sensor: query.sensor,
+      value: readings::decode(kind, value, payload)?,
+      measuredate: measuredate,
+      createdate: createdate,
+      position: position,
+    });
+  }
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// device locations
+
+pub fn get_device_location(pool: &DbPool, uid: i64, device: i64) -> Result<DeviceLocation, Error> {
+  let conn = pool.get()?;
+
+  check_device_owner(&conn, uid, device)?;
+
+  match conn.query_row(
+    "SELECT lat, lon, elevation FROM devicelocation WHERE device = ?1",
+    params![device],
+    |row| {
+      Ok(Location {
+        lat: row.get(0)?,
+        lon: row.get(1)?,
+        elevation: row.get(2)?,
+      })
+    },
+  ) {
+    Ok(l) => Ok(DeviceLocation {
+      device: device,
+      location: Some(l),
+    }),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(DeviceLocation {
+      device: device,
+      location: None,
+    }),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn set_device_location(pool: &DbPool, uid: i64, dl: &DeviceLocation) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_device_owner(&conn, uid, dl.device)?;
+
+  match &dl.location {
+    Some(l) => {
+      geo::check_location(l)?;
+      conn.execute(
+        "INSERT INTO devicelocation (device, lat, lon, elevation) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(device) DO UPDATE SET
+           lat = excluded.lat, lon = excluded.lon, elevation = excluded.elevation",
+        params![dl.device, l.lat, l.lon, l.elevation],
+      )?;
+    }
+    None => {
+      conn.execute(
+        "DELETE FROM devicelocation WHERE device = ?1",
+        params![dl.device],
+      )?;
+    }
+  }
+
+  Ok(())
+}
+
+// the user's devices that have a location, optionally inside a bbox.
+pub fn located_devices(
+  pool: &DbPool,
+  uid: i64,
+  bbox: &Option<BBox>,
+) -> Result<Vec<LocatedDevice>, Error> {
+  let conn = pool.get()?;
+
+  match bbox {
+    Some(b) => geo::check_bbox(b)?,
+    None => (),
+  }
+  let b = geo::bbox_params(bbox);
+
+  let mut pstmt = conn.prepare(
+    "SELECT device.id, device.name, device.description,
+            devicelocation.lat, devicelocation.lon, devicelocation.elevation
+       FROM device, devicelocation
+       WHERE devicelocation.device = device.id AND device.user = ?1
+       AND (?2 IS NULL OR (lat >= ?2 AND lat <= ?3 AND lon >= ?4 AND lon <= ?5))
+       ORDER BY device.id",
+  )?;
+
+  let rec_iter = pstmt.query_map(params![uid, b[0], b[1], b[2], b[3]], |row| {
+    Ok(LocatedDevice {
+      id: row.get(0)?,
+      name: row.get(1)?,
+      description: row.get(2)?,
+      location: Location {
+        lat: row.get(3)?,
+        lon: row.get(4)?,
+        elevation: row.get(5)?,
+      },
+    })
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    pv.push(rec?);
+  }
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// device tokens
+
+// set or, with no hash, delete a device's token.
+pub fn set_device_token(
+  pool: &DbPool,
+  uid: i64,
+  device: i64,
+  hash: Option<&str>,
+) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  check_device_owner(&conn, uid, device)?;
+
+  let tx = conn.transaction()?;
+  tx.execute("DELETE FROM tokensensor WHERE device = ?1", params![device])?;
+  match hash {
+    Some(h) => {
+      // the sensors are numbered afresh, in id order.
+      tx.execute(
+        "INSERT INTO tokensensor (device, idx, sensor)
+         SELECT ?1, (SELECT count(*) FROM sensor s WHERE s.device = ?1 AND s.id < sensor.id), id
+         FROM sensor WHERE device = ?1",
+        params![device],
+      )?;
+      tx.execute(
+        "INSERT INTO devicetoken (device, tokenhash, createdate, nextidx)
+         VALUES (?1, ?2, ?3, (SELECT count(*) FROM sensor WHERE device = ?1))
+         ON CONFLICT(device) DO UPDATE SET
+           tokenhash = excluded.tokenhash, createdate = excluded.createdate,
+           nextidx = excluded.nextidx",
+        params![device, h, now()?],
+      )?;
+    }
+    None => {
+      tx.execute("DELETE FROM devicetoken WHERE device = ?1", params![device])?;
+    }
+  }
+  tx.commit()?;
+
+  Ok(())
+}
+
+// give a new sensor its device's next udp index, if the device has a token.
+fn add_token_sensor(conn: &Connection, device: i64, sensor: i64) -> Result<(), Error> {
+  conn.execute(
+    "UPDATE devicetoken SET nextidx = nextidx + 1 WHERE device = ?1",
+    params![device],
+  )?;
+  conn.execute(
+    "INSERT INTO tokensensor (device, idx, sensor)
+     SELECT device, nextidx - 1, ?2 FROM devicetoken WHERE device = ?1",
+    params![device, sensor],
+  )?;
+  Ok(())
+}
+
+// the device with this token hash, if its user is registered and enabled.
+pub fn token_device(pool: &DbPool, hash: &str) -> Result<TokenDevice, Error> {
+  let conn = pool.get()?;
+
+  let (user, device) = match conn.query_row(
+    "SELECT device.user, device.id FROM devicetoken, device, user
+      WHERE devicetoken.tokenhash = ?1 AND devicetoken.device = device.id
+        AND device.user = user.id AND user.registration_key IS NULL AND NOT user.disabled",
+    params![hash],
+    |row| Ok((row.get(0)?, row.get(1)?)),
+  ) {
+    Ok(ud) => ud,
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      return Err(Error::NotFound("unknown device token".to_string()))
+    }
+    Err(e) => return Err(e.into()),
+  };
+
+  let mut pstmt = conn.prepare("SELECT idx, sensor FROM tokensensor WHERE device = ?1")?;
+  let sensors = pstmt
+    .query_map(params![device], |row| Ok((row.get(0)?, row.get(1)?)))?
+    .collect::<Result<HashMap<i64, i64>, _>>()?;
+
+  Ok(TokenDevice {
+    user,
+    device,
+    sensors,
+  })
+}
+
+// --------------------------------------------------------------------------------------
+// device templates
+
+fn template_sensors(conn: &Connection, template: i64) -> Result<Vec<TemplateSensor>, Error> {
+  let mut pstmt = conn.prepare(
+    "SELECT name, description, unit, kind, dims FROM templatesensor
+      WHERE template = ?1 ORDER BY seq",
+  )?;
+
+  let rec_iter = pstmt.query_map(params![template], |row| {
+    Ok((
+      row.get::<_, String>(0)?,
+      row.get::<_, String>(1)?,
+      row.get::<_, Option<String>>(2)?,
+      row.get::<_, Option<String>>(3)?,
+      row.get::<_, Option<i64>>(4)?,
+    ))
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    let (name, description, unit, kind, dims) = rec?;
+    pv.push(TemplateSensor {
+      name: name,
+      description: description,
+      unit: unit,
+      kind: match kind {
+        Some(k) => Some(ValueKind::parse(k.as_str())?),
+        None => None,
+      },
+      dims: dims,
+    });
+  }
+
+  Ok(pv)
+}
+
+fn insert_template_sensors(
+  conn: &Connection,
+  template: i64,
+  sensors: &Vec<TemplateSensor>,
+) -> Result<(), Error> {
+  let mut pstmt = conn.prepare(
+    "INSERT INTO templatesensor (template, seq, name, description, unit, kind, dims)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+  )?;
+  for (seq, s) in sensors.iter().enumerate() {
+    pstmt.execute(params![
+      template,
+      seq as i64,
+      s.name,
+      s.description,
+      s.unit,
+      s.kind.map(|k| k.as_str()),
+      s.dims
+    ])?;
+  }
+  Ok(())
+}
+
+pub fn save_template(pool: &DbPool, uid: i64, template: &SaveTemplate) -> Result<i64, Error> {
+  let mut conn = pool.get()?;
+
+  templates::check_template(template)?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let id = match template.id {
+    Some(id) => {
+      let count = tx.execute(
+        "UPDATE devicetemplate SET name = ?1, description = ?2, changeddate = ?3
+         WHERE id = ?4 AND user = ?5",
+        params![template.name, template.description, now, id, uid],
+      )?;
+      if count == 0 {
+        return Err(Error::NotFound(format!("template not found: {}", id)));
+      }
+      tx.execute(
+        "DELETE FROM templatesensor WHERE template = ?1",
+        params![id],
+      )?;
+      id
+    }
+    None => {
+      tx.execute(
+        "INSERT INTO devicetemplate (user, name, description, createdate, changeddate)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![uid, template.name, template.description, now, now],
+      )?;
+      tx.last_insert_rowid()
+    }
+  };
+
+  insert_template_sensors(&tx, id, &template.sensors)?;
+
+  tx.commit()?;
+
+  Ok(id)
+}
+
+fn read_template_conn(conn: &Connection, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+  let mut template = match conn.query_row(
+    "SELECT name, description, createdate, changeddate FROM devicetemplate
+      WHERE id = ?1 AND user = ?2",
+    params![id, uid],
+    |row| {
+      Ok(DeviceTemplate {
+        id: id,
+        name: row.get(0)?,
+        description: row.get(1)?,
+        createdate: row.get(2)?,
+        changeddate: row.get(3)?,
+        sensors: Vec::new(),
+      })
+    },
+  ) {
+    Ok(t) => t,
+    Err(rusqlite::Error::QueryReturnedNoRows) => {
+      return Err(Error::NotFound(format!("template not found: {}", id)))
+    }
+    Err(e) => return Err(e.into()),
+  };
+
+  template.sensors = template_sensors(conn, id)?;
+
+  Ok(template)
+}
+
+pub fn read_template(pool: &DbPool, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+  let conn = pool.get()?;
+
+  read_template_conn(&conn, uid, id)
+}
+
+pub fn template_listing(pool: &DbPool, uid: i64) -> Result<Vec<DeviceTemplate>, Error> {
+  let conn = pool.get()?;
+
+  let mut ids = Vec::new();
+  {
+    let mut pstmt = conn.prepare("SELECT id FROM devicetemplate WHERE user = ?1 ORDER BY id")?;
+    let rec_iter = pstmt.query_map(params![uid], |row| row.get::<_, i64>(0))?;
+    for rec in rec_iter {
+      ids.push(rec?);
+    }
+  }
+
+  let mut pv = Vec::new();
+  for id in ids {
+    pv.push(read_template_conn(&conn, uid, id)?);
+  }
+
+  Ok(pv)
+}
+
+pub fn delete_template(pool: &DbPool, uid: i64, id: i64) -> Result<(), Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  tx.execute(
+    "DELETE FROM templatesensor WHERE template = ?1
+      AND template IN (SELECT id FROM devicetemplate WHERE user = ?2)",
+    params![id, uid],
+  )?;
+  tx.execute(
+    "DELETE FROM devicetemplate WHERE id = ?1 AND user = ?2",
+    params![id, uid],
+  )?;
+
+  tx.commit()?;
+
+  Ok(())
+}
+
+// make devices and their sensors from a template, all or nothing.
+pub fn instantiate_template(
+  pool: &DbPool,
+  uid: i64,
+  inst: &Instantiate,
+) -> Result<Vec<InstantiatedDevice>, Error> {
+  let mut conn = pool.get()?;
+
+  let now = now()?;
+
+  let tx = conn.transaction()?;
+
+  let template = read_template_conn(&tx, uid, inst.template)?;
+  let names = templates::device_names(
+    inst.name.as_ref().unwrap_or(&template.name).as_str(),
+    inst.count,
+    inst.first,
+  )?;
+  let description = inst.description.as_ref().unwrap_or(&template.description);
+
+  let mut devices = Vec::new();
+  for name in names {
+    tx.execute(
+      "INSERT INTO device (name, user, description, createdate, changeddate)
+       VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![name, uid, description, now, now],
+    )?;
+    let deviceid = tx.last_insert_rowid();
+
+    let mut sensors = Vec::new();
+    for ts in template.sensors.iter() {
+      tx.execute(
+        "INSERT INTO sensor (name, device, description, createdate, changeddate)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![ts.name, deviceid, ts.description, now, now],
+      )?;
+      let sensorid = tx.last_insert_rowid();
+
+      if templates::needs_type(ts) {
+        tx.execute(
+          "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES (?1, ?2, ?3, ?4)",
+          params![
+            sensorid,
+            ts.kind.unwrap_or(ValueKind::Float).as_str(),
+            ts.dims,
+            ts.unit
+          ],
+        )?;
+      }
+
+      sensors.push(InstantiatedSensor {
+        id: sensorid,
+        name: ts.name.clone(),
+      });
+    }
+
+    devices.push(InstantiatedDevice {
+      id: deviceid,
+      name: name,
+      sensors: sensors,
+    });
+  }
+
+  tx.commit()?;
+
+  Ok(devices)
+}
+
+// --------------------------------------------------------------------------------------
+// retention and rollups
+
+pub fn get_retention(pool: &DbPool, uid: i64, sensor: i64) -> Result<SensorRetention, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sensor)?;
+
+  match conn.query_row(
+    "SELECT rawdays, hourlydays, dailydays FROM retention WHERE sensor = ?1",
+    params![sensor],
+    |row| {
+      Ok(SensorRetention {
+        sensor: sensor,
+        rawdays: row.get(0)?,
+        hourlydays: row.get(1)?,
+        dailydays: row.get(2)?,
+      })
+    },
+  ) {
+    Ok(sr) => Ok(sr),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(SensorRetention {
+      sensor: sensor,
+      rawdays: None,
+      hourlydays: None,
+      dailydays: None,
+    }),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn set_retention(pool: &DbPool, uid: i64, sr: &SensorRetention) -> Result<(), Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, sr.sensor)?;
+
+  conn.execute(
+    "INSERT INTO retention (sensor, rawdays, hourlydays, dailydays)
+     VALUES (?1, ?2, ?3, ?4)
+     ON CONFLICT(sensor) DO UPDATE SET
+       rawdays = excluded.rawdays,
+       hourlydays = excluded.hourlydays,
+       dailydays = excluded.dailydays",
+    params![sr.sensor, sr.rawdays, sr.hourlydays, sr.dailydays],
+  )?;
+
+  Ok(())
+}
+
+// every sensor, with its retention overrides if any.
+pub fn sensor_retentions(pool: &DbPool) -> Result<Vec<SensorRetention>, Error> {
+  let conn = pool.get()?;
+
+  let mut pstmt = conn.prepare(
+    "SELECT sensor.id, retention.rawdays, retention.hourlydays, retention.dailydays
+       FROM sensor LEFT JOIN retention ON retention.sensor = sensor.id",
+  )?;
+
+  let rec_iter = pstmt.query_map(params![], |row| {
+    Ok(SensorRetention {
+      sensor: row.get(0)?,
+      rawdays: row.get(1)?,
+      hourlydays: row.get(2)?,
+      dailydays: row.get(3)?,
+    })
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    pv.push(rec?);
+  }
+
+  Ok(pv)
+}
+
+// the start of the rollup period holding 'measuredate', rounding down for
+// dates before 1970 too.  ?4 is the period's length.
+const PERIOD_START: &str = "measuredate - ((measuredate % ?4) + ?4) % ?4";
+
+// the oldest measurements from before 'before', at most 'limit' of them.
+const ROLLUP_BATCH: &str = "SELECT id FROM measurement WHERE sensor = ?1 AND measuredate < ?2
+  ORDER BY measuredate LIMIT ?3";
+
+// add at most 'limit' of a sensor's measurements from before 'before' to the
+// hourly and daily rollups, oldest first, and delete them, in one transaction.
+// So a measurement is only ever deleted once it's been counted, however late
+// it arrived.  Readings with a payload aren't numbers, and are deleted without
+// being counted.  The number deleted.
+//
+// rolledupto moves to 'before' once a batch takes everything before it, or
+// to the batch's last measuredate if there may be more; an empty batch leaves
+// it alone.
+pub fn rollup_measurements(
+  pool: &DbPool,
+  sensor: i64,
+  before: i64,
+  limit: i64,
+) -> Result<i64, Error> {
+  let mut conn = pool.get()?;
+
+  // immediate, so no other write can change the batch between statements.
+  let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+  let (count, last): (i64, Option<i64>) = tx.query_row(
+    format!(
+      "SELECT count(*), max(measuredate) FROM measurement WHERE id IN ({})",
+      ROLLUP_BATCH
+    )
+    .as_str(),
+    params![sensor, before, limit],
+    |row| Ok((row.get(0)?, row.get(1)?)),
+  )?;
+  let rolledupto = match last {
+    Some(last) if count >= limit => last,
+    Some(_) => before,
+    None => return Ok(0),
+  };
+
+  for rollup in [Rollup::Hourly, Rollup::Daily].iter() {
+    tx.execute(
+      format!(
+        "INSERT INTO {table} (sensor, period, samples, total, minimum, maximum)
+         SELECT sensor, {period}, count(*), sum(value), min(value), max(value)
+           FROM measurement WHERE id IN ({batch}) AND payload IS NULL
+           GROUP BY sensor, 2
+         ON CONFLICT(sensor, period) DO UPDATE SET
+           samples = {table}.samples + excluded.samples,
+           total = {table}.total + excluded.total,
+           minimum = min({table}.minimum, excluded.minimum),
+           maximum = max({table}.maximum, excluded.maximum)",
+        table = rollup.table(),
+        period = PERIOD_START,
+        batch = ROLLUP_BATCH
+      )
+      .as_str(),
+      params![sensor, before, limit, rollup.period()],
+    )?;
+  }
+
+  let count = tx.execute(
+    format!("DELETE FROM measurement WHERE id IN ({})", ROLLUP_BATCH).as_str(),
+    params![sensor, before, limit],
+  )?;
+
+  tx.execute(
+    "INSERT INTO rollup (sensor, rolledupto) VALUES (?1, ?2)
+     ON CONFLICT(sensor) DO UPDATE SET
+       rolledupto = max(rollup.rolledupto, excluded.rolledupto)",
+    params![sensor, rolledupto],
+  )?;
+
+  tx.commit()?;
+
+  Ok(count as i64)
+}
+
+// delete at most 'limit' rollup rows for periods starting before 'before'.
+pub fn prune_rollups(
+  pool: &DbPool,
+  rollup: Rollup,
+  sensor: i64,
+  before: i64,
+  limit: i64,
+) -> Result<i64, Error> {
+  let conn = pool.get()?;
+
+  let count = conn.execute(
+    format!(
+      "DELETE FROM {table} WHERE rowid IN
+        (SELECT rowid FROM {table} WHERE sensor = ?1 AND period < ?2 LIMIT ?3)",
+      table = rollup.table()
+    )
+    .as_str(),
+    params![sensor, before, limit],
+  )?;
+
+  Ok(count as i64)
+}
+
+// list rollup rows for a sensor, optionally limited to periods in [from, to).
+pub fn rollup_listing(
+  pool: &DbPool,
+  uid: i64,
+  query: &RollupQuery,
+) -> Result<Vec<RollupEntry>, Error> {
+  let conn = pool.get()?;
+
+  check_sensor_owner(&conn, uid, query.sensor)?;
+
+  let mut pstmt = conn.prepare(
+    format!(
+      "SELECT period, samples, total, minimum, maximum
+         FROM {} WHERE sensor = ?1
+         AND period >= ?2
+         AND period < ?3
+         ORDER BY period",
+      query.rollup.table()
+    )
+    .as_str(),
+  )?;
+
+  let rec_iter = pstmt.query_map(
+    params![
+      query.sensor,
+      query.from.unwrap_or(i64::min_value()),
+      query.to.unwrap_or(i64::max_value())
+    ],
+    |row| {
+      let samples: i64 = row.get(1)?;
+      let total: f64 = row.get(2)?;
+      Ok(RollupEntry {
+        sensor: query.sensor,
+        period: row.get(0)?,
+        samples: samples,
+        mean: total / samples as f64,
+        min: row.get(3)?,
+        max: row.get(4)?,
+      })
+    },
+  )?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    pv.push(rec?);
+  }
+
+  calibration::calibrate_rollups(&sensor_calibrations(&conn, query.sensor)?, &mut pv);
+
+  Ok(pv)
+}
+
+// --------------------------------------------------------------------------------------
+// export and import
+
+fn archive_rollups(
+  conn: &Connection,
+  rollup: Rollup,
+  sensor: i64,
+) -> Result<Vec<ArchiveRollup>, Error> {
+  let mut pstmt = conn.prepare(
+    format!(
+      "SELECT period, samples, total, minimum, maximum FROM {} WHERE sensor = ?1 ORDER BY period",
+      rollup.table()
+    )
+    .as_str(),
+  )?;
+
+  let rec_iter = pstmt.query_map(params![sensor], |row| {
+    Ok(ArchiveRollup {
+      period: row.get(0)?,
+      samples: row.get(1)?,
+      total: row.get(2)?,
+      minimum: row.get(3)?,
+      maximum: row.get(4)?,
+    })
+  })?;
+
+  let mut pv = Vec::new();
+  for rec in rec_iter {
+    pv.push(rec?);
+  }
+  Ok(pv)
+}
+
+fn archive_sensor(conn: &Connection, id: i64) -> Result<ArchiveSensor, Error> {
+  let mut sensor = conn.query_row(
+    "SELECT sensor.name, sensor.description, sensor.createdate, sensor.changeddate,
+            retention.rawdays, retention.hourlydays, retention.dailydays, rollup.rolledupto,
+            sensortype.dims, sensortype.unit
+       FROM sensor
+       LEFT JOIN retention ON retention.sensor = sensor.id
+       LEFT JOIN rollup ON rollup.sensor = sensor.id
+       LEFT JOIN sensortype ON sensortype.sensor = sensor.id
+       WHERE sensor.id = ?1",
+    params![id],
+    |row| {
+      Ok(ArchiveSensor {
+        id: id,
+        name: row.get(0)?,
+        description: row.get(1)?,
+        createdate: row.get(2)?,
+        changeddate: row.get(3)?,
+        kind: None,
+        dims: row.get(8)?,
+        unit: row.get(9)?,
+        calibration: None,
+        calibrations: None,
+        formula: None,
+        rawdays: row.get(4)?,
+        hourlydays: row.get(5)?,
+        dailydays: row.get(6)?,
+        rolledupto: row.get(7)?,
+        measurements: Vec::new(),
+        hourly: Vec::new(),
+        daily: Vec::new(),
+      })
+    },
+  )?;
+
+  sensor.kind = Some(sensor_kind(conn, id)?.0);
+  sensor.formula = sensor_formula(conn, id)?;
+
+  let history = sensor_calibrations(conn, id)?;
+  {
+    let mut pstmt = conn.prepare(
+      "SELECT effective, transform, createdate FROM calibrationrecord
+        WHERE sensor = ?1 ORDER BY effective",
+    )?;
+    let rec_iter = pstmt.query_map(params![id], |row| {
+      Ok((
+        row.get::<_, i64>(0)?,
+        row.get::<_, Option<String>>(1)?,
+        row.get::<_, i64>(2)?,
+      ))
+    })?;
+    let mut calibrations = Vec::new();
+    for rec in rec_iter {
+      let (effective, transform, createdate) = rec?;
+      calibrations.push(ArchiveCalibration {
+        effective: effective,
+        calibration: parse_transform(transform)?,
+        createdate: createdate,
+      });
+    }
+    sensor.calibrations = Some(calibrations);
+  }
+
+  let mut pstmt = conn.prepare(
+    "SELECT value, payload, measuredate, createdate, lat, lon, elevation
+       FROM measurement WHERE sensor = ?1
+      ORDER BY measuredate",
+  )?;
+  let rec_iter = pstmt.query_map(params![id], |row| {
+    Ok(ArchiveMeasurement {
+      value: row.get(0)?,
+      payload: row.get(1)?,
+      measuredate: row.get(2)?,
+      createdate: row.get(3)?,
+      position: geo::from_columns(row.get(4)?, row.get(5)?, row.get(6)?),
+      calibrated: None,
+    })
+  })?;
+  for rec in rec_iter {
+    let mut m = rec?;
+    m.calibrated = calibration::in_force(&history, m.measuredate).map(|c| c.apply(m.value));
+    sensor.measurements.push(m);
+  }
+
+  sensor.hourly = archive_rollups(conn, Rollup::Hourly, id)?;
+  sensor.daily = archive_rollups(conn, Rollup::Daily, id)?;
+
+  Ok(sensor)
+}
+
+// everything belonging to a user, read in one transaction so the archive is
+// consistent.
+pub fn export_user(pool: &DbPool, uid: i64) -> Result<Archive, Error> {
+  let mut conn = pool.get()?;
+
+  let tx = conn.transaction()?;
+
+  let name: String = tx.query_row("SELECT name FROM user WHERE id = ?1", params![uid], |row| {
+    row.get(0)
+  })?;
+
+  let mut devices = Vec::new();
+  {
+    let mut pstmt = tx.prepare(
+      "SELECT device.id, device.name, device.description, device.createdate,
+              device.changeddate, devicelocation.lat, devicelocation.lon, devicelocation.elevation
+         FROM device
+         LEFT JOIN devicelocation ON devicelocation.device = device.id
+        WHERE device.user = ?1 ORDER BY device.id",
+    )?;
+    let rec_iter = pstmt.query_map(params![uid], |row| {
+      Ok(ArchiveDevice {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        description: row.get(2)?,
+        createdate: row.get(3)?,
+        changeddate: row.get(4)?,
+        location: geo::from_columns(row.get(5)?, row.get(6)?, row.get(7)?),
+        sensors: Vec::new(),
+      })
+    })?;
+    for rec in rec_iter {
+      devices.push(rec?);
+    }
+  }
+
+  for device in devices.iter_mut() {
+    let mut ids = Vec::new();
+    {
+      let mut pstmt = tx.prepare("SELECT id FROM sensor WHERE device = ?1 ORDER BY id")?;
+      let rec_iter = pstmt.query_map(params![device.id], |row| row.get(0))?;
+      for rec in rec_iter {
+        ids.push(rec?);
+      }
+    }
+    for id in ids {
+      device.sensors.push(archive_sensor(&tx, id)?);
+    }
+  }
+
+  tx.commit()?;
+
+  Ok(Archive {
+    version: ARCHIVE_VERSION,
+    exportdate: now()?,
+    user: name,
+    devices: devices,
+  })
+}
+
+// add the archive's devices, sensors and measurements to a user's, with new
+// ids.  All or nothing.
+pub fn import_user(pool: &DbPool, uid: i64, archive: &Archive) -> Result<ImportSummary, Error> {
+  let mut conn = pool.get()?;
+
+  check_version(archive)?;
+
+  let now = now()?;
+  let tx = conn.transaction()?;
+
+  let mut summary = ImportSummary {
+    devices: 0,
+    sensors: 0,
+    measurements: 0,
+  };
+
+  // the archive's sensor ids to the new ones, for the virtual sensor inputs.
+  let mut sensorids = HashMap::new();
+  let mut formulas = Vec::new();
+
+  for device in archive.devices.iter() {
+    tx.execute(
+      "INSERT INTO device (user, name, description, createdate, changeddate)
+       VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![
+        uid,
+        device.name,
+        device.description,
+        device.createdate,
+        device.changeddate
+      ],
+    )?;
+    let deviceid = tx.last_insert_rowid();
+    summary.devices += 1;
+
+    match &device.location {
+      Some(l) => {
+        geo::check_location(l)?;
+        tx.execute(
+          "INSERT INTO devicelocation (device, lat, lon, elevation) VALUES (?1, ?2, ?3, ?4)",
+          params![deviceid, l.lat, l.lon, l.elevation],
+        )?;
+      }
+      None => (),
+    }
+
+    for sensor in device.sensors.iter() {
+      tx.execute(
+        "INSERT INTO sensor (device, name, description, createdate, changeddate)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+          deviceid,
+          sensor.name,
+          sensor.description,
+          sensor.createdate,
+          sensor.changeddate
+        ],
+      )?;
+      let sensorid = tx.last_insert_rowid();
+      summary.sensors += 1;
+      sensorids.insert(sensor.id, sensorid);
+
+      for c in archive::sensor_calibrations(sensor, archive.exportdate).iter() {
+        match &c.calibration {
+          Some(c) => c.check()?,
+          None => (),
+        }
+        let transform = transform_string(&c.calibration)?;
+        tx.execute(
+          "INSERT INTO calibrationrecord (sensor, effective, transform, createdate, user)
+           VALUES (?1, ?2, ?3, ?4, ?5)",
+          params![sensorid, c.effective, transform, c.createdate, uid],
+        )?;
+        add_calibration_audit(
+          &tx,
+          uid,
+          sensorid,
+          calibration::AUDIT_IMPORT,
+          c.effective,
+          &transform,
+          now,
+        )?;
+      }
+      match &sensor.formula {
+        Some(f) => formulas.push((sensorid, f)),
+        None => (),
+      }
+
+      if sensor.rawdays.is_some() || sensor.hourlydays.is_some() || sensor.dailydays.is_some() {
+        tx.execute(
+          "INSERT INTO retention (sensor, rawdays, hourlydays, dailydays)
+           VALUES (?1, ?2, ?3, ?4)",
+          params![
+            sensorid,
+            sensor.rawdays,
+            sensor.hourlydays,
+            sensor.dailydays
+          ],
+        )?;
+      }
+      match sensor.rolledupto {
+        Some(to) => {
+          tx.execute(
+            "INSERT INTO rollup (sensor, rolledupto) VALUES (?1, ?2)",
+            params![sensorid, to],
+          )?;
+        }
+        None => (),
+      }
+      match (sensor.kind, &sensor.unit) {
+        (Some(ValueKind::Float), None) | (None, None) => (),
+        (kind, unit) => {
+          let kind = kind.unwrap_or(ValueKind::Float);
+          readings::check_kind(kind, sensor.dims)?;
+          tx.execute(
+            "INSERT INTO sensortype (sensor, kind, dims, unit) VALUES (?1, ?2, ?3, ?4)",
+            params![sensorid, kind.as_str(), sensor.dims, unit],
+          )?;
+        }
+      }
+
+      {
+        let mut pstmt = tx.prepare(
+          "INSERT INTO measurement
+             (sensor, value, payload, measuredate, createdate, lat, lon, elevation)
+           VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        if sensor.formula.is_some() && !sensor.measurements.is_empty() {
+          return Err(Error::BadRequest(format!(
+            "virtual sensor {} can't have measurements",
+            sensor.id
+          )));
+        }
+        let kind = sensor.kind.unwrap_or(ValueKind::Float);
+        for m in sensor.measurements.iter() {
+          let (value, payload) = readings::check_archived(
+            kind,
+            sensor.dims,
+            m.value,
+            m.payload.as_ref().map(|p| p.as_str()),
+          )?;
+          let p = m.position.as_ref();
+          match p {
+            Some(p) => geo::check_location(p)?,
+            None => (),
+          }
+          pstmt.execute(params![
+            sensorid,
+            value,
+            payload,
+            m.measuredate,
+            m.createdate,
+            p.map(|p| p.lat),
+            p.map(|p| p.lon),
+            p.and_then(|p| p.elevation)
+          ])?;
+          summary.measurements += 1;
+        }
+      }
+
+      for (rollup, rows) in [
+        (Rollup::Hourly, &sensor.hourly),
+        (Rollup::Daily, &sensor.daily),
+      ]
+      .iter()
+      {
+        let mut pstmt = tx.prepare(
+          format!(
+            "INSERT INTO {} (sensor, period, samples, total, minimum, maximum)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rollup.table()
+          )
+          .as_str(),
+        )?;
+        for r in rows.iter() {
+          pstmt.execute(params![
+            sensorid, r.period, r.samples, r.total, r.minimum, r.maximum
+          ])?;
+        }
+      }
+    }
+  }
+
+  for (sensorid, f) in formulas {
+    insert_formula(&tx, sensorid, &calibration::remap_inputs(f, &sensorids)?)?;
+  }
+
+  tx.commit()?;
+
+  Ok(summary)
+}
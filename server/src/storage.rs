@@ -0,0 +1,888 @@
+use archive::{Archive, ImportSummary};
+use calibration::{CalibrationAudit, CalibrationRecord, SensorCalibration, VirtualSensor};
+use config::Config;
+use error::Error;
+use geo::{BBox, DeviceLocation, LocatedDevice};
+use influx::NamedMeasurement;
+use metrics;
+use pgdata;
+use pgdata::PgPool;
+use readings::{Reading, ReadingQuery, SaveReading, SensorType};
+use retention::{Rollup, RollupEntry, RollupQuery, SensorRetention};
+use sciota_protocol::protocol::{
+  Device, Measurement, SaveDevice, SaveMeasurement, SaveSensor, Sensor,
+};
+use sqldata;
+use sqldata::{DbPool, User, UserListEntry};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use templates::{DeviceTemplate, Instantiate, InstantiatedDevice, SaveTemplate};
+use udp::TokenDevice;
+
+// the database operations used by the interfaces.  There's an implementation
+// for sqlite (sqldata) and for postgres (pgdata); which one is used is chosen
+// by 'backend' in the config.
+pub trait Storage: Send + Sync {
+  // users
+  fn read_user(&self, name: &str) -> Result<User, Error>;
+  fn update_user(&self, user: &User) -> Result<(), Error>;
+  fn new_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+    registration_key: String,
+  ) -> Result<i64, Error>;
+  // a user who's registered already, as if they'd followed the email link.
+  fn new_registered_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+  ) -> Result<i64, Error>;
+
+  // user admin
+  fn user_listing(&self) -> Result<Vec<UserListEntry>, Error>;
+  fn set_admins(&self, admins: &Vec<String>) -> Result<(), Error>;
+  fn set_user_disabled(&self, name: &str, disabled: bool) -> Result<(), Error>;
+  fn confirm_user(&self, name: &str) -> Result<(), Error>;
+  fn delete_user(&self, name: &str) -> Result<(), Error>;
+
+  // devices
+  fn save_device(&self, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error>;
+  fn read_device(&self, uid: i64, id: i64) -> Result<Device, Error>;
+  fn delete_device(&self, uid: i64, id: i64) -> Result<(), Error>;
+  fn devicelisting(&self, uid: i64) -> Result<Vec<Device>, Error>;
+
+  // sensors
+  fn save_sensor(&self, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error>;
+  fn read_sensor(&self, uid: i64, id: i64) -> Result<Sensor, Error>;
+  fn delete_sensor(&self, uid: i64, id: i64) -> Result<(), Error>;
+  fn sensorlisting(&self, uid: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error>;
+
+  // measurements
+  fn add_measurement(&self, uid: i64, measurement: &SaveMeasurement) -> Result<i64, Error>;
+  fn add_measurements(&self, uid: i64, measurements: &[SaveMeasurement])
+    -> Result<Vec<i64>, Error>;
+  fn add_named_measurements(
+    &self,
+    uid: i64,
+    measurements: &[NamedMeasurement],
+    autocreate: bool,
+  ) -> Result<usize, Error>;
+  fn measurement_listing(
+    &self,
+    uid: i64,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error>;
+
+  // typed readings
+  fn get_sensor_type(&self, uid: i64, sensor: i64) -> Result<SensorType, Error>;
+  fn set_sensor_type(&self, uid: i64, st: &SensorType) -> Result<(), Error>;
+  fn add_reading(&self, uid: i64, reading: &SaveReading) -> Result<i64, Error>;
+  fn reading_listing(&self, uid: i64, query: &ReadingQuery) -> Result<Vec<Reading>, Error>;
+
+  // calibration and virtual sensors
+  fn get_calibration(&self, uid: i64, sensor: i64) -> Result<SensorCalibration, Error>;
+  fn set_calibration(&self, uid: i64, sc: &SensorCalibration) -> Result<(), Error>;
+  fn calibration_history(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationRecord>, Error>;
+  fn delete_calibration(&self, uid: i64, id: i64) -> Result<(), Error>;
+  fn calibration_audit(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationAudit>, Error>;
+  fn get_virtual_sensor(&self, uid: i64, sensor: i64) -> Result<VirtualSensor, Error>;
+  fn set_virtual_sensor(&self, uid: i64, vs: &VirtualSensor) -> Result<(), Error>;
+
+  // device locations
+  fn get_device_location(&self, uid: i64, device: i64) -> Result<DeviceLocation, Error>;
+  fn set_device_location(&self, uid: i64, dl: &DeviceLocation) -> Result<(), Error>;
+  fn located_devices(&self, uid: i64, bbox: &Option<BBox>) -> Result<Vec<LocatedDevice>, Error>;
+
+  // device tokens, for udp ingest.  Tokens are stored as hashes.
+  fn set_device_token(&self, uid: i64, device: i64, hash: Option<&str>) -> Result<(), Error>;
+  fn token_device(&self, hash: &str) -> Result<TokenDevice, Error>;
+
+  // device templates
+  fn save_template(&self, uid: i64, template: &SaveTemplate) -> Result<i64, Error>;
+  fn read_template(&self, uid: i64, id: i64) -> Result<DeviceTemplate, Error>;
+  fn template_listing(&self, uid: i64) -> Result<Vec<DeviceTemplate>, Error>;
+  fn delete_template(&self, uid: i64, id: i64) -> Result<(), Error>;
+  fn instantiate_template(
+    &self,
+    uid: i64,
+    inst: &Instantiate,
+  ) -> Result<Vec<InstantiatedDevice>, Error>;
+
+  // retention and rollups
+  fn get_retention(&self, uid: i64, sensor: i64) -> Result<SensorRetention, Error>;
+  fn set_retention(&self, uid: i64, sr: &SensorRetention) -> Result<(), Error>;
+  fn rollup_listing(&self, uid: i64, query: &RollupQuery) -> Result<Vec<RollupEntry>, Error>;
+
+  // a user's data as a portable archive, and importing one.
+  fn export_user(&self, uid: i64) -> Result<Archive, Error>;
+  fn import_user(&self, uid: i64, archive: &Archive) -> Result<ImportSummary, Error>;
+
+  // write a snapshot of the database to 'dest'.
+  fn backup(&self, dest: &Path) -> Result<(), Error>;
+  // the schema version, as set by migrations.
+  fn migration_level(&self) -> Result<i64, Error>;
+  // problems found by the database's own consistency checks, if any.
+  fn integrity_check(&self) -> Result<Vec<String>, Error>;
+
+  // for the retention job; these aren't limited to one user's sensors.
+  fn sensor_retentions(&self) -> Result<Vec<SensorRetention>, Error>;
+  // roll up and delete a batch of raw measurements; see sqldata.
+  fn rollup_measurements(&self, sensor: i64, before: i64, limit: i64) -> Result<i64, Error>;
+  fn prune_rollups(
+    &self,
+    rollup: Rollup,
+    sensor: i64,
+    before: i64,
+    limit: i64,
+  ) -> Result<i64, Error>;
+}
+
+pub type Db = Box<dyn Storage>;
+
+// migrate the configured database and open a connection pool for it.
+pub fn open_storage(config: &Config) -> Result<Db, Error> {
+  connect(config, true)
+}
+
+// open a connection pool for the configured database, migrating it first if
+// 'migrate' is set.  Without migrating, a sqlite database has to exist
+// already.
+pub fn connect(config: &Config, migrate: bool) -> Result<Db, Error> {
+  let pool_size = config.pool_size.unwrap_or(8);
+
+  match config.backend.as_ref().map(|b| b.as_str()) {
+    None | Some("sqlite") => {
+      if migrate {
+        sqldata::dbinit(config.db.as_path())?;
+      } else if !config.db.exists() {
+        return Err(Error::NotFound(format!("no database at {:?}", config.db)));
+      }
+      let pool = sqldata::connection_pool(
+        config.db.as_path(),
+        pool_size,
+        Duration::from_millis(config.busy_timeout_ms.unwrap_or(5000)),
+      )?;
+      Ok(Box::new(MeteredStorage {
+        inner: Box::new(SqliteStorage { pool: pool }),
+      }))
+    }
+    Some("postgres") => {
+      let url = config.postgres_url.as_ref().ok_or(Error::Internal(
+        "'postgres_url' is required for the postgres backend".to_string(),
+      ))?;
+      let pool = pgdata::connection_pool(url.as_str(), pool_size)?;
+      if migrate {
+        pgdata::dbinit(&pool)?;
+      }
+      Ok(Box::new(MeteredStorage {
+        inner: Box::new(PgStorage { pool: pool }),
+      }))
+    }
+    Some(b) => Err(Error::Internal(format!("unknown database backend: {}", b))),
+  }
+}
+
+pub struct SqliteStorage {
+  pub pool: DbPool,
+}
+
+impl Storage for SqliteStorage {
+  fn read_user(&self, name: &str) -> Result<User, Error> {
+    sqldata::read_user(&self.pool, name)
+  }
+  fn update_user(&self, user: &User) -> Result<(), Error> {
+    sqldata::update_user(&self.pool, user)
+  }
+  fn new_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+    registration_key: String,
+  ) -> Result<i64, Error> {
+    sqldata::new_user(&self.pool, name, hashwd, salt, email, registration_key)
+  }
+  fn new_registered_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+  ) -> Result<i64, Error> {
+    sqldata::new_registered_user(&self.pool, name, hashwd, salt, email)
+  }
+
+  fn user_listing(&self) -> Result<Vec<UserListEntry>, Error> {
+    sqldata::user_listing(&self.pool)
+  }
+  fn set_admins(&self, admins: &Vec<String>) -> Result<(), Error> {
+    sqldata::set_admins(&self.pool, admins)
+  }
+  fn set_user_disabled(&self, name: &str, disabled: bool) -> Result<(), Error> {
+    sqldata::set_user_disabled(&self.pool, name, disabled)
+  }
+  fn confirm_user(&self, name: &str) -> Result<(), Error> {
+    sqldata::confirm_user(&self.pool, name)
+  }
+  fn delete_user(&self, name: &str) -> Result<(), Error> {
+    sqldata::delete_user(&self.pool, name)
+  }
+
+  fn save_device(&self, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error> {
+    sqldata::save_device(&self.pool, uid, savedevice)
+  }
+  fn read_device(&self, uid: i64, id: i64) -> Result<Device, Error> {
+    sqldata::read_device(&self.pool, uid, id)
+  }
+  fn delete_device(&self, uid: i64, id: i64) -> Result<(), Error> {
+    sqldata::delete_device(&self.pool, uid, id)
+  }
+  fn devicelisting(&self, uid: i64) -> Result<Vec<Device>, Error> {
+    sqldata::devicelisting(&self.pool, uid)
+  }
+
+  fn save_sensor(&self, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error> {
+    sqldata::save_sensor(&self.pool, uid, sensor)
+  }
+  fn read_sensor(&self, uid: i64, id: i64) -> Result<Sensor, Error> {
+    sqldata::read_sensor(&self.pool, uid, id)
+  }
+  fn delete_sensor(&self, uid: i64, id: i64) -> Result<(), Error> {
+    sqldata::delete_sensor(&self.pool, uid, id)
+  }
+  fn sensorlisting(&self, uid: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error> {
+    sqldata::sensorlisting(&self.pool, uid, device)
+  }
+
+  fn add_measurement(&self, uid: i64, measurement: &SaveMeasurement) -> Result<i64, Error> {
+    sqldata::add_measurement(&self.pool, uid, measurement)
+  }
+  fn add_measurements(
+    &self,
+    uid: i64,
+    measurements: &[SaveMeasurement],
+  ) -> Result<Vec<i64>, Error> {
+    sqldata::add_measurements(&self.pool, uid, measurements)
+  }
+  fn add_named_measurements(
+    &self,
+    uid: i64,
+    measurements: &[NamedMeasurement],
+    autocreate: bool,
+  ) -> Result<usize, Error> {
+    sqldata::add_named_measurements(&self.pool, uid, measurements, autocreate)
+  }
+  fn measurement_listing(
+    &self,
+    uid: i64,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error> {
+    sqldata::measurement_listing(&self.pool, uid, sensor, from, to)
+  }
+
+  fn get_sensor_type(&self, uid: i64, sensor: i64) -> Result<SensorType, Error> {
+    sqldata::get_sensor_type(&self.pool, uid, sensor)
+  }
+  fn set_sensor_type(&self, uid: i64, st: &SensorType) -> Result<(), Error> {
+    sqldata::set_sensor_type(&self.pool, uid, st)
+  }
+  fn add_reading(&self, uid: i64, reading: &SaveReading) -> Result<i64, Error> {
+    sqldata::add_reading(&self.pool, uid, reading)
+  }
+  fn reading_listing(&self, uid: i64, query: &ReadingQuery) -> Result<Vec<Reading>, Error> {
+    sqldata::reading_listing(&self.pool, uid, query)
+  }
+  fn get_calibration(&self, uid: i64, sensor: i64) -> Result<SensorCalibration, Error> {
+    sqldata::get_calibration(&self.pool, uid, sensor)
+  }
+  fn set_calibration(&self, uid: i64, sc: &SensorCalibration) -> Result<(), Error> {
+    sqldata::set_calibration(&self.pool, uid, sc)
+  }
+  fn calibration_history(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationRecord>, Error> {
+    sqldata::calibration_history(&self.pool, uid, sensor)
+  }
+  fn delete_calibration(&self, uid: i64, id: i64) -> Result<(), Error> {
+    sqldata::delete_calibration(&self.pool, uid, id)
+  }
+  fn calibration_audit(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationAudit>, Error> {
+    sqldata::calibration_audit(&self.pool, uid, sensor)
+  }
+  fn get_virtual_sensor(&self, uid: i64, sensor: i64) -> Result<VirtualSensor, Error> {
+    sqldata::get_virtual_sensor(&self.pool, uid, sensor)
+  }
+  fn set_virtual_sensor(&self, uid: i64, vs: &VirtualSensor) -> Result<(), Error> {
+    sqldata::set_virtual_sensor(&self.pool, uid, vs)
+  }
+  fn get_device_location(&self, uid: i64, device: i64) -> Result<DeviceLocation, Error> {
+    sqldata::get_device_location(&self.pool, uid, device)
+  }
+  fn set_device_location(&self, uid: i64, dl: &DeviceLocation) -> Result<(), Error> {
+    sqldata::set_device_location(&self.pool, uid, dl)
+  }
+  fn located_devices(&self, uid: i64, bbox: &Option<BBox>) -> Result<Vec<LocatedDevice>, Error> {
+    sqldata::located_devices(&self.pool, uid, bbox)
+  }
+  fn set_device_token(&self, uid: i64, device: i64, hash: Option<&str>) -> Result<(), Error> {
+    sqldata::set_device_token(&self.pool, uid, device, hash)
+  }
+  fn token_device(&self, hash: &str) -> Result<TokenDevice, Error> {
+    sqldata::token_device(&self.pool, hash)
+  }
+  fn save_template(&self, uid: i64, template: &SaveTemplate) -> Result<i64, Error> {
+    sqldata::save_template(&self.pool, uid, template)
+  }
+  fn read_template(&self, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+    sqldata::read_template(&self.pool, uid, id)
+  }
+  fn template_listing(&self, uid: i64) -> Result<Vec<DeviceTemplate>, Error> {
+    sqldata::template_listing(&self.pool, uid)
+  }
+  fn delete_template(&self, uid: i64, id: i64) -> Result<(), Error> {
+    sqldata::delete_template(&self.pool, uid, id)
+  }
+  fn instantiate_template(
+    &self,
+    uid: i64,
+    inst: &Instantiate,
+  ) -> Result<Vec<InstantiatedDevice>, Error> {
+    sqldata::instantiate_template(&self.pool, uid, inst)
+  }
+
+  fn get_retention(&self, uid: i64, sensor: i64) -> Result<SensorRetention, Error> {
+    sqldata::get_retention(&self.pool, uid, sensor)
+  }
+  fn set_retention(&self, uid: i64, sr: &SensorRetention) -> Result<(), Error> {
+    sqldata::set_retention(&self.pool, uid, sr)
+  }
+  fn rollup_listing(&self, uid: i64, query: &RollupQuery) -> Result<Vec<RollupEntry>, Error> {
+    sqldata::rollup_listing(&self.pool, uid, query)
+  }
+
+  fn export_user(&self, uid: i64) -> Result<Archive, Error> {
+    sqldata::export_user(&self.pool, uid)
+  }
+  fn import_user(&self, uid: i64, archive: &Archive) -> Result<ImportSummary, Error> {
+    sqldata::import_user(&self.pool, uid, archive)
+  }
+
+  fn backup(&self, dest: &Path) -> Result<(), Error> {
+    sqldata::backup(&self.pool, dest)
+  }
+  fn migration_level(&self) -> Result<i64, Error> {
+    sqldata::migration_level(&self.pool)
+  }
+  fn integrity_check(&self) -> Result<Vec<String>, Error> {
+    sqldata::integrity_check(&self.pool)
+  }
+
+  fn sensor_retentions(&self) -> Result<Vec<SensorRetention>, Error> {
+    sqldata::sensor_retentions(&self.pool)
+  }
+  fn rollup_measurements(&self, sensor: i64, before: i64, limit: i64) -> Result<i64, Error> {
+    sqldata::rollup_measurements(&self.pool, sensor, before, limit)
+  }
+  fn prune_rollups(
+    &self,
+    rollup: Rollup,
+    sensor: i64,
+    before: i64,
+    limit: i64,
+  ) -> Result<i64, Error> {
+    sqldata::prune_rollups(&self.pool, rollup, sensor, before, limit)
+  }
+}
+
+pub struct PgStorage {
+  pub pool: PgPool,
+}
+
+impl Storage for PgStorage {
+  fn read_user(&self, name: &str) -> Result<User, Error> {
+    pgdata::read_user(&self.pool, name)
+  }
+  fn update_user(&self, user: &User) -> Result<(), Error> {
+    pgdata::update_user(&self.pool, user)
+  }
+  fn new_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+    registration_key: String,
+  ) -> Result<i64, Error> {
+    pgdata::new_user(&self.pool, name, hashwd, salt, email, registration_key)
+  }
+  fn new_registered_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+  ) -> Result<i64, Error> {
+    pgdata::new_registered_user(&self.pool, name, hashwd, salt, email)
+  }
+
+  fn user_listing(&self) -> Result<Vec<UserListEntry>, Error> {
+    pgdata::user_listing(&self.pool)
+  }
+  fn set_admins(&self, admins: &Vec<String>) -> Result<(), Error> {
+    pgdata::set_admins(&self.pool, admins)
+  }
+  fn set_user_disabled(&self, name: &str, disabled: bool) -> Result<(), Error> {
+    pgdata::set_user_disabled(&self.pool, name, disabled)
+  }
+  fn confirm_user(&self, name: &str) -> Result<(), Error> {
+    pgdata::confirm_user(&self.pool, name)
+  }
+  fn delete_user(&self, name: &str) -> Result<(), Error> {
+    pgdata::delete_user(&self.pool, name)
+  }
+
+  fn save_device(&self, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error> {
+    pgdata::save_device(&self.pool, uid, savedevice)
+  }
+  fn read_device(&self, uid: i64, id: i64) -> Result<Device, Error> {
+    pgdata::read_device(&self.pool, uid, id)
+  }
+  fn delete_device(&self, uid: i64, id: i64) -> Result<(), Error> {
+    pgdata::delete_device(&self.pool, uid, id)
+  }
+  fn devicelisting(&self, uid: i64) -> Result<Vec<Device>, Error> {
+    pgdata::devicelisting(&self.pool, uid)
+  }
+
+  fn save_sensor(&self, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error> {
+    pgdata::save_sensor(&self.pool, uid, sensor)
+  }
+  fn read_sensor(&self, uid: i64, id: i64) -> Result<Sensor, Error> {
+    pgdata::read_sensor(&self.pool, uid, id)
+  }
+  fn delete_sensor(&self, uid: i64, id: i64) -> Result<(), Error> {
+    pgdata::delete_sensor(&self.pool, uid, id)
+  }
+  fn sensorlisting(&self, uid: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error> {
+    pgdata::sensorlisting(&self.pool, uid, device)
+  }
+
+  fn add_measurement(&self, uid: i64, measurement: &SaveMeasurement) -> Result<i64, Error> {
+    pgdata::add_measurement(&self.pool, uid, measurement)
+  }
+  fn add_measurements(
+    &self,
+    uid: i64,
+    measurements: &[SaveMeasurement],
+  ) -> Result<Vec<i64>, Error> {
+    pgdata::add_measurements(&self.pool, uid, measurements)
+  }
+  fn add_named_measurements(
+    &self,
+    uid: i64,
+    measurements: &[NamedMeasurement],
+    autocreate: bool,
+  ) -> Result<usize, Error> {
+    pgdata::add_named_measurements(&self.pool, uid, measurements, autocreate)
+  }
+  fn measurement_listing(
+    &self,
+    uid: i64,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error> {
+    pgdata::measurement_listing(&self.pool, uid, sensor, from, to)
+  }
+
+  fn get_sensor_type(&self, uid: i64, sensor: i64) -> Result<SensorType, Error> {
+    pgdata::get_sensor_type(&self.pool, uid, sensor)
+  }
+  fn set_sensor_type(&self, uid: i64, st: &SensorType) -> Result<(), Error> {
+    pgdata::set_sensor_type(&self.pool, uid, st)
+  }
+  fn add_reading(&self, uid: i64, reading: &SaveReading) -> Result<i64, Error> {
+    pgdata::add_reading(&self.pool, uid, reading)
+  }
+  fn reading_listing(&self, uid: i64, query: &ReadingQuery) -> Result<Vec<Reading>, Error> {
+    pgdata::reading_listing(&self.pool, uid, query)
+  }
+  fn get_calibration(&self, uid: i64, sensor: i64) -> Result<SensorCalibration, Error> {
+    pgdata::get_calibration(&self.pool, uid, sensor)
+  }
+  fn set_calibration(&self, uid: i64, sc: &SensorCalibration) -> Result<(), Error> {
+    pgdata::set_calibration(&self.pool, uid, sc)
+  }
+  fn calibration_history(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationRecord>, Error> {
+    pgdata::calibration_history(&self.pool, uid, sensor)
+  }
+  fn delete_calibration(&self, uid: i64, id: i64) -> Result<(), Error> {
+    pgdata::delete_calibration(&self.pool, uid, id)
+  }
+  fn calibration_audit(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationAudit>, Error> {
+    pgdata::calibration_audit(&self.pool, uid, sensor)
+  }
+  fn get_virtual_sensor(&self, uid: i64, sensor: i64) -> Result<VirtualSensor, Error> {
+    pgdata::get_virtual_sensor(&self.pool, uid, sensor)
+  }
+  fn set_virtual_sensor(&self, uid: i64, vs: &VirtualSensor) -> Result<(), Error> {
+    pgdata::set_virtual_sensor(&self.pool, uid, vs)
+  }
+  fn get_device_location(&self, uid: i64, device: i64) -> Result<DeviceLocation, Error> {
+    pgdata::get_device_location(&self.pool, uid, device)
+  }
+  fn set_device_location(&self, uid: i64, dl: &DeviceLocation) -> Result<(), Error> {
+    pgdata::set_device_location(&self.pool, uid, dl)
+  }
+  fn located_devices(&self, uid: i64, bbox: &Option<BBox>) -> Result<Vec<LocatedDevice>, Error> {
+    pgdata::located_devices(&self.pool, uid, bbox)
+  }
+  fn set_device_token(&self, uid: i64, device: i64, hash: Option<&str>) -> Result<(), Error> {
+    pgdata::set_device_token(&self.pool, uid, device, hash)
+  }
+  fn token_device(&self, hash: &str) -> Result<TokenDevice, Error> {
+    pgdata::token_device(&self.pool, hash)
+  }
+  fn save_template(&self, uid: i64, template: &SaveTemplate) -> Result<i64, Error> {
+    pgdata::save_template(&self.pool, uid, template)
+  }
+  fn read_template(&self, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+    pgdata::read_template(&self.pool, uid, id)
+  }
+  fn template_listing(&self, uid: i64) -> Result<Vec<DeviceTemplate>, Error> {
+    pgdata::template_listing(&self.pool, uid)
+  }
+  fn delete_template(&self, uid: i64, id: i64) -> Result<(), Error> {
+    pgdata::delete_template(&self.pool, uid, id)
+  }
+  fn instantiate_template(
+    &self,
+    uid: i64,
+    inst: &Instantiate,
+  ) -> Result<Vec<InstantiatedDevice>, Error> {
+    pgdata::instantiate_template(&self.pool, uid, inst)
+  }
+
+  fn get_retention(&self, uid: i64, sensor: i64) -> Result<SensorRetention, Error> {
+    pgdata::get_retention(&self.pool, uid, sensor)
+  }
+  fn set_retention(&self, uid: i64, sr: &SensorRetention) -> Result<(), Error> {
+    pgdata::set_retention(&self.pool, uid, sr)
+  }
+  fn rollup_listing(&self, uid: i64, query: &RollupQuery) -> Result<Vec<RollupEntry>, Error> {
+    pgdata::rollup_listing(&self.pool, uid, query)
+  }
+
+  fn export_user(&self, uid: i64) -> Result<Archive, Error> {
+    pgdata::export_user(&self.pool, uid)
+  }
+  fn import_user(&self, uid: i64, archive: &Archive) -> Result<ImportSummary, Error> {
+    pgdata::import_user(&self.pool, uid, archive)
+  }
+
+  fn backup(&self, _dest: &Path) -> Result<(), Error> {
+    Err(Error::BadRequest(
+      "backups are only supported for the sqlite backend; use pg_dump for postgres".to_string(),
+    ))
+  }
+  fn migration_level(&self) -> Result<i64, Error> {
+    pgdata::migration_level(&self.pool)
+  }
+  fn integrity_check(&self) -> Result<Vec<String>, Error> {
+    pgdata::integrity_check(&self.pool)
+  }
+
+  fn sensor_retentions(&self) -> Result<Vec<SensorRetention>, Error> {
+    pgdata::sensor_retentions(&self.pool)
+  }
+  fn rollup_measurements(&self, sensor: i64, before: i64, limit: i64) -> Result<i64, Error> {
+    pgdata::rollup_measurements(&self.pool, sensor, before, limit)
+  }
+  fn prune_rollups(
+    &self,
+    rollup: Rollup,
+    sensor: i64,
+    before: i64,
+    limit: i64,
+  ) -> Result<i64, Error> {
+    pgdata::prune_rollups(&self.pool, rollup, sensor, before, limit)
+  }
+}
+
+// another Storage, timing each operation for the metrics and the debug log,
+// and counting the measurements and readings stored.
+pub struct MeteredStorage {
+  pub inner: Db,
+}
+
+fn timed<T>(op: &'static str, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+  let start = Instant::now();
+  let res = f();
+  let elapsed = start.elapsed();
+  metrics::db_operation(op, res.is_ok(), elapsed);
+  match &res {
+    Ok(_) => debug!("db {}: ok in {:?}", op, elapsed),
+    Err(e) => debug!("db {}: {} in {:?}", op, e.code(), elapsed),
+  }
+  res
+}
+
+impl Storage for MeteredStorage {
+  fn read_user(&self, name: &str) -> Result<User, Error> {
+    timed("read_user", || self.inner.read_user(name))
+  }
+  fn update_user(&self, user: &User) -> Result<(), Error> {
+    timed("update_user", || self.inner.update_user(user))
+  }
+  fn new_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+    registration_key: String,
+  ) -> Result<i64, Error> {
+    timed("new_user", || {
+      self
+        .inner
+        .new_user(name, hashwd, salt, email, registration_key)
+    })
+  }
+  fn new_registered_user(
+    &self,
+    name: String,
+    hashwd: String,
+    salt: String,
+    email: String,
+  ) -> Result<i64, Error> {
+    timed("new_registered_user", || {
+      self.inner.new_registered_user(name, hashwd, salt, email)
+    })
+  }
+  fn user_listing(&self) -> Result<Vec<UserListEntry>, Error> {
+    timed("user_listing", || self.inner.user_listing())
+  }
+  fn set_admins(&self, admins: &Vec<String>) -> Result<(), Error> {
+    timed("set_admins", || self.inner.set_admins(admins))
+  }
+  fn set_user_disabled(&self, name: &str, disabled: bool) -> Result<(), Error> {
+    timed("set_user_disabled", || {
+      self.inner.set_user_disabled(name, disabled)
+    })
+  }
+  fn confirm_user(&self, name: &str) -> Result<(), Error> {
+    timed("confirm_user", || self.inner.confirm_user(name))
+  }
+  fn delete_user(&self, name: &str) -> Result<(), Error> {
+    timed("delete_user", || self.inner.delete_user(name))
+  }
+  fn save_device(&self, uid: i64, savedevice: &SaveDevice) -> Result<i64, Error> {
+    timed("save_device", || self.inner.save_device(uid, savedevice))
+  }
+  fn read_device(&self, uid: i64, id: i64) -> Result<Device, Error> {
+    timed("read_device", || self.inner.read_device(uid, id))
+  }
+  fn delete_device(&self, uid: i64, id: i64) -> Result<(), Error> {
+    timed("delete_device", || self.inner.delete_device(uid, id))
+  }
+  fn devicelisting(&self, uid: i64) -> Result<Vec<Device>, Error> {
+    timed("devicelisting", || self.inner.devicelisting(uid))
+  }
+  fn save_sensor(&self, uid: i64, sensor: &SaveSensor) -> Result<Sensor, Error> {
+    timed("save_sensor", || self.inner.save_sensor(uid, sensor))
+  }
+  fn read_sensor(&self, uid: i64, id: i64) -> Result<Sensor, Error> {
+    timed("read_sensor", || self.inner.read_sensor(uid, id))
+  }
+  fn delete_sensor(&self, uid: i64, id: i64) -> Result<(), Error> {
+    timed("delete_sensor", || self.inner.delete_sensor(uid, id))
+  }
+  fn sensorlisting(&self, uid: i64, device: Option<i64>) -> Result<Vec<Sensor>, Error> {
+    timed("sensorlisting", || self.inner.sensorlisting(uid, device))
+  }
+  fn add_measurement(&self, uid: i64, measurement: &SaveMeasurement) -> Result<i64, Error> {
+    let id = timed("add_measurement", || {
+      self.inner.add_measurement(uid, measurement)
+    })?;
+    metrics::ingested(1);
+    Ok(id)
+  }
+  fn add_measurements(
+    &self,
+    uid: i64,
+    measurements: &[SaveMeasurement],
+  ) -> Result<Vec<i64>, Error> {
+    let ids = timed("add_measurements", || {
+      self.inner.add_measurements(uid, measurements)
+    })?;
+    metrics::ingested(ids.len());
+    Ok(ids)
+  }
+  fn add_named_measurements(
+    &self,
+    uid: i64,
+    measurements: &[NamedMeasurement],
+    autocreate: bool,
+  ) -> Result<usize, Error> {
+    let n = timed("add_named_measurements", || {
+      self
+        .inner
+        .add_named_measurements(uid, measurements, autocreate)
+    })?;
+    metrics::ingested(n);
+    Ok(n)
+  }
+  fn measurement_listing(
+    &self,
+    uid: i64,
+    sensor: i64,
+    from: Option<i64>,
+    to: Option<i64>,
+  ) -> Result<Vec<Measurement>, Error> {
+    timed("measurement_listing", || {
+      self.inner.measurement_listing(uid, sensor, from, to)
+    })
+  }
+  fn get_sensor_type(&self, uid: i64, sensor: i64) -> Result<SensorType, Error> {
+    timed("get_sensor_type", || {
+      self.inner.get_sensor_type(uid, sensor)
+    })
+  }
+  fn set_sensor_type(&self, uid: i64, st: &SensorType) -> Result<(), Error> {
+    timed("set_sensor_type", || self.inner.set_sensor_type(uid, st))
+  }
+  fn add_reading(&self, uid: i64, reading: &SaveReading) -> Result<i64, Error> {
+    let id = timed("add_reading", || self.inner.add_reading(uid, reading))?;
+    metrics::readings_ingested(1);
+    Ok(id)
+  }
+  fn reading_listing(&self, uid: i64, query: &ReadingQuery) -> Result<Vec<Reading>, Error> {
+    timed("reading_listing", || self.inner.reading_listing(uid, query))
+  }
+  fn get_calibration(&self, uid: i64, sensor: i64) -> Result<SensorCalibration, Error> {
+    timed("get_calibration", || {
+      self.inner.get_calibration(uid, sensor)
+    })
+  }
+  fn set_calibration(&self, uid: i64, sc: &SensorCalibration) -> Result<(), Error> {
+    timed("set_calibration", || self.inner.set_calibration(uid, sc))
+  }
+  fn calibration_history(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationRecord>, Error> {
+    timed("calibration_history", || {
+      self.inner.calibration_history(uid, sensor)
+    })
+  }
+  fn delete_calibration(&self, uid: i64, id: i64) -> Result<(), Error> {
+    timed("delete_calibration", || {
+      self.inner.delete_calibration(uid, id)
+    })
+  }
+  fn calibration_audit(&self, uid: i64, sensor: i64) -> Result<Vec<CalibrationAudit>, Error> {
+    timed("calibration_audit", || {
+      self.inner.calibration_audit(uid, sensor)
+    })
+  }
+  fn get_virtual_sensor(&self, uid: i64, sensor: i64) -> Result<VirtualSensor, Error> {
+    timed("get_virtual_sensor", || {
+      self.inner.get_virtual_sensor(uid, sensor)
+    })
+  }
+  fn set_virtual_sensor(&self, uid: i64, vs: &VirtualSensor) -> Result<(), Error> {
+    timed("set_virtual_sensor", || {
+      self.inner.set_virtual_sensor(uid, vs)
+    })
+  }
+  fn get_device_location(&self, uid: i64, device: i64) -> Result<DeviceLocation, Error> {
+    timed("get_device_location", || {
+      self.inner.get_device_location(uid, device)
+    })
+  }
+  fn set_device_location(&self, uid: i64, dl: &DeviceLocation) -> Result<(), Error> {
+    timed("set_device_location", || {
+      self.inner.set_device_location(uid, dl)
+    })
+  }
+  fn located_devices(&self, uid: i64, bbox: &Option<BBox>) -> Result<Vec<LocatedDevice>, Error> {
+    timed("located_devices", || self.inner.located_devices(uid, bbox))
+  }
+  fn set_device_token(&self, uid: i64, device: i64, hash: Option<&str>) -> Result<(), Error> {
+    timed("set_device_token", || {
+      self.inner.set_device_token(uid, device, hash)
+    })
+  }
+  fn token_device(&self, hash: &str) -> Result<TokenDevice, Error> {
+    timed("token_device", || self.inner.token_device(hash))
+  }
+  fn save_template(&self, uid: i64, template: &SaveTemplate) -> Result<i64, Error> {
+    timed("save_template", || self.inner.save_template(uid, template))
+  }
+  fn read_template(&self, uid: i64, id: i64) -> Result<DeviceTemplate, Error> {
+    timed("read_template", || self.inner.read_template(uid, id))
+  }
+  fn template_listing(&self, uid: i64) -> Result<Vec<DeviceTemplate>, Error> {
+    timed("template_listing", || self.inner.template_listing(uid))
+  }
+  fn delete_template(&self, uid: i64, id: i64) -> Result<(), Error> {
+    timed("delete_template", || self.inner.delete_template(uid, id))
+  }
+  fn instantiate_template(
+    &self,
+    uid: i64,
+    inst: &Instantiate,
+  ) -> Result<Vec<InstantiatedDevice>, Error> {
+    timed("instantiate_template", || {
+      self.inner.instantiate_template(uid, inst)
+    })
+  }
+  fn get_retention(&self, uid: i64, sensor: i64) -> Result<SensorRetention, Error> {
+    timed("get_retention", || self.inner.get_retention(uid, sensor))
+  }
+  fn set_retention(&self, uid: i64, sr: &SensorRetention) -> Result<(), Error> {
+    timed("set_retention", || self.inner.set_retention(uid, sr))
+  }
+  fn rollup_listing(&self, uid: i64, query: &RollupQuery) -> Result<Vec<RollupEntry>, Error> {
+    timed("rollup_listing", || self.inner.rollup_listing(uid, query))
+  }
+  fn export_user(&self, uid: i64) -> Result<Archive, Error> {
+    timed("export_user", || self.inner.export_user(uid))
+  }
+  fn import_user(&self, uid: i64, archive: &Archive) -> Result<ImportSummary, Error> {
+    timed("import_user", || self.inner.import_user(uid, archive))
+  }
+  fn backup(&self, dest: &Path) -> Result<(), Error> {
+    timed("backup", || self.inner.backup(dest))
+  }
+  fn migration_level(&self) -> Result<i64, Error> {
+    timed("migration_level", || self.inner.migration_level())
+  }
+  fn integrity_check(&self) -> Result<Vec<String>, Error> {
+    timed("integrity_check", || self.inner.integrity_check())
+  }
+  fn sensor_retentions(&self) -> Result<Vec<SensorRetention>, Error> {
+    timed("sensor_retentions", || self.inner.sensor_retentions())
+  }
+  fn rollup_measurements(&self, sensor: i64, before: i64, limit: i64) -> Result<i64, Error> {
+    timed("rollup_measurements", || {
+      self.inner.rollup_measurements(sensor, before, limit)
+    })
+  }
+  fn prune_rollups(
+    &self,
+    rollup: Rollup,
+    sensor: i64,
+    before: i64,
+    limit: i64,
+  ) -> Result<i64, Error> {
+    timed("prune_rollups", || {
+      self.inner.prune_rollups(rollup, sensor, before, limit)
+    })
+  }
+}
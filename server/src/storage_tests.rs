@@ -0,0 +1,1230 @@
+// the Storage tests, run against each backend: sqlite, in a file under the
+// temp dir, and postgres if SCIOTA_TEST_POSTGRES_URL is set.  That can be any
+// database the tests may migrate; users are made with unique names and
+// deleted at the end.  The helpers are for other modules' tests too.
+
+use archive::Archive;
+use calibration::{Calibration, Formula, SensorCalibration, VirtualSensor};
+use config::Config;
+use error::Error;
+use geo::{DeviceLocation, Location};
+use influx::NamedMeasurement;
+use pgdata;
+use readings::{ReadingQuery, SaveReading, SensorType, ValueKind};
+use retention::{Rollup, RollupQuery, SensorRetention};
+use rusqlite::params;
+use sciota_protocol::protocol::{SaveDevice, SaveMeasurement, SaveSensor};
+use serde_json;
+use serde_json::Value;
+use sqldata;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::{Duration, SystemTime};
+use storage::{PgStorage, SqliteStorage, Storage};
+use templates::{Instantiate, SaveTemplate, TemplateSensor};
+use toml;
+
+pub const POSTGRES_URL_VAR: &str = "SCIOTA_TEST_POSTGRES_URL";
+
+static UNIQUE: AtomicUsize = AtomicUsize::new(0);
+// the tests run in parallel, but the postgres database is shared.
+static PG_MIGRATE: Once = Once::new();
+
+// a name no other test run has used.
+fn unique(prefix: &str) -> String {
+  let nanos = SystemTime::now()
+    .duration_since(SystemTime::UNIX_EPOCH)
+    .map(|d| d.as_nanos())
+    .unwrap_or(0);
+  format!(
+    "{}-{}-{}-{}",
+    prefix,
+    std::process::id(),
+    nanos,
+    UNIQUE.fetch_add(1, Ordering::SeqCst)
+  )
+}
+
+// a sqlite database file, deleted with its wal when dropped.
+pub struct TempDb {
+  pub path: PathBuf,
+}
+
+impl Drop for TempDb {
+  fn drop(&mut self) {
+    for ext in ["", "-wal", "-shm"].iter() {
+      let _ = fs::remove_file(format!("{}{}", self.path.display(), ext));
+    }
+  }
+}
+
+// a config with just the required settings, for the sqlite file at 'db'.
+pub fn config(db: &Path) -> Config {
+  toml::from_str(
+    format!(
+      "ip = \"127.0.0.1\"
+port = 8002
+db = {:?}
+mainsite = \"http://localhost:8002\"
+appname = \"sciota-test\"
+domain = \"localhost\"",
+      db.to_string_lossy()
+    )
+    .as_str(),
+  )
+  .unwrap()
+}
+
+pub fn sqlite() -> (TempDb, SqliteStorage) {
+  let tmp = TempDb {
+    path: env::temp_dir().join(format!("{}.db", unique("sciota-test"))),
+  };
+  sqldata::dbinit(tmp.path.as_path()).unwrap();
+  let pool = sqldata::connection_pool(tmp.path.as_path(), 4, Duration::from_secs(5)).unwrap();
+  (tmp, SqliteStorage { pool })
+}
+
+fn postgres() -> Option<PgStorage> {
+  match env::var(POSTGRES_URL_VAR) {
+    Ok(url) => {
+      let pool = pgdata::connection_pool(url.as_str(), 4).unwrap();
+      PG_MIGRATE.call_once(|| pgdata::dbinit(&pool).unwrap());
+      Some(PgStorage { pool })
+    }
+    Err(_) => None,
+  }
+}
+
+// run 'test' on each backend.
+fn each_backend<F: Fn(&dyn Storage)>(test: F) {
+  let (_tmp, db) = sqlite();
+  test(&db);
+  match postgres() {
+    Some(db) => test(&db),
+    None => (),
+  }
+}
+
+// a registered user, deleted when dropped.
+pub struct TestUser<'a> {
+  db: &'a dyn Storage,
+  pub name: String,
+  pub id: i64,
+}
+
+impl<'a> Drop for TestUser<'a> {
+  fn drop(&mut self) {
+    let _ = self.db.delete_user(self.name.as_str());
+  }
+}
+
+pub fn user(db: &dyn Storage) -> TestUser {
+  let name = unique("user");
+  let id = db
+    .new_registered_user(
+      name.clone(),
+      "hash".to_string(),
+      "salt".to_string(),
+      "test@example.com".to_string(),
+    )
+    .unwrap();
+  TestUser { db, name, id }
+}
+
+pub fn device(db: &dyn Storage, uid: i64, name: &str) -> i64 {
+  db.save_device(
+    uid,
+    &SaveDevice {
+      id: None,
+      name: name.to_string(),
+      description: "a device".to_string(),
+    },
+  )
+  .unwrap()
+}
+
+pub fn sensor(db: &dyn Storage, uid: i64, device: i64, name: &str) -> i64 {
+  db.save_sensor(
+    uid,
+    &SaveSensor {
+      id: None,
+      device,
+      name: name.to_string(),
+      description: "a sensor".to_string(),
+    },
+  )
+  .unwrap()
+  .id
+}
+
+fn measurement(sensor: i64, value: f64, measuredate: i64) -> SaveMeasurement {
+  SaveMeasurement {
+    value,
+    sensor,
+    measuredate,
+  }
+}
+
+fn reading(sensor: i64, value: Value, measuredate: i64) -> SaveReading {
+  SaveReading {
+    sensor,
+    value,
+    measuredate,
+    position: None,
+  }
+}
+
+fn values(db: &dyn Storage, uid: i64, sensor: i64) -> Vec<f64> {
+  db.measurement_listing(uid, sensor, None, None)
+    .unwrap()
+    .iter()
+    .map(|m| m.value)
+    .collect()
+}
+
+// an archive as json, less what importing it changes: the device and sensor
+// ids, the user and the export date.  With 'ids', virtual sensor inputs are
+// mapped through it.
+fn archived(archive: &Archive, ids: Option<&HashMap<i64, i64>>) -> Value {
+  let mut v = serde_json::to_value(archive).unwrap();
+  v["user"] = Value::Null;
+  v["exportdate"] = Value::Null;
+  for d in v["devices"].as_array_mut().unwrap().iter_mut() {
+    d["id"] = Value::Null;
+    for s in d["sensors"].as_array_mut().unwrap().iter_mut() {
+      s["id"] = Value::Null;
+      if let (Some(Value::Object(inputs)), Some(ids)) = (s.pointer_mut("/formula/inputs"), ids) {
+        for input in inputs.values_mut() {
+          *input = json!(ids[&input.as_i64().unwrap()]);
+        }
+      }
+    }
+  }
+  v
+}
+
+fn is_not_found<T>(res: Result<T, Error>) -> bool {
+  match res {
+    Err(Error::NotFound(_)) => true,
+    _ => false,
+  }
+}
+
+#[test]
+fn migrations() {
+  let (tmp, db) = sqlite();
+  assert_eq!(db.migration_level().unwrap(), sqldata::MIGRATION_LEVEL);
+  assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  // migrating again changes nothing.
+  sqldata::dbinit(tmp.path.as_path()).unwrap();
+  assert_eq!(db.migration_level().unwrap(), sqldata::MIGRATION_LEVEL);
+
+  match postgres() {
+    Some(db) => {
+      assert_eq!(db.migration_level().unwrap(), sqldata::MIGRATION_LEVEL);
+      pgdata::dbinit(&db.pool).unwrap();
+      assert_eq!(db.migration_level().unwrap(), sqldata::MIGRATION_LEVEL);
+      assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+    }
+    None => (),
+  }
+}
+
+#[test]
+fn failed_migrations_roll_back() {
+  let (tmp, db) = sqlite();
+  // back to level 8, as if update9 hadn't run, but with one of its tables
+  // there already: update9 creates calibrationrecord, then fails on
+  // calibrationaudit.
+  {
+    let conn = sqldata::connection_open(tmp.path.as_path()).unwrap();
+    conn.execute_batch("DROP TABLE calibrationrecord").unwrap();
+    conn
+      .execute(
+        "UPDATE singlevalue SET value = '8' WHERE name = 'migration_level'",
+        params![],
+      )
+      .unwrap();
+  }
+
+  assert!(sqldata::dbinit(tmp.path.as_path()).is_err());
+  assert_eq!(db.migration_level().unwrap(), 8);
+  let conn = sqldata::connection_open(tmp.path.as_path()).unwrap();
+  let tables: i64 = conn
+    .query_row(
+      "SELECT count(*) FROM sqlite_master WHERE name = 'calibrationrecord'",
+      params![],
+      |row| row.get(0),
+    )
+    .unwrap();
+  assert_eq!(tables, 0);
+}
+
+#[test]
+fn users() {
+  each_backend(|db| {
+    let u = user(db);
+
+    let mut read = db.read_user(u.name.as_str()).unwrap();
+    assert_eq!(read.id, u.id);
+    assert_eq!(read.registration_key, None);
+    assert!(!read.disabled);
+
+    read.email = "changed@example.com".to_string();
+    db.update_user(&read).unwrap();
+    assert_eq!(
+      db.read_user(u.name.as_str()).unwrap().email,
+      "changed@example.com"
+    );
+
+    db.set_user_disabled(u.name.as_str(), true).unwrap();
+    assert!(db.read_user(u.name.as_str()).unwrap().disabled);
+
+    let listed = db.user_listing().unwrap();
+    assert!(listed.iter().any(|e| e.id == u.id && e.disabled));
+
+    // names are unique.
+    let again = db.new_registered_user(
+      u.name.clone(),
+      "hash".to_string(),
+      "salt".to_string(),
+      "".to_string(),
+    );
+    assert!(match again {
+      Err(Error::Conflict(_)) => true,
+      _ => false,
+    });
+
+    // registering through the email link: a key until it's confirmed.
+    let pending = unique("user");
+    db.new_user(
+      pending.clone(),
+      "hash".to_string(),
+      "salt".to_string(),
+      "".to_string(),
+      "key".to_string(),
+    )
+    .unwrap();
+    assert_eq!(
+      db.read_user(pending.as_str()).unwrap().registration_key,
+      Some("key".to_string())
+    );
+    db.confirm_user(pending.as_str()).unwrap();
+    assert_eq!(
+      db.read_user(pending.as_str()).unwrap().registration_key,
+      None
+    );
+    db.delete_user(pending.as_str()).unwrap();
+
+    db.delete_user(u.name.as_str()).unwrap();
+    assert!(is_not_found(db.read_user(u.name.as_str())));
+    assert!(is_not_found(db.confirm_user(u.name.as_str())));
+  });
+}
+
+#[test]
+fn devices() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+
+    let id = device(db, u.id, "first");
+    assert_eq!(db.read_device(u.id, id).unwrap().name, "first");
+
+    db.save_device(
+      u.id,
+      &SaveDevice {
+        id: Some(id),
+        name: "renamed".to_string(),
+        description: "changed".to_string(),
+      },
+    )
+    .unwrap();
+    let read = db.read_device(u.id, id).unwrap();
+    assert_eq!(read.name, "renamed");
+    assert_eq!(read.description, "changed");
+
+    device(db, u.id, "second");
+    assert_eq!(db.devicelisting(u.id).unwrap().len(), 2);
+
+    // other users can't see, change or delete it.
+    assert!(is_not_found(db.read_device(other.id, id)));
+    assert!(db.devicelisting(other.id).unwrap().is_empty());
+    assert!(db
+      .save_device(
+        other.id,
+        &SaveDevice {
+          id: Some(id),
+          name: "taken".to_string(),
+          description: "".to_string(),
+        },
+      )
+      .is_err());
+    let _ = db.delete_device(other.id, id);
+    assert_eq!(db.read_device(u.id, id).unwrap().name, "renamed");
+
+    db.delete_device(u.id, id).unwrap();
+    assert!(is_not_found(db.read_device(u.id, id)));
+    assert_eq!(db.devicelisting(u.id).unwrap().len(), 1);
+  });
+}
+
+#[test]
+fn sensors() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let dev = device(db, u.id, "dev");
+
+    let id = sensor(db, u.id, dev, "temp");
+    let read = db.read_sensor(u.id, id).unwrap();
+    assert_eq!(read.name, "temp");
+    assert_eq!(read.device, dev);
+
+    sensor(db, u.id, dev, "humidity");
+    let dev2 = device(db, u.id, "dev2");
+    sensor(db, u.id, dev2, "pressure");
+    assert_eq!(db.sensorlisting(u.id, None).unwrap().len(), 3);
+    assert_eq!(db.sensorlisting(u.id, Some(dev)).unwrap().len(), 2);
+
+    // no adding sensors to other users' devices, or reading theirs.
+    assert!(db
+      .save_sensor(
+        other.id,
+        &SaveSensor {
+          id: None,
+          device: dev,
+          name: "intruder".to_string(),
+          description: "".to_string(),
+        },
+      )
+      .is_err());
+    assert!(is_not_found(db.read_sensor(other.id, id)));
+    assert!(db.sensorlisting(other.id, None).unwrap().is_empty());
+    assert!(is_not_found(db.delete_sensor(other.id, id)));
+    assert!(db.read_sensor(u.id, id).is_ok());
+
+    // a virtual sensor's inputs can't be deleted, but other users only hear
+    // that there's no such sensor.
+    let mut inputs = BTreeMap::new();
+    inputs.insert("t".to_string(), id);
+    let virt = sensor(db, u.id, dev, "virtual");
+    db.set_virtual_sensor(
+      u.id,
+      &VirtualSensor {
+        sensor: virt,
+        formula: Some(Formula {
+          expression: "t".to_string(),
+          inputs,
+        }),
+      },
+    )
+    .unwrap();
+    assert!(is_not_found(db.delete_sensor(other.id, id)));
+    assert!(match db.delete_sensor(u.id, id) {
+      Err(Error::Conflict(_)) => true,
+      _ => false,
+    });
+    db.delete_sensor(u.id, virt).unwrap();
+
+    db.delete_sensor(u.id, id).unwrap();
+    assert!(is_not_found(db.read_sensor(u.id, id)));
+    assert_eq!(db.sensorlisting(u.id, Some(dev)).unwrap().len(), 1);
+  });
+}
+
+#[test]
+fn measurements() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let dev = device(db, u.id, "dev");
+    let s = sensor(db, u.id, dev, "temp");
+
+    db.add_measurement(u.id, &measurement(s, 1.5, 1000))
+      .unwrap();
+    let ids = db
+      .add_measurements(
+        u.id,
+        &[
+          measurement(s, 2.5, 2000),
+          measurement(s, 3.5, 3000),
+          measurement(s, 4.5, 4000),
+        ],
+      )
+      .unwrap();
+    assert_eq!(ids.len(), 3);
+
+    let all = db.measurement_listing(u.id, s, None, None).unwrap();
+    assert_eq!(
+      all.iter().map(|m| m.value).collect::<Vec<f64>>(),
+      vec![1.5, 2.5, 3.5, 4.5]
+    );
+    let some = db
+      .measurement_listing(u.id, s, Some(2000), Some(4000))
+      .unwrap();
+    assert_eq!(
+      some.iter().map(|m| m.measuredate).collect::<Vec<i64>>(),
+      vec![2000, 3000]
+    );
+
+    // other users can't add to or list the sensor, and a batch with one of
+    // their measurements adds none of them.
+    assert!(db
+      .add_measurement(other.id, &measurement(s, 9.0, 5000))
+      .is_err());
+    assert!(db.measurement_listing(other.id, s, None, None).is_err());
+    let theirs = sensor(db, other.id, device(db, other.id, "theirs"), "theirs");
+    assert!(db
+      .add_measurements(
+        u.id,
+        &[measurement(s, 9.0, 5000), measurement(theirs, 9.0, 5000)]
+      )
+      .is_err());
+    assert_eq!(
+      db.measurement_listing(u.id, s, None, None).unwrap().len(),
+      4
+    );
+    assert!(db.add_measurements(u.id, &[]).is_err());
+
+    // deleting the device takes its sensors and measurements with it.
+    db.delete_device(u.id, dev).unwrap();
+    assert!(is_not_found(db.read_sensor(u.id, s)));
+    assert!(db.measurement_listing(u.id, s, None, None).is_err());
+    assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  });
+}
+
+#[test]
+fn named_measurements() {
+  let named = |device: &str, sensor: &str, value: f64| NamedMeasurement {
+    device: device.to_string(),
+    sensor: sensor.to_string(),
+    value,
+    measuredate: 1000,
+  };
+  each_backend(|db| {
+    let u = user(db);
+    let dev = device(db, u.id, "dev");
+    let flag = sensor(db, u.id, dev, "flag");
+    db.set_sensor_type(
+      u.id,
+      &SensorType {
+        sensor: flag,
+        kind: ValueKind::Boolean,
+        dims: None,
+        unit: None,
+      },
+    )
+    .unwrap();
+
+    // a missing name anywhere in the write stores nothing.
+    assert!(is_not_found(db.add_named_measurements(
+      u.id,
+      &[named("dev", "flag", 1.0), named("dev", "missing", 1.0)],
+      false
+    )));
+    assert!(db
+      .measurement_listing(u.id, flag, None, None)
+      .unwrap()
+      .is_empty());
+
+    // nor does a bad value, and what would have been created isn't.
+    assert!(db
+      .add_named_measurements(
+        u.id,
+        &[named("new", "new.x", 1.0), named("dev", "flag", 2.0)],
+        true
+      )
+      .is_err());
+    assert_eq!(db.devicelisting(u.id).unwrap().len(), 1);
+    assert_eq!(db.sensorlisting(u.id, None).unwrap().len(), 1);
+
+    let n = db
+      .add_named_measurements(
+        u.id,
+        &[
+          named("new", "new.x", 1.0),
+          named("new", "new.x", 2.0),
+          named("dev", "flag", 1.0),
+        ],
+        true,
+      )
+      .unwrap();
+    assert_eq!(n, 3);
+    let devices = db.devicelisting(u.id).unwrap();
+    assert_eq!(devices.len(), 2);
+    let new = devices.iter().find(|d| d.name == "new").unwrap();
+    let sensors = db.sensorlisting(u.id, Some(new.id)).unwrap();
+    assert_eq!(sensors.len(), 1);
+    assert_eq!(
+      db.measurement_listing(u.id, sensors[0].id, None, None)
+        .unwrap()
+        .len(),
+      2
+    );
+    assert_eq!(
+      db.measurement_listing(u.id, flag, None, None)
+        .unwrap()
+        .len(),
+      1
+    );
+    assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  });
+}
+
+#[test]
+fn readings() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "label");
+
+    // float until it's declared otherwise.
+    assert_eq!(db.get_sensor_type(u.id, s).unwrap().kind, ValueKind::Float);
+    db.set_sensor_type(
+      u.id,
+      &SensorType {
+        sensor: s,
+        kind: ValueKind::String,
+        dims: None,
+        unit: Some("tag".to_string()),
+      },
+    )
+    .unwrap();
+    let st = db.get_sensor_type(u.id, s).unwrap();
+    assert_eq!(st.kind, ValueKind::String);
+    assert_eq!(st.unit, Some("tag".to_string()));
+
+    db.add_reading(u.id, &reading(s, json!("first"), 1000))
+      .unwrap();
+    db.add_reading(
+      u.id,
+      &SaveReading {
+        position: Some(Location {
+          lat: 45.5,
+          lon: -122.6,
+          elevation: None,
+        }),
+        ..reading(s, json!("second"), 2000)
+      },
+    )
+    .unwrap();
+    // not a string, and not a plain measurement.
+    assert!(db.add_reading(u.id, &reading(s, json!(1.5), 3000)).is_err());
+    assert!(db
+      .add_measurement(u.id, &measurement(s, 1.5, 3000))
+      .is_err());
+
+    let query = |from| ReadingQuery {
+      sensor: s,
+      from,
+      to: None,
+      bbox: None,
+    };
+    let listed = db.reading_listing(u.id, &query(None)).unwrap();
+    assert_eq!(
+      listed
+        .iter()
+        .map(|r| r.value.clone())
+        .collect::<Vec<Value>>(),
+      vec![json!("first"), json!("second")]
+    );
+    assert!(listed[0].position.is_none());
+    assert_eq!(listed[1].position.as_ref().map(|p| p.lat), Some(45.5));
+    assert_eq!(
+      db.reading_listing(u.id, &query(Some(1500))).unwrap().len(),
+      1
+    );
+
+    assert!(db.reading_listing(other.id, &query(None)).is_err());
+    assert!(db
+      .add_reading(other.id, &reading(s, json!("theirs"), 4000))
+      .is_err());
+    assert!(db.get_sensor_type(other.id, s).is_err());
+  });
+}
+
+#[test]
+fn calibration_history() {
+  let linear = |scale| {
+    Some(Calibration::Linear {
+      offset: 0.0,
+      scale: scale,
+    })
+  };
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "temp");
+    db.add_measurements(
+      u.id,
+      &[measurement(s, 1.0, 1000), measurement(s, 1.0, 3000)],
+    )
+    .unwrap();
+
+    db.set_calibration(
+      u.id,
+      &SensorCalibration {
+        sensor: s,
+        effective: Some(2000),
+        calibration: linear(2.0),
+      },
+    )
+    .unwrap();
+    db.set_calibration(
+      u.id,
+      &SensorCalibration {
+        sensor: s,
+        effective: Some(0),
+        calibration: linear(10.0),
+      },
+    )
+    .unwrap();
+
+    // each measurement has the calibration in force at its measuredate.
+    assert_eq!(values(db, u.id, s), vec![10.0, 2.0]);
+    let history = db.calibration_history(u.id, s).unwrap();
+    assert_eq!(
+      history.iter().map(|r| r.effective).collect::<Vec<i64>>(),
+      vec![0, 2000]
+    );
+    assert!(history.iter().all(|r| r.user == u.name));
+    assert_eq!(db.get_calibration(u.id, s).unwrap().effective, Some(2000));
+
+    // other users can't see or change it.
+    assert!(db.calibration_history(other.id, s).is_err());
+    assert!(db.delete_calibration(other.id, history[1].id).is_err());
+    assert_eq!(db.calibration_history(u.id, s).unwrap().len(), 2);
+
+    db.delete_calibration(u.id, history[1].id).unwrap();
+    assert_eq!(values(db, u.id, s), vec![10.0, 10.0]);
+    assert!(is_not_found(db.delete_calibration(u.id, history[1].id)));
+
+    let audit = db.calibration_audit(u.id, s).unwrap();
+    assert_eq!(
+      audit
+        .iter()
+        .map(|a| (a.action.as_str(), a.effective))
+        .collect::<Vec<(&str, i64)>>(),
+      vec![("set", 2000), ("set", 0), ("delete", 2000)]
+    );
+  });
+}
+
+#[test]
+fn templates() {
+  let template_sensor = |name: &str| TemplateSensor {
+    name: name.to_string(),
+    description: "".to_string(),
+    unit: None,
+    kind: None,
+    dims: None,
+  };
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+
+    let id = db
+      .save_template(
+        u.id,
+        &SaveTemplate {
+          id: None,
+          name: "station".to_string(),
+          description: "weather".to_string(),
+          sensors: vec![
+            template_sensor("temp"),
+            TemplateSensor {
+              unit: Some("%".to_string()),
+              kind: Some(ValueKind::Integer),
+              ..template_sensor("humidity")
+            },
+          ],
+        },
+      )
+      .unwrap();
+    let t = db.read_template(u.id, id).unwrap();
+    assert_eq!(t.name, "station");
+    assert_eq!(
+      t.sensors
+        .iter()
+        .map(|s| s.name.as_str())
+        .collect::<Vec<&str>>(),
+      vec!["temp", "humidity"]
+    );
+    assert_eq!(db.template_listing(u.id).unwrap().len(), 1);
+
+    let inst = |count| Instantiate {
+      template: id,
+      name: None,
+      description: None,
+      count,
+      first: None,
+    };
+    assert!(is_not_found(db.read_template(other.id, id)));
+    assert!(db.template_listing(other.id).unwrap().is_empty());
+    assert!(db.instantiate_template(other.id, &inst(None)).is_err());
+
+    let made = db.instantiate_template(u.id, &inst(Some(2))).unwrap();
+    assert_eq!(
+      made.iter().map(|d| d.name.as_str()).collect::<Vec<&str>>(),
+      vec!["station 1", "station 2"]
+    );
+    let humidity = db.get_sensor_type(u.id, made[1].sensors[1].id).unwrap();
+    assert_eq!(humidity.kind, ValueKind::Integer);
+    assert_eq!(humidity.unit, Some("%".to_string()));
+    assert_eq!(
+      db.get_sensor_type(u.id, made[1].sensors[0].id)
+        .unwrap()
+        .kind,
+      ValueKind::Float
+    );
+
+    // deleting the template leaves the devices made from it.
+    db.delete_template(u.id, id).unwrap();
+    assert!(is_not_found(db.read_template(u.id, id)));
+    assert_eq!(db.devicelisting(u.id).unwrap().len(), 2);
+  });
+}
+
+#[test]
+fn retention_and_rollups() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "temp");
+
+    let none = db.get_retention(u.id, s).unwrap();
+    assert_eq!(
+      (none.rawdays, none.hourlydays, none.dailydays),
+      (None, None, None)
+    );
+    let sr = SensorRetention {
+      sensor: s,
+      rawdays: Some(7),
+      hourlydays: Some(30),
+      dailydays: None,
+    };
+    db.set_retention(u.id, &sr).unwrap();
+    let read = db.get_retention(u.id, s).unwrap();
+    assert_eq!(
+      (read.rawdays, read.hourlydays, read.dailydays),
+      (Some(7), Some(30), None)
+    );
+    assert!(db
+      .sensor_retentions()
+      .unwrap()
+      .iter()
+      .any(|r| r.sensor == s && r.rawdays == Some(7)));
+    assert!(db.get_retention(other.id, s).is_err());
+    assert!(db.set_retention(other.id, &sr).is_err());
+
+    db.add_measurements(
+      u.id,
+      &[
+        measurement(s, 1.0, 1000),
+        measurement(s, 3.0, 2000),
+        measurement(s, 5.0, 3000),
+      ],
+    )
+    .unwrap();
+    // the batch is the oldest measurements before 'before'.
+    assert_eq!(db.rollup_measurements(s, 2500, 10).unwrap(), 2);
+    assert_eq!(values(db, u.id, s), vec![5.0]);
+
+    let query = |rollup| RollupQuery {
+      sensor: s,
+      rollup,
+      from: None,
+      to: None,
+    };
+    for rollup in [Rollup::Hourly, Rollup::Daily].iter() {
+      let rows = db.rollup_listing(u.id, &query(*rollup)).unwrap();
+      assert_eq!(rows.len(), 1);
+      assert_eq!((rows[0].period, rows[0].samples), (0, 2));
+      assert_eq!((rows[0].mean, rows[0].min, rows[0].max), (2.0, 1.0, 3.0));
+    }
+    assert!(db.rollup_listing(other.id, &query(Rollup::Hourly)).is_err());
+
+    assert_eq!(db.prune_rollups(Rollup::Hourly, s, 1, 10).unwrap(), 1);
+    assert!(db
+      .rollup_listing(u.id, &query(Rollup::Hourly))
+      .unwrap()
+      .is_empty());
+    assert_eq!(
+      db.rollup_listing(u.id, &query(Rollup::Daily))
+        .unwrap()
+        .len(),
+      1
+    );
+  });
+}
+
+#[test]
+fn rollup_periods() {
+  use retention::{DAY, HOUR};
+  each_backend(|db| {
+    let u = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "temp");
+    let listing = |rollup| {
+      db.rollup_listing(
+        u.id,
+        &RollupQuery {
+          sensor: s,
+          rollup,
+          from: None,
+          to: None,
+        },
+      )
+      .unwrap()
+      .iter()
+      .map(|r| (r.period, r.samples, r.min, r.max))
+      .collect::<Vec<(i64, i64, f64, f64)>>()
+    };
+
+    // periods start at multiples of their length, rounding down before 1970
+    // too.
+    db.add_measurements(
+      u.id,
+      &[
+        measurement(s, 1.0, -HOUR - 1),
+        measurement(s, 2.0, -1),
+        measurement(s, 3.0, 0),
+        measurement(s, 4.0, HOUR - 1),
+        measurement(s, 5.0, HOUR),
+        measurement(s, 6.0, DAY + 5),
+      ],
+    )
+    .unwrap();
+    assert_eq!(db.rollup_measurements(s, 2 * DAY, 100).unwrap(), 6);
+    assert_eq!(
+      listing(Rollup::Hourly),
+      vec![
+        (-2 * HOUR, 1, 1.0, 1.0),
+        (-HOUR, 1, 2.0, 2.0),
+        (0, 2, 3.0, 4.0),
+        (HOUR, 1, 5.0, 5.0),
+        (DAY, 1, 6.0, 6.0),
+      ]
+    );
+    assert_eq!(
+      listing(Rollup::Daily),
+      vec![(-DAY, 2, 1.0, 2.0), (0, 3, 3.0, 5.0), (DAY, 1, 6.0, 6.0)]
+    );
+
+    // later batches merge into the periods already there, one at a time.
+    db.add_measurements(
+      u.id,
+      &[
+        measurement(s, 10.0, 10),
+        measurement(s, -1.0, 20),
+        measurement(s, 3.5, 30),
+      ],
+    )
+    .unwrap();
+    for _ in 0..3 {
+      assert_eq!(db.rollup_measurements(s, 2 * DAY, 1).unwrap(), 1);
+    }
+    assert_eq!(listing(Rollup::Hourly)[2], (0, 5, -1.0, 10.0));
+    assert_eq!(listing(Rollup::Daily)[1], (0, 6, -1.0, 10.0));
+    let mean = db
+      .rollup_listing(
+        u.id,
+        &RollupQuery {
+          sensor: s,
+          rollup: Rollup::Hourly,
+          from: Some(0),
+          to: Some(1),
+        },
+      )
+      .unwrap()[0]
+      .mean;
+    assert!((mean - (3.0 + 4.0 + 10.0 - 1.0 + 3.5) / 5.0).abs() < 1e-9);
+  });
+}
+
+#[test]
+fn rollup_progress() {
+  each_backend(|db| {
+    let u = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "temp");
+    let rolledupto = || db.export_user(u.id).unwrap().devices[0].sensors[0].rolledupto;
+
+    // nothing to roll up.
+    assert_eq!(db.rollup_measurements(s, 5000, 2).unwrap(), 0);
+    assert_eq!(rolledupto(), None);
+
+    db.add_measurements(
+      u.id,
+      &[
+        measurement(s, 1.0, 1000),
+        measurement(s, 2.0, 2000),
+        measurement(s, 3.0, 3000),
+      ],
+    )
+    .unwrap();
+    // a full batch may have left some behind.
+    assert_eq!(db.rollup_measurements(s, 5000, 2).unwrap(), 2);
+    assert_eq!(rolledupto(), Some(2000));
+    assert_eq!(db.rollup_measurements(s, 5000, 2).unwrap(), 1);
+    assert_eq!(rolledupto(), Some(5000));
+    assert_eq!(db.rollup_measurements(s, 9000, 2).unwrap(), 0);
+    assert_eq!(rolledupto(), Some(5000));
+    // it never goes back.
+    db.add_measurement(u.id, &measurement(s, 4.0, 500)).unwrap();
+    assert_eq!(db.rollup_measurements(s, 1000, 2).unwrap(), 1);
+    assert_eq!(rolledupto(), Some(5000));
+  });
+}
+
+#[test]
+fn export_import() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let s = sensor(db, u.id, device(db, u.id, "dev"), "temp");
+    db.add_measurements(
+      u.id,
+      &[measurement(s, 1.0, 1000), measurement(s, 2.0, 2000)],
+    )
+    .unwrap();
+
+    let archive = db.export_user(u.id).unwrap();
+    assert_eq!(archive.user, u.name);
+    assert_eq!(archive.devices.len(), 1);
+    assert_eq!(archive.devices[0].sensors[0].measurements.len(), 2);
+
+    let summary = db.import_user(other.id, &archive).unwrap();
+    assert_eq!(
+      (summary.devices, summary.sensors, summary.measurements),
+      (1, 1, 2)
+    );
+    let theirs = db.sensorlisting(other.id, None).unwrap();
+    assert_eq!(theirs.len(), 1);
+    assert_ne!(theirs[0].id, s);
+    assert_eq!(values(db, other.id, theirs[0].id), vec![1.0, 2.0]);
+    // the exporter's data is untouched.
+    assert_eq!(values(db, u.id, s), vec![1.0, 2.0]);
+    assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  });
+}
+
+#[test]
+fn export_import_round_trip() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+
+    let station = device(db, u.id, "station");
+    db.set_device_location(
+      u.id,
+      &DeviceLocation {
+        device: station,
+        location: Some(Location {
+          lat: 45.5,
+          lon: -122.6,
+          elevation: Some(15.0),
+        }),
+      },
+    )
+    .unwrap();
+    let t = sensor(db, u.id, station, "t");
+    let rh = sensor(db, u.id, station, "rh");
+    let dew = sensor(db, u.id, station, "dew");
+    let spare = sensor(db, u.id, device(db, u.id, "spare"), "x");
+
+    db.set_sensor_type(
+      u.id,
+      &SensorType {
+        sensor: rh,
+        kind: ValueKind::Integer,
+        dims: None,
+        unit: Some("%".to_string()),
+      },
+    )
+    .unwrap();
+    for (effective, scale) in [(0, 2.0), (2500, 1.0)].iter() {
+      db.set_calibration(
+        u.id,
+        &SensorCalibration {
+          sensor: t,
+          effective: Some(*effective),
+          calibration: Some(Calibration::Linear {
+            offset: 0.0,
+            scale: *scale,
+          }),
+        },
+      )
+      .unwrap();
+    }
+    let mut inputs = BTreeMap::new();
+    inputs.insert("t".to_string(), t);
+    inputs.insert("rh".to_string(), rh);
+    db.set_virtual_sensor(
+      u.id,
+      &VirtualSensor {
+        sensor: dew,
+        formula: Some(Formula {
+          expression: "t - (100 - rh) / 5".to_string(),
+          inputs,
+        }),
+      },
+    )
+    .unwrap();
+
+    db.add_measurements(
+      u.id,
+      &[
+        measurement(t, 10.0, 1000),
+        measurement(t, 12.0, 2000),
+        measurement(t, 14.0, 3000),
+        measurement(rh, 50.0, 1000),
+        measurement(rh, 60.0, 2000),
+        measurement(rh, 70.0, 3000),
+        measurement(spare, 9.0, 1000),
+      ],
+    )
+    .unwrap();
+    db.add_reading(
+      u.id,
+      &SaveReading {
+        position: Some(Location {
+          lat: 45.6,
+          lon: -122.7,
+          elevation: None,
+        }),
+        ..reading(t, json!(16.0), 4000)
+      },
+    )
+    .unwrap();
+    db.set_retention(
+      u.id,
+      &SensorRetention {
+        sensor: rh,
+        rawdays: Some(7),
+        hourlydays: None,
+        dailydays: Some(365),
+      },
+    )
+    .unwrap();
+    assert_eq!(db.rollup_measurements(rh, 1500, 10).unwrap(), 1);
+
+    let archive = db.export_user(u.id).unwrap();
+    let summary = db.import_user(other.id, &archive).unwrap();
+    assert_eq!(
+      (summary.devices, summary.sensors, summary.measurements),
+      (2, 4, 7)
+    );
+    let theirs = db.export_user(other.id).unwrap();
+
+    // every device and sensor is new, and the virtual sensor's inputs are
+    // the new sensors.
+    let archive_ids = |a: &Archive| {
+      let devices = a.devices.iter().map(|d| d.id).collect::<Vec<i64>>();
+      let sensors = a
+        .devices
+        .iter()
+        .flat_map(|d| d.sensors.iter().map(|s| s.id))
+        .collect::<Vec<i64>>();
+      (devices, sensors)
+    };
+    let (devices, sensors) = archive_ids(&archive);
+    let (new_devices, new_sensors) = archive_ids(&theirs);
+    assert_eq!(sensors, vec![t, rh, dew, spare]);
+    assert_eq!(new_devices.len(), devices.len());
+    assert_eq!(new_sensors.len(), sensors.len());
+    assert!(new_devices.iter().all(|d| !devices.contains(d)));
+    assert!(new_sensors.iter().all(|s| !sensors.contains(s)));
+    let ids = sensors
+      .iter()
+      .cloned()
+      .zip(new_sensors.iter().cloned())
+      .collect::<HashMap<i64, i64>>();
+    let formula = theirs.devices[0].sensors[2].formula.as_ref().unwrap();
+    assert_eq!(
+      formula.inputs.values().cloned().collect::<Vec<i64>>(),
+      vec![ids[&rh], ids[&t]]
+    );
+
+    // and everything else is as it was.
+    assert_eq!(archived(&theirs, None), archived(&archive, Some(&ids)));
+    for s in sensors.iter() {
+      assert_eq!(values(db, other.id, ids[s]), values(db, u.id, *s));
+    }
+    assert_eq!(values(db, other.id, ids[&dew]).len(), 3);
+    assert_eq!(
+      db.calibration_history(other.id, ids[&t])
+        .unwrap()
+        .iter()
+        .map(|r| r.effective)
+        .collect::<Vec<i64>>(),
+      vec![0, 2500]
+    );
+    assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  });
+}
+
+#[test]
+fn device_tokens() {
+  each_backend(|db| {
+    let u = user(db);
+    let other = user(db);
+    let dev = device(db, u.id, "dev");
+    let a = sensor(db, u.id, dev, "a");
+    let b = sensor(db, u.id, dev, "b");
+    // token hashes are unique across users, and the postgres database is
+    // shared.
+    let hash = unique("token");
+
+    assert!(is_not_found(db.token_device(hash.as_str())));
+    db.set_device_token(u.id, dev, Some(hash.as_str())).unwrap();
+    let td = db.token_device(hash.as_str()).unwrap();
+    assert_eq!((td.user, td.device), (u.id, dev));
+    assert_eq!(
+      (td.sensors.get(&0), td.sensors.get(&1)),
+      (Some(&a), Some(&b))
+    );
+
+    // new sensors take the next index, and deleting one doesn't shift the
+    // others.
+    let c = sensor(db, u.id, dev, "c");
+    db.delete_sensor(u.id, a).unwrap();
+    let td = db.token_device(hash.as_str()).unwrap();
+    assert_eq!(td.sensors.len(), 2);
+    assert_eq!(
+      (td.sensors.get(&1), td.sensors.get(&2)),
+      (Some(&b), Some(&c))
+    );
+
+    assert!(db.set_device_token(other.id, dev, None).is_err());
+    assert!(db.token_device(hash.as_str()).is_ok());
+
+    // disabled users' tokens don't work.
+    db.set_user_disabled(u.name.as_str(), true).unwrap();
+    assert!(is_not_found(db.token_device(hash.as_str())));
+    db.set_user_disabled(u.name.as_str(), false).unwrap();
+
+    db.set_device_token(u.id, dev, None).unwrap();
+    assert!(is_not_found(db.token_device(hash.as_str())));
+  });
+}
+
+#[test]
+fn delete_user_deletes_their_data() {
+  each_backend(|db| {
+    let u = user(db);
+    let dev = device(db, u.id, "dev");
+    let s = sensor(db, u.id, dev, "temp");
+    db.add_measurement(u.id, &measurement(s, 1.0, 1000))
+      .unwrap();
+
+    db.delete_user(u.name.as_str()).unwrap();
+    assert!(is_not_found(db.read_device(u.id, dev)));
+    assert!(!db.user_listing().unwrap().iter().any(|e| e.id == u.id));
+    assert_eq!(db.integrity_check().unwrap(), Vec::<String>::new());
+  });
+}
@@ -0,0 +1,128 @@
+use error::Error;
+use readings;
+use readings::ValueKind;
+use schemars::JsonSchema;
+use std::collections::HashSet;
+
+// device templates: a named list of sensor definitions, for provisioning many
+// identical devices.  Instantiating one creates a device and all its sensors
+// in one transaction.  Later changes to a template don't touch the devices
+// already made from it.
+
+// most sensors in a template, and most devices made in one instantiation.
+pub const MAX_TEMPLATE_SENSORS: usize = 100;
+pub const MAX_INSTANCES: i64 = 1000;
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct TemplateSensor {
+  pub name: String,
+  pub description: String,
+  pub unit: Option<String>,
+  // float if missing.
+  pub kind: Option<ValueKind>,
+  pub dims: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct SaveTemplate {
+  pub id: Option<i64>,
+  pub name: String,
+  pub description: String,
+  pub sensors: Vec<TemplateSensor>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct DeviceTemplate {
+  pub id: i64,
+  pub name: String,
+  pub description: String,
+  pub createdate: i64,
+  pub changeddate: i64,
+  pub sensors: Vec<TemplateSensor>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct Instantiate {
+  pub template: i64,
+  // the device name and description; the template's if missing.
+  pub name: Option<String>,
+  pub description: Option<String>,
+  // make this many devices, named "<name> <number>".  One device, named
+  // just <name>, if missing.
+  pub count: Option<i64>,
+  // the first number; 1 if missing.
+  pub first: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct InstantiatedSensor {
+  pub id: i64,
+  pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct InstantiatedDevice {
+  pub id: i64,
+  pub name: String,
+  pub sensors: Vec<InstantiatedSensor>,
+}
+
+pub fn check_template(t: &SaveTemplate) -> Result<(), Error> {
+  if t.name.trim().is_empty() {
+    return Err(Error::BadRequest("templates need a name".to_string()));
+  }
+  if t.sensors.is_empty() || t.sensors.len() > MAX_TEMPLATE_SENSORS {
+    return Err(Error::BadRequest(format!(
+      "templates need between 1 and {} sensors",
+      MAX_TEMPLATE_SENSORS
+    )));
+  }
+
+  let mut names = HashSet::new();
+  for s in t.sensors.iter() {
+    if !names.insert(s.name.as_str()) {
+      return Err(Error::BadRequest(format!(
+        "duplicate sensor name in template: {}",
+        s.name
+      )));
+    }
+    readings::check_kind(s.kind.unwrap_or(ValueKind::Float), s.dims)?;
+  }
+
+  Ok(())
+}
+
+// the names of the devices to make.  Numbers are zero padded to the same
+// width, so the names sort in order.
+pub fn device_names(
+  name: &str,
+  count: Option<i64>,
+  first: Option<i64>,
+) -> Result<Vec<String>, Error> {
+  match count {
+    None => Ok(vec![name.to_string()]),
+    Some(count) => {
+      if count < 1 || count > MAX_INSTANCES {
+        return Err(Error::BadRequest(format!(
+          "count must be between 1 and {}",
+          MAX_INSTANCES
+        )));
+      }
+      let first = first.unwrap_or(1);
+      if first < 0 {
+        return Err(Error::BadRequest("first must not be negative".to_string()));
+      }
+      let width = (first + count - 1).to_string().len();
+      Ok(
+        (first..first + count)
+          .map(|n| format!("{} {:0width$}", name, n, width = width))
+          .collect(),
+      )
+    }
+  }
+}
+
+// template sensors that need a sensortype row.
+pub fn needs_type(s: &TemplateSensor) -> bool {
+  s.unit.is_some() || s.kind.map(|k| k != ValueKind::Float).unwrap_or(false)
+}
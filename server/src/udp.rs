@@ -0,0 +1,513 @@
+use actix_web::web;
+use crypto_hash::{hex_digest, Algorithm};
+use error::Error;
+use logging;
+use metrics;
+use rand::Rng;
+use schemars::JsonSchema;
+use sciota_protocol::protocol::SaveMeasurement;
+use sqldata;
+use std::collections::{HashMap, VecDeque};
+use std::net::UdpSocket;
+use std::thread;
+use storage::{Db, Storage};
+
+// compact measurement ingest over UDP, for devices that can't afford TLS, HTTP
+// and json per measurement.  A device sends its token and a few bytes per
+// measurement, and gets a five byte acknowledgement back.
+//
+// Tokens are made per device with the 'makedevicetoken' message (or POST
+// /api/devices/{id}/token), which replaces any earlier token.  Only a hash of
+// the token is stored, so it's shown just the once.  Sensors are addressed by
+// index: a new token numbers the device's sensors from 0, in id order, as
+// listed with it.  A sensor added later gets the next index.  Indexes stay
+// put: deleting a sensor leaves a gap, and its index isn't given to another
+// sensor, so packets for it are refused with BadSensor.
+//
+// A packet, all integers big endian:
+//
+//   byte  0       version, 1
+//   bytes 1-2     message id, echoed in the ack
+//   bytes 3-18    device token
+//   then per measurement, 7 bytes:
+//     byte  0     sensor index
+//     bytes 1-2   age: seconds before the packet was sent
+//     bytes 3-6   value, an IEEE 754 single
+//
+// The ack is version, message id, a status (see Status) and the number of
+// measurements stored.  A packet's measurements are stored together or not at
+// all.  A device that doesn't get an ack can send the same packet again: acks
+// for recent packets are remembered, and sent again without storing the
+// measurements twice.  Resends are recognised by their whole content, not
+// just the message id, so a device that restarts its ids after a reboot
+// isn't taken to be resending.  A packet with no measurements just checks the
+// token.  Packets too short to have a token aren't answered.
+//
+// There's no encryption: tokens can be sniffed on the way, and should be
+// treated as write-only credentials for the one device.
+
+pub const VERSION: u8 = 1;
+pub const TOKEN_BYTES: usize = 16;
+const HEADER_BYTES: usize = 3 + TOKEN_BYTES;
+const MEASUREMENT_BYTES: usize = 7;
+// fits in one IPv6 packet without fragmenting; 173 measurements.
+pub const MAX_PACKET: usize = 1232;
+// acks kept for retransmits.
+const RECENT_ACKS: usize = 4096;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UdpConfig {
+  // defaults to the server's ip.
+  pub ip: Option<String>,
+  pub port: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, JsonSchema)]
+pub struct DeviceToken {
+  pub device: i64,
+  // hex.
+  pub token: String,
+  // the device's sensor ids, by index.
+  pub sensors: Vec<i64>,
+}
+
+// the device a token is for, with its sensor ids by index.
+#[derive(Debug, Clone)]
+pub struct TokenDevice {
+  pub user: i64,
+  pub device: i64,
+  pub sensors: HashMap<i64, i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+  Ok = 0,
+  Malformed = 1,
+  UnknownVersion = 2,
+  Unauthorized = 3,
+  BadSensor = 4,
+  // the measurements were refused, say for a sensor that isn't numeric.
+  Refused = 5,
+  // a database problem; try again later.
+  ServerError = 6,
+}
+
+impl Status {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Status::Ok => "ok",
+      Status::Malformed => "malformed",
+      Status::UnknownVersion => "unknown_version",
+      Status::Unauthorized => "unauthorized",
+      Status::BadSensor => "bad_sensor",
+      Status::Refused => "refused",
+      Status::ServerError => "server_error",
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CompactMeasurement {
+  pub index: u8,
+  pub age: u16,
+  pub value: f32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Packet {
+  pub id: u16,
+  pub token: Vec<u8>,
+  pub measurements: Vec<CompactMeasurement>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn token_hash(token: &[u8]) -> String {
+  hex_digest(Algorithm::SHA256, token)
+}
+
+// a new token for 'device', replacing any it had.
+pub fn make_token(db: &dyn Storage, uid: i64, device: i64) -> Result<DeviceToken, Error> {
+  let token: [u8; TOKEN_BYTES] = rand::thread_rng().gen();
+  db.set_device_token(uid, device, Some(token_hash(&token).as_str()))?;
+  let mut sensors: Vec<i64> = db
+    .sensorlisting(uid, Some(device))?
+    .iter()
+    .map(|s| s.id)
+    .collect();
+  sensors.sort();
+  Ok(DeviceToken {
+    device,
+    token: hex(&token),
+    sensors,
+  })
+}
+
+// the message id, if there's enough of a packet to have one.
+pub fn message_id(buf: &[u8]) -> Option<u16> {
+  if buf.len() < 3 {
+    None
+  } else {
+    Some(u16::from(buf[1]) << 8 | u16::from(buf[2]))
+  }
+}
+
+pub fn parse_packet(buf: &[u8]) -> Result<Packet, Status> {
+  if buf.len() < HEADER_BYTES || buf.len() > MAX_PACKET {
+    return Err(Status::Malformed);
+  }
+  if buf[0] != VERSION {
+    return Err(Status::UnknownVersion);
+  }
+  let body = &buf[HEADER_BYTES..];
+  if body.len() % MEASUREMENT_BYTES != 0 {
+    return Err(Status::Malformed);
+  }
+  let mut measurements = Vec::new();
+  for m in body.chunks(MEASUREMENT_BYTES) {
+    let value = f32::from_bits(
+      u32::from(m[3]) << 24 | u32::from(m[4]) << 16 | u32::from(m[5]) << 8 | u32::from(m[6]),
+    );
+    if !value.is_finite() {
+      return Err(Status::Malformed);
+    }
+    measurements.push(CompactMeasurement {
+      index: m[0],
+      age: u16::from(m[1]) << 8 | u16::from(m[2]),
+      value,
+    });
+  }
+  Ok(Packet {
+    id: message_id(buf).unwrap_or(0),
+    token: buf[3..HEADER_BYTES].to_vec(),
+    measurements,
+  })
+}
+
+pub fn ack(id: u16, status: Status, stored: usize) -> Vec<u8> {
+  vec![
+    VERSION,
+    (id >> 8) as u8,
+    id as u8,
+    status as u8,
+    stored.min(255) as u8,
+  ]
+}
+
+// store a packet's measurements; the status and how many were stored.
+fn store(db: &dyn Storage, td: &TokenDevice, packet: &Packet) -> (Status, usize) {
+  let now = match sqldata::now() {
+    Ok(now) => now,
+    Err(_) => return (Status::ServerError, 0),
+  };
+  let mut ms = Vec::new();
+  for m in packet.measurements.iter() {
+    match td.sensors.get(&i64::from(m.index)) {
+      Some(sensor) => ms.push(SaveMeasurement {
+        value: f64::from(m.value),
+        sensor: *sensor,
+        measuredate: now - i64::from(m.age) * 1000,
+      }),
+      None => return (Status::BadSensor, 0),
+    }
+  }
+  if ms.is_empty() {
+    return (Status::Ok, 0);
+  }
+  match db.add_measurements(td.user, &ms) {
+    Ok(_) => (Status::Ok, ms.len()),
+    Err(Error::BadRequest(_)) | Err(Error::NotFound(_)) | Err(Error::Forbidden(_)) => {
+      (Status::Refused, 0)
+    }
+    Err(e) => {
+      error!("udp ingest error: {:?}", e);
+      (Status::ServerError, 0)
+    }
+  }
+}
+
+// acks for recent packets, by a hash of the packet.
+#[derive(Default)]
+struct Recent {
+  acks: HashMap<String, Vec<u8>>,
+  order: VecDeque<String>,
+}
+
+impl Recent {
+  fn add(&mut self, key: String, ack: Vec<u8>) {
+    if self.order.len() >= RECENT_ACKS {
+      if let Some(old) = self.order.pop_front() {
+        self.acks.remove(&old);
+      }
+    }
+    self.order.push_back(key.clone());
+    self.acks.insert(key, ack);
+  }
+}
+
+// the ack for a packet, if it gets one.
+fn handle(db: &dyn Storage, recent: &mut Recent, buf: &[u8]) -> Option<Vec<u8>> {
+  // no reply to anything without a token, so a spoofed sender address can't
+  // get more back than it sent.
+  if buf.len() < HEADER_BYTES {
+    metrics::udp_packet(Status::Malformed.as_str());
+    return None;
+  }
+  let id = message_id(buf)?;
+  let packet = match parse_packet(buf) {
+    Ok(p) => p,
+    Err(status) => {
+      metrics::udp_packet(status.as_str());
+      return Some(ack(id, status, 0));
+    }
+  };
+
+  let key = hex_digest(Algorithm::SHA256, buf);
+  if let Some(a) = recent.acks.get(&key) {
+    metrics::udp_packet("duplicate");
+    return Some(a.clone());
+  }
+
+  let (status, stored) = match db.token_device(token_hash(&packet.token).as_str()) {
+    Ok(td) => store(db, &td, &packet),
+    Err(Error::NotFound(_)) => {
+      metrics::login_failed();
+      (Status::Unauthorized, 0)
+    }
+    Err(e) => {
+      error!("udp token lookup error: {:?}", e);
+      (Status::ServerError, 0)
+    }
+  };
+  metrics::udp_packet(status.as_str());
+
+  let a = ack(packet.id, status, stored);
+  if status == Status::Ok {
+    recent.add(key, a.clone());
+  }
+  Some(a)
+}
+
+// listen on the configured port.  Packets are handled one at a time, on a
+// thread of their own, each logged as a request.
+pub fn start(db: web::Data<Db>, ip: &str, config: UdpConfig) -> Result<(), Error> {
+  let ip = config.ip.unwrap_or(ip.to_string());
+  let socket = UdpSocket::bind((ip.as_str(), config.port))?;
+  info!("udp ingest on {}:{}", ip, config.port);
+
+  thread::spawn(move || {
+    // one more than allowed, to see oversize packets.
+    let mut buf = [0u8; MAX_PACKET + 1];
+    let mut recent = Recent::default();
+    loop {
+      match socket.recv_from(&mut buf) {
+        Ok((n, from)) => logging::with_request(Some(logging::next_request_id()), || {
+          match handle(&**db, &mut recent, &buf[..n]) {
+            Some(a) => match socket.send_to(&a, from) {
+              Ok(_) => (),
+              Err(e) => warn!("udp ack to {} failed: {:?}", from, e),
+            },
+            None => (),
+          }
+        }),
+        Err(e) => error!("udp receive error: {:?}", e),
+      }
+    }
+  });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use storage_tests;
+
+  fn packet(id: u16, token: &[u8], measurements: &[(u8, u16, f32)]) -> Vec<u8> {
+    let mut buf = vec![VERSION, (id >> 8) as u8, id as u8];
+    buf.extend_from_slice(token);
+    for (index, age, value) in measurements.iter() {
+      let bits = value.to_bits();
+      buf.extend_from_slice(&[
+        *index,
+        (age >> 8) as u8,
+        *age as u8,
+        (bits >> 24) as u8,
+        (bits >> 16) as u8,
+        (bits >> 8) as u8,
+        bits as u8,
+      ]);
+    }
+    buf
+  }
+
+  fn from_hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+      .collect()
+  }
+
+  const TOKEN: [u8; TOKEN_BYTES] = [7; TOKEN_BYTES];
+
+  #[test]
+  fn parse() {
+    let buf = packet(0x1234, &TOKEN, &[(0, 60, 21.5), (3, 0x0102, -1.0)]);
+    assert_eq!(message_id(&buf), Some(0x1234));
+    assert_eq!(
+      parse_packet(&buf),
+      Ok(Packet {
+        id: 0x1234,
+        token: TOKEN.to_vec(),
+        measurements: vec![
+          CompactMeasurement {
+            index: 0,
+            age: 60,
+            value: 21.5,
+          },
+          CompactMeasurement {
+            index: 3,
+            age: 0x0102,
+            value: -1.0,
+          },
+        ],
+      })
+    );
+
+    // just the token.
+    assert_eq!(
+      parse_packet(&packet(1, &TOKEN, &[])).map(|p| p.measurements.len()),
+      Ok(0)
+    );
+  }
+
+  #[test]
+  fn malformed() {
+    let buf = packet(1, &TOKEN, &[(0, 0, 1.0)]);
+    assert_eq!(message_id(&buf[..2]), None);
+    assert_eq!(parse_packet(&buf[..2]), Err(Status::Malformed));
+    assert_eq!(
+      parse_packet(&buf[..HEADER_BYTES - 1]),
+      Err(Status::Malformed)
+    );
+    // a partial measurement.
+    assert_eq!(parse_packet(&buf[..buf.len() - 1]), Err(Status::Malformed));
+
+    let mut wrong = buf.clone();
+    wrong[0] = VERSION + 1;
+    assert_eq!(parse_packet(&wrong), Err(Status::UnknownVersion));
+    // too short is malformed whatever the version.
+    assert_eq!(parse_packet(&wrong[..4]), Err(Status::Malformed));
+
+    for v in [std::f32::NAN, std::f32::INFINITY, std::f32::NEG_INFINITY].iter() {
+      assert_eq!(
+        parse_packet(&packet(1, &TOKEN, &[(0, 0, 1.0), (1, 0, *v)])),
+        Err(Status::Malformed)
+      );
+    }
+  }
+
+  #[test]
+  fn packet_size() {
+    let most = (MAX_PACKET - HEADER_BYTES) / MEASUREMENT_BYTES;
+    let fits: Vec<(u8, u16, f32)> = (0..most).map(|i| (i as u8, 0, 1.0)).collect();
+    let buf = packet(1, &TOKEN, &fits);
+    assert!(buf.len() <= MAX_PACKET);
+    assert_eq!(parse_packet(&buf).map(|p| p.measurements.len()), Ok(most));
+
+    let mut over = buf.clone();
+    over.resize(MAX_PACKET + 1, 0);
+    assert_eq!(parse_packet(&over), Err(Status::Malformed));
+    let more: Vec<(u8, u16, f32)> = (0..most + 1).map(|i| (i as u8, 0, 1.0)).collect();
+    assert_eq!(
+      parse_packet(&packet(1, &TOKEN, &more)),
+      Err(Status::Malformed)
+    );
+  }
+
+  #[test]
+  fn acks() {
+    assert_eq!(ack(0x1234, Status::Ok, 3), vec![VERSION, 0x12, 0x34, 0, 3]);
+    assert_eq!(ack(1, Status::ServerError, 0), vec![VERSION, 0, 1, 6, 0]);
+    // the count saturates.
+    assert_eq!(ack(1, Status::Ok, 1000)[4], 255);
+  }
+
+  #[test]
+  fn recent_acks_are_bounded() {
+    let mut recent = Recent::default();
+    for i in 0..RECENT_ACKS + 10 {
+      recent.add(i.to_string(), ack(i as u16, Status::Ok, 1));
+    }
+    assert_eq!(recent.acks.len(), RECENT_ACKS);
+    assert_eq!(recent.order.len(), RECENT_ACKS);
+    // the oldest went first.
+    assert!(!recent.acks.contains_key("9"));
+    assert!(recent.acks.contains_key("10"));
+    assert!(recent.acks.contains_key(&(RECENT_ACKS + 9).to_string()));
+  }
+
+  #[test]
+  fn duplicates_are_acked_not_stored() {
+    let (_tmp, db) = storage_tests::sqlite();
+    let u = storage_tests::user(&db);
+    let dev = storage_tests::device(&db, u.id, "dev");
+    let s0 = storage_tests::sensor(&db, u.id, dev, "s0");
+    let s1 = storage_tests::sensor(&db, u.id, dev, "s1");
+    let dt = make_token(&db, u.id, dev).unwrap();
+    assert_eq!(dt.sensors, vec![s0, s1]);
+    let token = from_hex(dt.token.as_str());
+    let mut recent = Recent::default();
+
+    let buf = packet(42, &token, &[(0, 0, 1.0), (1, 5, 2.0)]);
+    let first = handle(&db, &mut recent, &buf).unwrap();
+    assert_eq!(first, ack(42, Status::Ok, 2));
+    // a resend gets the same ack and stores nothing.
+    assert_eq!(handle(&db, &mut recent, &buf), Some(first));
+    let count = |s: i64| db.measurement_listing(u.id, s, None, None).unwrap().len();
+    assert_eq!((count(s0), count(s1)), (1, 1));
+
+    // a new message id is a new packet.
+    let next = packet(43, &token, &[(0, 0, 3.0)]);
+    assert_eq!(
+      handle(&db, &mut recent, &next),
+      Some(ack(43, Status::Ok, 1))
+    );
+    assert_eq!(count(s0), 2);
+
+    // failures aren't remembered, so they can be retried.
+    let bad = packet(44, &token, &[(0, 0, 4.0), (9, 0, 4.0)]);
+    assert_eq!(
+      handle(&db, &mut recent, &bad),
+      Some(ack(44, Status::BadSensor, 0))
+    );
+    assert_eq!(count(s0), 2);
+    assert!(!recent
+      .acks
+      .contains_key(&hex_digest(Algorithm::SHA256, &bad)));
+
+    // a device that rebooted and started its ids over isn't resending.
+    let rebooted = packet(42, &token, &[(0, 0, 5.0)]);
+    assert_eq!(
+      handle(&db, &mut recent, &rebooted),
+      Some(ack(42, Status::Ok, 1))
+    );
+    assert_eq!(count(s0), 3);
+
+    let stranger = packet(42, &[0; TOKEN_BYTES], &[(0, 0, 1.0)]);
+    assert_eq!(
+      handle(&db, &mut recent, &stranger),
+      Some(ack(42, Status::Unauthorized, 0))
+    );
+    // nothing short of a token gets a reply.
+    assert_eq!(handle(&db, &mut recent, &buf[..2]), None);
+    assert_eq!(handle(&db, &mut recent, &buf[..3]), None);
+    assert_eq!(handle(&db, &mut recent, &buf[..HEADER_BYTES - 1]), None);
+    assert_eq!(
+      handle(&db, &mut recent, &buf[..HEADER_BYTES + 1]),
+      Some(ack(42, Status::Malformed, 0))
+    );
+  }
+}
@@ -1,3 +1,4 @@
+use crypto_hash::{hex_digest, Algorithm};
 use rand;
 use rand::Rng;
 use std::error::Error;
@@ -25,6 +26,14 @@ pub fn salt_string() -> String {
   get_rand_string(10)
 }
 
+// the stored form of a password.
+pub fn hash_password(pwd: &str, salt: &str) -> String {
+  hex_digest(
+    Algorithm::SHA256,
+    (pwd.to_string() + salt).into_bytes().as_slice(),
+  )
+}
+
 pub fn get_rand_string(len: usize) -> String {
   let mut rng = rand::thread_rng();
   let mut rstr = String::with_capacity(len);